@@ -1,16 +1,72 @@
 use std::io::Write;
 
+use lua_ir::CompiledChunk;
 use lua_ir::LuaEnv;
 
+/// `exec --compile out.luac in.lua`; write the binary chunk of `in.lua` to `out.luac`.
+fn compile(output: &str, input: &str) -> Result<(), String> {
+    let source = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let env = LuaEnv::new();
+    let chunk = env
+        .compile(&source, input)
+        .map_err(|e| e.to_error_message(&env))?;
+    std::fs::write(output, chunk.dump(false)).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// `exec --dump-ir in.lua`; print the listing of the compiled code of `in.lua`.
+fn dump_ir(input: &str) -> Result<(), String> {
+    let source = std::fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+    let env = LuaEnv::new();
+    let chunk = if source.first() == Some(&0x1b) {
+        CompiledChunk::undump(&source, input)
+    } else {
+        env.compile(&source, input)
+    }
+    .map_err(|e| e.to_error_message(&env))?;
+    print!("{}", chunk.disassemble());
+    Ok(())
+}
+
 fn main() {
+    let mut arg = std::env::args();
+    arg.next();
+    let filename = arg.next();
+    if filename.as_deref() == Some("--compile") {
+        let (Some(output), Some(input)) = (arg.next(), arg.next()) else {
+            eprintln!("usage: exec --compile out.luac in.lua");
+            std::process::exit(1);
+        };
+        if let Err(message) = compile(&output, &input) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if filename.as_deref() == Some("--dump-ir") {
+        let Some(input) = arg.next() else {
+            eprintln!("usage: exec --dump-ir in.lua");
+            std::process::exit(1);
+        };
+        if let Err(message) = dump_ir(&input) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut env = LuaEnv::new();
     // env.eval_chunk(b"print('Hello, World!')").unwrap();
 
-    let mut arg = std::env::args();
-    arg.next();
-    if let Some(filename) = arg.next() {
-        let source = std::fs::read_to_string(&filename).expect("failed to read file");
-        match env.feed_line(source.as_bytes()) {
+    if let Some(filename) = filename {
+        let source = std::fs::read(&filename).expect("failed to read file");
+        // precompiled by `--compile`
+        let chunk = if source.first() == Some(&0x1b) {
+            CompiledChunk::undump(&source, &filename)
+        } else {
+            env.compile(&source, &filename)
+        };
+        let res = chunk.and_then(|chunk| env.run(&chunk, Vec::new()));
+        match res {
             Ok(_) => {}
             Err(e) => {
                 let message = e.to_error_message(&env);
@@ -0,0 +1,18 @@
+local keys = {}
+for i = 1, 1000 do
+    keys[i] = "key" .. i
+end
+
+local t = {}
+for round = 1, 100 do
+    for i = 1, #keys do
+        local k = keys[i]
+        t[k] = (t[k] or 0) + round
+    end
+end
+
+local sum = 0
+for i = 1, #keys do
+    sum = sum + t[keys[i]]
+end
+return sum
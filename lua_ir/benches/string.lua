@@ -0,0 +1,22 @@
+local parts = {}
+for i = 1, 20000 do
+    parts[#parts + 1] = i .. ":" .. tostring(i * 3)
+end
+local joined = table.concat(parts, ",")
+
+local count = 0
+for i = 1, #joined do
+    if string.byte(joined, i) == 44 then
+        count = count + 1
+    end
+end
+
+local acc = ""
+for i = 1, 2000 do
+    acc = acc .. string.sub(joined, i, i + 3) .. "-"
+end
+local upper = 0
+for i = 1, 5000 do
+    upper = upper + #string.upper("item" .. i)
+end
+return count + #acc + upper
@@ -0,0 +1,24 @@
+local t = {}
+for i = 1, 100000 do
+    t[i] = i * 2
+end
+local sum = 0
+for i = 1, #t do
+    sum = sum + t[i]
+end
+
+local points = {}
+for i = 1, 20000 do
+    points[i] = { x = i, y = i * 2 }
+end
+for _, p in ipairs(points) do
+    p.x, p.y = p.y, p.x
+    sum = sum + p.x - p.y
+end
+
+local counts = {}
+for i = 1, 50000 do
+    local key = i % 100
+    counts[key] = (counts[key] or 0) + 1
+end
+return sum + counts[7]
@@ -0,0 +1,50 @@
+//! Interpreter throughput on small Lua programs; `cargo bench -p lua_ir`.
+//!
+//! Each program is compiled once, then run repeatedly on a fresh `LuaEnv`.
+//! The best and the mean time per run are printed.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use lua_ir::LuaEnv;
+
+/// name and source of each benchmark
+const PROGRAMS: &[(&str, &[u8])] = &[
+    ("fib", include_bytes!("fib.lua")),
+    ("table", include_bytes!("table.lua")),
+    ("string", include_bytes!("string.lua")),
+    ("keys", include_bytes!("keys.lua")),
+];
+
+/// each benchmark runs at least this many times, and at least for `MIN_TIME`
+const MIN_RUNS: u32 = 5;
+const MIN_TIME: Duration = Duration::from_secs(2);
+
+fn main() {
+    // `cargo bench -- <filter>` runs the benchmarks whose name contains the filter
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    for (name, source) in PROGRAMS {
+        if filter
+            .as_deref()
+            .is_some_and(|filter| !name.contains(filter))
+        {
+            continue;
+        }
+        let chunk = LuaEnv::new().compile(source, name).unwrap();
+        let mut runs = 0;
+        let mut best = Duration::MAX;
+        let start = Instant::now();
+        while runs < MIN_RUNS || start.elapsed() < MIN_TIME {
+            let mut env = LuaEnv::new();
+            let begin = Instant::now();
+            env.run(&chunk, Vec::new()).unwrap();
+            best = best.min(begin.elapsed());
+            runs += 1;
+        }
+        let mean = start.elapsed() / runs;
+        println!(
+            "{:<8} best {:>10.3?}  mean {:>10.3?}  ({} runs)",
+            name, best, mean, runs
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::rc::Rc;
+
+use crate::FileSystem;
+use crate::LuaEnv;
+use crate::OptLevel;
+use crate::OsFileSystem;
+use crate::VirtualClock;
+
+bitflags::bitflags! {
+    /// Set of standard libraries to be loaded into [`LuaEnv`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StdLib: u32 {
+        /// basic functions; `print`, `pairs`, `pcall`, `_G`, ...
+        const BASE = 1 << 0;
+        /// `string` library
+        const STRING = 1 << 1;
+        /// `table` library
+        const TABLE = 1 << 2;
+        /// `math` library
+        const MATH = 1 << 3;
+        /// `coroutine` library
+        const COROUTINE = 1 << 4;
+        /// `io` library
+        const IO = 1 << 5;
+        /// `os` library
+        const OS = 1 << 6;
+        /// `utf8` library; not implemented yet
+        const UTF8 = 1 << 7;
+        /// `debug` library; only `debug.traceback` is implemented yet
+        const DEBUG = 1 << 8;
+        /// `package` library; not implemented yet
+        const PACKAGE = 1 << 9;
+
+        /// libraries without access to filesystem, process or interpreter internals
+        const SAFE = Self::BASE.bits()
+            | Self::STRING.bits()
+            | Self::TABLE.bits()
+            | Self::MATH.bits()
+            | Self::COROUTINE.bits()
+            | Self::UTF8.bits();
+    }
+}
+
+/// Builder for [`LuaEnv`], created by [`LuaEnv::builder`].
+#[derive(Clone)]
+pub struct LuaEnvBuilder {
+    pub(crate) stdlib: StdLib,
+    pub(crate) file_loading: bool,
+    pub(crate) file_system: Rc<dyn FileSystem>,
+    pub(crate) binary_chunks: bool,
+    pub(crate) opt_level: OptLevel,
+    /// seed of random number generator and virtual clock of deterministic mode
+    pub(crate) deterministic: Option<(u64, VirtualClock)>,
+}
+
+impl Default for LuaEnvBuilder {
+    fn default() -> Self {
+        LuaEnvBuilder {
+            stdlib: StdLib::all(),
+            file_loading: true,
+            file_system: Rc::new(OsFileSystem),
+            binary_chunks: true,
+            opt_level: OptLevel::default(),
+            deterministic: None,
+        }
+    }
+}
+
+impl LuaEnvBuilder {
+    /// Standard libraries to be loaded. Default is [`StdLib::all`].
+    pub fn stdlib(mut self, stdlib: StdLib) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+    /// If `false`, basic functions reading files, `dofile` and `loadfile`, are not loaded.
+    /// Default is `true`.
+    pub fn file_loading(mut self, file_loading: bool) -> Self {
+        self.file_loading = file_loading;
+        self
+    }
+    /// Filesystem for every file access. Default is [`OsFileSystem`].
+    pub fn file_system(mut self, file_system: impl FileSystem + 'static) -> Self {
+        self.file_system = Rc::new(file_system);
+        self
+    }
+    /// If `false`, loading precompiled binary chunks is not allowed.
+    /// Default is `true`.
+    pub fn binary_chunks(mut self, binary_chunks: bool) -> Self {
+        self.binary_chunks = binary_chunks;
+        self
+    }
+    /// Optimization of chunks compiled from source. Default is [`OptLevel::Full`].
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+    /// Deterministic mode, for reproducible execution across runs and machines:
+    /// - random number generator is seeded with `seed`, instead of entropy.
+    /// - `os.time`, `os.clock` and `os.date` read `clock` instead of the system clock.
+    /// - `tostring` of tables, functions, threads and userdata shows IDs numbered in order of appearance,
+    ///   instead of addresses.
+    ///
+    /// Iteration order of `pairs` and `next` depends only on the operations done on the table,
+    /// never on addresses.
+    pub fn deterministic(mut self, seed: u64, clock: VirtualClock) -> Self {
+        self.deterministic = Some((seed, clock));
+        self
+    }
+    /// Preset for running untrusted code;
+    /// loads [`StdLib::SAFE`] only, without filesystem access and binary chunks.
+    pub fn safe(self) -> Self {
+        self.stdlib(StdLib::SAFE)
+            .file_loading(false)
+            .binary_chunks(false)
+    }
+
+    pub fn build(self) -> LuaEnv {
+        LuaEnv::with_builder(self)
+    }
+}
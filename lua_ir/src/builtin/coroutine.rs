@@ -83,6 +83,7 @@ pub fn create(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     let func = env.pop();
     match func {
         LuaValue::Function(func) => {
+            env.charge_thread()?;
             env.push(LuaThread::new_coroutine(env, func).into());
             Ok(1)
         }
@@ -95,7 +96,7 @@ pub fn create(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
 pub fn isyieldable(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     // thread is yieldable if it is not the main thread
     let is_yieldable = match args {
-        0 => !Rc::ptr_eq(env.main_thread(), env.running_thread()),
+        0 => env.check_yieldable().is_ok(),
         _ => {
             env.pop_n(args - 1);
             let thread = env.pop();
@@ -139,6 +140,8 @@ pub fn resume(
     let mut co_borrow_mut = co.borrow_mut();
     match co_borrow_mut.status {
         ThreadStatus::Running => {
+            // `co` is the running thread
+            drop(co_borrow_mut);
             env.pop_n(args_num);
             // error resume() into current thread
             match expected_resume_return {
@@ -220,37 +223,9 @@ pub fn resume(
             drop(co_borrow_mut);
             let func = Rc::clone(co.borrow().function.as_ref().unwrap());
             env.coroutines.push(co);
-            let function_call_res = env.function_call(
-                args_num - 1,
-                LuaValue::Function(func),
-                expected_resume_return,
-            );
-            match function_call_res {
-                Ok(_) => {}
-
-                Err(err) => {
-                    let error_object = err.into_lua_value(env);
-                    env.coroutines.pop().unwrap().borrow_mut().set_dead();
-                    env.running_thread().borrow_mut().status = ThreadStatus::Running;
-
-                    match expected_resume_return {
-                        Some(0) => {}
-                        Some(1) => {
-                            env.push(false.into());
-                        }
-                        Some(expected_resume_return) => {
-                            env.push2(false.into(), error_object);
-                            env.running_thread().borrow_mut().data_stack.extend(
-                                std::iter::repeat(LuaValue::Nil).take(expected_resume_return - 2),
-                            );
-                        }
-                        None => {
-                            env.push2(false.into(), error_object);
-                        }
-                    }
-                }
-            }
-            Ok(())
+            run_resumed(env, |env| {
+                env.call_on_dispatch(args_num - 1, LuaValue::Function(func), None)
+            })
         }
         ThreadStatus::YieldPending(expected_yield_return) => {
             co_borrow_mut.status = ThreadStatus::Running;
@@ -270,48 +245,42 @@ pub fn resume(
             env.running_thread().borrow_mut().status =
                 ThreadStatus::ResumePending(expected_resume_return);
             env.coroutines.push(co);
-            let coroutine_len = env.coroutines.len();
-            while env.coroutines.len() >= coroutine_len {
-                let cycle_res = env.cycle();
-                match cycle_res {
-                    Ok(_) => {}
-                    Err(err) => {
-                        let error_object = err.into_lua_value(env);
-                        env.coroutines.pop().unwrap().borrow_mut().set_dead();
-                        env.running_thread().borrow_mut().status = ThreadStatus::Running;
-
-                        match expected_resume_return {
-                            Some(0) => {}
-                            Some(1) => {
-                                env.push(false.into());
-                            }
-                            Some(expected_resume_return) => {
-                                env.push2(false.into(), error_object);
-                                env.running_thread().borrow_mut().data_stack.extend(
-                                    std::iter::repeat(LuaValue::Nil)
-                                        .take(expected_resume_return - 2),
-                                );
-                            }
-                            None => {
-                                env.push2(false.into(), error_object);
-                            }
-                        }
-                    }
-                }
-            }
-
-            Ok(())
+            run_resumed(env, |_| Ok(()))
         }
     }
 }
+
+/// Run the resumed coroutine on the top of the coroutine stack until it yields, returns or raises an error.
+/// `start` is called first, on the resumed coroutine.
+///
+/// If `resume` was called by the dispatch loop, the coroutine is left to be run by the same loop.
+fn run_resumed(
+    env: &mut LuaEnv,
+    start: impl FnOnce(&mut LuaEnv) -> Result<(), RuntimeError>,
+) -> Result<(), RuntimeError> {
+    let coroutine_len = env.coroutines.len();
+    let mut res = match start(env) {
+        Ok(_) => Ok(()),
+        Err(err) => env.handle_error(err),
+    };
+    if env.is_continuable() {
+        return res;
+    }
+    let awaitable = std::mem::take(&mut env.awaitable);
+    while res.is_ok() && env.coroutines.len() >= coroutine_len {
+        res = env.cycle();
+    }
+    env.awaitable = awaitable;
+    res
+}
 pub fn yield_(
     env: &mut LuaEnv,
     args: usize,
     expected_yield_return: Option<usize>,
 ) -> Result<(), RuntimeError> {
-    if env.coroutines.len() == 1 {
+    if let Err(err) = env.check_yieldable() {
         env.pop_n(args);
-        return Err(RuntimeError::YieldOutsideCoroutine);
+        return Err(err);
     }
 
     let yield_thread = env.coroutines.pop().unwrap();
@@ -393,6 +362,8 @@ pub fn wrap(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     create(env, args)?;
     let co = env.pop();
 
+    let resume_func: LuaValue = LuaFunction::from_func_with_expected(resume).into();
+
     let wrap_inner =
         move |env: &mut LuaEnv, args: usize, expected: Option<usize>| -> Result<(), RuntimeError> {
             let mut thread = env.running_thread().borrow_mut();
@@ -401,40 +372,45 @@ pub fn wrap(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             thread.data_stack.insert(co_index, co.clone());
             drop(thread);
 
-            match expected {
-                Some(expected) => resume(env, args + 1, Some(expected + 1))?,
-                None => resume(env, args + 1, None)?,
-            }
+            let co = co.clone();
+            env.call_k(
+                args + 1,
+                resume_func.clone(),
+                expected.map(|expected| expected + 1),
+                move |env, res| {
+                    res?;
 
-            // bool, results*
-            // ^^^ co_index
-            let mut thread = env.running_thread().borrow_mut();
-            let res = thread.data_stack[co_index].to_bool();
-            if res {
-                // resume success
-                // remove bool from the stack
-                thread.data_stack.remove(co_index);
-                Ok(())
-            } else {
-                // resume fail
-                // bool, error_message
-                debug_assert!(thread.data_stack.len() == co_index + 2);
-                let error = thread.data_stack.pop().unwrap();
-                thread.data_stack.pop();
+                    // bool, results*
+                    // ^^^ co_index
+                    let mut thread = env.running_thread().borrow_mut();
+                    let res = thread.data_stack[co_index].to_bool();
+                    if res {
+                        // resume success
+                        // remove bool from the stack
+                        thread.data_stack.remove(co_index);
+                        Ok(())
+                    } else {
+                        // resume fail
+                        // bool, error_message
+                        debug_assert!(thread.data_stack.len() == co_index + 2);
+                        let error = thread.data_stack.pop().unwrap();
+                        thread.data_stack.pop();
 
-                // close coroutine
-                if let LuaValue::Thread(co) = &co {
-                    co.borrow_mut().set_dead();
-                } else {
-                    unreachable!("wrap: co is not a thread");
-                }
-                thread.data_stack.push(co.clone());
-                drop(thread);
-                close(env, 1)?;
+                        // close coroutine
+                        if let LuaValue::Thread(co) = &co {
+                            co.borrow_mut().set_dead();
+                        } else {
+                            unreachable!("wrap: co is not a thread");
+                        }
+                        thread.data_stack.push(co);
+                        drop(thread);
+                        close(env, 1)?;
 
-                // propagate error
-                Err(RuntimeError::Custom(error))
-            }
+                        // propagate error
+                        Err(RuntimeError::Custom(error))
+                    }
+                },
+            )
         };
     let func = LuaFunction::from_func_with_expected(wrap_inner);
     env.push(func.into());
@@ -0,0 +1,71 @@
+use std::rc::Rc;
+
+use crate::traceback::format_traceback;
+use crate::LuaEnv;
+use crate::LuaFunction;
+use crate::LuaTable;
+use crate::LuaValue;
+use crate::RuntimeError;
+
+/// init debug module
+pub fn init() -> Result<LuaValue, RuntimeError> {
+    let mut debug = LuaTable::new();
+
+    debug.insert("traceback".into(), LuaFunction::from_func(traceback).into());
+
+    Ok(debug.into())
+}
+
+/// `debug.traceback([thread,] [message [, level]])`
+pub fn traceback(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let mut args: Vec<_> = env.running_thread().borrow_mut().drain_last(args).collect();
+    let thread = match args.first() {
+        Some(LuaValue::Thread(thread)) => {
+            let thread = Rc::clone(thread);
+            args.remove(0);
+            Some(thread)
+        }
+        _ => None,
+    };
+    let mut args = args.into_iter();
+    let message = match args.next().unwrap_or_default() {
+        LuaValue::Nil => None,
+        LuaValue::String(s) => Some(s.to_string()),
+        LuaValue::Number(n) => Some(n.to_string()),
+        // other values are returned untouched
+        message => {
+            env.push(message);
+            return Ok(1);
+        }
+    };
+    let level = match args.next().unwrap_or_default() {
+        LuaValue::Nil => None,
+        level => {
+            let level = level
+                .try_to_int()
+                .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
+            Some(level.max(0) as usize)
+        }
+    };
+
+    let traceback = match thread {
+        Some(thread) if !Rc::ptr_eq(&thread, env.running_thread()) => {
+            thread.borrow().traceback(level.unwrap_or(0))
+        }
+        _ => {
+            // level 0 is `traceback` itself, which has no frame as a Rust function
+            let mut entries = env.running_thread().borrow().traceback_entries();
+            entries.insert(0, "[C]: in ?".to_string());
+            let level = level.unwrap_or(1).min(entries.len());
+            entries.drain(..level);
+            format_traceback(entries)
+        }
+    };
+    let traceback = match message {
+        Some(message) => format!("{}\n{}", message, traceback),
+        None => traceback,
+    };
+    env.charge_memory(traceback.len())?;
+    env.push(traceback.into());
+    Ok(1)
+}
@@ -1,57 +1,775 @@
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::stdio::InputStream;
+use crate::stdio::OutputStream;
+use crate::stdio::StdStreams;
+use crate::File;
+use crate::IntType;
 use crate::LuaEnv;
 use crate::LuaFunction;
+use crate::LuaString;
 use crate::LuaTable;
+use crate::LuaUserData;
 use crate::LuaValue;
+use crate::OpenOptions;
 use crate::RuntimeError;
 
-/// init math module
-pub fn init() -> Result<LuaValue, RuntimeError> {
+/// stream of a file handle
+pub(crate) enum Stream {
+    /// file opened from the filesystem
+    File(Box<dyn File>),
+    /// `io.stdin`
+    Input(InputStream),
+    /// `io.stdout` and `io.stderr`
+    Output(OutputStream),
+}
+
+fn bad_file_descriptor() -> std::io::Error {
+    std::io::Error::other("Bad file descriptor")
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::File(file) => file.read(buf),
+            Stream::Input(input) => input.borrow_mut().read(buf),
+            Stream::Output(_) => Err(bad_file_descriptor()),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::File(file) => file.write(buf),
+            Stream::Input(_) => Err(bad_file_descriptor()),
+            Stream::Output(output) => output.borrow_mut().write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::File(file) => file.flush(),
+            Stream::Input(_) => Ok(()),
+            Stream::Output(output) => output.borrow_mut().flush(),
+        }
+    }
+}
+impl Stream {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Stream::File(file) => file.seek(pos),
+            _ => Err(std::io::Error::other("Illegal seek")),
+        }
+    }
+    /// next byte of the stream, without consuming it
+    fn peek_byte(&mut self) -> std::io::Result<Option<u8>> {
+        match self {
+            Stream::File(file) => {
+                let byte = read_byte(file)?;
+                if byte.is_some() {
+                    file.seek(SeekFrom::Current(-1))?;
+                }
+                Ok(byte)
+            }
+            Stream::Input(input) => Ok(input.borrow_mut().fill_buf()?.first().copied()),
+            Stream::Output(_) => Err(bad_file_descriptor()),
+        }
+    }
+    fn is_standard(&self) -> bool {
+        !matches!(self, Stream::File(_))
+    }
+}
+
+/// file handle of `io` library
+pub(crate) struct LuaFile {
+    /// `None` if the file is closed
+    pub(crate) stream: Option<Stream>,
+}
+
+/// state of `io` library, shared by its functions
+struct IoLib {
+    /// metatable of file handles
+    meta: Rc<RefCell<LuaTable>>,
+    /// default input file, `io.input()`
+    input: RefCell<Rc<RefCell<LuaUserData>>>,
+    /// default output file, `io.output()`
+    output: RefCell<Rc<RefCell<LuaUserData>>>,
+}
+impl IoLib {
+    fn new_file(&self, stream: Stream) -> Rc<RefCell<LuaUserData>> {
+        Rc::new(RefCell::new(LuaUserData::new(
+            LuaFile {
+                stream: Some(stream),
+            },
+            Some(Rc::clone(&self.meta)),
+        )))
+    }
+    /// default input file; error if it is closed
+    fn input(&self) -> Result<Rc<RefCell<LuaUserData>>, RuntimeError> {
+        let input = Rc::clone(&self.input.borrow());
+        if is_closed(&input) {
+            return Err(RuntimeError::Custom("default input file is closed".into()));
+        }
+        Ok(input)
+    }
+    /// default output file; error if it is closed
+    fn output(&self) -> Result<Rc<RefCell<LuaUserData>>, RuntimeError> {
+        let output = Rc::clone(&self.output.borrow());
+        if is_closed(&output) {
+            return Err(RuntimeError::Custom("default output file is closed".into()));
+        }
+        Ok(output)
+    }
+    /// open `filename` through the filesystem of `env`; raise error on failure
+    fn open_or_error(
+        &self,
+        env: &LuaEnv,
+        filename: &str,
+        options: OpenOptions,
+    ) -> Result<Rc<RefCell<LuaUserData>>, RuntimeError> {
+        let file = env
+            .file_system()
+            .open(filename, options)
+            .map_err(|e| RuntimeError::Custom(crate::fs::error_message(filename, &e).into()))?;
+        Ok(self.new_file(Stream::File(file)))
+    }
+}
+
+/// init io module
+pub fn init(streams: &StdStreams) -> Result<LuaValue, RuntimeError> {
+    let mut methods = LuaTable::new();
+    methods.insert("close".into(), LuaFunction::from_func(file_close).into());
+    methods.insert("flush".into(), LuaFunction::from_func(file_flush).into());
+    methods.insert("lines".into(), LuaFunction::from_func(file_lines).into());
+    methods.insert("read".into(), LuaFunction::from_func(file_read).into());
+    methods.insert("seek".into(), LuaFunction::from_func(file_seek).into());
+    methods.insert(
+        "setvbuf".into(),
+        LuaFunction::from_func(file_setvbuf).into(),
+    );
+    methods.insert("write".into(), LuaFunction::from_func(file_write).into());
+
+    let mut meta = LuaTable::new();
+    meta.insert("__index".into(), methods.into());
+    meta.insert("__name".into(), "FILE*".into());
+    meta.insert(
+        "__tostring".into(),
+        LuaFunction::from_func(file_tostring).into(),
+    );
+    let meta = Rc::new(RefCell::new(meta));
+
+    let stdin = Rc::new(RefCell::new(LuaUserData::new(
+        LuaFile {
+            stream: Some(Stream::Input(Rc::clone(&streams.stdin))),
+        },
+        Some(Rc::clone(&meta)),
+    )));
+    let stdout = Rc::new(RefCell::new(LuaUserData::new(
+        LuaFile {
+            stream: Some(Stream::Output(Rc::clone(&streams.stdout))),
+        },
+        Some(Rc::clone(&meta)),
+    )));
+    let stderr = Rc::new(RefCell::new(LuaUserData::new(
+        LuaFile {
+            stream: Some(Stream::Output(Rc::clone(&streams.stderr))),
+        },
+        Some(Rc::clone(&meta)),
+    )));
+    let lib = Rc::new(IoLib {
+        meta,
+        input: RefCell::new(Rc::clone(&stdin)),
+        output: RefCell::new(Rc::clone(&stdout)),
+    });
+
     let mut io = LuaTable::new();
-    io.insert("close".into(), LuaFunction::from_func(close).into());
-    io.insert("flush".into(), LuaFunction::from_func(flush).into());
-    io.insert("input".into(), LuaFunction::from_func(input).into());
-    io.insert("lines".into(), LuaFunction::from_func(lines).into());
-    io.insert("open".into(), LuaFunction::from_func(open).into());
-    io.insert("output".into(), LuaFunction::from_func(output).into());
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "close".into(),
+            LuaFunction::from_func(move |env, args| close(env, args, &lib)).into(),
+        );
+    }
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "flush".into(),
+            LuaFunction::from_func(move |env, args| flush(env, args, &lib)).into(),
+        );
+    }
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "input".into(),
+            LuaFunction::from_func(move |env, args| input(env, args, &lib)).into(),
+        );
+    }
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "lines".into(),
+            LuaFunction::from_func(move |env, args| lines(env, args, &lib)).into(),
+        );
+    }
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "open".into(),
+            LuaFunction::from_func(move |env, args| open(env, args, &lib)).into(),
+        );
+    }
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "output".into(),
+            LuaFunction::from_func(move |env, args| output(env, args, &lib)).into(),
+        );
+    }
     io.insert("popen".into(), LuaFunction::from_func(popen).into());
-    io.insert("read".into(), LuaFunction::from_func(read).into());
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "read".into(),
+            LuaFunction::from_func(move |env, args| read(env, args, &lib)).into(),
+        );
+    }
     io.insert("tmpfile".into(), LuaFunction::from_func(tmpfile).into());
     io.insert("type".into(), LuaFunction::from_func(type_).into());
-    io.insert("write".into(), LuaFunction::from_func(write).into());
+    {
+        let lib = Rc::clone(&lib);
+        io.insert(
+            "write".into(),
+            LuaFunction::from_func(move |env, args| write(env, args, &lib)).into(),
+        );
+    }
+    io.insert("stdin".into(), LuaValue::UserData(stdin));
+    io.insert("stdout".into(), LuaValue::UserData(stdout));
+    io.insert("stderr".into(), LuaValue::UserData(stderr));
     Ok(io.into())
 }
 
-pub fn close(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.close");
+/// pop `args` arguments from the stack, in order.
+fn pop_args(env: &mut LuaEnv, args: usize) -> Vec<LuaValue> {
+    env.borrow_running_thread_mut().drain_last(args).collect()
 }
-pub fn flush(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.flush");
+
+/// push `nil, message, errno` for failed io operation
+fn push_io_error(env: &mut LuaEnv, path: Option<&str>, err: &std::io::Error) -> usize {
+    let message = match path {
+        Some(path) => crate::fs::error_message(path, err),
+        None => err.to_string(),
+    };
+    let errno = err.raw_os_error().unwrap_or(0) as IntType;
+    env.push3(LuaValue::Nil, message.into(), errno.into());
+    3
 }
-pub fn input(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.input");
+
+/// check if `value` is a file handle
+fn check_file(value: &LuaValue, arg: usize) -> Result<Rc<RefCell<LuaUserData>>, RuntimeError> {
+    match value {
+        LuaValue::UserData(userdata) if userdata.borrow().downcast_ref::<LuaFile>().is_some() => {
+            Ok(Rc::clone(userdata))
+        }
+        _ => Err(RuntimeError::BadArgument(
+            arg,
+            Box::new(RuntimeError::Expected("FILE*", value.type_str().into())),
+        )),
+    }
 }
-pub fn lines(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.lines");
+/// check if `value` is a filename
+fn check_filename(value: &LuaValue, arg: usize) -> Result<String, RuntimeError> {
+    match value {
+        LuaValue::String(s) => Ok(s.to_string()),
+        LuaValue::Number(n) => Ok(n.to_string()),
+        _ => Err(RuntimeError::BadArgument(
+            arg,
+            Box::new(RuntimeError::Expected("string", value.type_str().into())),
+        )),
+    }
 }
-pub fn open(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.open");
+fn is_closed(userdata: &Rc<RefCell<LuaUserData>>) -> bool {
+    matches!(
+        userdata.borrow().downcast_ref::<LuaFile>(),
+        Some(LuaFile { stream: None })
+    )
 }
-pub fn output(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.output");
+/// run `f` with the stream of `userdata`; error if the file is closed
+fn with_stream<R>(
+    userdata: &Rc<RefCell<LuaUserData>>,
+    f: impl FnOnce(&mut Stream) -> R,
+) -> Result<R, RuntimeError> {
+    let mut userdata = userdata.borrow_mut();
+    match userdata
+        .downcast_mut::<LuaFile>()
+        .and_then(|file| file.stream.as_mut())
+    {
+        Some(stream) => Ok(f(stream)),
+        None => Err(RuntimeError::Custom("attempt to use a closed file".into())),
+    }
 }
-pub fn popen(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.popen");
+
+/// format of `file:read`
+#[derive(Debug, Clone, Copy)]
+enum ReadFormat {
+    Number,
+    All,
+    /// `true` to keep the end of line
+    Line(bool),
+    Count(usize),
+}
+impl ReadFormat {
+    fn from_value(value: &LuaValue, arg: usize) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::Number(n) => {
+                let n = n
+                    .try_to_int()
+                    .map_err(|e| RuntimeError::BadArgument(arg, Box::new(e)))?;
+                Ok(ReadFormat::Count(n.max(0) as usize))
+            }
+            LuaValue::String(s) => {
+                let s = s.as_bytes();
+                let s = s.strip_prefix(b"*").unwrap_or(s);
+                match s.first() {
+                    Some(b'n') => Ok(ReadFormat::Number),
+                    Some(b'a') => Ok(ReadFormat::All),
+                    Some(b'l') => Ok(ReadFormat::Line(false)),
+                    Some(b'L') => Ok(ReadFormat::Line(true)),
+                    _ => Err(RuntimeError::BadArgument(
+                        arg,
+                        Box::new(RuntimeError::Custom("invalid format".into())),
+                    )),
+                }
+            }
+            _ => Err(RuntimeError::BadArgument(
+                arg,
+                Box::new(RuntimeError::Custom("invalid format".into())),
+            )),
+        }
+    }
+    fn from_args(args: &[LuaValue], first_arg: usize) -> Result<Vec<Self>, RuntimeError> {
+        if args.is_empty() {
+            return Ok(vec![ReadFormat::Line(false)]);
+        }
+        args.iter()
+            .enumerate()
+            .map(|(idx, arg)| Self::from_value(arg, first_arg + idx))
+            .collect()
+    }
+}
+
+fn read_byte(reader: &mut (impl Read + ?Sized)) -> std::io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// read a value in `format` from `stream`; `nil` on end of file
+fn read_format(stream: &mut Stream, format: ReadFormat) -> std::io::Result<LuaValue> {
+    match format {
+        ReadFormat::All => {
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf)?;
+            Ok(LuaString::from_vec(buf).into())
+        }
+        ReadFormat::Line(keep) => {
+            let mut buf = Vec::new();
+            loop {
+                match read_byte(stream)? {
+                    Some(b'\n') => {
+                        if keep {
+                            buf.push(b'\n');
+                        }
+                        break;
+                    }
+                    Some(byte) => buf.push(byte),
+                    None if buf.is_empty() => return Ok(LuaValue::Nil),
+                    None => break,
+                }
+            }
+            Ok(LuaString::from_vec(buf).into())
+        }
+        ReadFormat::Count(0) => {
+            // test end of file
+            match stream.peek_byte()? {
+                Some(_) => Ok(LuaString::from_static_str("").into()),
+                None => Ok(LuaValue::Nil),
+            }
+        }
+        ReadFormat::Count(n) => {
+            let mut buf = Vec::new();
+            stream.take(n as u64).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                Ok(LuaValue::Nil)
+            } else {
+                Ok(LuaString::from_vec(buf).into())
+            }
+        }
+        ReadFormat::Number => {
+            while stream.peek_byte()?.is_some_and(|b| b.is_ascii_whitespace()) {
+                read_byte(stream)?;
+            }
+            let mut buf = Vec::new();
+            while let Some(b) = stream.peek_byte()? {
+                if !(b.is_ascii_hexdigit() || b"+-.xXpP".contains(&b)) || buf.len() >= 200 {
+                    break;
+                }
+                buf.push(b);
+                read_byte(stream)?;
+            }
+            match LuaString::from_vec(buf).try_to_number() {
+                Ok(n) => Ok(n.into()),
+                Err(_) => Ok(LuaValue::Nil),
+            }
+        }
+    }
 }
 
-pub fn read(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.read");
+/// read values in `formats` from `stream`, stopping at the first `nil`.
+fn read_formats(stream: &mut Stream, formats: &[ReadFormat]) -> std::io::Result<Vec<LuaValue>> {
+    let mut values = Vec::with_capacity(formats.len());
+    for &format in formats {
+        let value = read_format(stream, format)?;
+        let is_nil = value.is_nil();
+        values.push(value);
+        if is_nil {
+            break;
+        }
+    }
+    Ok(values)
+}
+/// add the strings read by `read_formats` to the allocated bytes
+fn charge_read(env: &mut LuaEnv, values: &[LuaValue]) -> Result<(), RuntimeError> {
+    let bytes = values
+        .iter()
+        .map(|value| match value {
+            LuaValue::String(s) => s.len(),
+            _ => 0,
+        })
+        .sum();
+    env.charge_memory(bytes)
+}
+
+/// `file:close()`
+fn close_file(env: &mut LuaEnv, file: &Rc<RefCell<LuaUserData>>) -> Result<usize, RuntimeError> {
+    if with_stream(file, |stream| stream.is_standard())? {
+        env.push2(LuaValue::Nil, "cannot close standard file".into());
+        return Ok(2);
+    }
+    let res = with_stream(file, |stream| stream.flush())?;
+    if let Some(file) = file.borrow_mut().downcast_mut::<LuaFile>() {
+        file.stream = None;
+    }
+    match res {
+        Ok(_) => {
+            env.push(true.into());
+            Ok(1)
+        }
+        Err(err) => Ok(push_io_error(env, None, &err)),
+    }
+}
+/// `file:flush()`
+fn flush_file(env: &mut LuaEnv, file: Rc<RefCell<LuaUserData>>) -> Result<usize, RuntimeError> {
+    match with_stream(&file, |stream| stream.flush())? {
+        Ok(_) => {
+            env.push(LuaValue::UserData(file));
+            Ok(1)
+        }
+        Err(err) => Ok(push_io_error(env, None, &err)),
+    }
+}
+/// `file:read(...)`, with formats in `args` starting from argument `first_arg`
+fn read_file(
+    env: &mut LuaEnv,
+    file: &Rc<RefCell<LuaUserData>>,
+    args: &[LuaValue],
+    first_arg: usize,
+) -> Result<usize, RuntimeError> {
+    let formats = ReadFormat::from_args(args, first_arg)?;
+    match with_stream(file, |stream| read_formats(stream, &formats))? {
+        Ok(values) => {
+            charge_read(env, &values)?;
+            let len = values.len();
+            env.borrow_running_thread_mut().data_stack.extend(values);
+            Ok(len)
+        }
+        Err(err) => Ok(push_io_error(env, None, &err)),
+    }
+}
+/// `file:write(...)`, with values in `args` starting from argument `first_arg`
+fn write_file(
+    env: &mut LuaEnv,
+    file: Rc<RefCell<LuaUserData>>,
+    args: &[LuaValue],
+    first_arg: usize,
+) -> Result<usize, RuntimeError> {
+    let mut buf = Vec::new();
+    for (idx, arg) in args.iter().enumerate() {
+        match arg {
+            LuaValue::String(s) => buf.extend_from_slice(s.as_bytes()),
+            LuaValue::Number(n) => buf.extend_from_slice(n.to_string().as_bytes()),
+            _ => {
+                return Err(RuntimeError::BadArgument(
+                    first_arg + idx,
+                    Box::new(RuntimeError::Expected("string", arg.type_str().into())),
+                ))
+            }
+        }
+    }
+    match with_stream(&file, |stream| stream.write_all(&buf))? {
+        Ok(_) => {
+            env.push(LuaValue::UserData(file));
+            Ok(1)
+        }
+        Err(err) => Ok(push_io_error(env, None, &err)),
+    }
+}
+/// iterator function for `lines`; closes the file at the end of file if `close` is true
+fn lines_iterator(
+    file: Rc<RefCell<LuaUserData>>,
+    formats: Vec<ReadFormat>,
+    close: bool,
+) -> LuaValue {
+    LuaFunction::from_func(move |env, args| {
+        env.pop_n(args);
+        let values = with_stream(&file, |stream| read_formats(stream, &formats))?
+            .map_err(|e| RuntimeError::Custom(e.to_string().into()))?;
+        if values.first().is_none_or(|value| value.is_nil()) && close {
+            if let Some(file) = file.borrow_mut().downcast_mut::<LuaFile>() {
+                file.stream = None;
+            }
+        }
+        charge_read(env, &values)?;
+        let len = values.len();
+        env.borrow_running_thread_mut().data_stack.extend(values);
+        Ok(len)
+    })
+    .into()
+}
+
+fn close(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = match args.first() {
+        None | Some(LuaValue::Nil) => lib.output()?,
+        Some(file) => check_file(file, 1)?,
+    };
+    close_file(env, &file)
+}
+fn flush(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    env.pop_n(args);
+    let file = lib.output()?;
+    flush_file(env, file)
+}
+fn input(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    match args.first() {
+        None | Some(LuaValue::Nil) => {}
+        Some(LuaValue::UserData(_)) => {
+            *lib.input.borrow_mut() = check_file(&args[0], 1)?;
+        }
+        Some(filename) => {
+            let filename = check_filename(filename, 1)?;
+            let options = OpenOptions {
+                read: true,
+                ..Default::default()
+            };
+            *lib.input.borrow_mut() = lib.open_or_error(env, &filename, options)?;
+        }
+    }
+    env.push(LuaValue::UserData(Rc::clone(&lib.input.borrow())));
+    Ok(1)
+}
+fn lines(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let formats = ReadFormat::from_args(args.get(1..).unwrap_or_default(), 2)?;
+    let iterator = match args.first() {
+        None | Some(LuaValue::Nil) => lines_iterator(lib.input()?, formats, false),
+        Some(filename) => {
+            let filename = check_filename(filename, 1)?;
+            let options = OpenOptions {
+                read: true,
+                ..Default::default()
+            };
+            let file = lib.open_or_error(env, &filename, options)?;
+            lines_iterator(file, formats, true)
+        }
+    };
+    env.push(iterator);
+    Ok(1)
+}
+fn open(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let filename = match args.first() {
+        Some(filename) => check_filename(filename, 1)?,
+        None => return Err(RuntimeError::new_empty_argument(1, "string")),
+    };
+    let options = match args.get(1) {
+        None | Some(LuaValue::Nil) => OpenOptions::from_mode("r"),
+        Some(LuaValue::String(mode)) => OpenOptions::from_mode(&mode.to_string()),
+        Some(mode) => {
+            return Err(RuntimeError::BadArgument(
+                2,
+                Box::new(RuntimeError::Expected("string", mode.type_str().into())),
+            ))
+        }
+    };
+    let Some(options) = options else {
+        return Err(RuntimeError::BadArgument(
+            2,
+            Box::new(RuntimeError::Custom("invalid mode".into())),
+        ));
+    };
+    match env.file_system().open(&filename, options) {
+        Ok(file) => {
+            env.push(LuaValue::UserData(lib.new_file(Stream::File(file))));
+            Ok(1)
+        }
+        Err(err) => Ok(push_io_error(env, Some(&filename), &err)),
+    }
+}
+fn output(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    match args.first() {
+        None | Some(LuaValue::Nil) => {}
+        Some(LuaValue::UserData(_)) => {
+            *lib.output.borrow_mut() = check_file(&args[0], 1)?;
+        }
+        Some(filename) => {
+            let filename = check_filename(filename, 1)?;
+            let options = OpenOptions::from_mode("w").unwrap();
+            *lib.output.borrow_mut() = lib.open_or_error(env, &filename, options)?;
+        }
+    }
+    env.push(LuaValue::UserData(Rc::clone(&lib.output.borrow())));
+    Ok(1)
+}
+pub fn popen(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
+    Err(RuntimeError::NotImplemented("io.popen"))
+}
+fn read(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = lib.input()?;
+    read_file(env, &file, &args, 1)
 }
 pub fn tmpfile(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.tmpfile");
+    Err(RuntimeError::NotImplemented("io.tmpfile"))
+}
+pub fn type_(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    if args == 0 {
+        return Err(RuntimeError::new_empty_argument(1, "value"));
+    }
+    env.pop_n(args - 1);
+    let value = env.pop();
+    let res = match value {
+        LuaValue::UserData(userdata) => match userdata.borrow().downcast_ref::<LuaFile>() {
+            Some(LuaFile { stream: Some(_) }) => "file".into(),
+            Some(LuaFile { stream: None }) => "closed file".into(),
+            None => LuaValue::Nil,
+        },
+        _ => LuaValue::Nil,
+    };
+    env.push(res);
+    Ok(1)
+}
+fn write(env: &mut LuaEnv, args: usize, lib: &IoLib) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = lib.output()?;
+    write_file(env, file, &args, 1)
+}
+
+pub fn file_close(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    close_file(env, &file)
+}
+pub fn file_flush(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    flush_file(env, file)
+}
+pub fn file_lines(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    let formats = ReadFormat::from_args(&args[1..], 2)?;
+    env.push(lines_iterator(file, formats, false));
+    Ok(1)
+}
+pub fn file_read(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    read_file(env, &file, &args[1..], 2)
+}
+pub fn file_seek(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    // `IntType` is `i32` with `32bit` feature
+    #[allow(clippy::unnecessary_cast)]
+    let offset = match args.get(2) {
+        None | Some(LuaValue::Nil) => 0,
+        Some(offset) => offset
+            .try_to_int()
+            .map_err(|e| RuntimeError::BadArgument(3, Box::new(e)))? as i64,
+    };
+    let pos = match args.get(1) {
+        None | Some(LuaValue::Nil) => SeekFrom::Current(offset),
+        Some(LuaValue::String(whence)) => match whence.as_bytes() {
+            b"set" => SeekFrom::Start(offset.max(0) as u64),
+            b"cur" => SeekFrom::Current(offset),
+            b"end" => SeekFrom::End(offset),
+            _ => {
+                return Err(RuntimeError::BadArgument(
+                    2,
+                    Box::new(RuntimeError::Custom(
+                        format!("invalid option '{}'", whence).into(),
+                    )),
+                ))
+            }
+        },
+        Some(whence) => {
+            return Err(RuntimeError::BadArgument(
+                2,
+                Box::new(RuntimeError::Expected("string", whence.type_str().into())),
+            ))
+        }
+    };
+    match with_stream(&file, |stream| stream.seek(pos))? {
+        Ok(pos) => {
+            env.push((pos as IntType).into());
+            Ok(1)
+        }
+        Err(err) => Ok(push_io_error(env, None, &err)),
+    }
+}
+pub fn file_setvbuf(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    with_stream(&file, |_| ())?;
+    env.push(true.into());
+    Ok(1)
 }
-pub fn type_(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.type");
+pub fn file_write(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    write_file(env, file, &args[1..], 2)
 }
-pub fn write(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("io.write");
+pub fn file_tostring(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let args = pop_args(env, args);
+    let file = check_file(args.first().unwrap_or(&LuaValue::Nil), 1)?;
+    let s = if is_closed(&file) {
+        "file (closed)".to_string()
+    } else {
+        format!("file ({})", env.address_of(file))
+    };
+    env.push(s.into());
+    Ok(1)
 }
@@ -1,11 +1,9 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use rand::rngs::StdRng;
-use rand::Rng;
-use rand::SeedableRng;
-
+use crate::random::Xoshiro256;
 use crate::FloatType;
+use crate::IntType;
 use crate::LuaEnv;
 use crate::LuaFunction;
 use crate::LuaNumber;
@@ -322,65 +320,89 @@ pub fn ult(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
 }
 
 pub fn random(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
-    let rand = match args {
+    let rv = env.rng.next_u64();
+    let (low, up) = match args {
         0 => {
             // [0,1)
-            env.rng.gen_range(0.0..1.0).into()
+            env.push(Xoshiro256::float_of(rv).into());
+            return Ok(1);
         }
         1 => {
             // [1,num]
-            let arg = env.pop();
-            let arg = arg
+            let up = env
+                .pop()
                 .try_to_int()
                 .map_err(|e| RuntimeError::BadArgument(1, Box::new(e)))?;
-            env.rng.gen_range(1..=arg).into()
+            if up == 0 {
+                // full random integer
+                env.push((rv as IntType).into());
+                return Ok(1);
+            }
+            (1, up)
         }
-        _ => {
+        2 => {
             // [m, n]
-            env.pop_n(args - 2);
             let (m, n) = env.pop2();
-
             let m = m
                 .try_to_int()
                 .map_err(|e| RuntimeError::BadArgument(1, Box::new(e)))?;
             let n = n
                 .try_to_int()
                 .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
-
-            env.rng.gen_range(m..=n).into()
+            (m, n)
+        }
+        _ => {
+            env.pop_n(args);
+            return Err(RuntimeError::Custom("wrong number of arguments".into()));
         }
     };
-    env.push(rand);
+    if low > up {
+        return Err(RuntimeError::BadArgument(
+            1,
+            Box::new(RuntimeError::Custom("interval is empty".into())),
+        ));
+    }
+    let rand = env.rng.int_in_range(rv, low, up);
+    env.push(rand.into());
     Ok(1)
 }
 
+/// returns the two seed components
 pub fn randomseed(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
-    match args {
+    let (n1, n2) = match args {
         0 => {
-            env.rng = StdRng::from_entropy();
-        }
-        1 => {
-            let seed = env.pop();
-            let seed = seed
-                .try_to_int()
-                .map_err(|e| RuntimeError::BadArgument(1, Box::new(e)))?;
-            env.rng = StdRng::seed_from_u64(seed as u64);
+            if env.is_deterministic() {
+                // keep the sequence reproducible in deterministic mode
+                (env.rng.next_u64() as IntType, env.rng.next_u64() as IntType)
+            } else {
+                (
+                    rand::random::<u64>() as IntType,
+                    rand::random::<u64>() as IntType,
+                )
+            }
         }
         _ => {
-            env.pop_n(args - 2);
-            let (seed1, seed2) = env.pop2();
-            let seed1 = seed1
+            let (n1, n2) = if args == 1 {
+                (env.pop(), LuaValue::Nil)
+            } else {
+                env.pop_n(args - 2);
+                env.pop2()
+            };
+            let n1 = n1
                 .try_to_int()
                 .map_err(|e| RuntimeError::BadArgument(1, Box::new(e)))?;
-            let seed2 = seed2
-                .try_to_int()
-                .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
-            // @TODO this should be 128bit seed
-            let seed = (seed1.rotate_left(32) ^ seed2) as u64;
-            env.rng = StdRng::seed_from_u64(seed as u64);
+            let n2 = match n2 {
+                LuaValue::Nil => 0,
+                n2 => n2
+                    .try_to_int()
+                    .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?,
+            };
+            (n1, n2)
         }
-    }
-    Ok(0)
+    };
+    env.rng = Xoshiro256::from_seed(n1, n2);
+    env.push2(n1.into(), n2.into());
+    Ok(2)
 }
 
 pub fn modf(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
@@ -424,6 +446,12 @@ pub fn fmod(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     let y = y
         .try_to_number()
         .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
+    if let (LuaNumber::Int(_), LuaNumber::Int(0)) = (x, y) {
+        return Err(RuntimeError::BadArgument(
+            2,
+            Box::new(RuntimeError::Custom("zero".into())),
+        ));
+    }
     env.push((x % y).into());
     Ok(1)
 }
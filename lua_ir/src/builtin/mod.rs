@@ -1,17 +1,21 @@
 use lua_tokenizer::IntType;
 
-use std::io::Read;
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::rc::Rc;
 
+use crate::stdio::StdStreams;
+use crate::CompiledChunk;
 use crate::LuaEnv;
 use crate::LuaFunction;
-use crate::LuaFunctionLua;
 use crate::LuaNumber;
 use crate::LuaTable;
 use crate::LuaValue;
 use crate::RuntimeError;
+use crate::StdLib;
 
-mod coroutine;
+pub(crate) mod coroutine;
+mod debug;
 mod io;
 mod math;
 mod os;
@@ -22,119 +26,293 @@ pub use string::init_string_metatable;
 
 const VERSION: &str = "Lua 5.4 in Rust";
 
-/// generate default `_ENV` table
-pub fn init_env() -> Result<LuaTable, RuntimeError> {
+/// generate default `_ENV` table with libraries in `stdlib`.
+/// If `file_loading` is false, basic functions loading files are not loaded.
+/// `io` library uses `streams` for `io.stdin`, `io.stdout` and `io.stderr`.
+pub(crate) fn init_env(
+    stdlib: StdLib,
+    file_loading: bool,
+    streams: &StdStreams,
+) -> Result<LuaTable, RuntimeError> {
     // @TODO
     let mut env: LuaTable = LuaTable::new();
-    env.insert(
-        "pcall".into(),
-        LuaFunction::from_func_with_expected(pcall).into(),
-    );
-    env.insert("xpcall".into(), LuaFunction::from_func(xpcall).into());
-    env.insert("print".into(), LuaFunction::from_func(print).into());
-    env.insert("rawequal".into(), LuaFunction::from_func(rawequal).into());
-    env.insert("rawlen".into(), LuaFunction::from_func(rawlen).into());
-    env.insert("rawget".into(), LuaFunction::from_func(rawget).into());
-    env.insert("rawset".into(), LuaFunction::from_func(rawset).into());
-    env.insert("type".into(), LuaFunction::from_func(type_).into());
-    env.insert("tostring".into(), LuaFunction::from_func(tostring).into());
-    env.insert("select".into(), LuaFunction::from_func(select).into());
-    env.insert(
-        "setmetatable".into(),
-        LuaFunction::from_func(setmetatable).into(),
-    );
-    env.insert(
-        "getmetatable".into(),
-        LuaFunction::from_func(getmetatable).into(),
-    );
-    env.insert("assert".into(), LuaFunction::from_func(assert).into());
-    env.insert("error".into(), LuaFunction::from_func(error).into());
-
-    env.insert("ipairs".into(), LuaFunction::from_func(ipairs).into());
-    env.insert("next".into(), LuaFunction::from_func(next).into());
-    env.insert("pairs".into(), LuaFunction::from_func(pairs).into());
-    env.insert("tonumber".into(), LuaFunction::from_func(tonumber).into());
-    env.insert(
-        "collectgarbage".into(),
-        LuaFunction::from_func(collectgarbage).into(),
-    );
-    env.insert("load".into(), LuaFunction::from_func(load).into());
-    env.insert("loadfile".into(), LuaFunction::from_func(loadfile).into());
-    env.insert(
-        "dofile".into(),
-        LuaFunction::from_func_with_expected(dofile).into(),
-    );
+    if stdlib.contains(StdLib::BASE) {
+        env.insert(
+            "pcall".into(),
+            LuaFunction::from_func_with_expected(pcall).into(),
+        );
+        env.insert("xpcall".into(), LuaFunction::from_func(xpcall).into());
+        env.insert("print".into(), LuaFunction::from_func(print).into());
+        {
+            // warnings are off by default; turned on and off by `warn("@on")`, `warn("@off")`
+            let enabled = Rc::new(Cell::new(false));
+            env.insert(
+                "warn".into(),
+                LuaFunction::from_func(move |env, args| warn(env, args, &enabled)).into(),
+            );
+        }
+        env.insert("rawequal".into(), LuaFunction::from_func(rawequal).into());
+        env.insert("rawlen".into(), LuaFunction::from_func(rawlen).into());
+        env.insert("rawget".into(), LuaFunction::from_func(rawget).into());
+        env.insert("rawset".into(), LuaFunction::from_func(rawset).into());
+        env.insert("type".into(), LuaFunction::from_func(type_).into());
+        env.insert("tostring".into(), LuaFunction::from_func(tostring).into());
+        env.insert("select".into(), LuaFunction::from_func(select).into());
+        env.insert(
+            "setmetatable".into(),
+            LuaFunction::from_func(setmetatable).into(),
+        );
+        env.insert(
+            "getmetatable".into(),
+            LuaFunction::from_func(getmetatable).into(),
+        );
+        env.insert("assert".into(), LuaFunction::from_func(assert).into());
+        env.insert("error".into(), LuaFunction::from_func(error).into());
+
+        env.insert("ipairs".into(), LuaFunction::from_func(ipairs).into());
+        env.insert("next".into(), LuaFunction::from_func(next).into());
+        env.insert("pairs".into(), LuaFunction::from_func(pairs).into());
+        env.insert("tonumber".into(), LuaFunction::from_func(tonumber).into());
+        env.insert(
+            "collectgarbage".into(),
+            LuaFunction::from_func(collectgarbage).into(),
+        );
+        env.insert("load".into(), LuaFunction::from_func(load).into());
+        if file_loading {
+            env.insert("loadfile".into(), LuaFunction::from_func(loadfile).into());
+            env.insert(
+                "dofile".into(),
+                LuaFunction::from_func_with_expected(dofile).into(),
+            );
+        }
 
-    env.insert("_VERSION".into(), VERSION.into());
+        env.insert("_VERSION".into(), VERSION.into());
+    }
 
-    env.insert("string".into(), string::init()?.into());
-    env.insert("math".into(), math::init()?.into());
-    env.insert("table".into(), table::init()?.into());
-    env.insert("coroutine".into(), coroutine::init()?.into());
-    env.insert("os".into(), os::init()?.into());
-    env.insert("io".into(), io::init()?.into());
+    if stdlib.contains(StdLib::STRING) {
+        env.insert("string".into(), string::init()?);
+    }
+    if stdlib.contains(StdLib::MATH) {
+        env.insert("math".into(), math::init()?);
+    }
+    if stdlib.contains(StdLib::TABLE) {
+        env.insert("table".into(), table::init()?);
+    }
+    if stdlib.contains(StdLib::COROUTINE) {
+        env.insert("coroutine".into(), coroutine::init()?);
+    }
+    if stdlib.contains(StdLib::OS) {
+        env.insert("os".into(), os::init()?);
+    }
+    if stdlib.contains(StdLib::DEBUG) {
+        env.insert("debug".into(), debug::init()?);
+    }
+    if stdlib.contains(StdLib::IO) {
+        env.insert("io".into(), io::init(streams)?);
+    }
 
-    // `_G` will be added in `VM::new_stack()` or `Stack::new()`
+    // `_G` will be added in `LuaEnv::with_builder()`
     Ok(env)
 }
 
-fn load(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("load");
+fn load(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (chunk, chunkname, mode, chunk_env) = match args {
+        0 => return Err(RuntimeError::new_empty_argument(1, "string")),
+        1 => (env.pop(), LuaValue::Nil, LuaValue::Nil, LuaValue::Nil),
+        2 => {
+            let (chunk, chunkname) = env.pop2();
+            (chunk, chunkname, LuaValue::Nil, LuaValue::Nil)
+        }
+        3 => {
+            let (chunk, chunkname, mode) = env.pop3();
+            (chunk, chunkname, mode, LuaValue::Nil)
+        }
+        _ => {
+            env.pop_n(args - 4);
+            env.pop4()
+        }
+    };
+    let chunkname = match chunkname {
+        LuaValue::Nil => "=(load)".to_string(),
+        LuaValue::String(s) => s.to_string(),
+        chunkname => {
+            return Err(RuntimeError::BadArgument(
+                2,
+                Box::new(RuntimeError::Expected(
+                    "string",
+                    chunkname.type_str().into(),
+                )),
+            ))
+        }
+    };
+    let mode = mode_arg(mode, 3)?;
+    let chunk_env = chunk_env_arg(chunk_env, 4)?;
+
+    let res = match chunk {
+        LuaValue::String(s) => load_chunk(env, s.as_bytes(), &chunkname, &mode),
+        LuaValue::Function(_) => {
+            read_chunk_pieces(env, chunk).and_then(|buf| load_chunk(env, &buf, &chunkname, &mode))
+        }
+        chunk => {
+            return Err(RuntimeError::BadArgument(
+                1,
+                Box::new(RuntimeError::Expected("string", chunk.type_str().into())),
+            ))
+        }
+    };
+    push_loaded(env, res, chunk_env)
+}
+/// call `reader` until it returns `nil` or an empty string, and concatenate the returned pieces.
+fn read_chunk_pieces(env: &mut LuaEnv, reader: LuaValue) -> Result<Vec<u8>, RuntimeError> {
+    let mut buf = Vec::new();
+    loop {
+        env.function_call(0, reader.clone(), Some(1))?;
+        match env.pop() {
+            LuaValue::Nil => break,
+            LuaValue::String(s) if s.is_empty() => break,
+            LuaValue::String(s) => buf.extend_from_slice(s.as_bytes()),
+            _ => {
+                return Err(RuntimeError::Custom(
+                    "reader function must return a string".into(),
+                ))
+            }
+        }
+    }
+    Ok(buf)
+}
+/// compile `buf` as source code, or load it as a binary chunk, as allowed by `mode`.
+fn load_chunk(
+    env: &LuaEnv,
+    buf: &[u8],
+    chunkname: &str,
+    mode: &str,
+) -> Result<CompiledChunk, RuntimeError> {
+    if crate::dump::is_binary(buf) {
+        if !mode.contains('b') {
+            return Err(RuntimeError::Custom(
+                format!("attempt to load a binary chunk (mode is '{}')", mode).into(),
+            ));
+        }
+        if !env.binary_chunks_allowed() {
+            return Err(RuntimeError::Custom(
+                "attempt to load a binary chunk (binary chunks are not allowed)".into(),
+            ));
+        }
+        CompiledChunk::undump(buf, chunkname)
+    } else {
+        if !mode.contains('t') {
+            return Err(RuntimeError::Custom(
+                format!("attempt to load a text chunk (mode is '{}')", mode).into(),
+            ));
+        }
+        env.compile(buf, chunkname)
+    }
+}
+/// push the function of the loaded chunk, or `nil` and the error message.
+fn push_loaded(
+    env: &mut LuaEnv,
+    res: Result<CompiledChunk, RuntimeError>,
+    chunk_env: Option<Rc<RefCell<LuaTable>>>,
+) -> Result<usize, RuntimeError> {
+    match res {
+        Ok(chunk) => {
+            env.push(LuaFunction::LuaFunc(chunk.to_function(chunk_env)).into());
+            Ok(1)
+        }
+        Err(err @ RuntimeError::BadArgument(..)) => Err(err),
+        Err(err) => {
+            let message = err.into_lua_value(env);
+            env.push2(LuaValue::Nil, message);
+            Ok(2)
+        }
+    }
+}
+/// `mode` argument of `load` and `loadfile`; `"bt"` if `nil`.
+fn mode_arg(mode: LuaValue, index: usize) -> Result<String, RuntimeError> {
+    match mode {
+        LuaValue::Nil => Ok("bt".to_string()),
+        LuaValue::String(mode) => Ok(mode.to_string()),
+        mode => Err(RuntimeError::BadArgument(
+            index,
+            Box::new(RuntimeError::Expected("string", mode.type_str().into())),
+        )),
+    }
+}
+/// `env` argument of `load` and `loadfile`; `None` for the global environment.
+fn chunk_env_arg(
+    chunk_env: LuaValue,
+    index: usize,
+) -> Result<Option<Rc<RefCell<LuaTable>>>, RuntimeError> {
+    match chunk_env {
+        LuaValue::Nil => Ok(None),
+        LuaValue::Table(table) => Ok(Some(table)),
+        chunk_env => Err(RuntimeError::BadArgument(
+            index,
+            Box::new(RuntimeError::Expected("table", chunk_env.type_str().into())),
+        )),
+    }
 }
-fn loadfile(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("loadfile");
+/// read the source of a chunk for `dofile` and `loadfile`;
+/// from the file `filename` through the filesystem of `env`, or from stdin of `env` if `filename` is `nil`.
+/// returns the source and the name of the chunk.
+fn read_chunk_source(
+    env: &mut LuaEnv,
+    filename: LuaValue,
+) -> Result<(Vec<u8>, String), RuntimeError> {
+    let filename = match filename {
+        LuaValue::Nil => return Ok((env.read_stdin()?, "stdin".to_string())),
+        LuaValue::Number(n) => n.to_string(),
+        LuaValue::String(s) => s.to_string(),
+        filename => {
+            return Err(RuntimeError::BadArgument(
+                1,
+                Box::new(RuntimeError::Expected("string", filename.type_str().into())),
+            ))
+        }
+    };
+    let buf = env.read_file(&filename)?;
+    Ok((buf, filename))
+}
+fn loadfile(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (filename, mode, chunk_env) = match args {
+        0 => (LuaValue::Nil, LuaValue::Nil, LuaValue::Nil),
+        1 => (env.pop(), LuaValue::Nil, LuaValue::Nil),
+        2 => {
+            let (filename, mode) = env.pop2();
+            (filename, mode, LuaValue::Nil)
+        }
+        _ => {
+            env.pop_n(args - 3);
+            env.pop3()
+        }
+    };
+    let chunk_env = chunk_env_arg(chunk_env, 3)?;
+    let mode = mode_arg(mode, 2)?;
+
+    let res = read_chunk_source(env, filename)
+        .and_then(|(buf, chunkname)| load_chunk(env, &buf, &chunkname, &mode));
+    push_loaded(env, res, chunk_env)
 }
 fn dofile(env: &mut LuaEnv, args: usize, expected_ret: Option<usize>) -> Result<(), RuntimeError> {
-    let buf = if args == 0 {
-        let mut buf = String::new();
-        std::io::stdin().read_to_string(&mut buf).map_err(|e| {
-            RuntimeError::Custom(format!("failed to read from stdin: {}", e).into())
-        })?;
-        buf.into_bytes()
+    let filename = if args == 0 {
+        LuaValue::Nil
     } else {
         env.pop_n(args - 1);
-        let filename = env.pop();
-        match filename {
-            LuaValue::Nil => {
-                let mut buf = String::new();
-                std::io::stdin().read_to_string(&mut buf).map_err(|e| {
-                    RuntimeError::Custom(format!("failed to read from stdin: {}", e).into())
-                })?;
-                buf.into_bytes()
-            }
-            LuaValue::Number(n) => {
-                let filename = n.to_string();
-                env.read_file(&filename)?
-            }
-            LuaValue::String(s) => {
-                let filename = s.to_string();
-                env.read_file(&filename)?
-            }
-            filename => {
-                return Err(RuntimeError::BadArgument(
-                    1,
-                    Box::new(RuntimeError::Expected("string", filename.type_str().into())),
-                ))
-            }
-        }
+        env.pop()
     };
+    let (buf, chunkname) = read_chunk_source(env, filename)?;
 
-    let chunk = env.load_chunk(&buf)?;
+    let chunk = load_chunk(env, &buf, &chunkname, "bt")?;
     drop(buf);
-    let func = LuaFunctionLua {
-        chunk,
-        args: 0,
-        is_variadic: false,
-        upvalues: Vec::new(),
-    };
-    let func = LuaFunction::LuaFunc(func);
-    env.function_call(0, func.into(), expected_ret)
+    let func = LuaFunction::LuaFunc(chunk.to_function(None));
+    // returned values are already adjusted to `expected_ret`
+    env.call_k(0, func.into(), expected_ret, |_, _| Ok(()))
 }
 fn collectgarbage(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("collectgarbage");
+    Err(RuntimeError::NotImplemented("collectgarbage"))
 }
 fn xpcall(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("xpcall");
+    Err(RuntimeError::NotImplemented("xpcall"))
 }
 fn tonumber(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     match args {
@@ -168,7 +346,7 @@ fn tonumber(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
                     Box::new(RuntimeError::BaseOutOfRange),
                 ));
             }
-            let _value = match value {
+            let value = match value {
                 LuaValue::String(s) => s,
                 _ => {
                     return Err(RuntimeError::BadArgument(
@@ -177,11 +355,36 @@ fn tonumber(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
                     ))
                 }
             };
-            unreachable!("tonumber with more than 1 argument");
+            let res = match str_to_int_base(value.as_bytes(), base as u32) {
+                Some(n) => LuaValue::from(n),
+                None => LuaValue::Nil,
+            };
+            env.push(res);
+            Ok(1)
         }
     }
 }
 
+/// integer numeral `s` in `base`, surrounded by optional whitespaces; wraps around on overflow
+fn str_to_int_base(s: &[u8], base: u32) -> Option<IntType> {
+    let s = s.trim_ascii();
+    let (negative, digits) = match s.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: IntType = 0;
+    for &c in digits {
+        let digit = (c as char).to_digit(base)?;
+        n = n
+            .wrapping_mul(base as IntType)
+            .wrapping_add(digit as IntType);
+    }
+    Some(if negative { n.wrapping_neg() } else { n })
+}
+
 pub fn pcall(
     env: &mut LuaEnv,
     args: usize,
@@ -191,81 +394,89 @@ pub fn pcall(
         return Err(RuntimeError::new_empty_argument(1, "value"));
     }
 
-    let coroutine_count = env.coroutines.len();
-    let thread_borrow = env.running_thread().borrow();
-    let mut thread_state = thread_borrow.to_state();
-    thread_state.data_stack -= args;
-    let func = thread_borrow.data_stack[thread_state.data_stack].clone();
-    drop(thread_borrow);
-
-    // @TODO use call stack frame
-    match expected_ret {
-        Some(0) => match env.function_call(args - 1, func, Some(0)) {
-            Ok(_) => Ok(()),
-            Err(_e) => {
-                env.coroutines.truncate(coroutine_count);
-                env.running_thread().borrow_mut().from_state(thread_state);
-                Ok(())
-            }
-        },
-        Some(expected_ret) => match env.function_call(args - 1, func, Some(expected_ret - 1)) {
-            Ok(_) => {
-                env.running_thread().borrow_mut().data_stack[thread_state.data_stack] =
-                    LuaValue::Boolean(true);
-                Ok(())
-            }
-            Err(e) => {
-                env.coroutines.truncate(coroutine_count);
-                let error_obj = e.into_lua_value(env);
-                let mut thread_mut = env.running_thread().borrow_mut();
-                thread_mut.from_state(thread_state);
-                thread_mut.data_stack.push(false.into());
-                if expected_ret > 1 {
-                    thread_mut.data_stack.push(error_obj);
+    // function object stays on the stack, it will be replaced by the status boolean
+    let status_index = env.running_thread().borrow().data_stack.len() - args;
+    let func = env.top_i(args - 1);
+    env.pcall_k(
+        args - 1,
+        func,
+        expected_ret.map(|expected| expected.saturating_sub(1)),
+        move |env, res| {
+            let mut thread_mut = env.running_thread().borrow_mut();
+            match res {
+                Ok(_) => {
+                    thread_mut.data_stack[status_index] = true.into();
                 }
-                if expected_ret > 2 {
-                    thread_mut
-                        .data_stack
-                        .extend(std::iter::repeat(LuaValue::Nil).take(expected_ret - 2));
+                Err(err) => {
+                    drop(thread_mut);
+                    let error_obj = err.into_lua_value(env);
+                    thread_mut = env.running_thread().borrow_mut();
+                    thread_mut.data_stack[status_index] = false.into();
+                    thread_mut.data_stack.push(error_obj);
                 }
-                Ok(())
-            }
-        },
-        None => match env.function_call(args - 1, func, None) {
-            Ok(_) => {
-                env.running_thread().borrow_mut().data_stack[thread_state.data_stack] =
-                    LuaValue::Boolean(true);
-                Ok(())
             }
-            Err(e) => {
-                env.coroutines.truncate(coroutine_count);
-                let error_obj = e.into_lua_value(env);
-                let mut thread_mut = env.running_thread().borrow_mut();
-                thread_mut.from_state(thread_state);
-                thread_mut.data_stack.push(false.into());
-                thread_mut.data_stack.push(error_obj);
-                Ok(())
+            if let Some(expected) = expected_ret {
+                thread_mut
+                    .data_stack
+                    .resize_with(status_index + expected, Default::default);
             }
+            Ok(())
         },
-    }
+    )
 }
 pub fn print(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let mut buf = Vec::new();
     for i in 0..args {
         if i > 0 {
-            print!("\t");
+            buf.push(b'\t');
         }
         let ith = env.top_i(args - i - 1);
         env.push(ith);
         env.tostring()?;
         let s = env.pop();
         if let LuaValue::String(s) = s {
-            print!("{}", s);
+            buf.extend_from_slice(s.as_bytes());
         } else {
             unreachable!("string expected");
         }
     }
-    println!();
+    buf.push(b'\n');
     env.pop_n(args);
+    env.write_stdout(&buf)?;
+    Ok(0)
+}
+fn warn(env: &mut LuaEnv, args: usize, enabled: &Cell<bool>) -> Result<usize, RuntimeError> {
+    if args == 0 {
+        return Err(RuntimeError::new_empty_argument(1, "string"));
+    }
+    let mut message = Vec::new();
+    for (idx, arg) in env.borrow_running_thread_mut().drain_last(args).enumerate() {
+        match arg {
+            LuaValue::String(s) => message.extend_from_slice(s.as_bytes()),
+            LuaValue::Number(n) => message.extend_from_slice(n.to_string().as_bytes()),
+            arg => {
+                return Err(RuntimeError::BadArgument(
+                    idx + 1,
+                    Box::new(RuntimeError::Expected("string", arg.type_str().into())),
+                ))
+            }
+        }
+    }
+    // control message
+    if args == 1 && message.first() == Some(&b'@') {
+        match &message[1..] {
+            b"on" => enabled.set(true),
+            b"off" => enabled.set(false),
+            _ => {}
+        }
+        return Ok(0);
+    }
+    if enabled.get() {
+        let mut buf = b"Lua warning: ".to_vec();
+        buf.extend_from_slice(&message);
+        buf.push(b'\n');
+        env.write_stderr(&buf)?;
+    }
     Ok(0)
 }
 pub fn rawequal(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
@@ -357,6 +568,7 @@ pub fn rawset(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     } else if key.is_nan() {
         return Err(RuntimeError::TableIndexNan);
     }
+    env.charge_table_entries(1)?;
     table.borrow_mut().insert(key, value);
     env.push(LuaValue::Table(table));
     Ok(1)
@@ -368,7 +580,7 @@ pub fn select(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
 
     let index = env.top_i(args - 1);
     if let LuaValue::String(s) = &index {
-        if s[0] == b'#' {
+        if s.as_bytes().first() == Some(&b'#') {
             env.pop_n(args);
             env.push(((args - 1) as IntType).into());
             return Ok(1);
@@ -384,7 +596,7 @@ pub fn select(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             Box::new(RuntimeError::IndexOutOfRange),
         ));
     } else if index < 0 {
-        if (-index) as usize > args - 1 {
+        if index < -((args - 1) as IntType) {
             env.pop_n(args);
             return Err(RuntimeError::BadArgument(
                 1,
@@ -394,7 +606,7 @@ pub fn select(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             (args as IntType + index - 1) as usize
         }
     } else {
-        if index as usize > args - 1 {
+        if index > (args - 1) as IntType {
             env.pop_n(args);
             return Ok(0);
         } else {
@@ -424,8 +636,8 @@ pub fn setmetatable(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError
         if let Some(meta_old) = &table.borrow().meta {
             if meta_old
                 .borrow()
-                .map
-                .contains_key(&LuaValue::from("__metatable"))
+                .get_table(&LuaValue::from("__metatable"))
+                .is_some()
             {
                 // try to modify protected metatable (__metatable defined)
                 return Err(RuntimeError::Custom(
@@ -617,69 +829,21 @@ pub fn next(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
         }
     };
 
-    // iterate table through
-    // integer keys (array part) first, then hash part
-
     match table {
         LuaValue::Table(table) => {
-            match index {
-                // index is nil, get first key for iteration
-                LuaValue::Nil => {
-                    if let Some((k, v)) = table.borrow().arr.first_key_value() {
-                        env.push2((*k).into(), v.clone());
-                        Ok(2)
-                    } else {
-                        // no array part
-                        if let Some((k, v)) = table.borrow().map.first() {
-                            env.push2(k.clone(), v.clone());
-                            Ok(2)
-                        } else {
-                            // no hash part
-                            env.push(LuaValue::Nil);
-                            Ok(1)
-                        }
-                    }
+            // array part first, then hash part
+            let next = table.borrow().next(&index);
+            match next {
+                Some(Some((k, v))) => {
+                    env.push2(k, v);
+                    Ok(2)
                 }
-
-                // index is integer, get next element in array part
-                LuaValue::Number(LuaNumber::Int(n)) => {
-                    let table = table.borrow();
-                    let mut range_it = table.arr.range(n..);
-                    if range_it.next().map(|(k, _)| *k) == Some(n) {
-                        if let Some((k, v)) = range_it.next() {
-                            env.push2((*k).into(), v.clone());
-                            Ok(2)
-                        } else {
-                            // n is the last element, check hash part
-                            if let Some((k, v)) = table.map.first() {
-                                env.push2(k.clone(), v.clone());
-                                Ok(2)
-                            } else {
-                                // no hash part
-                                env.push(LuaValue::Nil);
-                                Ok(1)
-                            }
-                        }
-                    } else {
-                        Err(RuntimeError::Error)
-                    }
-                }
-
-                index => {
-                    // hash part
-                    if let Some(cur_idx) = table.borrow().map.get_index_of(&index) {
-                        if let Some((k, v)) = table.borrow().map.get_index(cur_idx + 1) {
-                            env.push2(k.clone(), v.clone());
-                            Ok(2)
-                        } else {
-                            // no more elements
-                            env.push(LuaValue::Nil);
-                            Ok(1)
-                        }
-                    } else {
-                        Err(RuntimeError::Error)
-                    }
+                // no more elements
+                Some(None) => {
+                    env.push(LuaValue::Nil);
+                    Ok(1)
                 }
+                None => Err(RuntimeError::Error),
             }
         }
         // @TODO next() with non-table was possible...
@@ -1,5 +1,8 @@
+use crate::FloatType;
+use crate::IntType;
 use crate::LuaEnv;
 use crate::LuaFunction;
+use crate::LuaString;
 use crate::LuaTable;
 use crate::LuaValue;
 use crate::RuntimeError;
@@ -24,45 +27,403 @@ pub fn init() -> Result<LuaValue, RuntimeError> {
     Ok(os.into())
 }
 
-pub fn clock(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.clock");
+/// broken-down time, in UTC
+#[derive(Debug, Clone, Copy)]
+struct DateTime {
+    year: IntType,
+    /// 1..=12
+    month: IntType,
+    /// 1..=31
+    day: IntType,
+    hour: IntType,
+    min: IntType,
+    sec: IntType,
+    /// day of week, 1..=7, Sunday is 1
+    wday: IntType,
+    /// day of year, 1..=366
+    yday: IntType,
 }
-pub fn date(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.date");
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// number of days since 1970-01-01 of the date in proleptic Gregorian calendar
+fn days_from_civil(year: IntType, month: IntType, day: IntType) -> IntType {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+/// date of the number of days since 1970-01-01; `(year, month, day)`
+fn civil_from_days(days: IntType) -> (IntType, IntType, IntType) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// broken-down time of seconds since Unix epoch
+    fn from_timestamp(time: IntType) -> Self {
+        let days = time.div_euclid(86400);
+        let secs = time.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            // 1970-01-01 is Thursday
+            wday: (days + 4).rem_euclid(7) + 1,
+            yday: days - days_from_civil(year, 1, 1) + 1,
+        }
+    }
+    /// seconds since Unix epoch; fields out of range are normalized
+    fn timestamp(&self) -> IntType {
+        let year = self.year + (self.month - 1).div_euclid(12);
+        let month = (self.month - 1).rem_euclid(12) + 1;
+        let days = days_from_civil(year, month, 1) + self.day - 1;
+        days * 86400 + self.hour * 3600 + self.min * 60 + self.sec
+    }
+
+    fn to_table(self) -> LuaTable {
+        let mut table = LuaTable::new();
+        table.insert("year".into(), self.year.into());
+        table.insert("month".into(), self.month.into());
+        table.insert("day".into(), self.day.into());
+        table.insert("hour".into(), self.hour.into());
+        table.insert("min".into(), self.min.into());
+        table.insert("sec".into(), self.sec.into());
+        table.insert("wday".into(), self.wday.into());
+        table.insert("yday".into(), self.yday.into());
+        table.insert("isdst".into(), false.into());
+        table
+    }
+    /// `strftime` with `format`
+    fn format(&self, format: &[u8], out: &mut Vec<u8>) -> Result<(), RuntimeError> {
+        let weekday = WEEKDAYS[(self.wday - 1) as usize];
+        let month = MONTHS[(self.month - 1) as usize];
+        let hour12 = if self.hour % 12 == 0 {
+            12
+        } else {
+            self.hour % 12
+        };
+        let mut chars = format.iter();
+        while let Some(&c) = chars.next() {
+            if c != b'%' {
+                out.push(c);
+                continue;
+            }
+            let s = match chars.next() {
+                Some(b'a') => weekday[..3].to_string(),
+                Some(b'A') => weekday.to_string(),
+                Some(b'b') | Some(b'h') => month[..3].to_string(),
+                Some(b'B') => month.to_string(),
+                Some(b'c') => format!(
+                    "{} {} {:2} {:02}:{:02}:{:02} {}",
+                    &weekday[..3],
+                    &month[..3],
+                    self.day,
+                    self.hour,
+                    self.min,
+                    self.sec,
+                    self.year
+                ),
+                Some(b'C') => format!("{:02}", self.year.div_euclid(100)),
+                Some(b'd') => format!("{:02}", self.day),
+                Some(b'D') | Some(b'x') => format!(
+                    "{:02}/{:02}/{:02}",
+                    self.month,
+                    self.day,
+                    self.year.rem_euclid(100)
+                ),
+                Some(b'e') => format!("{:2}", self.day),
+                Some(b'F') => format!("{}-{:02}-{:02}", self.year, self.month, self.day),
+                Some(b'H') => format!("{:02}", self.hour),
+                Some(b'I') => format!("{:02}", hour12),
+                Some(b'j') => format!("{:03}", self.yday),
+                Some(b'm') => format!("{:02}", self.month),
+                Some(b'M') => format!("{:02}", self.min),
+                Some(b'n') => "\n".to_string(),
+                Some(b'p') => if self.hour < 12 { "AM" } else { "PM" }.to_string(),
+                Some(b'r') => format!(
+                    "{:02}:{:02}:{:02} {}",
+                    hour12,
+                    self.min,
+                    self.sec,
+                    if self.hour < 12 { "AM" } else { "PM" }
+                ),
+                Some(b'R') => format!("{:02}:{:02}", self.hour, self.min),
+                Some(b'S') => format!("{:02}", self.sec),
+                Some(b't') => "\t".to_string(),
+                Some(b'T') | Some(b'X') => {
+                    format!("{:02}:{:02}:{:02}", self.hour, self.min, self.sec)
+                }
+                Some(b'u') => format!("{}", (self.wday + 5) % 7 + 1),
+                Some(b'w') => format!("{}", self.wday - 1),
+                Some(b'y') => format!("{:02}", self.year.rem_euclid(100)),
+                Some(b'Y') => format!("{}", self.year),
+                Some(b'z') => "+0000".to_string(),
+                Some(b'Z') => "UTC".to_string(),
+                Some(b'%') => "%".to_string(),
+                c => {
+                    let conversion = c.map(|&c| (c as char).to_string()).unwrap_or_default();
+                    return Err(RuntimeError::BadArgument(
+                        1,
+                        Box::new(RuntimeError::Custom(
+                            format!("invalid conversion specifier '%{}'", conversion).into(),
+                        )),
+                    ));
+                }
+            };
+            out.extend_from_slice(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// current time of the clock of `env`, in seconds since Unix epoch
+fn now(env: &LuaEnv) -> IntType {
+    env.clock.time().floor() as IntType
 }
 
-pub fn difftime(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.difftime");
+/// `os.clock`; seconds elapsed since `LuaEnv` was created, or on the virtual clock in deterministic mode
+pub fn clock(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    env.pop_n(args);
+    let clock = env.clock.clock() as FloatType;
+    env.push(clock.into());
+    Ok(1)
+}
+/// `os.date`; local time is UTC
+pub fn date(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (format, time) = match args {
+        0 => (LuaValue::Nil, LuaValue::Nil),
+        1 => (env.pop(), LuaValue::Nil),
+        _ => {
+            env.pop_n(args - 2);
+            env.pop2()
+        }
+    };
+    let time = match time {
+        LuaValue::Nil => now(env),
+        time => time
+            .try_to_int()
+            .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?,
+    };
+    let format = match &format {
+        LuaValue::Nil => b"%c".as_slice(),
+        LuaValue::String(s) => s.as_bytes(),
+        format => {
+            return Err(RuntimeError::BadArgument(
+                1,
+                Box::new(RuntimeError::Expected("string", format.type_str().into())),
+            ))
+        }
+    };
+    let format = format.strip_prefix(b"!").unwrap_or(format);
+    let datetime = DateTime::from_timestamp(time);
+    if format.starts_with(b"*t") {
+        // year, month, day, hour, min, sec, wday, yday and isdst
+        env.charge_table(9)?;
+        env.push(datetime.to_table().into());
+    } else {
+        let mut out = Vec::new();
+        datetime.format(format, &mut out)?;
+        env.charge_memory(out.len())?;
+        env.push(LuaString::from_vec(out).into());
+    }
+    Ok(1)
+}
+
+pub fn difftime(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (t2, t1) = match args {
+        0 => return Err(RuntimeError::new_empty_argument(1, "number")),
+        1 => return Err(RuntimeError::new_empty_argument(2, "number")),
+        _ => {
+            env.pop_n(args - 2);
+            env.pop2()
+        }
+    };
+    let t2 = t2
+        .try_to_int()
+        .map_err(|e| RuntimeError::BadArgument(1, Box::new(e)))?;
+    let t1 = t1
+        .try_to_int()
+        .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
+    env.push(((t2 - t1) as FloatType).into());
+    Ok(1)
 }
 
 pub fn execute(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.execute");
+    Err(RuntimeError::NotImplemented("os.execute"))
 }
 
 pub fn exit(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.exit");
+    Err(RuntimeError::NotImplemented("os.exit"))
 }
 
 pub fn getenv(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.getenv");
+    Err(RuntimeError::NotImplemented("os.getenv"))
+}
+
+/// check if `value` is a filename
+fn check_filename(value: LuaValue, arg: usize) -> Result<String, RuntimeError> {
+    match value {
+        LuaValue::String(s) => Ok(s.to_string()),
+        LuaValue::Number(n) => Ok(n.to_string()),
+        value => Err(RuntimeError::BadArgument(
+            arg,
+            Box::new(RuntimeError::Expected("string", value.type_str().into())),
+        )),
+    }
+}
+/// push `true`, or `nil, message, errno` for failed operation
+fn push_result(env: &mut LuaEnv, path: &str, res: std::io::Result<()>) -> usize {
+    match res {
+        Ok(_) => {
+            env.push(true.into());
+            1
+        }
+        Err(err) => {
+            let errno = err.raw_os_error().unwrap_or(0) as IntType;
+            env.push3(
+                LuaValue::Nil,
+                crate::fs::error_message(path, &err).into(),
+                errno.into(),
+            );
+            3
+        }
+    }
 }
 
-pub fn remove(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.remove");
+pub fn remove(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    if args == 0 {
+        return Err(RuntimeError::new_empty_argument(1, "string"));
+    }
+    env.pop_n(args - 1);
+    let filename = check_filename(env.pop(), 1)?;
+    let res = env.file_system().remove(&filename);
+    Ok(push_result(env, &filename, res))
 }
 
-pub fn rename(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.rename");
+pub fn rename(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (from, to) = match args {
+        0 => return Err(RuntimeError::new_empty_argument(1, "string")),
+        1 => return Err(RuntimeError::new_empty_argument(2, "string")),
+        _ => {
+            env.pop_n(args - 2);
+            env.pop2()
+        }
+    };
+    let from = check_filename(from, 1)?;
+    let to = check_filename(to, 2)?;
+    let res = env.file_system().rename(&from, &to);
+    Ok(push_result(env, &from, res))
 }
 
 pub fn setlocale(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.setlocale");
+    Err(RuntimeError::NotImplemented("os.setlocale"))
+}
+
+/// get integer field `key` of date table, `default` if absent
+fn date_field(
+    table: &LuaTable,
+    key: &'static str,
+    default: Option<IntType>,
+) -> Result<IntType, RuntimeError> {
+    match table.get(&key.into()) {
+        None | Some(LuaValue::Nil) => default.ok_or_else(|| {
+            RuntimeError::Custom(format!("field '{}' missing in date table", key).into())
+        }),
+        Some(value) => {
+            let value = value.try_to_int().map_err(|_| {
+                RuntimeError::Custom(format!("field '{}' is not an integer", key).into())
+            })?;
+            if !(i32::MIN as IntType..=i32::MAX as IntType).contains(&value) {
+                return Err(RuntimeError::Custom(
+                    format!("field '{}' is out-of-bound", key).into(),
+                ));
+            }
+            Ok(value)
+        }
+    }
 }
 
-pub fn time(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.time");
+/// `os.time`; local time is UTC
+pub fn time(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    if args == 0 {
+        let time = now(env);
+        env.push(time.into());
+        return Ok(1);
+    }
+    env.pop_n(args - 1);
+    let table = match env.pop() {
+        LuaValue::Nil => {
+            let time = now(env);
+            env.push(time.into());
+            return Ok(1);
+        }
+        LuaValue::Table(table) => table,
+        table => {
+            return Err(RuntimeError::BadArgument(
+                1,
+                Box::new(RuntimeError::Expected("table", table.type_str().into())),
+            ))
+        }
+    };
+    let time = {
+        let table = table.borrow();
+        DateTime {
+            year: date_field(&table, "year", None)?,
+            month: date_field(&table, "month", None)?,
+            day: date_field(&table, "day", None)?,
+            hour: date_field(&table, "hour", Some(12))?,
+            min: date_field(&table, "min", Some(0))?,
+            sec: date_field(&table, "sec", Some(0))?,
+            wday: 0,
+            yday: 0,
+        }
+        .timestamp()
+    };
+    // update fields with normalized values
+    for (key, value) in DateTime::from_timestamp(time).to_table().map {
+        table.borrow_mut().insert(key, value);
+    }
+    env.push(time.into());
+    Ok(1)
 }
 
 pub fn tmpname(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("os.tmpname");
+    Err(RuntimeError::NotImplemented("os.tmpname"))
 }
@@ -4,6 +4,7 @@ use std::rc::Rc;
 use crate::IntType;
 use crate::LuaEnv;
 use crate::LuaFunction;
+use crate::LuaNumber;
 use crate::LuaString;
 use crate::LuaTable;
 use crate::LuaValue;
@@ -39,29 +40,57 @@ pub fn init() -> Result<LuaValue, RuntimeError> {
     Ok(LuaValue::Table(Rc::new(RefCell::new(string))))
 }
 
-pub fn dump(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.dump");
+pub fn dump(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    let (function, strip) = match args {
+        0 => return Err(RuntimeError::new_empty_argument(1, "function")),
+        1 => (env.pop(), false),
+        _ => {
+            env.pop_n(args - 2);
+            let (function, strip) = env.pop2();
+            (function, strip.to_bool())
+        }
+    };
+    let binary = match &function {
+        LuaValue::Function(f) => match &*f.borrow() {
+            LuaFunction::LuaFunc(f) => crate::dump::dump_function(f, strip),
+            LuaFunction::RustFunc(_) => {
+                return Err(RuntimeError::Custom("unable to dump given function".into()))
+            }
+        },
+        _ => {
+            return Err(RuntimeError::BadArgument(
+                1,
+                Box::new(RuntimeError::Expected(
+                    "function",
+                    function.type_str().into(),
+                )),
+            ))
+        }
+    };
+    env.charge_memory(binary.len())?;
+    env.push(binary.into());
+    Ok(1)
 }
 pub fn format(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.format");
+    Err(RuntimeError::NotImplemented("string.format"))
 }
 pub fn gmatch(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.gmatch");
+    Err(RuntimeError::NotImplemented("string.gmatch"))
 }
 pub fn gsub(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.gsub");
+    Err(RuntimeError::NotImplemented("string.gsub"))
 }
 pub fn match_(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.match");
+    Err(RuntimeError::NotImplemented("string.match"))
 }
 pub fn pack(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.pack");
+    Err(RuntimeError::NotImplemented("string.pack"))
 }
 pub fn packsize(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.packsize");
+    Err(RuntimeError::NotImplemented("string.packsize"))
 }
 pub fn unpack(_env: &mut LuaEnv, _args: usize) -> Result<usize, RuntimeError> {
-    unimplemented!("string.unpack");
+    Err(RuntimeError::NotImplemented("string.unpack"))
 }
 
 pub fn sub_impl(s: &[u8], mut i: IntType, mut j: IntType) -> &'_ [u8] {
@@ -156,15 +185,11 @@ pub fn byte(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     Ok(sub.len())
 }
 pub fn sub(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
-    if args == 0 {
-    } else if args == 1 {
-        env.pop();
-    }
     let (s, i, j) = match args {
         0 => return Err(RuntimeError::new_empty_argument(1, "string")),
         1 => {
             env.pop();
-            return Err(RuntimeError::new_empty_argument(1, "number"));
+            return Err(RuntimeError::new_empty_argument(2, "number"));
         }
         2 => {
             let (s, i) = env.pop2();
@@ -210,6 +235,7 @@ pub fn sub(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     };
 
     let sub = sub_impl(s.as_bytes(), i, j);
+    env.charge_memory(sub.len())?;
     env.push(LuaString::from_slice(sub).into());
     Ok(1)
 }
@@ -230,7 +256,9 @@ pub fn char_(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
         }
         s.push(ch as u8);
     }
-    thread_mut.data_stack.push(LuaString::from_vec(s).into());
+    drop(thread_mut);
+    env.charge_memory(s.len())?;
+    env.push(LuaString::from_vec(s).into());
     Ok(1)
 }
 
@@ -269,13 +297,15 @@ pub fn lower(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     let arg = env.pop();
     match arg {
         LuaValue::String(s) => {
+            env.charge_memory(s.len())?;
             let ret = LuaValue::String(s.into_mapped(|c| c.to_ascii_lowercase()));
             env.push(ret);
             Ok(1)
         }
         LuaValue::Number(n) => {
-            let ret = LuaValue::String(LuaString::from_string(n.to_string()));
-            env.push(ret);
+            let ret = LuaString::from_string(n.to_string());
+            env.charge_memory(ret.len())?;
+            env.push(ret.into());
             Ok(1)
         }
         _ => {
@@ -295,13 +325,15 @@ pub fn upper(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     let arg = env.pop();
     match arg {
         LuaValue::String(s) => {
+            env.charge_memory(s.len())?;
             let ret = LuaValue::String(s.into_mapped(|c| c.to_ascii_uppercase()));
             env.push(ret);
             Ok(1)
         }
         LuaValue::Number(n) => {
-            let ret = LuaValue::String(LuaString::from_string(n.to_string()));
-            env.push(ret);
+            let ret = LuaString::from_string(n.to_string());
+            env.charge_memory(ret.len())?;
+            env.push(ret.into());
             Ok(1)
         }
         _ => {
@@ -334,12 +366,8 @@ pub fn rep(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             let n = n
                 .try_to_int()
                 .map_err(|e| RuntimeError::BadArgument(2, Box::new(e)))?;
-            if n <= 0 {
-                env.push(LuaString::from_static_str("").into());
-            } else {
-                env.push(LuaString::from_vec(s.into_vec().repeat(n as usize)).into());
-            }
-
+            let ret = rep_impl(env, s.as_bytes(), n, b"")?;
+            env.push(LuaString::from_vec(ret).into());
             Ok(1)
         }
         _ => {
@@ -370,24 +398,34 @@ pub fn rep(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
                 }
             };
 
-            if n <= 0 {
-                env.push(LuaString::from_static_str("").into());
-            } else {
-                let mut ret =
-                    Vec::with_capacity(s.len() * n as usize + sep.len() * (n as usize - 1));
-                for i in 0..n {
-                    if i != 0 {
-                        ret.extend_from_slice(sep.as_bytes());
-                    }
-                    ret.extend_from_slice(s.as_bytes());
-                }
-                env.push(LuaString::from_vec(ret).into());
-            }
-
+            let ret = rep_impl(env, s.as_bytes(), n, sep.as_bytes())?;
+            env.push(LuaString::from_vec(ret).into());
             Ok(1)
         }
     }
 }
+/// `n` copies of `s` separated by `sep`
+fn rep_impl(env: &mut LuaEnv, s: &[u8], n: IntType, sep: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+    if n <= 0 || s.len() + sep.len() == 0 {
+        return Ok(Vec::new());
+    }
+    let n = n as usize;
+    let len = (s.len() + sep.len())
+        .checked_mul(n)
+        .ok_or_else(|| RuntimeError::Custom("resulting string too large".into()))?
+        - sep.len();
+    env.charge_memory(len)?;
+    let mut ret = Vec::new();
+    ret.try_reserve_exact(len)
+        .map_err(|_| RuntimeError::OutOfMemory)?;
+    for i in 0..n {
+        if i != 0 {
+            ret.extend_from_slice(sep);
+        }
+        ret.extend_from_slice(s);
+    }
+    Ok(ret)
+}
 
 pub fn reverse(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     if args == 0 {
@@ -406,6 +444,7 @@ pub fn reverse(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             ))
         }
     };
+    env.charge_memory(s.len())?;
     let mut s = s.into_vec();
     s.reverse();
     env.push(LuaString::from_vec(s).into());
@@ -610,6 +649,9 @@ pub(crate) fn string_mod(env: &mut LuaEnv, args: usize) -> Result<usize, Runtime
             return Err(RuntimeError::AttemptTo("mod", "number", s2.type_str()));
         }
     };
+    if let (LuaNumber::Int(_), LuaNumber::Int(0)) = (s1, s2) {
+        return Err(RuntimeError::Custom("attempt to perform 'n%%0'".into()));
+    }
     let res = s1 % s2;
     env.push(res.into());
     Ok(1)
@@ -652,6 +694,9 @@ pub(crate) fn string_idiv(env: &mut LuaEnv, args: usize) -> Result<usize, Runtim
             return Err(RuntimeError::AttemptTo("idiv", "number", s2.type_str()));
         }
     };
+    if let (LuaNumber::Int(_), LuaNumber::Int(0)) = (s1, s2) {
+        return Err(RuntimeError::Custom("attempt to perform 'n//0'".into()));
+    }
     let res = s1.floor_div(s2);
     env.push(res.into());
     Ok(1)
@@ -1,5 +1,4 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
 use std::rc::Rc;
 
 use crate::IntType;
@@ -10,6 +9,9 @@ use crate::LuaTable;
 use crate::LuaValue;
 use crate::RuntimeError;
 
+/// maximum number of values `table.unpack` can return
+const MAX_UNPACK: usize = 1_000_000;
+
 /// init table module
 pub fn init() -> Result<LuaValue, RuntimeError> {
     let mut table = LuaTable::new();
@@ -19,7 +21,10 @@ pub fn init() -> Result<LuaValue, RuntimeError> {
     table.insert("move".into(), LuaFunction::from_func(move_).into());
     table.insert("pack".into(), LuaFunction::from_func(pack).into());
     table.insert("remove".into(), LuaFunction::from_func(remove).into());
-    table.insert("sort".into(), LuaFunction::from_func(sort).into());
+    table.insert(
+        "sort".into(),
+        LuaFunction::from_func_with_expected(sort).into(),
+    );
     table.insert("unpack".into(), LuaFunction::from_func(unpack).into());
 
     Ok(LuaValue::Table(Rc::new(RefCell::new(table))))
@@ -136,7 +141,9 @@ pub fn concat(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
         return Ok(1);
     }
 
-    let mut ret = Vec::with_capacity(sep.len() * (j - i) as usize + ((j - i + 1) * 4) as usize);
+    // the range may span far beyond the table; it fails at the first missing element
+    let count = (j.abs_diff(i) as usize).min(list.borrow().len() as usize);
+    let mut ret = Vec::with_capacity(sep.len() * count + (count + 1) * 4);
     for k in i..=j {
         if k != i {
             ret.extend_from_slice(sep.as_bytes());
@@ -160,6 +167,7 @@ pub fn concat(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             }
         }
     }
+    env.charge_memory(ret.len())?;
     env.push(LuaString::from_vec(ret).into());
     Ok(1)
 }
@@ -177,8 +185,9 @@ pub fn insert(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             let (table, value) = env.pop2();
             match table {
                 LuaValue::Table(table) => {
+                    env.charge_table_entries(1)?;
                     let len = table.borrow().len();
-                    table.borrow_mut().arr.insert(len + 1, value);
+                    table.borrow_mut().insert_arr(len + 1, value);
                 }
                 _ => {
                     return Err(RuntimeError::BadArgument(
@@ -211,16 +220,22 @@ pub fn insert(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
                     Box::new(RuntimeError::PositionOutOfBounds),
                 ));
             }
+            env.charge_table_entries(1)?;
 
-            if pos == len + 1 {
-                table.borrow_mut().arr.insert(len + 1, value);
+            let mut table = table.borrow_mut();
+            if len as usize <= table.arr.len() {
+                // shift `t[pos..=len]` up in the array part; `t[len + 1]` is nil
+                if len as usize == table.arr.len() {
+                    table.push_arr(LuaValue::Nil);
+                }
+                table.arr[len as usize] = value;
+                table.arr[pos as usize - 1..=len as usize].rotate_right(1);
             } else {
-                let mut table_mut = table.borrow_mut();
-                let split_right = table_mut.arr.split_off(&pos);
-                table_mut.arr.insert(pos, value);
-                table_mut
-                    .arr
-                    .extend(split_right.into_iter().map(|(idx, val)| (idx + 1, val)));
+                for k in (pos..=len).rev() {
+                    let moved = table.get_arr(k).cloned().unwrap_or_default();
+                    table.insert_arr(k + 1, moved);
+                }
+                table.insert_arr(pos, value);
             }
             Ok(0)
         }
@@ -298,25 +313,36 @@ pub fn move_(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
         .map_err(|e| RuntimeError::BadArgument(4, Box::new(e)))?;
 
     if f <= e {
-        for i in f..=e {
-            let value = a1.borrow_mut().arr.remove(&i).unwrap_or_default();
-            a2.borrow_mut().insert_arr(i - f + t, value);
+        if f <= 0 && e >= IntType::MAX + f {
+            return Err(RuntimeError::BadArgument(
+                3,
+                Box::new(RuntimeError::Custom("too many elements to move".into())),
+            ));
+        }
+        if t > IntType::MAX - (e - f) {
+            return Err(RuntimeError::BadArgument(
+                4,
+                Box::new(RuntimeError::Custom("destination wrap around".into())),
+            ));
+        }
+        // copy backward if the destination overlaps the end of the source
+        let overlaps = Rc::ptr_eq(&a1, &a2) && t > f && t <= e;
+        for i in 0..=e - f {
+            let i = if overlaps { e - f - i } else { i };
+            let value = a1.borrow().get_arr(f + i).cloned().unwrap_or_default();
+            env.charge_table_entries(1)?;
+            a2.borrow_mut().insert_arr(t + i, value);
         }
     }
     env.push(LuaValue::Table(a2));
     Ok(1)
 }
 pub fn pack(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
+    env.charge_table(args + 1)?;
     let mut new_table = LuaTable::new();
     let mut thread_mut = env.borrow_running_thread_mut();
     let len = thread_mut.data_stack.len();
-    new_table.arr = BTreeMap::from_iter(
-        thread_mut
-            .data_stack
-            .drain(len - args..)
-            .enumerate()
-            .map(|(idx, value)| (idx as IntType + 1, value)),
-    );
+    new_table.arr = thread_mut.data_stack.drain(len - args..).collect();
     new_table.map.insert("n".into(), (args as IntType).into());
     thread_mut.data_stack.push(new_table.into());
     Ok(1)
@@ -330,12 +356,10 @@ pub fn remove(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             let list = env.pop();
             match list {
                 LuaValue::Table(table) => {
-                    let removed = table
-                        .borrow_mut()
-                        .arr
-                        .pop_last()
-                        .map(|(_, v)| v)
-                        .unwrap_or_default();
+                    let mut table = table.borrow_mut();
+                    let len = table.len();
+                    let removed = table.remove(&len.into()).unwrap_or_default();
+                    drop(table);
                     env.push(removed);
                     Ok(1)
                 }
@@ -376,16 +400,23 @@ pub fn remove(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
                         return Ok(1);
                     }
 
-                    let mut split_right = table.borrow_mut().arr.split_off(&pos);
-                    let first_pos = split_right.first_key_value().map(|(k, _)| *k);
-                    if first_pos == Some(pos) {
-                        let removed = split_right.pop_first().unwrap().1;
-                        env.push(removed);
-                    }
-                    table
-                        .borrow_mut()
-                        .arr
-                        .extend(split_right.into_iter().map(|(idx, val)| (idx - 1, val)));
+                    let mut table = table.borrow_mut();
+                    let removed = if len as usize <= table.arr.len() {
+                        // shift `t[pos + 1..=len]` down in the array part
+                        let removed = std::mem::take(&mut table.arr[pos as usize - 1]);
+                        table.arr[pos as usize - 1..len as usize].rotate_left(1);
+                        removed
+                    } else {
+                        let removed = table.get_arr(pos).cloned().unwrap_or_default();
+                        for k in pos..len {
+                            let moved = table.get_arr(k + 1).cloned().unwrap_or_default();
+                            table.insert_arr(k, moved);
+                        }
+                        table.insert_arr(len, LuaValue::Nil);
+                        removed
+                    };
+                    drop(table);
+                    env.push(removed);
                     Ok(1)
                 }
                 _ => {
@@ -398,146 +429,183 @@ pub fn remove(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
         }
     }
 }
-pub fn sort(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
-    match args {
+pub fn sort(env: &mut LuaEnv, args: usize, expected: Option<usize>) -> Result<(), RuntimeError> {
+    let (list, cmp) = match args {
         0 => return Err(RuntimeError::new_empty_argument(1, "table")),
-        1 => {
-            let list = env.pop();
-            let list = match list {
-                LuaValue::Table(table) => table,
-                _ => {
-                    return Err(RuntimeError::BadArgument(
-                        1,
-                        Box::new(RuntimeError::Expected("table", list.type_str().into())),
-                    ))
-                }
-            };
-            if list.borrow().len() < 2 {
-                return Ok(0);
-            }
-            let mut list_to_vec_elem = Vec::new();
-            unpack_impl(
-                Rc::clone(&list),
-                1,
-                list.borrow().len() as IntType,
-                &mut list_to_vec_elem,
-            )?;
-            list_to_vec_elem.sort_unstable_by(|a, b| {
-                env.push2(a.clone(), b.clone());
-                if env.lt().is_err() {
-                    std::cmp::Ordering::Equal
-                } else {
-                    let ret = env.pop().to_bool();
-                    if ret {
-                        std::cmp::Ordering::Less
-                    } else {
-                        env.push2(b.clone(), a.clone());
-                        if env.lt().is_err() {
-                            std::cmp::Ordering::Equal
-                        } else {
-                            let ret = env.pop().to_bool();
-                            if ret {
-                                std::cmp::Ordering::Greater
-                            } else {
-                                std::cmp::Ordering::Equal
-                            }
-                        }
-                    }
-                }
-            });
-            list.borrow_mut().arr.extend(
-                list_to_vec_elem
-                    .into_iter()
-                    .enumerate()
-                    .map(|(idx, val)| (idx as IntType + 1, val)),
-            );
-            Ok(0)
-        }
+        1 => (env.pop(), None),
         _ => {
             env.pop_n(args - 2);
             let (list, cmp) = env.pop2();
-
-            let list = match list {
-                LuaValue::Table(table) => table,
+            match cmp {
+                LuaValue::Nil => (list, None),
+                LuaValue::Function(_) => (list, Some(cmp)),
                 _ => {
                     return Err(RuntimeError::BadArgument(
-                        1,
-                        Box::new(RuntimeError::Expected("table", list.type_str().into())),
+                        2,
+                        Box::new(RuntimeError::Expected("function", cmp.type_str().into())),
                     ))
                 }
-            };
-            if list.borrow().len() < 2 {
-                return Ok(0);
             }
-            let mut list_to_vec_elem = Vec::new();
-            unpack_impl(
-                Rc::clone(&list),
+        }
+    };
+    let list = match list {
+        LuaValue::Table(table) => table,
+        _ => {
+            return Err(RuntimeError::BadArgument(
                 1,
-                list.borrow().len() as IntType,
-                &mut list_to_vec_elem,
-            )?;
-            list_to_vec_elem.sort_unstable_by(|a, b| {
-                env.push2(a.clone(), b.clone());
-                if env.function_call(2, cmp.clone(), Some(1)).is_err() {
-                    std::cmp::Ordering::Equal
-                } else {
-                    let ret = env.pop().to_bool();
-                    if ret {
-                        std::cmp::Ordering::Less
-                    } else {
-                        env.push2(b.clone(), a.clone());
-                        if env.function_call(2, cmp.clone(), Some(1)).is_err() {
-                            std::cmp::Ordering::Equal
-                        } else {
-                            let ret = env.pop().to_bool();
-                            if ret {
-                                std::cmp::Ordering::Greater
-                            } else {
-                                std::cmp::Ordering::Equal
+                Box::new(RuntimeError::Expected("table", list.type_str().into())),
+            ))
+        }
+    };
+
+    let len = list.borrow().len();
+    let mut elems = Vec::new();
+    if len >= 2 {
+        unpack_impl(Rc::clone(&list), 1, len, &mut elems)?;
+    }
+    let state = SortState {
+        table: list,
+        cmp,
+        expected,
+        dst: Vec::with_capacity(elems.len()),
+        src: elems,
+        width: 1,
+        lo: 0,
+        i: 0,
+        j: 1,
+    };
+    state.run(env, None)
+}
+
+/// State of bottom-up merge sort for `table.sort`.
+/// Every comparison can call a lua function, which could yield;
+/// the state is moved into the continuation of the comparison so that sorting can be resumed.
+struct SortState {
+    table: Rc<RefCell<LuaTable>>,
+    /// comparison function; `<` operator if `None`
+    cmp: Option<LuaValue>,
+    /// number of return values expected for `table.sort`
+    expected: Option<usize>,
+
+    /// runs of `width` sorted elements
+    src: Vec<LuaValue>,
+    /// runs of `width * 2` sorted elements, merged from `src`
+    dst: Vec<LuaValue>,
+    width: usize,
+    /// start of the pair of runs being merged
+    lo: usize,
+    /// next element of the left run
+    i: usize,
+    /// next element of the right run
+    j: usize,
+}
+impl SortState {
+    /// Continue sorting.
+    /// `less` is the result of the pending comparison `src[j] < src[i]`, if any.
+    fn run(mut self, env: &mut LuaEnv, mut less: Option<bool>) -> Result<(), RuntimeError> {
+        let n = self.src.len();
+        loop {
+            if self.width >= n {
+                break;
+            }
+            if self.lo >= n {
+                // next pass
+                std::mem::swap(&mut self.src, &mut self.dst);
+                self.dst.clear();
+                self.width *= 2;
+                self.lo = 0;
+                self.i = 0;
+                self.j = self.width.min(n);
+                continue;
+            }
+            let mid = (self.lo + self.width).min(n);
+            let hi = (self.lo + self.width * 2).min(n);
+            if self.i < mid && self.j < hi {
+                let less = match less.take() {
+                    Some(less) => less,
+                    None => {
+                        env.push2(self.src[self.j].clone(), self.src[self.i].clone());
+                        match &self.cmp {
+                            None => {
+                                env.lt()?;
+                            }
+                            Some(cmp) => {
+                                let cmp = cmp.clone();
+                                if env.is_continuable() {
+                                    return env.call_k(2, cmp, Some(1), move |env, res| {
+                                        res?;
+                                        let less = env.pop().to_bool();
+                                        self.run(env, Some(less))
+                                    });
+                                }
+                                env.function_call(2, cmp, Some(1))?;
                             }
                         }
+                        env.pop().to_bool()
                     }
+                };
+                if less {
+                    self.dst.push(self.src[self.j].clone());
+                    self.j += 1;
+                } else {
+                    self.dst.push(self.src[self.i].clone());
+                    self.i += 1;
                 }
-            });
-            list.borrow_mut().arr.extend(
-                list_to_vec_elem
-                    .into_iter()
-                    .enumerate()
-                    .map(|(idx, val)| (idx as IntType + 1, val)),
-            );
-            Ok(0)
+            } else if self.i < mid {
+                self.dst.push(self.src[self.i].clone());
+                self.i += 1;
+            } else if self.j < hi {
+                self.dst.push(self.src[self.j].clone());
+                self.j += 1;
+            } else {
+                // merge next pair of runs
+                self.lo = hi;
+                self.i = hi;
+                self.j = (hi + self.width).min(n);
+            }
+        }
+
+        let mut table = self.table.borrow_mut();
+        for (idx, value) in self.src.into_iter().enumerate() {
+            table.insert_arr(idx as IntType + 1, value);
+        }
+        drop(table);
+        if let Some(expected) = self.expected {
+            for _ in 0..expected {
+                env.push(LuaValue::Nil);
+            }
         }
+        Ok(())
     }
 }
 
 fn unpack_impl(
     table: Rc<RefCell<LuaTable>>,
-    mut i: IntType,
+    i: IntType,
     j: IntType,
     out: &mut Vec<LuaValue>,
 ) -> Result<usize, RuntimeError> {
     if j < i {
         return Ok(0);
     }
-    let len = j - i + 1;
-    out.reserve(len as usize);
-
-    for (idx, value) in table.borrow().arr.range(i..=j) {
-        if i < *idx {
-            let nil_count = *idx - i;
-
-            out.resize_with(out.len() + nil_count as usize, Default::default);
+    let len = (j as i128 - i as i128 + 1) as usize;
+    out.reserve(len);
+    let table = table.borrow();
+    let get = |k: IntType| table.get_arr(k).cloned().unwrap_or_default();
+    // keys in the array part are copied at once
+    let lo = (i as i128).max(1);
+    let hi = (j as i128).min(table.arr.len() as i128);
+    if lo > hi {
+        out.extend((i..=j).map(get));
+    } else {
+        out.extend((i..lo as IntType).map(get));
+        out.extend_from_slice(&table.arr[lo as usize - 1..hi as usize]);
+        if hi < j as i128 {
+            out.extend((hi as IntType + 1..=j).map(get));
         }
-        // maybe unwrapped add?
-        i = *idx + 1;
-        out.push(value.clone());
-    }
-    if i <= j {
-        let nil_count = j - i + 1;
-        out.resize_with(out.len() + nil_count as usize, Default::default);
     }
 
-    Ok(len as usize)
+    Ok(len)
 }
 pub fn unpack(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
     let (list, i, j) = match args {
@@ -607,6 +675,9 @@ pub fn unpack(env: &mut LuaEnv, args: usize) -> Result<usize, RuntimeError> {
             (list, i, j)
         }
     };
+    if j >= i && j as i128 - i as i128 >= MAX_UNPACK as i128 {
+        return Err(RuntimeError::Custom("too many results to unpack".into()));
+    }
     let mut thread_mut = env.borrow_running_thread_mut();
     unpack_impl(list, i, j, &mut thread_mut.data_stack)
 }
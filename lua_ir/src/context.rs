@@ -1,14 +1,25 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use lua_semantics::Block;
 use lua_semantics::ExprLocalVariable;
 use lua_semantics::Expression;
+use lua_semantics::Span;
 use lua_semantics::Statement;
+use lua_semantics::VariableInfo;
 
+use crate::fold;
+use crate::fold::Constant;
+use crate::optimize;
+use crate::optimize::OptLevel;
+use crate::table::FieldCache;
 use crate::vm::Chunk;
 use crate::Instruction;
 use crate::LabelType;
-use crate::LuaFunctionLua;
+use crate::LuaString;
+use crate::Prototype;
+use crate::RuntimeError;
 
 #[derive(Debug)]
 pub struct Context {
@@ -21,6 +32,38 @@ pub struct Context {
     pub label_map: Vec<Option<usize>>,
     /// user defined label -> label_type map
     pub user_defined_label: HashMap<String, LabelType>,
+
+    /// first register for temporary values; registers below it hold local variables
+    temp_base: usize,
+    /// next free register for temporary values
+    free_reg: usize,
+    /// number of registers used so far, the frame size of the function
+    max_reg: usize,
+    /// if `cells[offset]` is true, the local variable at the offset is captured by closures and lives in a cell
+    cells: Vec<bool>,
+    /// name of the local variable in each register, for debug information
+    local_names: Vec<String>,
+    /// prototypes of nested functions, by `FunctionInit`
+    prototypes: Vec<Rc<Prototype>>,
+    /// string constants, by `String`
+    constants: Vec<LuaString>,
+    /// index of each string in `constants`
+    constant_index: HashMap<LuaString, usize>,
+    /// number of inline caches, by `GetField` and `SetField`
+    caches: usize,
+
+    /// name of the chunk, for debug information
+    chunkname: Rc<str>,
+    /// byte offset of the start of each line of the source code; empty if the source is unknown
+    line_starts: Rc<[usize]>,
+    /// source line of each instruction, filled up to the last `set_line` call
+    lines: Vec<u32>,
+    /// source line of the statement being emitted
+    line: u32,
+    /// first error found while emitting, e.g. syntax not supported by the code generator
+    error: Option<RuntimeError>,
+    /// optimization of the generated code
+    opt_level: OptLevel,
 }
 
 impl Context {
@@ -30,7 +73,56 @@ impl Context {
             label_map: Default::default(),
             loop_stack: Vec::new(),
             user_defined_label: HashMap::new(),
+            temp_base: 0,
+            free_reg: 0,
+            max_reg: 0,
+            cells: Vec::new(),
+            local_names: Vec::new(),
+            prototypes: Vec::new(),
+            constants: Vec::new(),
+            constant_index: HashMap::new(),
+            caches: 0,
+            chunkname: Rc::from("?"),
+            line_starts: Rc::from([]),
+            lines: Vec::new(),
+            line: 0,
+            error: None,
+            opt_level: OptLevel::default(),
+        }
+    }
+    /// context that records source line numbers of instructions, for code generated from `source`
+    pub fn with_source(source: &[u8], chunkname: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(
+                source
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &ch)| ch == b'\n')
+                    .map(|(idx, _)| idx + 1),
+            )
+            .collect();
+        Context {
+            chunkname: Rc::from(chunkname),
+            line_starts,
+            ..Self::new()
+        }
+    }
+
+    /// optimize the generated code by `opt_level`
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// following instructions are generated from the code at `span`
+    fn set_line(&mut self, span: Span) {
+        if self.line_starts.is_empty() || span.is_none() {
+            return;
         }
+        self.lines.resize(self.instructions.len(), self.line);
+        self.line = self
+            .line_starts
+            .partition_point(|&start| start <= span.start) as u32;
     }
 
     /// generate unique label id
@@ -46,36 +138,136 @@ impl Context {
         self.label_map[label] = Some(index);
     }
 
+    /// index of string constant `value`, added to the constant pool if not there yet
+    fn string_constant(&mut self, value: &[u8]) -> usize {
+        let value = LuaString::from_slice(value);
+        if let Some(&index) = self.constant_index.get(&value) {
+            return index;
+        }
+        let index = self.constants.len();
+        self.constants.push(value.clone());
+        self.constant_index.insert(value, index);
+        index
+    }
+    /// new inline cache for `GetField` or `SetField`
+    fn field_cache(&mut self) -> usize {
+        self.caches += 1;
+        self.caches - 1
+    }
+
+    /// record an error for the code which cannot be generated; only the first one is kept
+    fn unsupported(&mut self, what: String) {
+        if self.error.is_none() {
+            self.error = Some(RuntimeError::Custom(format!("unsupported {}", what).into()));
+        }
+    }
+
+    /// allocate `count` registers for temporary values, returns the first one
+    fn alloc(&mut self, count: usize) -> usize {
+        let reg = self.free_reg;
+        self.free_reg += count;
+        self.max_reg = self.max_reg.max(self.free_reg);
+        reg
+    }
+    /// free temporary registers from `reg`
+    fn free_to(&mut self, reg: usize) {
+        self.free_reg = reg;
+    }
+    /// registers below `end` are written
+    fn touch(&mut self, end: usize) {
+        self.max_reg = self.max_reg.max(end);
+    }
+
+    /// local variable comes into scope; returns `true` if it lives in a cell
+    fn declare(&mut self, var: &VariableInfo) -> bool {
+        if self.cells.len() <= var.offset {
+            self.cells.resize(var.offset + 1, false);
+            self.local_names.resize(var.offset + 1, String::new());
+        }
+        self.cells[var.offset] = var.is_reference;
+        if self.local_names[var.offset].is_empty() {
+            self.local_names[var.offset] = var.name.clone();
+        }
+        var.is_reference
+    }
+    fn is_cell(&self, offset: usize) -> bool {
+        self.cells.get(offset).copied().unwrap_or(false)
+    }
+
     /// return address of newly added instruction to be executed
-    pub fn emit(mut self, mut block: Block) -> Chunk {
+    pub fn emit(self, block: Block) -> Result<Chunk, RuntimeError> {
+        self.emit_function(&[], block)
+    }
+    /// code of a function body, with parameters in the first registers
+    fn emit_function(
+        mut self,
+        params: &[Rc<RefCell<VariableInfo>>],
+        mut block: Block,
+    ) -> Result<Chunk, RuntimeError> {
         if block.return_statement.is_none() {
-            block.return_statement = Some(lua_semantics::ReturnStatement::new(Vec::new()));
+            block.return_statement = Some(lua_semantics::ReturnStatement::new(
+                Vec::new(),
+                Span::new_none(),
+            ));
         }
         let stack_size = block.stack_size.unwrap();
+        self.temp_base = stack_size;
+        self.free_reg = stack_size;
+        self.max_reg = stack_size.max(params.len());
+        for param in params {
+            let param = param.borrow();
+            if self.declare(&param) {
+                self.instructions.push(Instruction::InitCell(param.offset));
+            }
+        }
         self.emit_block(block);
+        if !self.line_starts.is_empty() {
+            self.lines.resize(self.instructions.len(), self.line);
+        }
 
-        Chunk {
-            instructions: self.instructions,
-            label_map: self.label_map.into_iter().map(|x| x.unwrap()).collect(),
-            stack_size,
+        if let Some(err) = self.error {
+            return Err(err);
         }
+        // `goto` to a label not visible in this function
+        for (name, label) in self.user_defined_label.iter() {
+            if self.label_map[*label].is_none() {
+                return Err(RuntimeError::Custom(
+                    format!("no visible label '{}' for goto", name).into(),
+                ));
+            }
+        }
+
+        let mut instructions = self.instructions;
+        optimize::resolve_labels(&mut instructions, &self.label_map);
+        let mut local_names = self.local_names;
+        local_names.truncate(self.max_reg);
+        let mut chunk = Chunk {
+            instructions,
+            stack_size: self.max_reg,
+            lines: self.lines,
+            constants: self.constants,
+            caches: vec![FieldCache::default(); self.caches],
+            local_names,
+            // set by the enclosing function, which knows where the upvalues come from
+            upvalue_names: Vec::new(),
+            source: self.chunkname,
+            prototypes: self.prototypes,
+        };
+        optimize::optimize(&mut chunk, self.opt_level);
+        Ok(chunk)
     }
 
     fn emit_block(&mut self, block: Block) {
+        let mut spans = block.spans.into_iter();
         for stmt in block.statements {
+            if let Some(span) = spans.next() {
+                self.set_line(span);
+            }
             self.emit_statement(stmt);
         }
         if let Some(ret) = block.return_statement {
-            // self.instructions.push(Instruction::Sp);
-            let rhs_len = ret.values.len();
-            for (idx, value) in ret.values.into_iter().enumerate() {
-                if idx == rhs_len - 1 {
-                    self.emit_expression(value, None);
-                } else {
-                    self.emit_expression(value, Some(1));
-                }
-            }
-            self.instructions.push(Instruction::Return);
+            self.set_line(ret.span);
+            self.emit_return(ret.values);
         }
     }
 
@@ -96,370 +288,419 @@ impl Context {
             lua_semantics::Statement::Goto(stmt) => self.emit_statement_goto(stmt),
             lua_semantics::Statement::Label(stmt) => self.emit_statement_label(stmt),
 
-            _ => {
-                unimplemented!("unimplemented statement: {:?}", statement);
+            _ => self.unsupported(format!("statement: {:?}", statement)),
+        }
+    }
+
+    /// `true` if `expression` can have multiple values
+    fn is_multi(expression: &Expression) -> bool {
+        matches!(
+            expression,
+            Expression::FunctionCall(_) | Expression::Variadic
+        )
+    }
+
+    /// emit instructions that evaluate an expression into `R[dst], R[dst + 1], ...`, adjusted to `expected` values.
+    /// `None` leaves every value from `R[dst]` to the top of the data stack;
+    /// it is allowed only for multi-value expressions, with `dst` the last allocated register.
+    fn emit_expression(&mut self, expression: Expression, dst: usize, expected: Option<usize>) {
+        match expression {
+            lua_semantics::Expression::FunctionCall(expr) => {
+                self.emit_expression_functioncall(expr, dst, expected)
+            }
+            lua_semantics::Expression::Variadic => {
+                if let Some(expected) = expected {
+                    self.touch(dst + expected);
+                }
+                self.instructions
+                    .push(Instruction::GetVariadic(dst, expected));
+            }
+            // nothing to evaluate
+            lua_semantics::Expression::Env
+            | lua_semantics::Expression::Nil
+            | lua_semantics::Expression::Boolean(_)
+            | lua_semantics::Expression::Numeric(_)
+            | lua_semantics::Expression::String(_)
+            | lua_semantics::Expression::LocalVariable(_)
+                if expected == Some(0) => {}
+            expression => {
+                if expected == Some(0) {
+                    // evaluated only for side effects
+                    let mark = self.free_reg;
+                    let tmp = self.alloc(1);
+                    self.emit_expression_single(expression, tmp);
+                    self.free_to(mark);
+                    return;
+                }
+                self.emit_expression_single(expression, dst);
+                if let Some(expected) = expected {
+                    if expected > 1 {
+                        self.touch(dst + expected);
+                        self.instructions
+                            .push(Instruction::Nil(dst + 1, expected - 1));
+                    }
+                }
             }
         }
     }
-    /// emit an instruction that evalulates an expression and store the result in a register AX
-    fn emit_expression(&mut self, expression: Expression, expected: Option<usize>) {
+    /// emit instructions that evaluate an expression into `R[dst]`, adjusted to one value
+    fn emit_expression_single(&mut self, expression: Expression, dst: usize) {
         match expression {
-            lua_semantics::Expression::Env => self.emit_expression_env(expected),
-            lua_semantics::Expression::Variadic => self.emit_expression_variadic(expected),
-            lua_semantics::Expression::Nil => self.emit_expression_nil(expected),
-            lua_semantics::Expression::Boolean(value) => {
-                self.emit_expression_boolean(value, expected)
+            lua_semantics::Expression::Env => self.instructions.push(Instruction::GetEnv(dst)),
+            lua_semantics::Expression::Variadic => {
+                self.instructions
+                    .push(Instruction::GetVariadic(dst, Some(1)));
             }
-            lua_semantics::Expression::Numeric(value) => {
-                self.emit_expression_numeric(value, expected)
+            lua_semantics::Expression::Nil => self.instructions.push(Instruction::Nil(dst, 1)),
+            lua_semantics::Expression::Boolean(value) => {
+                self.instructions.push(Instruction::Boolean(dst, value))
             }
+            lua_semantics::Expression::Numeric(value) => self.emit_expression_numeric(value, dst),
             lua_semantics::Expression::String(value) => {
-                self.emit_expression_string(value, expected)
+                let index = self.string_constant(&value);
+                self.instructions.push(Instruction::String(dst, index))
             }
             lua_semantics::Expression::LocalVariable(expr) => {
-                self.emit_expression_localvariable(expr, expected)
+                self.emit_expression_localvariable(expr, dst)
             }
             lua_semantics::Expression::TableIndex(expr) => {
-                self.emit_expression_tableindex(expr, expected)
+                self.emit_expression_tableindex(expr, dst)
             }
-            lua_semantics::Expression::Binary(expr) => self.emit_expression_binary(expr, expected),
-            lua_semantics::Expression::Unary(expr) => self.emit_expression_unary(expr, expected),
+            lua_semantics::Expression::Binary(expr) => self.emit_expression_binary(expr, dst),
+            lua_semantics::Expression::Unary(expr) => self.emit_expression_unary(expr, dst),
             lua_semantics::Expression::TableConstructor(expr) => {
-                self.emit_expression_tableconstructor(expr, expected)
+                self.emit_expression_tableconstructor(expr, dst)
             }
             lua_semantics::Expression::FunctionCall(expr) => {
-                self.emit_expression_functioncall(expr, expected)
+                self.emit_expression_functioncall(expr, dst, Some(1))
             }
             lua_semantics::Expression::FunctionObject(expr) => {
-                self.emit_expression_function_object(expr, expected)
-            }
-            _ => {
-                unimplemented!("unimplemented expression: {:?}", expression);
+                self.emit_expression_function_object(expr, dst)
             }
+            _ => self.unsupported(format!("expression: {:?}", expression)),
         }
     }
-    fn emit_expression_set(&mut self, entry: lua_semantics::Expression) {
-        match entry {
-            lua_semantics::Expression::LocalVariable(expr) => match expr {
-                ExprLocalVariable::Stack(local_id, _name) => {
-                    self.instructions
-                        .push(Instruction::SetLocalVariable(local_id));
-                }
-                ExprLocalVariable::Upvalue(index, _name) => {
-                    self.instructions
-                        .push(Instruction::FunctionUpvalueSet(index));
-                }
-            },
-            lua_semantics::Expression::TableIndex(expr) => {
-                self.emit_expression(*expr.table, Some(1));
-                self.emit_expression(*expr.index, Some(1));
-                self.instructions.push(Instruction::TableIndexSet);
-            }
-            _ => {
-                unimplemented!("unimplemented expression: {:?}", entry);
+    /// emit instructions that evaluate an expression, returns the register holding its value.
+    /// Local variables are read from their registers in place;
+    /// others are evaluated into a newly allocated register.
+    fn emit_expression_reg(&mut self, expression: Expression) -> usize {
+        if let lua_semantics::Expression::LocalVariable(ExprLocalVariable::Stack(offset, _)) =
+            &expression
+        {
+            if !self.is_cell(*offset) {
+                return *offset;
             }
         }
+        let reg = self.alloc(1);
+        self.emit_expression_single(expression, reg);
+        reg
     }
-}
-
-impl Context {
-    fn emit_expression_env(&mut self, expected: Option<usize>) {
-        if expected == Some(0) {
-            return;
-        }
-        self.instructions.push(Instruction::GetEnv);
-        if let Some(expected) = expected {
-            self.emit_expression_nil(Some(expected - 1));
+    /// emit instructions that evaluate an expression into the register of local variable `reg`.
+    fn emit_expression_to_local(&mut self, expression: Expression, reg: usize) {
+        match expression {
+            // these write `R[reg]` before reading the operands
+            expression @ (lua_semantics::Expression::Binary(
+                lua_semantics::ExprBinary::LogicalAnd(_) | lua_semantics::ExprBinary::LogicalOr(_),
+            )
+            | lua_semantics::Expression::TableConstructor(_)) => {
+                let mark = self.free_reg;
+                let tmp = self.alloc(1);
+                self.emit_expression_single(expression, tmp);
+                self.instructions.push(Instruction::Move(reg, tmp));
+                self.free_to(mark);
+            }
+            expression => self.emit_expression_single(expression, reg),
         }
     }
-    fn emit_expression_nil(&mut self, expected: Option<usize>) {
-        if let Some(expected) = expected {
-            for _ in 0..expected {
-                self.instructions.push(Instruction::Nil);
+    /// emit instructions that evaluate expressions into newly allocated registers at the top.
+    /// Returns the number of values, or `None` if the last expression has multiple values,
+    /// which are left up to the top of the data stack.
+    fn emit_expression_list_top(&mut self, expressions: Vec<Expression>) -> Option<usize> {
+        let len = expressions.len();
+        for (idx, expression) in expressions.into_iter().enumerate() {
+            let reg = self.alloc(1);
+            if idx == len - 1 && Self::is_multi(&expression) {
+                self.emit_expression(expression, reg, None);
+                return None;
             }
-        } else {
-            self.instructions.push(Instruction::Nil);
+            self.emit_expression_single(expression, reg);
         }
+        Some(len)
     }
-    fn emit_expression_boolean(&mut self, value: bool, expected: Option<usize>) {
-        if expected == Some(0) {
-            return;
+    /// emit instructions that evaluate expressions into `R[base], ..., R[base + count - 1]`,
+    /// adjusted to `count` values like an assignment.
+    fn emit_expression_list(&mut self, expressions: Vec<Expression>, base: usize, count: usize) {
+        let len = expressions.len();
+        for (idx, expression) in expressions.into_iter().enumerate() {
+            if idx >= count {
+                let dst = self.free_reg;
+                self.emit_expression(expression, dst, Some(0));
+            } else if idx == len - 1 {
+                self.emit_expression(expression, base + idx, Some(count - idx));
+            } else {
+                self.emit_expression_single(expression, base + idx);
+            }
         }
-        self.instructions.push(Instruction::Boolean(value));
-        if let Some(expected) = expected {
-            self.emit_expression_nil(Some(expected - 1));
+        if len == 0 {
+            self.instructions.push(Instruction::Nil(base, count));
         }
     }
-    fn emit_expression_numeric(
-        &mut self,
-        value: lua_semantics::IntOrFloat,
-        expected: Option<usize>,
-    ) {
-        if expected == Some(0) {
-            return;
-        }
+}
+
+impl Context {
+    fn emit_expression_numeric(&mut self, value: lua_semantics::IntOrFloat, dst: usize) {
         match value {
             lua_semantics::IntOrFloat::Int(n) => {
-                self.instructions.push(Instruction::Numeric(n.into()));
+                self.instructions.push(Instruction::Numeric(dst, n.into()));
             }
             lua_semantics::IntOrFloat::Float(n) => {
-                self.instructions.push(Instruction::Numeric(n.into()));
+                self.instructions.push(Instruction::Numeric(dst, n.into()));
             }
         }
-        if let Some(expected) = expected {
-            self.emit_expression_nil(Some(expected - 1));
-        }
     }
-    fn emit_expression_string(&mut self, value: Vec<u8>, expected: Option<usize>) {
-        if expected == Some(0) {
+    fn emit_expression_tableindex(&mut self, expr: lua_semantics::ExprTableIndex, dst: usize) {
+        let mark = self.free_reg;
+        let table = self.emit_expression_reg(*expr.table);
+        if let lua_semantics::Expression::String(name) = &*expr.index {
+            let key = self.string_constant(name);
+            let cache = self.field_cache();
+            self.free_to(mark);
+            self.instructions
+                .push(Instruction::GetField(dst, table, key, cache));
             return;
         }
-        self.instructions.push(Instruction::String(value));
-        if let Some(expected) = expected {
-            self.emit_expression_nil(Some(expected - 1));
-        }
+        let index = self.emit_expression_reg(*expr.index);
+        self.free_to(mark);
+        self.instructions
+            .push(Instruction::TableIndex(dst, table, index));
     }
-    fn emit_expression_tableindex(
-        &mut self,
-        expr: lua_semantics::ExprTableIndex,
-        expected: Option<usize>,
-    ) {
-        self.emit_expression(*expr.table, Some(1));
-        self.emit_expression(*expr.index, Some(1));
-        self.instructions.push(Instruction::TableIndex);
-        if let Some(expected) = expected {
-            if expected == 0 {
-                self.instructions.push(Instruction::Pop);
-            } else {
-                self.emit_expression_nil(Some(expected - 1));
+    /// emit instruction that loads a value evaluated at compile time into `R[dst]`
+    fn emit_constant(&mut self, value: Constant, dst: usize) {
+        match value {
+            Constant::Nil => self.instructions.push(Instruction::Nil(dst, 1)),
+            Constant::Boolean(value) => self.instructions.push(Instruction::Boolean(dst, value)),
+            Constant::Number(value) => self.instructions.push(Instruction::Numeric(dst, value)),
+            Constant::String(value) => {
+                let index = self.string_constant(value.as_bytes());
+                self.instructions.push(Instruction::String(dst, index))
             }
         }
     }
-    fn emit_expression_unary(&mut self, expr: lua_semantics::ExprUnary, expected: Option<usize>) {
-        match expr {
+    fn emit_expression_unary(&mut self, expr: lua_semantics::ExprUnary, dst: usize) {
+        if let Some(value) = fold::unary(&expr) {
+            self.emit_constant(value, dst);
+            return;
+        }
+        let mark = self.free_reg;
+        let instruction = match expr {
             lua_semantics::ExprUnary::Minus(expr) => {
-                self.emit_expression(*expr.value, Some(1));
-                self.instructions.push(Instruction::UnaryMinus);
+                Instruction::UnaryMinus(dst, self.emit_expression_reg(*expr.value))
             }
             lua_semantics::ExprUnary::BitwiseNot(expr) => {
-                self.emit_expression(*expr.value, Some(1));
-                self.instructions.push(Instruction::UnaryBitwiseNot);
+                Instruction::UnaryBitwiseNot(dst, self.emit_expression_reg(*expr.value))
             }
             lua_semantics::ExprUnary::Length(expr) => {
-                self.emit_expression(*expr.value, Some(1));
-                self.instructions.push(Instruction::UnaryLength);
+                Instruction::UnaryLength(dst, self.emit_expression_reg(*expr.value))
             }
             lua_semantics::ExprUnary::LogicalNot(expr) => {
-                self.emit_expression(*expr.value, Some(1));
-                self.instructions.push(Instruction::UnaryLogicalNot);
-            }
-        }
-        if let Some(expected) = expected {
-            if expected == 0 {
-                self.instructions.push(Instruction::Pop);
-            } else {
-                self.emit_expression_nil(Some(expected - 1));
+                Instruction::UnaryLogicalNot(dst, self.emit_expression_reg(*expr.value))
             }
-        }
+        };
+        self.free_to(mark);
+        self.instructions.push(instruction);
     }
-    fn emit_expression_binary(&mut self, expr: lua_semantics::ExprBinary, expected: Option<usize>) {
-        match expr {
+    /// evaluate both operands of binary expression, returns their registers
+    fn emit_operands(&mut self, expr: lua_semantics::ExprBinaryData) -> (usize, usize) {
+        let lhs = self.emit_expression_reg(*expr.lhs);
+        let rhs = self.emit_expression_reg(*expr.rhs);
+        (lhs, rhs)
+    }
+    fn emit_expression_binary(&mut self, expr: lua_semantics::ExprBinary, dst: usize) {
+        if let Some(value) = fold::binary(&expr) {
+            self.emit_constant(value, dst);
+            return;
+        }
+        let mark = self.free_reg;
+        let instruction = match expr {
             lua_semantics::ExprBinary::Add(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryAdd);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryAdd(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Sub(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinarySub);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinarySub(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Mul(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryMul);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryMul(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Div(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryDiv);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryDiv(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::FloorDiv(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryFloorDiv);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryFloorDiv(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Mod(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryMod);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryMod(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Pow(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryPow);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryPow(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Concat(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryConcat);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryConcat(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::BitwiseAnd(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryBitwiseAnd);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryBitwiseAnd(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::BitwiseOr(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryBitwiseOr);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryBitwiseOr(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::BitwiseXor(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryBitwiseXor);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryBitwiseXor(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::ShiftLeft(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryShiftLeft);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryShiftLeft(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::ShiftRight(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryShiftRight);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryShiftRight(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::Equal(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryEqual);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryEqual(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::NotEqual(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryEqual);
-                self.instructions.push(Instruction::UnaryLogicalNot);
+                let (lhs, rhs) = self.emit_operands(expr);
+                self.instructions
+                    .push(Instruction::BinaryEqual(dst, lhs, rhs));
+                Instruction::UnaryLogicalNot(dst, dst)
             }
             lua_semantics::ExprBinary::LessThan(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryLessThan);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryLessThan(dst, lhs, rhs)
             }
             lua_semantics::ExprBinary::LessEqual(expr) => {
-                self.emit_expression(*expr.lhs, Some(1));
-                self.emit_expression(*expr.rhs, Some(1));
-                self.instructions.push(Instruction::BinaryLessEqual);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryLessEqual(dst, lhs, rhs)
             }
+            // operands are evaluated from left to right, and then swapped
             lua_semantics::ExprBinary::GreaterThan(expr) => {
-                self.emit_expression(*expr.rhs, Some(1));
-                self.emit_expression(*expr.lhs, Some(1));
-                self.instructions.push(Instruction::BinaryLessThan);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryLessThan(dst, rhs, lhs)
             }
             lua_semantics::ExprBinary::GreaterEqual(expr) => {
-                self.emit_expression(*expr.rhs, Some(1));
-                self.emit_expression(*expr.lhs, Some(1));
-                self.instructions.push(Instruction::BinaryLessEqual);
+                let (lhs, rhs) = self.emit_operands(expr);
+                Instruction::BinaryLessEqual(dst, rhs, lhs)
             }
             lua_semantics::ExprBinary::LogicalAnd(expr) => {
                 /*
                 The conjunction operator and returns its first argument if this value is false or nil;
                 otherwise, and returns its second argument.
 
-                AX <- eval(lhs)
-                if AX is false, jump to lhs_false_label
-                AX <- eval(rhs)
+                R[dst] <- eval(lhs)
+                if R[dst] is false, jump to lhs_false_label
+                R[dst] <- eval(rhs)
                 lhs_false_label:
                 */
 
+                // constant lhs decides which operand is the result
+                if let Some(lhs) = fold::constant(&expr.lhs) {
+                    if lhs.to_bool() {
+                        self.emit_expression_single(*expr.rhs, dst);
+                    } else {
+                        self.emit_constant(lhs, dst);
+                    }
+                    return;
+                }
                 let lhs_false_label = self.generate_label();
-                self.emit_expression(*expr.lhs, Some(1));
-                self.instructions.push(Instruction::Clone);
+                self.emit_expression_single(*expr.lhs, dst);
                 self.instructions
-                    .push(Instruction::JumpFalse(lhs_false_label));
-                self.instructions.push(Instruction::Pop);
-                self.emit_expression(*expr.rhs, Some(1));
+                    .push(Instruction::JumpFalse(dst, lhs_false_label));
+                self.emit_expression_single(*expr.rhs, dst);
                 self.set_label(lhs_false_label);
+                return;
             }
             lua_semantics::ExprBinary::LogicalOr(expr) => {
                 /*
                 The disjunction operator or returns its first argument if this value is different from nil and false;
                 otherwise, or returns its second argument.
 
-                AX <- eval(lhs)
-                if AX is true, jump to lhs_true_label
-                AX <- eval(rhs)
+                R[dst] <- eval(lhs)
+                if R[dst] is true, jump to lhs_true_label
+                R[dst] <- eval(rhs)
                 lhs_true_label:
                 */
+                if let Some(lhs) = fold::constant(&expr.lhs) {
+                    if lhs.to_bool() {
+                        self.emit_constant(lhs, dst);
+                    } else {
+                        self.emit_expression_single(*expr.rhs, dst);
+                    }
+                    return;
+                }
                 let lhs_true_label = self.generate_label();
-                self.emit_expression(*expr.lhs, Some(1));
-                self.instructions.push(Instruction::Clone);
+                self.emit_expression_single(*expr.lhs, dst);
                 self.instructions
-                    .push(Instruction::JumpTrue(lhs_true_label));
-                self.instructions.push(Instruction::Pop);
-                self.emit_expression(*expr.rhs, Some(1));
+                    .push(Instruction::JumpTrue(dst, lhs_true_label));
+                self.emit_expression_single(*expr.rhs, dst);
                 self.set_label(lhs_true_label);
+                return;
             }
-        }
-        if let Some(expected) = expected {
-            if expected == 0 {
-                self.instructions.push(Instruction::Pop);
-            } else {
-                self.emit_expression_nil(Some(expected - 1));
-            }
-        }
+        };
+        self.free_to(mark);
+        self.instructions.push(instruction);
     }
     fn emit_expression_localvariable(
         &mut self,
         expr: lua_semantics::ExprLocalVariable,
-        expected: Option<usize>,
+        dst: usize,
     ) {
-        if expected == Some(0) {
-            return;
-        }
         match expr {
-            lua_semantics::ExprLocalVariable::Stack(local_id, name) => {
-                self.instructions
-                    .push(Instruction::GetLocalVariable(local_id, name));
+            lua_semantics::ExprLocalVariable::Stack(offset, _name) => {
+                if self.is_cell(offset) {
+                    self.instructions.push(Instruction::GetCell(dst, offset));
+                } else if offset != dst {
+                    self.instructions.push(Instruction::Move(dst, offset));
+                }
             }
-            lua_semantics::ExprLocalVariable::Upvalue(index, name) => {
+            lua_semantics::ExprLocalVariable::Upvalue(index, _name) => {
                 self.instructions
-                    .push(Instruction::FunctionUpvalue(index, name));
+                    .push(Instruction::FunctionUpvalue(dst, index));
             }
         }
-        if let Some(expected) = expected {
-            self.emit_expression_nil(Some(expected - 1));
-        }
     }
 
-    fn emit_expression_variadic(&mut self, expected: Option<usize>) {
-        self.instructions.push(Instruction::GetVariadic(expected));
-    }
     fn emit_expression_tableconstructor(
         &mut self,
         expr: lua_semantics::ExprTableConstructor,
-        expected: Option<usize>,
+        dst: usize,
     ) {
         self.instructions
-            .push(Instruction::TableInit(expr.fields.len()));
+            .push(Instruction::TableInit(dst, expr.fields.len()));
 
+        let mark = self.free_reg;
         for (key, value) in expr.fields {
-            self.emit_expression(key, Some(1));
-            self.emit_expression(value, Some(1));
-            self.instructions.push(Instruction::TableIndexInit);
+            let key = self.emit_expression_reg(key);
+            let value = self.emit_expression_reg(value);
+            self.instructions
+                .push(Instruction::TableIndexInit(dst, key, value));
+            self.free_to(mark);
         }
 
         if let Some((last_idx, last_expr)) = expr.last_value_field {
-            self.instructions.push(Instruction::Sp);
-            self.emit_expression(*last_expr, None);
-            self.instructions.push(Instruction::TableInitLast(last_idx));
-        }
-
-        if let Some(expected) = expected {
-            if expected == 0 {
-                self.instructions.push(Instruction::Pop);
-            } else {
-                self.emit_expression_nil(Some(expected - 1));
-            }
+            let count = self.emit_expression_list_top(vec![*last_expr]);
+            self.instructions
+                .push(Instruction::TableInitLast(dst, mark, count, last_idx));
+            self.free_to(mark);
         }
     }
 }
@@ -467,108 +708,88 @@ impl Context {
 impl Context {
     fn emit_statement_for(&mut self, stmt: lua_semantics::StmtFor) {
         let break_label = self.generate_label();
-        let continue_label = self.generate_label();
+        let body_label = self.generate_label();
         self.loop_stack.push(break_label);
-        let control_offset = stmt.control_variable.borrow().offset;
 
-        self.emit_expression(stmt.start, Some(1));
+        // initial value, limit and step, evaluated once
+        let mark = self.free_reg;
+        let base = self.alloc(3);
+        self.emit_expression_single(stmt.start, base);
+        self.emit_expression_single(stmt.end, base + 1);
+        self.emit_expression_single(stmt.step, base + 2);
+        self.instructions
+            .push(Instruction::ForPrep(base, break_label));
 
-        self.set_label(continue_label);
+        self.set_label(body_label);
+        let control = stmt.control_variable.borrow();
+        let is_cell = self.declare(&control);
         self.instructions
-            .push(Instruction::InitLocalVariable(control_offset));
-        // check range
-        self.instructions.push(Instruction::GetLocalVariable(
-            control_offset,
-            "@control".to_string(),
-        ));
-        self.emit_expression(stmt.end, Some(1));
-        self.instructions.push(Instruction::BinaryLessEqual); // @TODO less, overflow check
-        self.instructions.push(Instruction::JumpFalse(break_label));
+            .push(Instruction::Move(control.offset, base));
+        if is_cell {
+            self.instructions
+                .push(Instruction::InitCell(control.offset));
+        }
+        drop(control);
 
         self.emit_block(stmt.block);
-        self.instructions.push(Instruction::GetLocalVariable(
-            control_offset,
-            "@control".to_string(),
-        ));
-        self.emit_expression(stmt.step, Some(1));
-        self.instructions.push(Instruction::BinaryAdd);
-        self.instructions.push(Instruction::Jump(continue_label));
+        self.instructions
+            .push(Instruction::ForLoop(base, body_label));
 
         self.set_label(break_label);
         self.loop_stack.pop();
+        self.free_to(mark);
     }
     fn emit_statement_forgeneric(&mut self, stmt: lua_semantics::StmtForGeneric) {
         let break_label = self.generate_label();
         let continue_label = self.generate_label();
         self.loop_stack.push(break_label);
 
-        // emit exactly 4 expressions for (iterator, state, initial_value, closing_value)
-        {
-            let mut emit_count = 0;
-            let exp_len = stmt.expressions.len();
-            for (idx, exp) in stmt.expressions.into_iter().enumerate() {
-                if idx == exp_len - 1 {
-                    if emit_count <= 4 {
-                        self.emit_expression(exp, Some(4 - emit_count));
-                    } else {
-                        self.emit_expression(exp, Some(0));
-                    }
-                } else {
-                    if emit_count < 4 {
-                        self.emit_expression(exp, Some(1));
-                        emit_count += 1;
-                    } else {
-                        self.emit_expression(exp, Some(0));
-                    }
-                }
-            }
-        }
-
-        self.instructions
-            .push(Instruction::InitLocalVariable(stmt.closing.borrow().offset));
-        self.instructions.push(Instruction::InitLocalVariable(
-            stmt.control_variables[0].borrow().offset,
-        ));
+        // (iterator, state, initial_value, closing_value) are evaluated to hidden local variables;
+        // the control value is kept in the register of closing value, which is not used
+        let iterator = stmt.iterator.borrow().offset;
+        let state = stmt.state.borrow().offset;
+        let control = stmt.closing.borrow().offset;
+        self.declare(&stmt.iterator.borrow());
+        self.declare(&stmt.state.borrow());
+        self.declare(&stmt.closing.borrow());
+        let mark = self.free_reg;
+        let values = self.alloc(4);
+        self.emit_expression_list(stmt.expressions, values, 4);
+        self.instructions.push(Instruction::Move(iterator, values));
+        self.instructions.push(Instruction::Move(state, values + 1));
         self.instructions
-            .push(Instruction::InitLocalVariable(stmt.state.borrow().offset));
-        self.instructions.push(Instruction::InitLocalVariable(
-            stmt.iterator.borrow().offset,
-        ));
+            .push(Instruction::Move(control, values + 2));
+        self.free_to(mark);
 
         // ::continue_label::
         self.set_label(continue_label);
 
         // iterator function call
         // control_variables* ... = iterator( state, control_variable_0 )
-        self.instructions.push(Instruction::Sp);
-        self.instructions.push(Instruction::GetLocalVariable(
-            stmt.state.borrow().offset,
-            "@control".to_string(),
-        ));
-        self.instructions.push(Instruction::GetLocalVariable(
-            stmt.control_variables[0].borrow().offset,
-            "@control".to_string(),
-        ));
-        self.instructions.push(Instruction::GetLocalVariable(
-            stmt.iterator.borrow().offset,
-            "@control".to_string(),
-        ));
-        self.instructions.push(Instruction::FunctionCall(Some(
-            stmt.control_variables.len(),
-        )));
-        for control_var in stmt.control_variables.iter().rev() {
+        let count = stmt.control_variables.len();
+        let func = self.alloc(3.max(count + 1));
+        self.instructions.push(Instruction::Move(func, iterator));
+        self.instructions.push(Instruction::Move(func + 1, state));
+        self.instructions.push(Instruction::Move(func + 2, control));
+        self.instructions
+            .push(Instruction::FunctionCall(func, Some(2), Some(count)));
+        // break if control_variable_0 is nil
+        self.instructions
+            .push(Instruction::IsNil(func + count, func));
+        self.instructions
+            .push(Instruction::JumpTrue(func + count, break_label));
+        self.instructions.push(Instruction::Move(control, func));
+        for (idx, control_var) in stmt.control_variables.iter().enumerate() {
+            let control_var = control_var.borrow();
+            let is_cell = self.declare(&control_var);
             self.instructions
-                .push(Instruction::InitLocalVariable(control_var.borrow().offset));
-        }
-        // get control_variable_0
-        self.instructions.push(Instruction::GetLocalVariable(
-            stmt.control_variables[0].borrow().offset,
-            "@control".to_string(),
-        ));
-        // check if it is nil
-        self.instructions.push(Instruction::IsNil);
-        // jump to break_label if it is nil
-        self.instructions.push(Instruction::JumpTrue(break_label));
+                .push(Instruction::Move(control_var.offset, func + idx));
+            if is_cell {
+                self.instructions
+                    .push(Instruction::InitCell(control_var.offset));
+            }
+        }
+        self.free_to(mark);
 
         self.emit_block(stmt.block);
         self.instructions.push(Instruction::Jump(continue_label));
@@ -580,92 +801,162 @@ impl Context {
     fn emit_expression_function_object(
         &mut self,
         expr: lua_semantics::ExprFunctionObject,
-        expected: Option<usize>,
+        dst: usize,
     ) {
-        let function_context = Self::new();
-        let lua_function = LuaFunctionLua {
-            upvalues: Vec::with_capacity(expr.upvalues_source.len()),
+        let function_context = Context {
+            chunkname: Rc::clone(&self.chunkname),
+            line_starts: Rc::clone(&self.line_starts),
+            opt_level: self.opt_level,
+            ..Self::new()
+        };
+        let prototype = Prototype {
             args: expr.definition.args.len(),
             is_variadic: expr.definition.variadic,
-            chunk: function_context.emit(expr.definition.body),
+            upvalues: expr.upvalues_source.len(),
+            chunk: match function_context.emit_function(&expr.definition.args, expr.definition.body)
+            {
+                Ok(mut chunk) => {
+                    chunk.upvalue_names = expr
+                        .upvalues_source
+                        .iter()
+                        .map(|upvalue| match upvalue {
+                            ExprLocalVariable::Stack(_, name)
+                            | ExprLocalVariable::Upvalue(_, name) => name.clone(),
+                        })
+                        .collect();
+                    chunk
+                }
+                Err(err) => {
+                    self.error.get_or_insert(err);
+                    Chunk::default()
+                }
+            },
         };
+        let index = self.prototypes.len();
+        self.prototypes.push(Rc::new(prototype));
 
         self.instructions
-            .push(Instruction::FunctionInit(Box::new(lua_function)));
+            .push(Instruction::FunctionInit(dst, index));
 
         // initialize upvalues
         for upvalue in expr.upvalues_source {
             match upvalue {
                 ExprLocalVariable::Stack(local_id, _name) => {
                     self.instructions
-                        .push(Instruction::FunctionInitUpvalueFromLocalVar(local_id));
+                        .push(Instruction::FunctionInitUpvalueFromLocalVar(dst, local_id));
                 }
                 ExprLocalVariable::Upvalue(index, _name) => {
                     self.instructions
-                        .push(Instruction::FunctionInitUpvalueFromUpvalue(index));
+                        .push(Instruction::FunctionInitUpvalueFromUpvalue(dst, index));
                 }
             }
         }
-        if let Some(expected) = expected {
-            if expected == 0 {
-                self.instructions.push(Instruction::Pop);
-            } else {
-                self.emit_expression_nil(Some(expected - 1));
-            }
-        }
     }
 
+    /// emit instructions that evaluate the function and the arguments of a call into `R[func], R[func + 1], ...`;
+    /// `R[func]` must be the last allocated register.
+    /// Returns the number of arguments, `None` if they are left up to the top of the data stack.
+    fn emit_call_operands(
+        &mut self,
+        expr: lua_semantics::ExprFunctionCall,
+        func: usize,
+    ) -> Option<usize> {
+        // prefix:method( args ) -> prefix.method( prefix, args )
+        if let Some(method) = expr.method {
+            let this = self.alloc(1);
+            self.emit_expression_single(*expr.prefix, this);
+            let key = self.string_constant(method.as_bytes());
+            let cache = self.field_cache();
+            self.instructions
+                .push(Instruction::GetField(func, this, key, cache));
+            self.emit_expression_list_top(expr.args)
+                .map(|count| count + 1)
+        } else {
+            self.emit_expression_single(*expr.prefix, func);
+            self.emit_expression_list_top(expr.args)
+        }
+    }
     fn emit_expression_functioncall(
         &mut self,
         expr: lua_semantics::ExprFunctionCall,
+        dst: usize,
         expected: Option<usize>,
     ) {
-        self.instructions.push(Instruction::Sp);
-        // prefix:method( args ) -> prefix.method( prefix, args )
-        if let Some(method) = expr.method {
-            self.emit_expression(*expr.prefix, Some(1));
-            let len = expr.args.len();
-            for (idx, arg) in expr.args.into_iter().enumerate() {
-                if idx == len - 1 {
-                    self.emit_expression(arg, None);
-                } else {
-                    self.emit_expression(arg, Some(1));
+        let mark = self.free_reg;
+        // call in place if no temporary value above `dst` is alive,
+        // since calls discard the registers above their arguments
+        let in_place = dst >= self.temp_base && self.free_reg <= dst + expected.unwrap_or(1).max(1);
+        if in_place {
+            self.free_to(dst);
+        }
+        let func = self.alloc(1);
+        let args = self.emit_call_operands(expr, func);
+        self.instructions
+            .push(Instruction::FunctionCall(func, args, expected));
+        if let Some(expected) = expected {
+            self.touch(func + expected);
+            if !in_place {
+                for idx in 0..expected {
+                    self.instructions
+                        .push(Instruction::Move(dst + idx, func + idx));
                 }
             }
+        }
+        self.free_to(mark);
+    }
 
-            self.instructions.push(Instruction::Deref);
-            self.instructions
-                .push(Instruction::String(method.into_bytes()));
-            self.instructions.push(Instruction::TableIndex);
-        } else {
-            let len = expr.args.len();
-            for (idx, arg) in expr.args.into_iter().enumerate() {
-                if idx == len - 1 {
-                    self.emit_expression(arg, None);
-                } else {
-                    self.emit_expression(arg, Some(1));
-                }
+    fn emit_return(&mut self, values: Vec<Expression>) {
+        let mark = self.free_reg;
+        match <[Expression; 1]>::try_from(values) {
+            // `return f(args)`
+            Ok([lua_semantics::Expression::FunctionCall(expr)]) => {
+                let func = self.alloc(1);
+                let args = self.emit_call_operands(expr, func);
+                self.instructions.push(Instruction::TailCall(func, args));
+                self.instructions.push(Instruction::Return(func, None));
+            }
+            Ok([value]) if !Self::is_multi(&value) => {
+                let reg = self.emit_expression_reg(value);
+                self.instructions.push(Instruction::Return(reg, Some(1)));
+            }
+            Ok([value]) => {
+                let base = self.alloc(1);
+                self.emit_expression(value, base, None);
+                self.instructions.push(Instruction::Return(base, None));
+            }
+            Err(values) => {
+                let base = self.free_reg;
+                let count = self.emit_expression_list_top(values);
+                self.instructions.push(Instruction::Return(base, count));
             }
-            self.emit_expression(*expr.prefix, Some(1));
         }
-        self.instructions.push(Instruction::FunctionCall(expected));
+        self.free_to(mark);
     }
 
+    /// emit instructions that evaluate a condition, returns its register
+    fn emit_condition(&mut self, condition: Expression) -> usize {
+        let mark = self.free_reg;
+        let reg = self.emit_expression_reg(condition);
+        self.free_to(mark);
+        reg
+    }
     fn emit_statement_if(&mut self, stmt: lua_semantics::StmtIf) {
         let end_label = self.generate_label();
 
         {
             let false_label = self.generate_label();
-            self.emit_expression(stmt.condition, Some(1));
-            self.instructions.push(Instruction::JumpFalse(false_label));
+            let condition = self.emit_condition(stmt.condition);
+            self.instructions
+                .push(Instruction::JumpFalse(condition, false_label));
             self.emit_block(stmt.block);
             self.instructions.push(Instruction::Jump(end_label));
             self.set_label(false_label);
         }
         for (cond, blk) in stmt.else_ifs {
             let false_label = self.generate_label();
-            self.emit_expression(cond, Some(1));
-            self.instructions.push(Instruction::JumpFalse(false_label));
+            let condition = self.emit_condition(cond);
+            self.instructions
+                .push(Instruction::JumpFalse(condition, false_label));
             self.emit_block(blk);
             self.instructions.push(Instruction::Jump(end_label));
             self.set_label(false_label);
@@ -676,34 +967,24 @@ impl Context {
         self.set_label(end_label);
     }
     fn emit_statement_functioncall(&mut self, stmt: lua_semantics::StmtFunctionCall) {
-        self.emit_expression_functioncall(stmt, Some(0))
+        let dst = self.free_reg;
+        self.emit_expression_functioncall(stmt, dst, Some(0))
     }
     fn emit_statement_localdeclaration(&mut self, stmt: lua_semantics::StmtLocalDeclaration) {
-        if let Some(expr) = stmt.values {
-            let lhs_len = stmt.decls.len();
-            let rhs_len = expr.len();
-            for (idx, rhs) in expr.into_iter().enumerate() {
-                if idx == rhs_len - 1 {
-                    if idx < lhs_len {
-                        self.emit_expression(rhs, Some(lhs_len - idx));
-                    } else {
-                        self.emit_expression(rhs, Some(0));
-                    }
-                } else {
-                    if idx < lhs_len {
-                        self.emit_expression(rhs, Some(1));
-                    } else {
-                        self.emit_expression(rhs, Some(0));
-                    }
-                }
-            }
-        } else {
-            self.emit_expression_nil(Some(stmt.decls.len()));
+        let Some(base) = stmt.decls.first().map(|(var, _)| var.borrow().offset) else {
+            return;
+        };
+        let count = stmt.decls.len();
+        // registers of new local variables are not alive yet; values are evaluated into them directly
+        match stmt.values {
+            Some(values) => self.emit_expression_list(values, base, count),
+            None => self.instructions.push(Instruction::Nil(base, count)),
         }
-        for (lhs_info, _attrib) in stmt.decls.into_iter().rev() {
-            let local_id = lhs_info.borrow().offset;
-            self.instructions
-                .push(Instruction::InitLocalVariable(local_id));
+        for (var, _attrib) in stmt.decls {
+            let var = var.borrow();
+            if self.declare(&var) {
+                self.instructions.push(Instruction::InitCell(var.offset));
+            }
         }
     }
     fn emit_statement_while(&mut self, stmt: lua_semantics::StmtWhile) {
@@ -712,8 +993,9 @@ impl Context {
         self.loop_stack.push(break_label);
 
         self.set_label(continue_label);
-        self.emit_expression(stmt.condition, Some(1));
-        self.instructions.push(Instruction::JumpFalse(break_label));
+        let condition = self.emit_condition(stmt.condition);
+        self.instructions
+            .push(Instruction::JumpFalse(condition, break_label));
         self.emit_block(stmt.block);
         self.instructions.push(Instruction::Jump(continue_label));
         self.set_label(break_label);
@@ -727,36 +1009,90 @@ impl Context {
 
         self.set_label(continue_label);
         self.emit_block(stmt.block);
-        self.emit_expression(stmt.condition, Some(1));
+        let condition = self.emit_condition(stmt.condition);
         self.instructions
-            .push(Instruction::JumpTrue(continue_label));
+            .push(Instruction::JumpFalse(condition, continue_label));
         self.set_label(break_label);
 
         self.loop_stack.pop();
     }
-    fn emit_statement_assignment(&mut self, stmt: lua_semantics::StmtAssignment) {
-        let lhs_len = stmt.lhs.len();
-        let rhs_len = stmt.rhs.len();
-
-        for (idx, rhs) in stmt.rhs.into_iter().enumerate() {
-            if idx == rhs_len - 1 {
-                if idx < lhs_len {
-                    self.emit_expression(rhs, Some(lhs_len - idx));
-                } else {
-                    self.emit_expression(rhs, Some(0));
+    /// store `R[src]` to assignment target `entry`
+    fn emit_expression_set(&mut self, entry: lua_semantics::Expression, src: usize) {
+        match entry {
+            lua_semantics::Expression::LocalVariable(expr) => match expr {
+                ExprLocalVariable::Stack(local_id, _name) => {
+                    if self.is_cell(local_id) {
+                        self.instructions.push(Instruction::SetCell(local_id, src));
+                    } else if local_id != src {
+                        self.instructions.push(Instruction::Move(local_id, src));
+                    }
                 }
-            } else {
-                if idx < lhs_len {
-                    self.emit_expression(rhs, Some(1));
+                ExprLocalVariable::Upvalue(index, _name) => {
+                    self.instructions
+                        .push(Instruction::FunctionUpvalueSet(index, src));
+                }
+            },
+            lua_semantics::Expression::TableIndex(expr) => {
+                let mark = self.free_reg;
+                let table = self.emit_expression_reg(*expr.table);
+                if let lua_semantics::Expression::String(name) = &*expr.index {
+                    let key = self.string_constant(name);
+                    let cache = self.field_cache();
+                    self.instructions
+                        .push(Instruction::SetField(table, key, src, cache));
                 } else {
-                    self.emit_expression(rhs, Some(0));
+                    let index = self.emit_expression_reg(*expr.index);
+                    self.instructions
+                        .push(Instruction::TableIndexSet(table, index, src));
                 }
+                self.free_to(mark);
             }
+            _ => self.unsupported(format!("assignment target: {:?}", entry)),
+        }
+    }
+    fn emit_statement_assignment(&mut self, stmt: lua_semantics::StmtAssignment) {
+        let mark = self.free_reg;
+        if stmt.lhs.len() == 1 && stmt.rhs.len() == 1 {
+            let lhs = stmt.lhs.into_iter().next().unwrap();
+            let rhs = stmt.rhs.into_iter().next().unwrap();
+            match lhs {
+                lua_semantics::Expression::LocalVariable(ExprLocalVariable::Stack(offset, _))
+                    if !self.is_cell(offset) =>
+                {
+                    self.emit_expression_to_local(rhs, offset);
+                }
+                lua_semantics::Expression::TableIndex(expr) => {
+                    let table = self.emit_expression_reg(*expr.table);
+                    if let lua_semantics::Expression::String(name) = &*expr.index {
+                        let key = self.string_constant(name);
+                        let cache = self.field_cache();
+                        let value = self.emit_expression_reg(rhs);
+                        self.instructions
+                            .push(Instruction::SetField(table, key, value, cache));
+                    } else {
+                        let index = self.emit_expression_reg(*expr.index);
+                        let value = self.emit_expression_reg(rhs);
+                        self.instructions
+                            .push(Instruction::TableIndexSet(table, index, value));
+                    }
+                }
+                lhs => {
+                    let value = self.emit_expression_reg(rhs);
+                    self.emit_expression_set(lhs, value);
+                }
+            }
+            self.free_to(mark);
+            return;
         }
 
-        for lhs in stmt.lhs.into_iter().rev() {
-            self.emit_expression_set(lhs);
+        // every value is evaluated before any assignment
+        let count = stmt.lhs.len();
+        let base = self.alloc(count);
+        self.emit_expression_list(stmt.rhs, base, count);
+        for (idx, lhs) in stmt.lhs.into_iter().enumerate() {
+            self.emit_expression_set(lhs, base + idx);
         }
+        self.free_to(mark);
     }
     fn emit_statement_break(&mut self) {
         let break_label = match self.loop_stack.last() {
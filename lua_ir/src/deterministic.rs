@@ -0,0 +1,129 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::rc::Weak;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::LuaEnv;
+use crate::LuaValue;
+
+/// Clock controlled by the host, read by `os.time`, `os.clock` and `os.date` in deterministic mode.
+///
+/// Clones share the same time, so the host can advance it while the `LuaEnv` holds a clone.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    /// time when the clock was created; `os.clock` counts from here
+    start: f64,
+    /// seconds since Unix epoch
+    now: Rc<Cell<f64>>,
+}
+impl VirtualClock {
+    /// Create a clock starting at `time`, in seconds since Unix epoch.
+    pub fn new(time: f64) -> Self {
+        VirtualClock {
+            start: time,
+            now: Rc::new(Cell::new(time)),
+        }
+    }
+    /// Current time, in seconds since Unix epoch.
+    pub fn time(&self) -> f64 {
+        self.now.get()
+    }
+    pub fn set_time(&self, time: f64) {
+        self.now.set(time);
+    }
+    /// Move the clock forward by `seconds`.
+    pub fn advance(&self, seconds: f64) {
+        self.now.set(self.now.get() + seconds);
+    }
+}
+
+/// Source of time for `os` library.
+#[derive(Debug, Clone)]
+pub(crate) enum Clock {
+    /// clock of the system; `os.clock` counts from the creation of `LuaEnv`
+    System(Instant),
+    Virtual(VirtualClock),
+}
+impl Clock {
+    /// seconds since Unix epoch
+    pub(crate) fn time(&self) -> f64 {
+        match self {
+            Clock::System(_) => match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs_f64(),
+                Err(err) => -err.duration().as_secs_f64(),
+            },
+            Clock::Virtual(clock) => clock.time(),
+        }
+    }
+    /// seconds elapsed, for `os.clock`
+    pub(crate) fn clock(&self) -> f64 {
+        match self {
+            Clock::System(start) => start.elapsed().as_secs_f64(),
+            Clock::Virtual(clock) => clock.time() - clock.start,
+        }
+    }
+}
+
+/// IDs of reference values, numbered in order of appearance.
+///
+/// Each entry holds a weak reference to the object, which keeps the allocation alive;
+/// so the address cannot be reused by another object while the entry exists.
+#[derive(Default)]
+pub(crate) struct StableIds {
+    ids: HashMap<*const (), (Weak<dyn Any>, usize)>,
+    /// number of IDs given so far
+    next: usize,
+    /// size of `ids` to remove entries of dropped objects
+    prune_at: usize,
+}
+impl StableIds {
+    fn id(&mut self, object: Rc<dyn Any>) -> usize {
+        let ptr = Rc::as_ptr(&object) as *const ();
+        if let Some((_, id)) = self.ids.get(&ptr) {
+            return *id;
+        }
+        if self.ids.len() >= self.prune_at {
+            self.ids.retain(|_, (object, _)| object.strong_count() > 0);
+            self.prune_at = (self.ids.len() * 2).max(64);
+        }
+        self.next += 1;
+        self.ids.insert(ptr, (Rc::downgrade(&object), self.next));
+        self.next
+    }
+}
+
+impl LuaEnv {
+    /// Returns `true` if this `LuaEnv` was built with [`crate::LuaEnvBuilder::deterministic`].
+    pub fn is_deterministic(&self) -> bool {
+        self.stable_ids.is_some()
+    }
+    /// Virtual clock of deterministic mode.
+    pub fn virtual_clock(&self) -> Option<&VirtualClock> {
+        match &self.clock {
+            Clock::Virtual(clock) => Some(clock),
+            Clock::System(_) => None,
+        }
+    }
+
+    /// Address of the reference value for `tostring`, `0x...`;
+    /// ID numbered in order of appearance in deterministic mode.
+    pub(crate) fn address_of(&mut self, object: Rc<dyn Any>) -> String {
+        match &mut self.stable_ids {
+            Some(ids) => format!("0x{:08x}", ids.id(object)),
+            None => format!("{:p}", Rc::as_ptr(&object) as *const ()),
+        }
+    }
+    /// `tostring` of a value without metamethods.
+    pub(crate) fn value_to_string(&mut self, value: &LuaValue) -> String {
+        match value {
+            LuaValue::Table(t) => format!("table: {}", self.address_of(t.clone())),
+            LuaValue::Function(f) => format!("function: {}", self.address_of(f.clone())),
+            LuaValue::UserData(u) => format!("userdata: {}", self.address_of(u.clone())),
+            LuaValue::Thread(t) => format!("thread: {}", self.address_of(t.clone())),
+            value => value.to_string(),
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::fmt::Write;
+
+use crate::vm::Chunk;
+use crate::CompiledChunk;
+use crate::Instruction;
+use crate::LuaNumber;
+use crate::Prototype;
+
+/// Where an upvalue of a nested function is captured from.
+enum UpvalueSource {
+    LocalVar(usize),
+    Upvalue(usize),
+    /// upvalues of a main chunk are set by the caller
+    Unknown,
+}
+
+/// Function prototype waiting to be listed.
+struct Pending<'a> {
+    id: usize,
+    /// line of the `FunctionInit` instruction, 0 if unknown
+    line: u32,
+    function: &'a Prototype,
+    upvalues: Vec<UpvalueSource>,
+}
+
+/// Writes listings of a chunk and its nested functions, like `luac -l -l`.
+struct Disassembler<'a> {
+    out: String,
+    chunkname: &'a str,
+    /// number of nested functions found so far; function `#0` is the main chunk
+    functions: usize,
+}
+
+impl<'a> Disassembler<'a> {
+    /// List `chunk`, then every function defined in it, depth first.
+    fn main(&mut self, chunk: &'a Chunk, args: usize, is_variadic: bool, upvalues: usize) {
+        let header = format!("main <{}>", self.chunkname);
+        let upvalues = (0..upvalues).map(|_| UpvalueSource::Unknown).collect();
+        let nested = self.function(&header, chunk, args, is_variadic, upvalues);
+        self.nested(nested);
+    }
+    fn nested(&mut self, nested: Vec<Pending<'a>>) {
+        for pending in nested {
+            let header = if pending.line == 0 {
+                format!("function #{} <{}>", pending.id, self.chunkname)
+            } else {
+                format!(
+                    "function #{} <{}:{}>",
+                    pending.id, self.chunkname, pending.line
+                )
+            };
+            let function = pending.function;
+            let nested = self.function(
+                &header,
+                &function.chunk,
+                function.args,
+                function.is_variadic,
+                pending.upvalues,
+            );
+            self.nested(nested);
+        }
+    }
+
+    /// List one function, returning the functions defined in it.
+    fn function(
+        &mut self,
+        header: &str,
+        chunk: &'a Chunk,
+        args: usize,
+        is_variadic: bool,
+        upvalues: Vec<UpvalueSource>,
+    ) -> Vec<Pending<'a>> {
+        // names of local variables and upvalues are kept in the debug information of the chunk
+        let mut local_names: Vec<Option<&str>> = (0..chunk.stack_size)
+            .map(|index| {
+                chunk
+                    .local_names
+                    .get(index)
+                    .map(String::as_str)
+                    .filter(|name| !name.is_empty())
+            })
+            .collect();
+        let upvalue_names: Vec<Option<&str>> = (0..upvalues.len())
+            .map(|index| upvalue_name_in(chunk, index))
+            .collect();
+        // locals without a name are named after the upvalues capturing them
+        for (pc, instruction) in chunk.instructions.iter().enumerate() {
+            let Instruction::FunctionInit(_, index) = instruction else {
+                continue;
+            };
+            let Some(function) = chunk.prototypes.get(*index) else {
+                continue;
+            };
+            let captured = upvalue_sources(&chunk.instructions[pc + 1..]);
+            for (upvalue, source) in captured.iter().enumerate() {
+                let UpvalueSource::LocalVar(index) = source else {
+                    continue;
+                };
+                let Some(slot) = local_names.get_mut(*index) else {
+                    continue;
+                };
+                if slot.is_none() {
+                    *slot = upvalue_name_in(&function.chunk, upvalue);
+                }
+            }
+        }
+        let local_name = |index: usize| local_names.get(index).copied().flatten();
+        let upvalue_name = |index: usize| upvalue_names.get(index).copied().flatten();
+
+        let Self { out, functions, .. } = self;
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let _ = writeln!(
+            out,
+            "{} ({} instructions)",
+            header,
+            chunk.instructions.len()
+        );
+        let _ = writeln!(
+            out,
+            "{}{} params, {} registers, {} upvalues",
+            args,
+            if is_variadic { "+" } else { "" },
+            chunk.stack_size,
+            upvalues.len(),
+        );
+
+        let mut nested = Vec::new();
+        for (pc, instruction) in chunk.instructions.iter().enumerate() {
+            let line = chunk.lines.get(pc).copied().unwrap_or(0);
+            let local_comment = |index: usize| local_name(index).unwrap_or_default().to_string();
+            let target_comment = |target: usize| format!("to {}", target);
+            let constant_comment = |index: usize| match chunk.constants.get(index) {
+                Some(value) => format!("{:?}", String::from_utf8_lossy(value.as_bytes())),
+                None => "?".to_string(),
+            };
+            let (operands, comment) = match instruction {
+                Instruction::Move(dst, src) => (registers(&[*dst, *src]), String::new()),
+                Instruction::Jump(target) => (target.to_string(), target_comment(*target)),
+                Instruction::JumpTrue(src, target) | Instruction::JumpFalse(src, target) => {
+                    (registers(&[*src, *target]), target_comment(*target))
+                }
+                Instruction::InitCell(cell) => (cell.to_string(), local_comment(*cell)),
+                Instruction::GetCell(dst, cell) => {
+                    (registers(&[*dst, *cell]), local_comment(*cell))
+                }
+                Instruction::SetCell(cell, src) => {
+                    (registers(&[*cell, *src]), local_comment(*cell))
+                }
+                Instruction::FunctionInitUpvalueFromLocalVar(func, cell) => {
+                    (registers(&[*func, *cell]), local_comment(*cell))
+                }
+                Instruction::FunctionUpvalue(dst, index) => (
+                    registers(&[*dst, *index]),
+                    upvalue_name(*index).unwrap_or_default().to_string(),
+                ),
+                Instruction::FunctionUpvalueSet(index, src) => (
+                    registers(&[*index, *src]),
+                    upvalue_name(*index).unwrap_or_default().to_string(),
+                ),
+                Instruction::FunctionInitUpvalueFromUpvalue(func, index) => (
+                    registers(&[*func, *index]),
+                    upvalue_name(*index).unwrap_or_default().to_string(),
+                ),
+                Instruction::IsNil(dst, src)
+                | Instruction::UnaryMinus(dst, src)
+                | Instruction::UnaryBitwiseNot(dst, src)
+                | Instruction::UnaryLength(dst, src)
+                | Instruction::UnaryLogicalNot(dst, src) => {
+                    (registers(&[*dst, *src]), String::new())
+                }
+                Instruction::Nil(dst, count) => (registers(&[*dst, *count]), String::new()),
+                Instruction::Boolean(dst, value) => (format!("{} {}", dst, value), String::new()),
+                Instruction::Numeric(dst, LuaNumber::Int(value)) => {
+                    (format!("{} {}", dst, value), String::new())
+                }
+                // `{:?}` keeps the fraction of integral floats
+                Instruction::Numeric(dst, LuaNumber::Float(value)) => {
+                    (format!("{} {:?}", dst, value), String::new())
+                }
+                Instruction::String(dst, index) => {
+                    (registers(&[*dst, *index]), constant_comment(*index))
+                }
+                Instruction::GetField(dst, table, key, cache) => (
+                    registers(&[*dst, *table, *key, *cache]),
+                    constant_comment(*key),
+                ),
+                Instruction::SetField(table, key, value, cache) => (
+                    registers(&[*table, *key, *value, *cache]),
+                    constant_comment(*key),
+                ),
+                Instruction::GetEnv(dst) => (dst.to_string(), String::new()),
+                Instruction::TableInit(dst, capacity) => {
+                    (registers(&[*dst, *capacity]), String::new())
+                }
+                Instruction::TableIndexInit(a, b, c)
+                | Instruction::TableIndex(a, b, c)
+                | Instruction::TableIndexSet(a, b, c)
+                | Instruction::BinaryAdd(a, b, c)
+                | Instruction::BinarySub(a, b, c)
+                | Instruction::BinaryMul(a, b, c)
+                | Instruction::BinaryDiv(a, b, c)
+                | Instruction::BinaryFloorDiv(a, b, c)
+                | Instruction::BinaryMod(a, b, c)
+                | Instruction::BinaryPow(a, b, c)
+                | Instruction::BinaryConcat(a, b, c)
+                | Instruction::BinaryBitwiseAnd(a, b, c)
+                | Instruction::BinaryBitwiseOr(a, b, c)
+                | Instruction::BinaryBitwiseXor(a, b, c)
+                | Instruction::BinaryShiftLeft(a, b, c)
+                | Instruction::BinaryShiftRight(a, b, c)
+                | Instruction::BinaryEqual(a, b, c)
+                | Instruction::BinaryLessThan(a, b, c)
+                | Instruction::BinaryLessEqual(a, b, c) => {
+                    (registers(&[*a, *b, *c]), String::new())
+                }
+                Instruction::TableInitLast(table, base, count, start) => (
+                    format!("{} {} {} {}", table, base, count_of(*count), start),
+                    String::new(),
+                ),
+                Instruction::ForPrep(base, target) | Instruction::ForLoop(base, target) => {
+                    (registers(&[*base, *target]), target_comment(*target))
+                }
+                Instruction::FunctionCall(func, args, expected) => (
+                    format!("{} {} {}", func, count_of(*args), count_of(*expected)),
+                    String::new(),
+                ),
+                Instruction::TailCall(func, args) => {
+                    (format!("{} {}", func, count_of(*args)), String::new())
+                }
+                Instruction::Return(base, count) | Instruction::GetVariadic(base, count) => {
+                    (format!("{} {}", base, count_of(*count)), String::new())
+                }
+                Instruction::FunctionInit(dst, index) => match chunk.prototypes.get(*index) {
+                    Some(function) => {
+                        *functions += 1;
+                        let id = *functions;
+                        let upvalues = upvalue_sources(&chunk.instructions[pc + 1..]);
+                        let comment = format!("{} upvalues", upvalues.len());
+                        nested.push(Pending {
+                            id,
+                            line,
+                            function,
+                            upvalues,
+                        });
+                        (format!("{} #{}", dst, id), comment)
+                    }
+                    None => (format!("{} #?", dst), String::new()),
+                },
+            };
+            let name = name_of(instruction);
+
+            let line = if line == 0 {
+                "[-]".to_string()
+            } else {
+                format!("[{}]", line)
+            };
+            let row = if comment.is_empty() {
+                format!("\t{}\t{}\t{:<20}\t{}", pc, line, name, operands)
+            } else {
+                format!(
+                    "\t{}\t{}\t{:<20}\t{:<8}\t; {}",
+                    pc, line, name, operands, comment
+                )
+            };
+            let _ = writeln!(out, "{}", row.trim_end());
+        }
+
+        let _ = writeln!(out, "registers ({}):", chunk.stack_size);
+        for index in 0..chunk.stack_size {
+            let _ = writeln!(out, "\t{}\t{}", index, local_name(index).unwrap_or("?"));
+        }
+        let _ = writeln!(out, "upvalues ({}):", upvalues.len());
+        for (index, source) in upvalues.iter().enumerate() {
+            let source = match source {
+                UpvalueSource::LocalVar(source) => format!("local {}", source),
+                UpvalueSource::Upvalue(source) => format!("upvalue {}", source),
+                UpvalueSource::Unknown => "-".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "\t{}\t{}\t{}",
+                index,
+                upvalue_name(index).unwrap_or("?"),
+                source
+            );
+        }
+        nested
+    }
+}
+
+/// Upvalues of a new function, captured by the instructions right after its `FunctionInit`.
+fn upvalue_sources(instructions: &[Instruction]) -> Vec<UpvalueSource> {
+    instructions
+        .iter()
+        .map_while(|instruction| match instruction {
+            Instruction::FunctionInitUpvalueFromLocalVar(_, index) => {
+                Some(UpvalueSource::LocalVar(*index))
+            }
+            Instruction::FunctionInitUpvalueFromUpvalue(_, index) => {
+                Some(UpvalueSource::Upvalue(*index))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Name of the `upvalue`'th upvalue of `chunk`, if there is debug information.
+fn upvalue_name_in(chunk: &Chunk, upvalue: usize) -> Option<&str> {
+    chunk
+        .upvalue_names
+        .get(upvalue)
+        .map(String::as_str)
+        .filter(|name| !name.is_empty())
+}
+
+/// Register operands, separated by spaces.
+fn registers(registers: &[usize]) -> String {
+    registers
+        .iter()
+        .map(|register| register.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Count operand; `None` is every value up to the top of the stack.
+fn count_of(count: Option<usize>) -> String {
+    match count {
+        Some(count) => count.to_string(),
+        None => "multi".to_string(),
+    }
+}
+
+/// Name of the variant of `instruction`, without its operands.
+fn name_of(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Instruction::Move(..) => "Move",
+        Instruction::Jump(_) => "Jump",
+        Instruction::JumpTrue(..) => "JumpTrue",
+        Instruction::JumpFalse(..) => "JumpFalse",
+        Instruction::InitCell(_) => "InitCell",
+        Instruction::GetCell(..) => "GetCell",
+        Instruction::SetCell(..) => "SetCell",
+        Instruction::IsNil(..) => "IsNil",
+        Instruction::Nil(..) => "Nil",
+        Instruction::Boolean(..) => "Boolean",
+        Instruction::Numeric(..) => "Numeric",
+        Instruction::String(..) => "String",
+        Instruction::GetEnv(_) => "GetEnv",
+        Instruction::TableInit(..) => "TableInit",
+        Instruction::TableIndexInit(..) => "TableIndexInit",
+        Instruction::TableInitLast(..) => "TableInitLast",
+        Instruction::TableIndex(..) => "TableIndex",
+        Instruction::TableIndexSet(..) => "TableIndexSet",
+        Instruction::GetField(..) => "GetField",
+        Instruction::SetField(..) => "SetField",
+        Instruction::FunctionInit(..) => "FunctionInit",
+        Instruction::FunctionInitUpvalueFromLocalVar(..) => "FunctionInitUpvalueFromLocalVar",
+        Instruction::FunctionInitUpvalueFromUpvalue(..) => "FunctionInitUpvalueFromUpvalue",
+        Instruction::FunctionUpvalue(..) => "FunctionUpvalue",
+        Instruction::FunctionUpvalueSet(..) => "FunctionUpvalueSet",
+        Instruction::BinaryAdd(..) => "BinaryAdd",
+        Instruction::BinarySub(..) => "BinarySub",
+        Instruction::BinaryMul(..) => "BinaryMul",
+        Instruction::BinaryDiv(..) => "BinaryDiv",
+        Instruction::BinaryFloorDiv(..) => "BinaryFloorDiv",
+        Instruction::BinaryMod(..) => "BinaryMod",
+        Instruction::BinaryPow(..) => "BinaryPow",
+        Instruction::BinaryConcat(..) => "BinaryConcat",
+        Instruction::BinaryBitwiseAnd(..) => "BinaryBitwiseAnd",
+        Instruction::BinaryBitwiseOr(..) => "BinaryBitwiseOr",
+        Instruction::BinaryBitwiseXor(..) => "BinaryBitwiseXor",
+        Instruction::BinaryShiftLeft(..) => "BinaryShiftLeft",
+        Instruction::BinaryShiftRight(..) => "BinaryShiftRight",
+        Instruction::BinaryEqual(..) => "BinaryEqual",
+        Instruction::BinaryLessThan(..) => "BinaryLessThan",
+        Instruction::BinaryLessEqual(..) => "BinaryLessEqual",
+        Instruction::UnaryMinus(..) => "UnaryMinus",
+        Instruction::UnaryBitwiseNot(..) => "UnaryBitwiseNot",
+        Instruction::UnaryLength(..) => "UnaryLength",
+        Instruction::UnaryLogicalNot(..) => "UnaryLogicalNot",
+        Instruction::ForPrep(..) => "ForPrep",
+        Instruction::ForLoop(..) => "ForLoop",
+        Instruction::FunctionCall(..) => "FunctionCall",
+        Instruction::TailCall(..) => "TailCall",
+        Instruction::Return(..) => "Return",
+        Instruction::GetVariadic(..) => "GetVariadic",
+    }
+}
+
+impl Chunk {
+    /// Human-readable listing of this chunk, like `luac -l -l`.
+    ///
+    /// The chunk is listed as a main chunk; each instruction is printed on its own line,
+    /// with its index, source line and resolved jump target,
+    /// followed by the labels, locals and upvalues, and the listings of the functions defined in it.
+    pub fn disassemble(&self) -> String {
+        let mut disassembler = Disassembler {
+            out: String::new(),
+            chunkname: "?",
+            functions: 0,
+        };
+        disassembler.main(self, 0, true, 0);
+        disassembler.out
+    }
+}
+
+impl CompiledChunk {
+    /// Human-readable listing of this chunk, like `luac -l -l`. See [`Chunk::disassemble`].
+    pub fn disassemble(&self) -> String {
+        let mut disassembler = Disassembler {
+            out: String::new(),
+            chunkname: &self.name,
+            functions: 0,
+        };
+        let prototype = &self.prototype;
+        disassembler.main(
+            &prototype.chunk,
+            prototype.args,
+            prototype.is_variadic,
+            prototype.upvalues,
+        );
+        disassembler.out
+    }
+}
@@ -0,0 +1,98 @@
+use std::rc::Rc;
+
+use crate::serialize;
+use crate::serialize::Reader;
+use crate::serialize::Writer;
+use crate::CompiledChunk;
+use crate::FloatType;
+use crate::IntType;
+use crate::LuaFunctionLua;
+use crate::RuntimeError;
+
+/// First bytes of binary chunks.
+/// Starts with ESC like PUC Lua, so binary chunks are told from source code by the first byte.
+const SIGNATURE: &[u8] = b"\x1bLuaR";
+/// Version of the format; binary chunks of other versions are rejected.
+const VERSION: u8 = 8;
+
+/// flag for chunks stripped of debug information
+const FLAG_STRIPPED: u8 = 1;
+
+/// Returns `true` if `chunk` is a binary chunk, not source code.
+pub(crate) fn is_binary(chunk: &[u8]) -> bool {
+    chunk.first() == SIGNATURE.first()
+}
+
+/// Binary chunk of `function`, for `string.dump`.
+/// Upvalues are not saved; the loaded function has the same number of fresh upvalues, set to `nil`.
+pub(crate) fn dump_function(function: &LuaFunctionLua, strip: bool) -> Vec<u8> {
+    let chunk = CompiledChunk {
+        prototype: Rc::clone(&function.prototype),
+        name: Rc::from(""),
+    };
+    chunk.dump(strip)
+}
+
+impl CompiledChunk {
+    /// Serialize this chunk to a binary chunk, to be loaded by [`CompiledChunk::undump`] or `load`.
+    ///
+    /// If `strip` is true, debug information, the chunk name and the names of variables, are left out.
+    pub fn dump(&self, strip: bool) -> Vec<u8> {
+        let mut writer = Writer {
+            strip,
+            ..Default::default()
+        };
+        writer.buf.extend_from_slice(SIGNATURE);
+        writer.u8(VERSION);
+        writer.u8(std::mem::size_of::<IntType>() as u8);
+        writer.u8(std::mem::size_of::<FloatType>() as u8);
+        writer.u8(if strip { FLAG_STRIPPED } else { 0 });
+        writer.bytes(if strip { b"" } else { self.name.as_bytes() });
+        writer.prototype(&self.prototype);
+        writer.buf
+    }
+
+    /// Load a binary chunk written by [`CompiledChunk::dump`] or `string.dump`.
+    ///
+    /// `chunkname` names the chunk if the name was stripped, and is used in error messages.
+    /// Truncated or malformed input, and binary chunks of other versions or number types are rejected.
+    /// The code is verified to stay within its registers, constants and nested functions;
+    /// malformed code which passes the check raises a Lua error when it runs, instead of panicking.
+    pub fn undump(binary: &[u8], chunkname: &str) -> Result<CompiledChunk, RuntimeError> {
+        let error = |message: &str| {
+            RuntimeError::Custom(format!("{}: bad binary format ({})", chunkname, message).into())
+        };
+        let malformed = |_| error("malformed chunk");
+
+        let mut reader = Reader::new(binary);
+        if reader.take(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+            return Err(error("not a binary chunk"));
+        }
+        if reader.u8().map_err(malformed)? != VERSION {
+            return Err(error("version mismatch"));
+        }
+        if reader.u8().map_err(malformed)? != std::mem::size_of::<IntType>() as u8
+            || reader.u8().map_err(malformed)? != std::mem::size_of::<FloatType>() as u8
+        {
+            return Err(error("number format mismatch"));
+        }
+        let flags = reader.u8().map_err(malformed)?;
+        if flags & !FLAG_STRIPPED != 0 {
+            return Err(error("unknown flags"));
+        }
+        let name = match reader.bytes().map_err(malformed)? {
+            [] => chunkname.to_string(),
+            name => String::from_utf8_lossy(name).into_owned(),
+        };
+        let prototype = reader.prototype().map_err(malformed)?;
+        if !reader.is_empty() {
+            return Err(error("trailing data"));
+        }
+        serialize::validate(&prototype).map_err(malformed)?;
+
+        Ok(CompiledChunk {
+            prototype: Rc::new(prototype),
+            name: Rc::from(name),
+        })
+    }
+}
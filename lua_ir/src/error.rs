@@ -1,5 +1,6 @@
 use lua_tokenizer::TokenizeError;
 
+use crate::Limit;
 use crate::LuaString;
 use crate::{LuaEnv, LuaValue};
 
@@ -36,6 +37,15 @@ pub enum RuntimeError {
     CloseNormalThread,
 
     YieldOutsideCoroutine,
+    /// try to yield while a Rust function is waiting for a nested function call to return
+    YieldAcrossBoundary,
+    /// try to call an async function while execution is not driven by a future
+    AsyncCallOutsideFuture,
+    /// depth of calls exceeded [`crate::Limits::call_depth`] or [`crate::Limits::native_depth`]
+    StackOverflow,
+
+    /// execution limit exceeded; `bool` is true if the error can be caught by `pcall`
+    LimitExceeded(Limit, bool),
 
     AttemptToGetLengthOf(&'static str),
     AttemptToArithmeticOn(&'static str),
@@ -54,6 +64,13 @@ pub enum RuntimeError {
     /// method, lhs, rhs
     AttemptTo(&'static str, &'static str, &'static str),
 
+    /// function or feature not supported by this implementation
+    NotImplemented(&'static str),
+    /// a Rust function panicked; the panic message, if any
+    Panic(String),
+    /// memory allocation failed
+    OutOfMemory,
+
     // ========================
     /// not implemented yet (dummy error for some functions)
     Error,
@@ -75,11 +92,35 @@ impl RuntimeError {
             }
         }
     }
+    /// Returns `false` if this error cannot be caught by `pcall`.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, RuntimeError::LimitExceeded(_, false))
+    }
     pub fn to_error_message(&self, env: &LuaEnv) -> String {
         RuntimeErrorEnvPair(&self, env).to_string()
     }
 }
 
+/// Run host code `f`, converting a panic in it into [`RuntimeError::Panic`],
+/// so a buggy Rust function raises a Lua error instead of unwinding through the interpreter.
+pub(crate) fn catch_panic<T>(
+    f: impl FnOnce() -> Result<T, RuntimeError>,
+) -> Result<T, RuntimeError> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(RuntimeError::Panic(message))
+        }
+    }
+}
+
 struct RuntimeErrorEnvPair<'a>(&'a RuntimeError, &'a LuaEnv);
 
 impl<'a> std::fmt::Display for RuntimeErrorEnvPair<'a> {
@@ -108,6 +149,17 @@ impl<'a> std::fmt::Display for RuntimeErrorEnvPair<'a> {
             RuntimeError::YieldOutsideCoroutine => {
                 "attempt to yield from outside a coroutine".fmt(f)
             }
+            RuntimeError::YieldAcrossBoundary => "attempt to yield across a C-call boundary".fmt(f),
+            RuntimeError::AsyncCallOutsideFuture => {
+                "attempt to call an async function outside of an async context".fmt(f)
+            }
+            RuntimeError::StackOverflow => "stack overflow".fmt(f),
+            RuntimeError::LimitExceeded(limit, _) => match limit {
+                Limit::Instructions => "instruction limit exceeded".fmt(f),
+                Limit::Allocation => "not enough memory".fmt(f),
+                Limit::Deadline => "deadline exceeded".fmt(f),
+                Limit::Interrupt => "interrupted".fmt(f),
+            },
             RuntimeError::AttemptToGetLengthOf(type_str) => {
                 write!(f, "attempt to get length of a {} value", type_str)
             }
@@ -131,6 +183,10 @@ impl<'a> std::fmt::Display for RuntimeErrorEnvPair<'a> {
                 write!(f, "attempt to {} a '{}' with a '{}'", method, lhs, rhs)
             }
 
+            RuntimeError::NotImplemented(what) => write!(f, "'{}' is not implemented", what),
+            RuntimeError::Panic(message) => write!(f, "Rust function panicked: {}", message),
+            RuntimeError::OutOfMemory => write!(f, "not enough memory"),
+
             RuntimeError::Custom(val) => write!(f, "{}", val),
             _ => write!(f, "{:?}", self.0),
         }
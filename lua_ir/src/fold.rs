@@ -0,0 +1,219 @@
+//! Constant folding of expressions whose operands are all literals.
+//!
+//! Folded operations are evaluated with the same arithmetic the VM uses,
+//! so integer overflow wraps and int/float results match the runtime.
+//! Operations that could raise an error, call a metamethod
+//! or convert a string to a number are never folded and left for the runtime.
+
+use lua_semantics::ExprBinary;
+use lua_semantics::ExprBinaryData;
+use lua_semantics::ExprUnary;
+use lua_semantics::Expression;
+use lua_semantics::IntOrFloat;
+
+use crate::number::shift_left;
+use crate::number::shift_right;
+use crate::IntType;
+use crate::LuaNumber;
+use crate::LuaString;
+
+/// value of an expression known at compile time
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Nil,
+    Boolean(bool),
+    Number(LuaNumber),
+    String(LuaString),
+}
+
+impl Constant {
+    /// `false` for `nil` and `false`, like `LuaValue::to_bool`
+    pub fn to_bool(&self) -> bool {
+        !matches!(self, Constant::Nil | Constant::Boolean(false))
+    }
+    /// operand of arithmetic operations; strings are not coerced
+    fn number(&self) -> Option<LuaNumber> {
+        match self {
+            Constant::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    /// operand of bitwise operations
+    fn integer(&self) -> Option<IntType> {
+        self.number()?.try_to_int().ok()
+    }
+    /// operand of `..`; numbers are formatted like the VM does
+    fn bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Constant::Number(n) => Some(n.to_string().into_bytes()),
+            Constant::String(s) => Some(s.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+    /// raw equality; constants have no `__eq` metamethod
+    fn equal(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Constant::Nil, Constant::Nil) => true,
+            (Constant::Boolean(a), Constant::Boolean(b)) => a == b,
+            (Constant::Number(a), Constant::Number(b)) => a == b,
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false,
+        }
+    }
+    fn less_than(&self, other: &Self) -> Option<bool> {
+        match (self, other) {
+            (Constant::Number(a), Constant::Number(b)) => Some(a < b),
+            (Constant::String(a), Constant::String(b)) => Some(a < b),
+            _ => None,
+        }
+    }
+    fn less_equal(&self, other: &Self) -> Option<bool> {
+        match (self, other) {
+            (Constant::Number(a), Constant::Number(b)) => Some(a <= b),
+            (Constant::String(a), Constant::String(b)) => Some(a <= b),
+            _ => None,
+        }
+    }
+}
+
+/// evaluates `expression` at compile time, `None` if it is not a constant expression
+pub fn constant(expression: &Expression) -> Option<Constant> {
+    match expression {
+        Expression::Nil => Some(Constant::Nil),
+        Expression::Boolean(value) => Some(Constant::Boolean(*value)),
+        Expression::Numeric(IntOrFloat::Int(n)) => Some(Constant::Number(LuaNumber::Int(*n))),
+        Expression::Numeric(IntOrFloat::Float(n)) => Some(Constant::Number(LuaNumber::Float(*n))),
+        Expression::String(value) => Some(Constant::String(LuaString::from_slice(value))),
+        Expression::Unary(expr) => unary(expr),
+        Expression::Binary(expr) => binary(expr),
+        _ => None,
+    }
+}
+
+/// evaluates unary operation at compile time
+pub fn unary(expr: &ExprUnary) -> Option<Constant> {
+    match expr {
+        ExprUnary::Minus(expr) => Some(Constant::Number(-constant(&expr.value)?.number()?)),
+        ExprUnary::BitwiseNot(expr) => Some(Constant::Number(LuaNumber::Int(
+            !constant(&expr.value)?.integer()?,
+        ))),
+        ExprUnary::Length(expr) => match constant(&expr.value)? {
+            Constant::String(s) => Some(Constant::Number(LuaNumber::Int(s.len() as IntType))),
+            _ => None,
+        },
+        ExprUnary::LogicalNot(expr) => Some(Constant::Boolean(!constant(&expr.value)?.to_bool())),
+    }
+}
+
+fn operands(expr: &ExprBinaryData) -> Option<(Constant, Constant)> {
+    Some((constant(&expr.lhs)?, constant(&expr.rhs)?))
+}
+fn numbers(expr: &ExprBinaryData) -> Option<(LuaNumber, LuaNumber)> {
+    let (lhs, rhs) = operands(expr)?;
+    Some((lhs.number()?, rhs.number()?))
+}
+fn integers(expr: &ExprBinaryData) -> Option<(IntType, IntType)> {
+    let (lhs, rhs) = operands(expr)?;
+    Some((lhs.integer()?, rhs.integer()?))
+}
+
+/// evaluates binary operation at compile time
+pub fn binary(expr: &ExprBinary) -> Option<Constant> {
+    let value = match expr {
+        ExprBinary::Add(expr) => {
+            let (lhs, rhs) = numbers(expr)?;
+            Constant::Number(lhs + rhs)
+        }
+        ExprBinary::Sub(expr) => {
+            let (lhs, rhs) = numbers(expr)?;
+            Constant::Number(lhs - rhs)
+        }
+        ExprBinary::Mul(expr) => {
+            let (lhs, rhs) = numbers(expr)?;
+            Constant::Number(lhs * rhs)
+        }
+        ExprBinary::Div(expr) => {
+            let (lhs, rhs) = numbers(expr)?;
+            Constant::Number(lhs / rhs)
+        }
+        ExprBinary::FloorDiv(expr) => match numbers(expr)? {
+            // raises 'n//0' at runtime
+            (LuaNumber::Int(_), LuaNumber::Int(0)) => return None,
+            (lhs, rhs) => Constant::Number(lhs.floor_div(rhs)),
+        },
+        ExprBinary::Mod(expr) => match numbers(expr)? {
+            // raises 'n%%0' at runtime
+            (LuaNumber::Int(_), LuaNumber::Int(0)) => return None,
+            (lhs, rhs) => Constant::Number(lhs % rhs),
+        },
+        ExprBinary::Pow(expr) => {
+            let (lhs, rhs) = numbers(expr)?;
+            Constant::Number(lhs.pow(rhs))
+        }
+        ExprBinary::Concat(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            let mut bytes = lhs.bytes()?;
+            bytes.extend_from_slice(&rhs.bytes()?);
+            Constant::String(LuaString::from_vec(bytes))
+        }
+        ExprBinary::BitwiseAnd(expr) => {
+            let (lhs, rhs) = integers(expr)?;
+            Constant::Number(LuaNumber::Int(lhs & rhs))
+        }
+        ExprBinary::BitwiseOr(expr) => {
+            let (lhs, rhs) = integers(expr)?;
+            Constant::Number(LuaNumber::Int(lhs | rhs))
+        }
+        ExprBinary::BitwiseXor(expr) => {
+            let (lhs, rhs) = integers(expr)?;
+            Constant::Number(LuaNumber::Int(lhs ^ rhs))
+        }
+        ExprBinary::ShiftLeft(expr) => {
+            let (lhs, rhs) = integers(expr)?;
+            Constant::Number(LuaNumber::Int(shift_left(lhs, rhs)))
+        }
+        ExprBinary::ShiftRight(expr) => {
+            let (lhs, rhs) = integers(expr)?;
+            Constant::Number(LuaNumber::Int(shift_right(lhs, rhs)))
+        }
+        ExprBinary::Equal(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(lhs.equal(&rhs))
+        }
+        ExprBinary::NotEqual(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(!lhs.equal(&rhs))
+        }
+        ExprBinary::LessThan(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(lhs.less_than(&rhs)?)
+        }
+        ExprBinary::LessEqual(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(lhs.less_equal(&rhs)?)
+        }
+        ExprBinary::GreaterThan(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(rhs.less_than(&lhs)?)
+        }
+        ExprBinary::GreaterEqual(expr) => {
+            let (lhs, rhs) = operands(expr)?;
+            Constant::Boolean(rhs.less_equal(&lhs)?)
+        }
+        ExprBinary::LogicalAnd(expr) => {
+            let lhs = constant(&expr.lhs)?;
+            if !lhs.to_bool() {
+                return Some(lhs);
+            }
+            constant(&expr.rhs)?
+        }
+        ExprBinary::LogicalOr(expr) => {
+            let lhs = constant(&expr.lhs)?;
+            if lhs.to_bool() {
+                return Some(lhs);
+            }
+            constant(&expr.rhs)?
+        }
+    };
+    Some(value)
+}
@@ -0,0 +1,95 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::FloatType;
+use crate::IntType;
+use crate::LuaFunction;
+use crate::LuaNumber;
+use crate::LuaString;
+use crate::LuaTable;
+use crate::LuaValue;
+use crate::RuntimeError;
+
+/// Conversion from lua value to rust type.
+pub trait FromLua: Sized {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError>;
+}
+
+impl FromLua for LuaValue {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        Ok(value)
+    }
+}
+impl FromLua for () {
+    fn from_lua(_value: LuaValue) -> Result<Self, RuntimeError> {
+        Ok(())
+    }
+}
+impl FromLua for bool {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::Boolean(b) => Ok(b),
+            _ => Err(RuntimeError::Expected("boolean", Some(value.type_str()))),
+        }
+    }
+}
+impl FromLua for LuaNumber {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        value.try_to_number()
+    }
+}
+impl FromLua for IntType {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        value.try_to_int()
+    }
+}
+impl FromLua for FloatType {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        Ok(value.try_to_number()?.to_float())
+    }
+}
+impl FromLua for LuaString {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::String(s) => Ok(s),
+            LuaValue::Number(n) => Ok(LuaString::from_string(n.to_string())),
+            _ => Err(RuntimeError::Expected("string", Some(value.type_str()))),
+        }
+    }
+}
+impl FromLua for String {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        let s = LuaString::from_lua(value)?;
+        Ok(String::from_utf8_lossy(s.as_bytes()).into_owned())
+    }
+}
+impl FromLua for Vec<u8> {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        Ok(LuaString::from_lua(value)?.into_vec())
+    }
+}
+impl FromLua for Rc<RefCell<LuaTable>> {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::Table(table) => Ok(table),
+            _ => Err(RuntimeError::Expected("table", Some(value.type_str()))),
+        }
+    }
+}
+impl FromLua for Rc<RefCell<LuaFunction>> {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::Function(func) => Ok(func),
+            _ => Err(RuntimeError::Expected("function", Some(value.type_str()))),
+        }
+    }
+}
+/// `nil` is converted to `None`
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: LuaValue) -> Result<Self, RuntimeError> {
+        match value {
+            LuaValue::Nil => Ok(None),
+            value => Ok(Some(T::from_lua(value)?)),
+        }
+    }
+}
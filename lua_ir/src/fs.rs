@@ -0,0 +1,394 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::rc::Rc;
+
+/// How to open a file; parsed from the mode string of `io.open`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// every write goes to the end of the file
+    pub append: bool,
+    /// truncate the file to zero length
+    pub truncate: bool,
+    /// create the file if it does not exist
+    pub create: bool,
+}
+impl OpenOptions {
+    /// Parse mode string of `io.open`: `"r"`, `"w"`, `"a"`, `"r+"`, `"w+"`, `"a+"`, with optional trailing `"b"`.
+    pub fn from_mode(mode: &str) -> Option<Self> {
+        let mode = mode.strip_suffix('b').unwrap_or(mode);
+        let (mode, update) = match mode.strip_suffix('+') {
+            Some(mode) => (mode, true),
+            None => (mode, false),
+        };
+        let options = match mode {
+            "r" => OpenOptions {
+                read: true,
+                write: update,
+                ..Default::default()
+            },
+            "w" => OpenOptions {
+                read: update,
+                write: true,
+                truncate: true,
+                create: true,
+                ..Default::default()
+            },
+            "a" => OpenOptions {
+                read: update,
+                write: true,
+                append: true,
+                create: true,
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(options)
+    }
+}
+
+/// Metadata of a file or a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// size of the file in bytes; 0 for directories
+    pub len: u64,
+}
+
+/// File opened by [`FileSystem::open`].
+pub trait File: io::Read + io::Write + io::Seek {}
+impl<T: io::Read + io::Write + io::Seek> File for T {}
+
+/// Every file access of `LuaEnv` goes through this trait;
+/// `dofile`, `loadfile`, `io.open`, `os.remove`, `os.rename`.
+///
+/// Paths are passed as given by Lua code.
+pub trait FileSystem {
+    fn open(&self, path: &str, options: OpenOptions) -> io::Result<Box<dyn File>>;
+    /// Read whole contents of the file.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut file = self.open(
+            path,
+            OpenOptions {
+                read: true,
+                ..Default::default()
+            },
+        )?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    /// Create or truncate the file, and write `contents` to it.
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        let mut file = self.open(
+            path,
+            OpenOptions {
+                write: true,
+                truncate: true,
+                create: true,
+                ..Default::default()
+            },
+        )?;
+        file.write_all(contents)?;
+        file.flush()
+    }
+    fn metadata(&self, path: &str) -> io::Result<Metadata>;
+    /// Remove the file or the empty directory.
+    fn remove(&self, path: &str) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Names of the entries in the directory, sorted.
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+}
+
+/// Filesystem of the operating system, with `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn open(&self, path: &str, options: OpenOptions) -> io::Result<Box<dyn File>> {
+        let file = std::fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .truncate(options.truncate)
+            .create(options.create)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+    fn write(&self, path: &str, contents: &[u8]) -> io::Result<()> {
+        std::fs::write(path, contents)
+    }
+    fn metadata(&self, path: &str) -> io::Result<Metadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+        })
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        if std::fs::metadata(path)?.is_dir() {
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Filesystem in memory, for scripts shipped without disk access, or tests.
+///
+/// Directories exist implicitly, as prefix of file paths.
+/// Clones share the same files.
+/// A file grows up to 1 GiB; seeking or writing past it fails.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    files: Rc<RefCell<BTreeMap<String, MemoryFileData>>>,
+}
+
+/// contents of a file in [`MemoryFileSystem`], shared with opened files
+type MemoryFileData = Rc<RefCell<Vec<u8>>>;
+
+/// largest size of a file in [`MemoryFileSystem`];
+/// a seek past it fails, instead of a later write allocating the gap
+const MAX_MEMORY_FILE_SIZE: u64 = 1 << 30;
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Create or overwrite the file at `path` with `contents`.
+    pub fn insert(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.files
+            .borrow_mut()
+            .insert(normalize_path(path), Rc::new(RefCell::new(contents.into())));
+    }
+    /// Contents of the file at `path`.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.files
+            .borrow()
+            .get(&normalize_path(path))
+            .map(|data| data.borrow().clone())
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        if path.is_empty() {
+            return true;
+        }
+        let prefix = format!("{}/", path);
+        self.files
+            .borrow()
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(name, _)| name.starts_with(&prefix))
+    }
+}
+
+/// Resolve `.` and `..` in `path`, and remove leading, trailing, and repeated `/`.
+fn normalize_path(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Error message for Lua; `path: message`.
+pub(crate) fn error_message(path: &str, err: &io::Error) -> String {
+    let message = match err.kind() {
+        io::ErrorKind::NotFound => "No such file or directory".to_string(),
+        io::ErrorKind::PermissionDenied => "Permission denied".to_string(),
+        io::ErrorKind::AlreadyExists => "File exists".to_string(),
+        _ => err.to_string(),
+    };
+    format!("{}: {}", path, message)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+}
+fn is_a_directory() -> io::Error {
+    io::Error::other("Is a directory")
+}
+fn file_too_large() -> io::Error {
+    io::Error::other("File too large")
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn open(&self, path: &str, options: OpenOptions) -> io::Result<Box<dyn File>> {
+        let path = normalize_path(path);
+        if self.is_dir(&path) {
+            return Err(is_a_directory());
+        }
+        let data = self.files.borrow().get(&path).cloned();
+        let data = match data {
+            Some(data) => {
+                if options.truncate {
+                    data.borrow_mut().clear();
+                }
+                data
+            }
+            None if options.create => {
+                let data = Rc::new(RefCell::new(Vec::new()));
+                self.files.borrow_mut().insert(path, Rc::clone(&data));
+                data
+            }
+            None => return Err(not_found()),
+        };
+        Ok(Box::new(MemoryFile {
+            data,
+            pos: 0,
+            options,
+        }))
+    }
+    fn metadata(&self, path: &str) -> io::Result<Metadata> {
+        let path = normalize_path(path);
+        if let Some(data) = self.files.borrow().get(&path) {
+            return Ok(Metadata {
+                is_dir: false,
+                len: data.borrow().len() as u64,
+            });
+        }
+        if self.is_dir(&path) {
+            Ok(Metadata {
+                is_dir: true,
+                len: 0,
+            })
+        } else {
+            Err(not_found())
+        }
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let path = normalize_path(path);
+        if self.files.borrow_mut().remove(&path).is_some() {
+            Ok(())
+        } else if self.is_dir(&path) {
+            Err(io::Error::other("Directory not empty"))
+        } else {
+            Err(not_found())
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (normalize_path(from), normalize_path(to));
+        if self.is_dir(&to) {
+            return Err(is_a_directory());
+        }
+        let mut files = self.files.borrow_mut();
+        let data = files.remove(&from).ok_or_else(not_found)?;
+        files.insert(to, data);
+        Ok(())
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = normalize_path(path);
+        if !self.is_dir(&path) {
+            return Err(not_found());
+        }
+        let prefix = if path.is_empty() {
+            path
+        } else {
+            format!("{}/", path)
+        };
+        let mut names: Vec<String> = Vec::new();
+        for name in self.files.borrow().keys() {
+            if let Some(rest) = name.strip_prefix(&prefix) {
+                let name = rest.split('/').next().unwrap();
+                if names.last().map(String::as_str) != Some(name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// File opened from [`MemoryFileSystem`].
+struct MemoryFile {
+    data: MemoryFileData,
+    pos: u64,
+    options: OpenOptions,
+}
+impl io::Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.options.read {
+            return Err(io::Error::other("Bad file descriptor"));
+        }
+        let data = self.data.borrow();
+        let begin = usize::try_from(self.pos)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let len = buf.len().min(data.len() - begin);
+        buf[..len].copy_from_slice(&data[begin..begin + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+impl io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.options.write {
+            return Err(io::Error::other("Bad file descriptor"));
+        }
+        let mut data = self.data.borrow_mut();
+        if self.options.append {
+            self.pos = data.len() as u64;
+        }
+        let begin = usize::try_from(self.pos).map_err(|_| file_too_large())?;
+        let end = begin
+            .checked_add(buf.len())
+            .filter(|&end| end as u64 <= MAX_MEMORY_FILE_SIZE)
+            .ok_or_else(file_too_large)?;
+        if data.len() < end {
+            let additional = end - data.len();
+            data.try_reserve(additional)
+                .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+            data.resize(end, 0);
+        }
+        data[begin..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl io::Seek for MemoryFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(offset) => (0, offset as i64),
+            io::SeekFrom::Current(offset) => (self.pos, offset),
+            io::SeekFrom::End(offset) => (self.data.borrow().len() as u64, offset),
+        };
+        match base
+            .checked_add_signed(offset)
+            .filter(|&pos| pos <= MAX_MEMORY_FILE_SIZE)
+        {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid argument",
+            )),
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::rc::Rc;
 
 use crate::Chunk;
 use crate::LuaEnv;
+use crate::LuaTable;
 use crate::LuaValue;
 use crate::RuntimeError;
 
@@ -51,15 +52,27 @@ impl LuaFunction {
     }
 }
 
-/// functions written in Lua
+/// Immutable part of a function written in Lua; shared by every closure created from it.
 #[derive(Debug, Clone)]
-pub struct LuaFunctionLua {
-    /// upvalues for this function object
-    pub upvalues: Vec<Rc<RefCell<LuaValue>>>,
+pub struct Prototype {
     /// number of arguments ( excluding variadic arguments )
     pub args: usize,
     /// if true, this function is variadic
     pub is_variadic: bool,
+    /// number of upvalues, captured when a closure is created
+    pub upvalues: usize,
 
     pub chunk: Chunk,
 }
+
+/// functions written in Lua
+#[derive(Debug, Clone)]
+pub struct LuaFunctionLua {
+    /// upvalues for this function object
+    pub upvalues: Vec<Rc<RefCell<LuaValue>>>,
+    /// `_ENV` table this function was created with.
+    /// `None` means the global environment of `LuaEnv`.
+    pub env: Option<Rc<RefCell<LuaTable>>>,
+
+    pub prototype: Rc<Prototype>,
+}
@@ -0,0 +1,207 @@
+//! Async host functions, enabled with the `async` feature.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Context;
+use std::task::Poll;
+
+use crate::error::catch_panic;
+use crate::vm::parse_chunk_or_expressions;
+use crate::CompiledChunk;
+use crate::LuaEnv;
+use crate::LuaFunction;
+use crate::LuaThread;
+use crate::LuaValue;
+use crate::RuntimeError;
+use crate::ThreadStatus;
+
+type LuaFuture = Pin<Box<dyn Future<Output = Result<Vec<LuaValue>, RuntimeError>>>>;
+
+/// Call of async function the running thread is waiting for.
+pub(crate) struct PendingCall {
+    future: LuaFuture,
+    /// number of return values expected
+    expected_ret: Option<usize>,
+}
+
+impl LuaFunction {
+    /// Create a function from async Rust function.
+    ///
+    /// `func` is called with the arguments, and the returned future resolves to the return values.
+    /// While the future is pending, the calling thread is suspended
+    /// and the future returned from [`LuaEnv::call_async`] or [`LuaEnv::eval_async`] returns `Poll::Pending`.
+    /// Calling it while execution is not driven by such a future, or from a nested function call of Rust function,
+    /// raises an error.
+    pub fn from_async<F, Fut>(func: F) -> Self
+    where
+        F: Fn(&mut LuaEnv, Vec<LuaValue>) -> Fut + 'static,
+        Fut: Future<Output = Result<Vec<LuaValue>, RuntimeError>> + 'static,
+    {
+        Self::from_func_with_expected(move |env, args_num, expected_ret| {
+            let args: Vec<LuaValue> = env
+                .borrow_running_thread_mut()
+                .drain_last(args_num)
+                .collect();
+            if !env.awaitable || !env.is_continuable() {
+                return Err(RuntimeError::AsyncCallOutsideFuture);
+            }
+            let future = Box::pin(func(env, args));
+            env.borrow_running_thread_mut().status = ThreadStatus::YieldPending(expected_ret);
+            env.pending = Some(PendingCall {
+                future,
+                expected_ret,
+            });
+            Ok(())
+        })
+    }
+}
+
+impl LuaEnv {
+    /// Call function `func` with `args`.
+    ///
+    /// Returns a future that runs the function until it returns, resolving to its return values.
+    /// The future returns `Poll::Pending` while the function is waiting for an async function.
+    pub fn call_async(
+        &mut self,
+        func: LuaValue,
+        args: Vec<LuaValue>,
+    ) -> impl Future<Output = Result<Vec<LuaValue>, RuntimeError>> + '_ {
+        let thread = LuaThread {
+            data_stack: args,
+            call_stack: Vec::new(),
+            function: None,
+            bp: 0,
+            status: ThreadStatus::Running,
+            boundaries: Vec::new(),
+            stack_charged: (0, 0),
+        };
+        Execution::new(self, thread, Some(func))
+    }
+
+    /// Async version of [`LuaEnv::eval`].
+    pub fn eval_async(
+        &mut self,
+        source: &[u8],
+    ) -> impl Future<Output = Result<Vec<LuaValue>, RuntimeError>> + '_ {
+        let chunk = parse_chunk_or_expressions(source)
+            .and_then(|block| Self::compile_block(block, source, "eval", self.opt_level));
+        async move {
+            let chunk = chunk?;
+            self.run_async(&chunk, Vec::new()).await
+        }
+    }
+
+    /// Async version of [`LuaEnv::run`].
+    pub fn run_async(
+        &mut self,
+        chunk: &CompiledChunk,
+        args: Vec<LuaValue>,
+    ) -> impl Future<Output = Result<Vec<LuaValue>, RuntimeError>> + '_ {
+        let thread = LuaThread::new_chunk(chunk, None, args);
+        Execution::new(self, thread, None)
+    }
+}
+
+/// Future running the dispatch loop until `thread` ends.
+///
+/// Dropping it before completion aborts the execution,
+/// and every coroutine running on it becomes dead.
+struct Execution<'a> {
+    env: &'a mut LuaEnv,
+    thread: Rc<RefCell<LuaThread>>,
+    /// function to be called on `thread` with its data stack as arguments, on first poll
+    func: Option<LuaValue>,
+    /// `env.coroutines.len()` before `thread` was pushed; `None` if not started yet
+    coroutine_len: Option<usize>,
+}
+
+impl<'a> Execution<'a> {
+    fn new(env: &'a mut LuaEnv, thread: LuaThread, func: Option<LuaValue>) -> Self {
+        Execution {
+            env,
+            thread: Rc::new(RefCell::new(thread)),
+            func,
+            coroutine_len: None,
+        }
+    }
+
+    fn run(&mut self, cx: &mut Context<'_>) -> Poll<Result<Vec<LuaValue>, RuntimeError>> {
+        let coroutine_len = match self.coroutine_len {
+            Some(coroutine_len) => coroutine_len,
+            None => {
+                let coroutine_len = self.env.coroutines.len();
+                self.coroutine_len = Some(coroutine_len);
+                self.env.coroutines.push(Rc::clone(&self.thread));
+                if let Some(func) = self.func.take() {
+                    let args_num = self.thread.borrow().data_stack.len();
+                    self.env.call_on_dispatch(args_num, func, None)?;
+                }
+                coroutine_len
+            }
+        };
+
+        loop {
+            if let Some(pending) = self.env.pending.as_mut() {
+                let res = match catch_panic(|| Ok(pending.future.as_mut().poll(cx))) {
+                    Ok(Poll::Ready(res)) => res,
+                    Ok(Poll::Pending) => return Poll::Pending,
+                    Err(err) => Err(err),
+                };
+                let expected_ret = self.env.pending.take().unwrap().expected_ret;
+                self.env.borrow_running_thread_mut().status = ThreadStatus::Running;
+                match res {
+                    Ok(values) => {
+                        let mut thread = self.env.borrow_running_thread_mut();
+                        let base = thread.data_stack.len();
+                        thread.data_stack.extend(values);
+                        if let Some(expected_ret) = expected_ret {
+                            thread
+                                .data_stack
+                                .resize_with(base + expected_ret, Default::default);
+                        }
+                    }
+                    Err(err) => self.env.handle_error(err)?,
+                }
+            }
+            if self.env.coroutines.len() <= coroutine_len {
+                break;
+            }
+            self.env.cycle()?;
+        }
+        let ret = std::mem::take(&mut self.thread.borrow_mut().data_stack);
+        Poll::Ready(Ok(ret))
+    }
+
+    /// Remove threads of this execution from the coroutine stack.
+    fn abort(&mut self) {
+        self.env.pending = None;
+        if let Some(coroutine_len) = self.coroutine_len {
+            for thread in self.env.coroutines.drain(coroutine_len..) {
+                thread.borrow_mut().set_dead();
+            }
+        }
+    }
+}
+
+impl Future for Execution<'_> {
+    type Output = Result<Vec<LuaValue>, RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let awaitable = std::mem::replace(&mut this.env.awaitable, true);
+        let res = this.run(cx);
+        this.env.awaitable = awaitable;
+        if let Poll::Ready(Err(_)) = &res {
+            this.abort();
+        }
+        res
+    }
+}
+
+impl Drop for Execution<'_> {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
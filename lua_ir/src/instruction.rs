@@ -1,107 +1,147 @@
 use lua_semantics::IntType;
 
-use crate::{LabelType, LuaFunctionLua, LuaNumber};
+use crate::{LabelType, LuaNumber};
 
 /// Instructions for Lua VM.
+///
+/// Operands are registers of the running function unless noted otherwise.
+/// Register `r` is the `r`'th slot of the frame, `data_stack[bp + r]`.
+/// Local variables live in the registers numbered by their stack offset,
+/// and temporary values in the registers above them.
+/// Local variables captured by closures live in cells instead, numbered by the same offset.
+///
+/// A call or `...` with multiple results (`None` count) leaves its values from its base register to the top of the data stack;
+/// the very next instruction consumes them.
+///
+/// Jump targets are labels while the code is generated, and instruction indices in a [`Chunk`](crate::Chunk).
 #[derive(Debug, Clone)]
 pub enum Instruction {
-    /// clone top of the data_stack and push it
-    Clone,
-    /// push current length of data_stack to usize_stack
-    Sp,
-    ///
-    Pop,
-
-    /// clone where top of the usize_stack points to.
-    /// Does not pop from usize_stack.
-    Deref,
+    /// `R[dst] = R[src]`
+    Move(usize, usize),
 
     /// jump to label
     Jump(LabelType),
-    /// pops data_stack and jump to label if stack_top is true.
-    JumpTrue(LabelType),
-    /// pops data_stack and jump to label if stack_top is false
-    JumpFalse(LabelType),
-
-    /// get i'th local variable and push the value to stack_top
-    GetLocalVariable(usize, String),
-    /// pop data_stack and set i'th local variable to the value.
-    /// If i'th local variable is `Ref`, the internal value will be set.
-    SetLocalVariable(usize),
-    /// pop data_stack and initialize i'th local variable to the `Value`.
-    InitLocalVariable(usize),
-
-    /// pop data_stack and check if it is nil.
-    IsNil,
-
-    /// push nil
-    Nil,
-    /// push bool
-    Boolean(bool),
-    /// push int or float
-    Numeric(LuaNumber),
-    /// push string
-    String(Vec<u8>),
-
-    /// push _ENV
-    GetEnv,
-
-    /// init new table with capacity
-    TableInit(usize),
-    /// table -> key -> value -> stack_top.
-    /// table must not be popped.
-    TableIndexInit,
-    /// sp pushed to usize_stack, points to the start of args.
-    /// table -> args... -> stack_top
-    ///          ^ sp
-    TableInitLast(IntType),
-    /// table -> index -> stack_top
-    TableIndex,
-    /// value -> table -> index -> stack_top
-    TableIndexSet,
-
-    /// function_id, number of upvalues
-    FunctionInit(Box<LuaFunctionLua>),
-    /// func -> top.
-    /// src_stack_id
-    FunctionInitUpvalueFromLocalVar(usize),
-    /// src_upvalue_id
-    FunctionInitUpvalueFromUpvalue(usize),
-
-    /// get i'th upvalue of current function
-    FunctionUpvalue(usize, String),
-    /// set i'th upvalue of current function
-    FunctionUpvalueSet(usize),
-
-    BinaryAdd,
-    BinarySub,
-    BinaryMul,
-    BinaryDiv,
-    BinaryFloorDiv,
-    BinaryMod,
-    BinaryPow,
-    BinaryConcat,
-    BinaryBitwiseAnd,
-    BinaryBitwiseOr,
-    BinaryBitwiseXor,
-    BinaryShiftLeft,
-    BinaryShiftRight,
-    BinaryEqual,
-    BinaryLessThan,
-    BinaryLessEqual,
-
-    UnaryMinus,
-    UnaryBitwiseNot,
-    UnaryLength,
-    UnaryLogicalNot,
-
-    /// return expected can be 0.
-    /// sp pushed to usize_stack, points to the start of args.
-    /// args -> function -> stack top
-    FunctionCall(Option<usize>),
-
-    Return,
-
+    /// jump to label if `R[src]` is true.
+    JumpTrue(usize, LabelType),
+    /// jump to label if `R[src]` is false.
+    JumpFalse(usize, LabelType),
+
+    /// create a new cell for captured local variable `cell`, initialized with `R[cell]`
+    InitCell(usize),
+    /// `R[dst] = cell`
+    GetCell(usize, usize),
+    /// `cell = R[src]`
+    SetCell(usize, usize),
+
+    /// `R[dst] = R[src] == nil`
+    IsNil(usize, usize),
+
+    /// `R[dst], ..., R[dst + count - 1] = nil`
+    Nil(usize, usize),
+    /// `R[dst] = bool`
+    Boolean(usize, bool),
+    /// `R[dst] = int or float`
+    Numeric(usize, LuaNumber),
+    /// `R[dst] =` i'th string constant
+    String(usize, usize),
+
+    /// `R[dst] = _ENV`
+    GetEnv(usize),
+
+    /// `R[dst] = {}` with capacity
+    TableInit(usize, usize),
+    /// `R[table][R[key]] = R[value]`, without metamethods.
+    TableIndexInit(usize, usize, usize),
+    /// table, base, count, start key.
+    /// Store `R[base], R[base + 1], ...` to the table from the start key, without metamethods.
+    /// `None` count stores every value up to the top of the data stack.
+    TableInitLast(usize, usize, Option<usize>, IntType),
+    /// `R[dst] = R[table][R[key]]`
+    TableIndex(usize, usize, usize),
+    /// `R[table][R[key]] = R[value]`
+    TableIndexSet(usize, usize, usize),
+    /// dst, table, i'th string constant, j'th inline cache.
+    /// `R[dst] = R[table][key]`; the key is looked up through the cache.
+    GetField(usize, usize, usize, usize),
+    /// table, i'th string constant, value, j'th inline cache.
+    /// `R[table][key] = R[value]`; the key is looked up through the cache.
+    SetField(usize, usize, usize, usize),
+
+    /// `R[dst] =` new closure of i'th nested function prototype
+    FunctionInit(usize, usize),
+    /// add cell of local variable as an upvalue of the closure in `R[func]`
+    FunctionInitUpvalueFromLocalVar(usize, usize),
+    /// add i'th upvalue of current function as an upvalue of the closure in `R[func]`
+    FunctionInitUpvalueFromUpvalue(usize, usize),
+
+    /// `R[dst] =` i'th upvalue of current function
+    FunctionUpvalue(usize, usize),
+    /// i'th upvalue of current function `= R[src]`
+    FunctionUpvalueSet(usize, usize),
+
+    /// `R[dst] = R[lhs] + R[rhs]`
+    BinaryAdd(usize, usize, usize),
+    /// `R[dst] = R[lhs] - R[rhs]`
+    BinarySub(usize, usize, usize),
+    /// `R[dst] = R[lhs] * R[rhs]`
+    BinaryMul(usize, usize, usize),
+    /// `R[dst] = R[lhs] / R[rhs]`
+    BinaryDiv(usize, usize, usize),
+    /// `R[dst] = R[lhs] // R[rhs]`
+    BinaryFloorDiv(usize, usize, usize),
+    /// `R[dst] = R[lhs] % R[rhs]`
+    BinaryMod(usize, usize, usize),
+    /// `R[dst] = R[lhs] ^ R[rhs]`
+    BinaryPow(usize, usize, usize),
+    /// `R[dst] = R[lhs] .. R[rhs]`
+    BinaryConcat(usize, usize, usize),
+    /// `R[dst] = R[lhs] & R[rhs]`
+    BinaryBitwiseAnd(usize, usize, usize),
+    /// `R[dst] = R[lhs] | R[rhs]`
+    BinaryBitwiseOr(usize, usize, usize),
+    /// `R[dst] = R[lhs] ~ R[rhs]`
+    BinaryBitwiseXor(usize, usize, usize),
+    /// `R[dst] = R[lhs] << R[rhs]`
+    BinaryShiftLeft(usize, usize, usize),
+    /// `R[dst] = R[lhs] >> R[rhs]`
+    BinaryShiftRight(usize, usize, usize),
+    /// `R[dst] = R[lhs] == R[rhs]`
+    BinaryEqual(usize, usize, usize),
+    /// `R[dst] = R[lhs] < R[rhs]`
+    BinaryLessThan(usize, usize, usize),
+    /// `R[dst] = R[lhs] <= R[rhs]`
+    BinaryLessEqual(usize, usize, usize),
+
+    /// `R[dst] = -R[src]`
+    UnaryMinus(usize, usize),
+    /// `R[dst] = ~R[src]`
+    UnaryBitwiseNot(usize, usize),
+    /// `R[dst] = #R[src]`
+    UnaryLength(usize, usize),
+    /// `R[dst] = not R[src]`
+    UnaryLogicalNot(usize, usize),
+
+    /// Prepare numeric `for` loop; `R[base]` is the initial value, `R[base + 1]` the limit and `R[base + 2]` the step.
+    /// Jump to label if the loop runs zero times.
+    ForPrep(usize, LabelType),
+    /// `R[base] += R[base + 2]`, and jump to label if `R[base]` did not pass the limit.
+    ForLoop(usize, LabelType),
+
+    /// function, number of arguments, number of return values expected.
+    /// Call `R[func]` with arguments `R[func + 1], ...`; return values are stored from `R[func]`.
+    /// `None` arguments takes every value up to the top of the data stack.
+    FunctionCall(usize, Option<usize>, Option<usize>),
+    /// `return f(args)`; same operands as `FunctionCall` with multiple results.
+    /// If the function is a Lua function, it replaces the frame of the running function,
+    /// and the following `Return` is not executed.
+    /// Otherwise it is called as `FunctionCall`.
+    TailCall(usize, Option<usize>),
+
+    /// base, count.
+    /// Return `R[base], R[base + 1], ...`; `None` count returns every value up to the top of the data stack.
+    Return(usize, Option<usize>),
+
+    /// `R[dst], ... = ...`
     /// Invalid call ( using `...` in a non-variadic function ) was filtered out in parser.
-    GetVariadic(Option<usize>),
+    GetVariadic(usize, Option<usize>),
 }
@@ -1,14 +1,33 @@
+mod builder;
 mod builtin;
 mod context;
+mod deterministic;
+mod disassemble;
+mod dump;
 mod error;
+mod fold;
+mod fromlua;
+mod fs;
 mod function;
+#[cfg(feature = "async")]
+mod future;
 mod instruction;
+mod limits;
 mod luaval;
 mod number;
+mod optimize;
+mod random;
+mod serialize;
+mod snapshot;
+mod stdio;
 mod string;
 mod table;
+mod traceback;
 mod vm;
 
+#[cfg(test)]
+mod test;
+
 /// The type of a label in the program.
 /// It is actually `usize`,
 /// we just use this type alias to make the code more readable,
@@ -18,20 +37,42 @@ type LabelType = usize;
 pub use lua_semantics::FloatType;
 pub use lua_semantics::IntType;
 
+pub use builder::LuaEnvBuilder;
+pub use builder::StdLib;
+pub use deterministic::VirtualClock;
+pub use fromlua::FromLua;
+pub use fs::File;
+pub use fs::FileSystem;
+pub use fs::MemoryFileSystem;
+pub use fs::Metadata;
+pub use fs::OpenOptions;
+pub use fs::OsFileSystem;
 pub use function::LuaFunction;
 pub use function::LuaFunctionLua;
+pub use function::Prototype;
+pub use luaval::LuaUserData;
 /// Type for any Lua value.
 pub use luaval::LuaValue;
 /// Type for Lua number.
 pub use number::LuaNumber;
+pub use optimize::OptLevel;
 /// Type for Lua table.
 pub use table::LuaTable;
 
 use context::Context;
 pub use error::RuntimeError;
 pub use instruction::Instruction;
+pub use limits::InterruptHandle;
+pub use limits::Limit;
+pub use limits::Limits;
+pub use limits::DEFAULT_CALL_DEPTH;
+pub use limits::DEFAULT_NATIVE_DEPTH;
+pub use limits::HANDLER_ALLOCATION;
+pub use limits::HANDLER_INSTRUCTIONS;
+pub use limits::HANDLER_TIME;
 pub use string::LuaString;
-use vm::Chunk;
+pub use vm::Chunk;
+pub use vm::CompiledChunk;
 pub use vm::LuaEnv;
 pub use vm::LuaThread;
 pub use vm::ThreadStatus;
@@ -0,0 +1,295 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::vm::CallStackFrame;
+use crate::vm::LuaThread;
+use crate::LuaEnv;
+use crate::LuaFunction;
+use crate::LuaTable;
+use crate::LuaValue;
+use crate::RuntimeError;
+
+/// number of instructions executed between checks of deadline, interrupt and stack size.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// default of [`Limits::call_depth`]
+pub const DEFAULT_CALL_DEPTH: usize = 200_000;
+/// default of [`Limits::native_depth`]
+pub const DEFAULT_NATIVE_DEPTH: usize = 200;
+
+/// instructions added to [`Limits::instructions`] after it raised a catchable error
+pub const HANDLER_INSTRUCTIONS: u64 = 10_000;
+/// bytes added to [`Limits::allocation`] after it raised a catchable error
+pub const HANDLER_ALLOCATION: usize = 64 << 10;
+/// time added to [`Limits::deadline`] after it raised a catchable error
+pub const HANDLER_TIME: Duration = Duration::from_millis(10);
+
+/// Kind of execution limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// number of executed instructions exceeded [`Limits::instructions`]
+    Instructions,
+    /// allocated bytes exceeded [`Limits::allocation`]
+    Allocation,
+    /// [`Limits::deadline`] has passed
+    Deadline,
+    /// [`InterruptHandle::interrupt`] was called
+    Interrupt,
+}
+
+/// Execution limits of [`LuaEnv`], checked while running Lua code.
+///
+/// Exceeding a limit raises [`RuntimeError::LimitExceeded`].
+/// Catchable errors can be caught by `pcall` like any other error.
+/// The limit stays armed: it is extended once by a small budget for the error handler,
+/// [`HANDLER_INSTRUCTIONS`], [`HANDLER_ALLOCATION`] or [`HANDLER_TIME`],
+/// and exceeding it again raises uncatchable error.
+/// Uncatchable errors unwind every `pcall` and coroutine, and are returned to the host.
+///
+/// Depth of calls is always limited; exceeding it raises catchable `stack overflow` error,
+/// [`RuntimeError::StackOverflow`].
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// maximum number of instructions to execute
+    pub instructions: Option<u64>,
+    /// maximum number of bytes to allocate; a budget for allocations, not a limit on memory in use.
+    ///
+    /// Bytes allocated for strings, tables, functions and threads are added up,
+    /// and never subtracted when they are freed,
+    /// so code creating many short-lived strings runs out of it even if little memory is in use at once.
+    /// Stacks are charged as they grow past the largest size they had so far.
+    pub allocation: Option<usize>,
+    /// wall-clock time to stop execution
+    pub deadline: Option<Instant>,
+
+    pub instructions_catchable: bool,
+    pub allocation_catchable: bool,
+    pub deadline_catchable: bool,
+    pub interrupt_catchable: bool,
+
+    /// maximum number of active function calls in a thread. Default is [`DEFAULT_CALL_DEPTH`].
+    pub call_depth: usize,
+    /// maximum number of Lua calls nested in Rust functions, like `table.sort` calling its comparator,
+    /// or a chunk run from a Rust function.
+    /// Each of them runs on the native stack, so this must be small enough not to overflow it.
+    /// Default is [`DEFAULT_NATIVE_DEPTH`].
+    pub native_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            instructions: None,
+            allocation: None,
+            deadline: None,
+            instructions_catchable: false,
+            allocation_catchable: false,
+            deadline_catchable: false,
+            interrupt_catchable: false,
+            call_depth: DEFAULT_CALL_DEPTH,
+            native_depth: DEFAULT_NATIVE_DEPTH,
+        }
+    }
+}
+
+impl Limits {
+    fn is_catchable(&self, limit: Limit) -> bool {
+        match limit {
+            Limit::Instructions => self.instructions_catchable,
+            Limit::Allocation => self.allocation_catchable,
+            Limit::Deadline => self.deadline_catchable,
+            Limit::Interrupt => self.interrupt_catchable,
+        }
+    }
+}
+
+/// Handle to interrupt execution of [`LuaEnv`] from another thread.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+impl InterruptHandle {
+    /// Request the running Lua code to stop.
+    /// The request is consumed by the next check of the dispatch loop,
+    /// which raises [`Limit::Interrupt`] error.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Counters of resources used since [`LuaEnv::set_limits`].
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    /// number of instructions executed
+    pub(crate) instructions: u64,
+    /// number of bytes allocated for strings, tables, functions, threads and stacks
+    pub(crate) allocated: usize,
+    /// incremented by each [`LuaEnv::set_limits`];
+    /// stack sizes charged before it are not counted in `allocated` anymore
+    pub(crate) epoch: u64,
+    /// `instructions` at which `check_limits` is called next
+    pub(crate) next_check: u64,
+    /// whether each limit has raised catchable error, and is extended for the error handler
+    pub(crate) instructions_raised: bool,
+    pub(crate) allocation_raised: bool,
+    pub(crate) deadline_raised: bool,
+    pub(crate) interrupt: Arc<AtomicBool>,
+}
+
+impl LuaEnv {
+    /// Set execution limits, and reset the counters of used resources.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.usage.instructions = 0;
+        self.usage.allocated = 0;
+        self.usage.epoch += 1;
+        self.usage.next_check = 0;
+        self.usage.instructions_raised = false;
+        self.usage.allocation_raised = false;
+        self.usage.deadline_raised = false;
+    }
+    /// Execution limits currently set.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    /// Number of instructions executed since [`LuaEnv::set_limits`].
+    pub fn instructions_executed(&self) -> u64 {
+        self.usage.instructions
+    }
+    /// Number of bytes allocated for strings, tables, functions, threads and stacks
+    /// since [`LuaEnv::set_limits`].
+    pub fn memory_allocated(&self) -> usize {
+        self.usage.allocated
+    }
+    /// Handle to interrupt execution from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            flag: Arc::clone(&self.usage.interrupt),
+        }
+    }
+
+    /// Enter a nested dispatch loop or recursion on the native stack.
+    /// Raises `stack overflow` if it would exceed [`Limits::native_depth`];
+    /// otherwise the caller must decrease `native_depth` when it leaves.
+    pub(crate) fn enter_native(&mut self) -> Result<(), RuntimeError> {
+        if self.native_depth >= self.limits.native_depth {
+            return Err(RuntimeError::StackOverflow);
+        }
+        self.native_depth += 1;
+        Ok(())
+    }
+
+    /// Count an instruction to be executed, checking limits periodically.
+    pub(crate) fn count_instruction(&mut self) -> Result<(), RuntimeError> {
+        self.usage.instructions += 1;
+        if self.usage.instructions >= self.usage.next_check {
+            self.check_limits()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        self.usage.next_check = self.usage.instructions + CHECK_INTERVAL;
+        if let Some(mut max) = self.limits.instructions {
+            if self.usage.instructions_raised {
+                max = max.saturating_add(HANDLER_INSTRUCTIONS);
+            }
+            if self.usage.instructions > max {
+                return Err(self.limit_exceeded(Limit::Instructions));
+            }
+            self.usage.next_check = self.usage.next_check.min(max + 1);
+        }
+        if self.usage.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(self.limit_exceeded(Limit::Interrupt));
+        }
+        if let Some(mut deadline) = self.limits.deadline {
+            if self.usage.deadline_raised {
+                deadline += HANDLER_TIME;
+            }
+            if Instant::now() >= deadline {
+                return Err(self.limit_exceeded(Limit::Deadline));
+            }
+        }
+        self.charge_stack()
+    }
+
+    /// Add `bytes` to the allocated bytes, and check memory limit.
+    pub(crate) fn charge_memory(&mut self, bytes: usize) -> Result<(), RuntimeError> {
+        self.usage.allocated = self.usage.allocated.saturating_add(bytes);
+        if let Some(mut max) = self.limits.allocation {
+            if self.usage.allocation_raised {
+                max = max.saturating_add(HANDLER_ALLOCATION);
+            }
+            if self.usage.allocated > max {
+                return Err(self.limit_exceeded(Limit::Allocation));
+            }
+        }
+        Ok(())
+    }
+    /// Add `n` table entries to the allocated bytes.
+    pub(crate) fn charge_table_entries(&mut self, n: usize) -> Result<(), RuntimeError> {
+        self.charge_memory(n.saturating_mul(2 * std::mem::size_of::<LuaValue>()))
+    }
+    /// Add a new table with `capacity` entries to the allocated bytes.
+    pub(crate) fn charge_table(&mut self, capacity: usize) -> Result<(), RuntimeError> {
+        self.charge_memory(std::mem::size_of::<LuaTable>())?;
+        self.charge_table_entries(capacity)
+    }
+
+    /// Add a new Lua function with `upvalues` upvalues to the allocated bytes.
+    pub(crate) fn charge_function(&mut self, upvalues: usize) -> Result<(), RuntimeError> {
+        self.charge_memory(
+            std::mem::size_of::<LuaFunction>()
+                + upvalues.saturating_mul(std::mem::size_of::<Rc<RefCell<LuaValue>>>()),
+        )
+    }
+    /// Add a new thread to the allocated bytes.
+    pub(crate) fn charge_thread(&mut self) -> Result<(), RuntimeError> {
+        self.charge_memory(std::mem::size_of::<LuaThread>())
+    }
+    /// Add the growth of the stacks of the running thread to the allocated bytes.
+    pub(crate) fn charge_stack(&mut self) -> Result<(), RuntimeError> {
+        let grown = self
+            .running_thread()
+            .borrow_mut()
+            .stack_growth(self.usage.epoch);
+        self.charge_memory(grown)
+    }
+
+    fn limit_exceeded(&mut self, limit: Limit) -> RuntimeError {
+        // each interrupt is raised once; other limits are catchable only the first time
+        let catchable = self.limits.is_catchable(limit)
+            && match limit {
+                Limit::Instructions => {
+                    !std::mem::replace(&mut self.usage.instructions_raised, true)
+                }
+                Limit::Allocation => !std::mem::replace(&mut self.usage.allocation_raised, true),
+                Limit::Deadline => !std::mem::replace(&mut self.usage.deadline_raised, true),
+                Limit::Interrupt => true,
+            };
+        RuntimeError::LimitExceeded(limit, catchable)
+    }
+}
+
+impl LuaThread {
+    /// Bytes the stacks grew past their largest size since `epoch`; the new size is the largest.
+    pub(crate) fn stack_growth(&mut self, epoch: u64) -> usize {
+        let bytes = self.data_stack.len() * std::mem::size_of::<LuaValue>()
+            + self.call_stack.len() * std::mem::size_of::<CallStackFrame>();
+        let charged = match self.stack_charged {
+            (charged_epoch, charged) if charged_epoch == epoch => charged,
+            _ => 0,
+        };
+        if bytes <= charged {
+            return 0;
+        }
+        self.stack_charged = (epoch, bytes);
+        bytes - charged
+    }
+}
@@ -10,18 +10,6 @@ use crate::RuntimeError;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-/// for local variables and upvalues.
-#[derive(Debug, Clone)]
-pub enum RefOrValue {
-    Ref(Rc<RefCell<LuaValue>>),
-    Value(LuaValue),
-}
-impl Default for RefOrValue {
-    fn default() -> Self {
-        RefOrValue::Value(LuaValue::Nil)
-    }
-}
-
 #[derive(Debug, Clone)]
 pub enum LuaValue {
     Nil,
@@ -187,7 +175,7 @@ impl From<String> for LuaValue {
 }
 impl From<&'static str> for LuaValue {
     fn from(s: &'static str) -> Self {
-        LuaValue::String(LuaString::from_str(s))
+        LuaValue::String(LuaString::from(s))
     }
 }
 impl From<&'static [u8]> for LuaValue {
@@ -221,5 +209,31 @@ impl From<LuaThread> for LuaValue {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct LuaUserData {}
+/// Arbitrary Rust value with metatable.
+pub struct LuaUserData {
+    pub(crate) data: Box<dyn std::any::Any>,
+    pub(crate) meta: Option<Rc<RefCell<LuaTable>>>,
+}
+impl LuaUserData {
+    pub fn new(data: impl std::any::Any, meta: Option<Rc<RefCell<LuaTable>>>) -> Self {
+        LuaUserData {
+            data: Box::new(data),
+            meta,
+        }
+    }
+    pub fn downcast_ref<T: std::any::Any>(&self) -> Option<&T> {
+        self.data.downcast_ref()
+    }
+    pub fn downcast_mut<T: std::any::Any>(&mut self) -> Option<&mut T> {
+        self.data.downcast_mut()
+    }
+    /// get metatable of this userdata.
+    pub fn metatable(&self) -> Option<&Rc<RefCell<LuaTable>>> {
+        self.meta.as_ref()
+    }
+}
+impl std::fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LuaUserData({:p})", self.data.as_ref() as *const _)
+    }
+}
@@ -36,7 +36,7 @@ impl LuaNumber {
 
     pub fn abs(self) -> LuaNumber {
         match self {
-            LuaNumber::Int(i) => LuaNumber::Int(i.abs()),
+            LuaNumber::Int(i) => LuaNumber::Int(i.wrapping_abs()),
             LuaNumber::Float(f) => LuaNumber::Float(f.abs()),
         }
     }
@@ -129,6 +129,31 @@ impl LuaNumber {
     }
 }
 
+/// `lhs << rhs`; negative `rhs` shifts to the right, and shifting by 64 bits or more gives 0
+pub(crate) fn shift_left(lhs: IntType, rhs: IntType) -> IntType {
+    if rhs <= -64 {
+        0
+    } else if rhs < 0 {
+        lhs.wrapping_shr((-rhs) as u32)
+    } else if rhs < 64 {
+        lhs.wrapping_shl(rhs as u32)
+    } else {
+        0
+    }
+}
+/// `lhs >> rhs`; negative `rhs` shifts to the left, and shifting by 64 bits or more gives 0
+pub(crate) fn shift_right(lhs: IntType, rhs: IntType) -> IntType {
+    if rhs <= -64 {
+        0
+    } else if rhs < 0 {
+        lhs.wrapping_shl((-rhs) as u32)
+    } else if rhs < 64 {
+        lhs.wrapping_shr(rhs as u32)
+    } else {
+        0
+    }
+}
+
 impl From<IntType> for LuaNumber {
     fn from(i: IntType) -> Self {
         LuaNumber::Int(i)
@@ -257,7 +282,7 @@ impl std::ops::Rem for LuaNumber {
     type Output = LuaNumber;
     fn rem(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (LuaNumber::Int(a), LuaNumber::Int(b)) => LuaNumber::Int(a % b),
+            (LuaNumber::Int(a), LuaNumber::Int(b)) => LuaNumber::Int(a.wrapping_rem(b)),
             (LuaNumber::Int(a), LuaNumber::Float(b)) => LuaNumber::Float(a as FloatType % b),
             (LuaNumber::Float(a), LuaNumber::Int(b)) => LuaNumber::Float(a % b as FloatType),
             (LuaNumber::Float(a), LuaNumber::Float(b)) => LuaNumber::Float(a % b),
@@ -269,7 +294,7 @@ impl std::ops::Neg for LuaNumber {
     type Output = LuaNumber;
     fn neg(self) -> Self::Output {
         match self {
-            LuaNumber::Int(i) => LuaNumber::Int(-i),
+            LuaNumber::Int(i) => LuaNumber::Int(i.wrapping_neg()),
             LuaNumber::Float(f) => LuaNumber::Float(-f),
         }
     }
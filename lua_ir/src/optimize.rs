@@ -0,0 +1,438 @@
+//! Optimization passes over the generated code of a function.
+//!
+//! Every pass keeps the behavior of the code; only the number of instructions run changes.
+
+use crate::vm::Chunk;
+use crate::Instruction;
+
+/// How much compiled chunks are optimized, set by [`LuaEnvBuilder::opt_level`](crate::LuaEnvBuilder::opt_level).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    /// instructions as generated
+    None,
+    /// jump threading, and removal of unreachable code and jumps to the next instruction
+    Basic,
+    /// `Basic`, and fusing conditional jumps with the instructions around them
+    #[default]
+    Full,
+}
+
+/// jump target operand of `instruction`
+fn target_mut(instruction: &mut Instruction) -> Option<&mut usize> {
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpTrue(_, target)
+        | Instruction::JumpFalse(_, target)
+        | Instruction::ForPrep(_, target)
+        | Instruction::ForLoop(_, target) => Some(target),
+        _ => None,
+    }
+}
+fn target(instruction: &Instruction) -> Option<usize> {
+    match *instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpTrue(_, target)
+        | Instruction::JumpFalse(_, target)
+        | Instruction::ForPrep(_, target)
+        | Instruction::ForLoop(_, target) => Some(target),
+        _ => None,
+    }
+}
+
+/// replace the label operands of jumps with the instruction indices the labels are set to
+pub(crate) fn resolve_labels(instructions: &mut [Instruction], label_map: &[Option<usize>]) {
+    for instruction in instructions {
+        if let Some(target) = target_mut(instruction) {
+            *target = label_map[*target].expect("jump to a label never set");
+        }
+    }
+}
+
+/// instructions that may run right after `instructions[pc]`
+fn successors(instructions: &[Instruction], pc: usize) -> impl Iterator<Item = usize> + '_ {
+    let next = match instructions[pc] {
+        Instruction::Jump(_) | Instruction::Return(..) => None,
+        _ => Some(pc + 1),
+    };
+    next.into_iter()
+        .chain(target(&instructions[pc]))
+        .filter(move |&pc| pc < instructions.len())
+}
+
+/// optimize the code of `chunk`, whose labels are resolved
+pub(crate) fn optimize(chunk: &mut Chunk, level: OptLevel) {
+    if level == OptLevel::None || chunk.instructions.is_empty() {
+        return;
+    }
+    loop {
+        let mut changed = thread_jumps(&mut chunk.instructions);
+        if level >= OptLevel::Full {
+            changed |= fuse(chunk);
+        }
+        changed |= remove_unused(chunk);
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// retarget jumps to jumps to their final destination
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    for pc in 0..instructions.len() {
+        let destination = match instructions[pc] {
+            Instruction::Jump(target)
+            | Instruction::ForPrep(_, target)
+            | Instruction::ForLoop(_, target) => destination(instructions, target, None),
+            Instruction::JumpTrue(src, target) => {
+                destination(instructions, target, Some((src, true)))
+            }
+            Instruction::JumpFalse(src, target) => {
+                destination(instructions, target, Some((src, false)))
+            }
+            _ => continue,
+        };
+        let target = target_mut(&mut instructions[pc]).unwrap();
+        if *target != destination {
+            *target = destination;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// where a jump to `target` ends up after following the jumps there.
+/// `condition` is the register tested by the jump and its truthiness, known when the jump is taken.
+fn destination(
+    instructions: &[Instruction],
+    mut target: usize,
+    condition: Option<(usize, bool)>,
+) -> usize {
+    // a cycle of jumps has no destination; stop anywhere in it
+    for _ in 0..instructions.len() {
+        let (src, on_true, next) = match instructions[target] {
+            Instruction::Jump(next) => {
+                target = next;
+                continue;
+            }
+            Instruction::JumpTrue(src, next) => (src, true, next),
+            Instruction::JumpFalse(src, next) => (src, false, next),
+            _ => break,
+        };
+        match condition {
+            Some((reg, value)) if reg == src => {
+                target = if value == on_true { next } else { target + 1 };
+            }
+            _ => break,
+        }
+    }
+    target
+}
+
+/// remove unreachable instructions, and jumps to the next instruction
+fn remove_unused(chunk: &mut Chunk) -> bool {
+    let instructions = &chunk.instructions;
+    let len = instructions.len();
+    let mut reachable = vec![false; len];
+    let mut stack = vec![0];
+    while let Some(pc) = stack.pop() {
+        if !reachable[pc] {
+            reachable[pc] = true;
+            stack.extend(successors(instructions, pc));
+        }
+    }
+    let keep: Vec<bool> = (0..len)
+        .map(|pc| {
+            // the counter never runs past the last `Return`
+            if pc == len - 1 {
+                return true;
+            }
+            match instructions[pc] {
+                Instruction::Jump(target)
+                | Instruction::JumpTrue(_, target)
+                | Instruction::JumpFalse(_, target)
+                    if target == pc + 1 =>
+                {
+                    false
+                }
+                _ => reachable[pc],
+            }
+        })
+        .collect();
+    compact(chunk, &keep)
+}
+
+/// remove instructions not to `keep`; jumps to a removed instruction go to the next instruction kept
+fn compact(chunk: &mut Chunk, keep: &[bool]) -> bool {
+    if keep.iter().all(|&keep| keep) {
+        return false;
+    }
+    let mut index = Vec::with_capacity(keep.len());
+    let mut count = 0;
+    for &keep in keep {
+        index.push(count);
+        if keep {
+            count += 1;
+        }
+    }
+    let mut keep_iter = keep.iter();
+    chunk.instructions.retain(|_| *keep_iter.next().unwrap());
+    for instruction in &mut chunk.instructions {
+        if let Some(target) = target_mut(instruction) {
+            *target = index[*target];
+        }
+    }
+    if !chunk.lines.is_empty() {
+        let mut keep_iter = keep.iter();
+        chunk.lines.retain(|_| *keep_iter.next().unwrap());
+    }
+    true
+}
+
+/// fuse conditional jumps with the instruction before or after them
+fn fuse(chunk: &mut Chunk) -> bool {
+    let len = chunk.instructions.len();
+    let live = live_after(chunk);
+    let mut targeted = vec![false; len];
+    for instruction in &chunk.instructions {
+        if let Some(target) = target(instruction) {
+            targeted[target] = true;
+        }
+    }
+    let mut keep = vec![true; len];
+    let mut changed = false;
+
+    let instructions = &mut chunk.instructions;
+    let mut pc = 0;
+    while pc + 1 < len {
+        // the second instruction is removed or replaced, so nothing may jump between them
+        if targeted[pc + 1] {
+            pc += 1;
+            continue;
+        }
+        let (src, on_true, target) = match instructions[pc + 1] {
+            Instruction::JumpTrue(src, target) => (src, true, target),
+            Instruction::JumpFalse(src, target) => (src, false, target),
+            Instruction::Jump(target) => match instructions[pc] {
+                // `JumpFalse r L1; Jump L2; L1:` -> `JumpTrue r L2`
+                Instruction::JumpTrue(src, skip) | Instruction::JumpFalse(src, skip)
+                    if skip == pc + 2 =>
+                {
+                    instructions[pc] = match instructions[pc] {
+                        Instruction::JumpTrue(..) => Instruction::JumpFalse(src, target),
+                        _ => Instruction::JumpTrue(src, target),
+                    };
+                    keep[pc + 1] = false;
+                    changed = true;
+                    pc += 2;
+                    continue;
+                }
+                _ => {
+                    pc += 1;
+                    continue;
+                }
+            },
+            _ => {
+                pc += 1;
+                continue;
+            }
+        };
+        let jump = |on_true: bool, src: usize| {
+            if on_true {
+                Instruction::JumpTrue(src, target)
+            } else {
+                Instruction::JumpFalse(src, target)
+            }
+        };
+        match instructions[pc] {
+            // `not x` tested for a jump; test `x` instead
+            Instruction::UnaryLogicalNot(dst, operand)
+                if dst == src && !live[pc + 1].contains(dst) =>
+            {
+                instructions[pc] = jump(!on_true, operand);
+                keep[pc + 1] = false;
+            }
+            // condition known at compile time, e.g. `while true do`
+            ref instruction => {
+                let Some(value) = constant_truthiness(instruction, src) else {
+                    pc += 1;
+                    continue;
+                };
+                if !live[pc + 1].contains(src) {
+                    keep[pc] = false;
+                }
+                if value == on_true {
+                    instructions[pc + 1] = Instruction::Jump(target);
+                } else {
+                    keep[pc + 1] = false;
+                }
+            }
+        }
+        changed = true;
+        pc += 2;
+    }
+    compact(chunk, &keep) || changed
+}
+
+/// truthiness of `R[reg]`, if `instruction` does nothing but writing a constant there
+fn constant_truthiness(instruction: &Instruction, reg: usize) -> Option<bool> {
+    match *instruction {
+        Instruction::Boolean(dst, value) if dst == reg => Some(value),
+        Instruction::Nil(dst, 1) if dst == reg => Some(false),
+        Instruction::Numeric(dst, _) | Instruction::String(dst, _) if dst == reg => Some(true),
+        _ => None,
+    }
+}
+
+/// set of registers
+#[derive(Clone, PartialEq)]
+struct Registers(Vec<u64>);
+impl Registers {
+    fn new(size: usize) -> Self {
+        Registers(vec![0; size.div_ceil(64)])
+    }
+    fn contains(&self, reg: usize) -> bool {
+        self.0
+            .get(reg / 64)
+            .is_some_and(|word| word & (1 << (reg % 64)) != 0)
+    }
+    fn insert(&mut self, reg: usize) {
+        if let Some(word) = self.0.get_mut(reg / 64) {
+            *word |= 1 << (reg % 64);
+        }
+    }
+    fn insert_range(&mut self, base: usize, count: Option<usize>) {
+        let end = match count {
+            Some(count) => base + count,
+            None => self.0.len() * 64,
+        };
+        for reg in base..end {
+            self.insert(reg);
+        }
+    }
+    fn union(&mut self, other: &Self) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+    fn subtract(&mut self, other: &Self) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word &= !other;
+        }
+    }
+}
+
+/// registers read and written by `instruction`.
+/// Reads may be more and writes fewer than the exact ones; registers are then thought to be live longer.
+fn access(instruction: &Instruction, reads: &mut Registers, writes: &mut Registers) {
+    match *instruction {
+        Instruction::Move(dst, src)
+        | Instruction::IsNil(dst, src)
+        | Instruction::UnaryMinus(dst, src)
+        | Instruction::UnaryBitwiseNot(dst, src)
+        | Instruction::UnaryLength(dst, src)
+        | Instruction::UnaryLogicalNot(dst, src) => {
+            reads.insert(src);
+            writes.insert(dst);
+        }
+        Instruction::Jump(_) => {}
+        Instruction::JumpTrue(src, _)
+        | Instruction::JumpFalse(src, _)
+        | Instruction::InitCell(src)
+        | Instruction::SetCell(_, src)
+        | Instruction::FunctionUpvalueSet(_, src)
+        | Instruction::FunctionInitUpvalueFromLocalVar(src, _)
+        | Instruction::FunctionInitUpvalueFromUpvalue(src, _) => reads.insert(src),
+        Instruction::GetCell(dst, _)
+        | Instruction::Boolean(dst, _)
+        | Instruction::Numeric(dst, _)
+        | Instruction::String(dst, _)
+        | Instruction::GetEnv(dst)
+        | Instruction::TableInit(dst, _)
+        | Instruction::FunctionInit(dst, _)
+        | Instruction::FunctionUpvalue(dst, _) => writes.insert(dst),
+        Instruction::Nil(dst, count) => writes.insert_range(dst, Some(count)),
+        Instruction::TableIndexInit(a, b, c) | Instruction::TableIndexSet(a, b, c) => {
+            reads.insert(a);
+            reads.insert(b);
+            reads.insert(c);
+        }
+        Instruction::GetField(dst, table, _, _) => {
+            reads.insert(table);
+            writes.insert(dst);
+        }
+        Instruction::SetField(table, _, value, _) => {
+            reads.insert(table);
+            reads.insert(value);
+        }
+        Instruction::TableInitLast(table, base, count, _) => {
+            reads.insert(table);
+            reads.insert_range(base, count);
+        }
+        Instruction::TableIndex(dst, lhs, rhs)
+        | Instruction::BinaryAdd(dst, lhs, rhs)
+        | Instruction::BinarySub(dst, lhs, rhs)
+        | Instruction::BinaryMul(dst, lhs, rhs)
+        | Instruction::BinaryDiv(dst, lhs, rhs)
+        | Instruction::BinaryFloorDiv(dst, lhs, rhs)
+        | Instruction::BinaryMod(dst, lhs, rhs)
+        | Instruction::BinaryPow(dst, lhs, rhs)
+        | Instruction::BinaryConcat(dst, lhs, rhs)
+        | Instruction::BinaryBitwiseAnd(dst, lhs, rhs)
+        | Instruction::BinaryBitwiseOr(dst, lhs, rhs)
+        | Instruction::BinaryBitwiseXor(dst, lhs, rhs)
+        | Instruction::BinaryShiftLeft(dst, lhs, rhs)
+        | Instruction::BinaryShiftRight(dst, lhs, rhs)
+        | Instruction::BinaryEqual(dst, lhs, rhs)
+        | Instruction::BinaryLessThan(dst, lhs, rhs)
+        | Instruction::BinaryLessEqual(dst, lhs, rhs) => {
+            reads.insert(lhs);
+            reads.insert(rhs);
+            writes.insert(dst);
+        }
+        Instruction::ForPrep(base, _) | Instruction::ForLoop(base, _) => {
+            reads.insert_range(base, Some(3))
+        }
+        Instruction::FunctionCall(func, args, _) | Instruction::TailCall(func, args) => {
+            reads.insert_range(func, args.map(|args| args + 1))
+        }
+        Instruction::Return(base, count) => reads.insert_range(base, count),
+        Instruction::GetVariadic(..) => {}
+    }
+}
+
+/// registers live after each instruction; those whose values may be read later
+fn live_after(chunk: &Chunk) -> Vec<Registers> {
+    let instructions = &chunk.instructions;
+    let empty = Registers::new(chunk.stack_size);
+    let (reads, writes): (Vec<_>, Vec<_>) = instructions
+        .iter()
+        .map(|instruction| {
+            let (mut reads, mut writes) = (empty.clone(), empty.clone());
+            access(instruction, &mut reads, &mut writes);
+            (reads, writes)
+        })
+        .unzip();
+
+    let mut live_in = vec![empty.clone(); instructions.len()];
+    let mut live_out = vec![empty.clone(); instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..instructions.len()).rev() {
+            let mut out = empty.clone();
+            for next in successors(instructions, pc) {
+                out.union(&live_in[next]);
+            }
+            let mut live = out.clone();
+            live.subtract(&writes[pc]);
+            live.union(&reads[pc]);
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                changed = true;
+            }
+            live_out[pc] = out;
+        }
+    }
+    live_out
+}
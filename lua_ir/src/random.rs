@@ -0,0 +1,88 @@
+use crate::FloatType;
+use crate::IntType;
+
+/// bits of `lua_Unsigned`, the unsigned counterpart of `IntType`
+const UNSIGNED_MASK: u64 = (IntType::MAX as u64) << 1 | 1;
+/// number of bits of random float, `FIGS` of Lua
+const FIGS: u32 = if FloatType::MANTISSA_DIGITS < 64 {
+    FloatType::MANTISSA_DIGITS
+} else {
+    64
+};
+
+/// xoshiro256** pseudo-random number generator, as in `lmathlib.c` of Lua 5.4.
+#[derive(Debug, Clone)]
+pub(crate) struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// `setseed` of Lua 5.4; same as `math.randomseed(n1, n2)`.
+    pub(crate) fn from_seed(n1: IntType, n2: IntType) -> Self {
+        let mut rng = Xoshiro256 {
+            // avoid a zero state
+            state: [
+                n1 as u64 & UNSIGNED_MASK,
+                0xff,
+                n2 as u64 & UNSIGNED_MASK,
+                0,
+            ],
+        };
+        // discard initial values to "spread" seed
+        for _ in 0..16 {
+            rng.next_u64();
+        }
+        rng
+    }
+
+    pub(crate) fn from_state(state: [u64; 4]) -> Self {
+        Xoshiro256 { state }
+    }
+
+    pub(crate) fn state(&self) -> [u64; 4] {
+        self.state
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        let [s0, s1, s2, s3] = self.state;
+        let result = s1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let s2 = s2 ^ s0;
+        let s3 = s3 ^ s1;
+        self.state = [s0 ^ s3, s1 ^ s2, s2 ^ (s1 << 17), s3.rotate_left(45)];
+        result
+    }
+
+    /// float in `[0, 1)` from random bits; `I2d` of Lua
+    pub(crate) fn float_of(rv: u64) -> FloatType {
+        (rv >> (64 - FIGS)) as FloatType * (0.5 as FloatType).powi(FIGS as i32)
+    }
+    /// integer in `[low, up]` from random bits `rv`; `low <= up`
+    pub(crate) fn int_in_range(&mut self, rv: u64, low: IntType, up: IntType) -> IntType {
+        let n = (up as u64).wrapping_sub(low as u64) & UNSIGNED_MASK;
+        let p = self.project(rv & UNSIGNED_MASK, n);
+        p.wrapping_add(low as u64) as IntType
+    }
+
+    /// project a random integer `ran` into the interval `[0, n]`; `project` of Lua
+    fn project(&mut self, mut ran: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            // `n + 1` is a power of 2
+            return ran & n;
+        }
+        // smallest `2^b - 1` not smaller than `n`
+        let mut lim = n;
+        lim |= lim >> 1;
+        lim |= lim >> 2;
+        lim |= lim >> 4;
+        lim |= lim >> 8;
+        lim |= lim >> 16;
+        lim |= lim >> 32;
+        loop {
+            ran &= lim;
+            if ran <= n {
+                return ran;
+            }
+            ran = self.next_u64() & UNSIGNED_MASK;
+        }
+    }
+}
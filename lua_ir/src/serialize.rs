@@ -0,0 +1,878 @@
+use std::rc::Rc;
+
+use crate::table::FieldCache;
+use crate::Chunk;
+use crate::FloatType;
+use crate::Instruction;
+use crate::IntType;
+use crate::LuaNumber;
+use crate::LuaString;
+use crate::Prototype;
+
+/// Maximum nesting of function prototypes read by [`Reader::prototype`].
+const MAX_PROTOTYPE_DEPTH: usize = 200;
+/// Maximum number of local variables of a function accepted by [`validate`].
+const MAX_STACK_SIZE: usize = 1 << 16;
+/// Name of variables in chunks stripped of debug information.
+const STRIPPED_NAME: &str = "?";
+
+/// Input ended early, or contains a value out of range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Malformed;
+
+/// Little-endian byte buffer for snapshots and binary chunks.
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+    /// if true, names of variables are not written
+    pub(crate) strip: bool,
+}
+impl Writer {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    /// LEB128; lengths and indices are mostly small.
+    pub(crate) fn usize(&mut self, value: usize) {
+        let mut value = value as u64;
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+    pub(crate) fn option_usize(&mut self, value: Option<usize>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.usize(value);
+            }
+            None => self.u8(0),
+        }
+    }
+    pub(crate) fn int(&mut self, value: IntType) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn float(&mut self, value: FloatType) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub(crate) fn number(&mut self, value: LuaNumber) {
+        match value {
+            LuaNumber::Int(i) => {
+                self.u8(0);
+                self.int(i);
+            }
+            LuaNumber::Float(f) => {
+                self.u8(1);
+                self.float(f);
+            }
+        }
+    }
+    /// length followed by the bytes
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.buf.extend_from_slice(value);
+    }
+
+    /// name of variable, for debug information
+    fn name(&mut self, name: &str) {
+        if self.strip {
+            self.usize(0);
+        } else {
+            self.bytes(name.as_bytes());
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.usize(chunk.instructions.len());
+        for instruction in &chunk.instructions {
+            self.instruction(instruction);
+        }
+        // caches start empty; only their number is written
+        self.usize(chunk.caches.len());
+        self.usize(chunk.stack_size);
+        self.usize(chunk.constants.len());
+        for constant in &chunk.constants {
+            self.bytes(constant.as_bytes());
+        }
+        // chunk name, line numbers and variable names are debug information
+        self.name(&chunk.source);
+        let lines: &[u32] = if self.strip { &[] } else { &chunk.lines };
+        self.usize(lines.len());
+        for &line in lines {
+            self.usize(line as usize);
+        }
+        for names in [&chunk.local_names, &chunk.upvalue_names] {
+            let names: &[String] = if self.strip { &[] } else { names };
+            self.usize(names.len());
+            for name in names {
+                self.bytes(name.as_bytes());
+            }
+        }
+        self.usize(chunk.prototypes.len());
+        for prototype in &chunk.prototypes {
+            self.prototype(prototype);
+        }
+    }
+    /// Function prototype; upvalues and `_ENV` are set when a closure is created.
+    pub(crate) fn prototype(&mut self, prototype: &Prototype) {
+        self.usize(prototype.args);
+        self.bool(prototype.is_variadic);
+        self.usize(prototype.upvalues);
+        self.chunk(&prototype.chunk);
+    }
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Move(dst, src) => {
+                self.u8(0);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::Jump(label) => {
+                self.u8(1);
+                self.usize(*label);
+            }
+            Instruction::JumpTrue(src, label) => {
+                self.u8(2);
+                self.usize(*src);
+                self.usize(*label);
+            }
+            Instruction::JumpFalse(src, label) => {
+                self.u8(3);
+                self.usize(*src);
+                self.usize(*label);
+            }
+            Instruction::InitCell(cell) => {
+                self.u8(4);
+                self.usize(*cell);
+            }
+            Instruction::GetCell(dst, cell) => {
+                self.u8(5);
+                self.usize(*dst);
+                self.usize(*cell);
+            }
+            Instruction::SetCell(cell, src) => {
+                self.u8(6);
+                self.usize(*cell);
+                self.usize(*src);
+            }
+            Instruction::IsNil(dst, src) => {
+                self.u8(7);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::Nil(dst, count) => {
+                self.u8(8);
+                self.usize(*dst);
+                self.usize(*count);
+            }
+            Instruction::Boolean(dst, b) => {
+                self.u8(9);
+                self.usize(*dst);
+                self.bool(*b);
+            }
+            Instruction::Numeric(dst, n) => {
+                self.u8(10);
+                self.usize(*dst);
+                self.number(*n);
+            }
+            Instruction::String(dst, index) => {
+                self.u8(11);
+                self.usize(*dst);
+                self.usize(*index);
+            }
+            Instruction::GetEnv(dst) => {
+                self.u8(12);
+                self.usize(*dst);
+            }
+            Instruction::TableInit(dst, capacity) => {
+                self.u8(13);
+                self.usize(*dst);
+                self.usize(*capacity);
+            }
+            Instruction::TableIndexInit(table, key, value) => {
+                self.u8(14);
+                self.usize(*table);
+                self.usize(*key);
+                self.usize(*value);
+            }
+            Instruction::TableInitLast(table, base, count, start) => {
+                self.u8(15);
+                self.usize(*table);
+                self.usize(*base);
+                self.option_usize(*count);
+                self.int(*start);
+            }
+            Instruction::TableIndex(dst, table, key) => {
+                self.u8(16);
+                self.usize(*dst);
+                self.usize(*table);
+                self.usize(*key);
+            }
+            Instruction::TableIndexSet(table, key, value) => {
+                self.u8(17);
+                self.usize(*table);
+                self.usize(*key);
+                self.usize(*value);
+            }
+            Instruction::FunctionInit(dst, index) => {
+                self.u8(18);
+                self.usize(*dst);
+                self.usize(*index);
+            }
+            Instruction::FunctionInitUpvalueFromLocalVar(func, cell) => {
+                self.u8(19);
+                self.usize(*func);
+                self.usize(*cell);
+            }
+            Instruction::FunctionInitUpvalueFromUpvalue(func, index) => {
+                self.u8(20);
+                self.usize(*func);
+                self.usize(*index);
+            }
+            Instruction::FunctionUpvalue(dst, index) => {
+                self.u8(21);
+                self.usize(*dst);
+                self.usize(*index);
+            }
+            Instruction::FunctionUpvalueSet(index, src) => {
+                self.u8(22);
+                self.usize(*index);
+                self.usize(*src);
+            }
+            Instruction::BinaryAdd(dst, lhs, rhs) => {
+                self.u8(23);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinarySub(dst, lhs, rhs) => {
+                self.u8(24);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryMul(dst, lhs, rhs) => {
+                self.u8(25);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryDiv(dst, lhs, rhs) => {
+                self.u8(26);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryFloorDiv(dst, lhs, rhs) => {
+                self.u8(27);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryMod(dst, lhs, rhs) => {
+                self.u8(28);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryPow(dst, lhs, rhs) => {
+                self.u8(29);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryConcat(dst, lhs, rhs) => {
+                self.u8(30);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryBitwiseAnd(dst, lhs, rhs) => {
+                self.u8(31);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryBitwiseOr(dst, lhs, rhs) => {
+                self.u8(32);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryBitwiseXor(dst, lhs, rhs) => {
+                self.u8(33);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryShiftLeft(dst, lhs, rhs) => {
+                self.u8(34);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryShiftRight(dst, lhs, rhs) => {
+                self.u8(35);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryEqual(dst, lhs, rhs) => {
+                self.u8(36);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryLessThan(dst, lhs, rhs) => {
+                self.u8(37);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::BinaryLessEqual(dst, lhs, rhs) => {
+                self.u8(38);
+                self.usize(*dst);
+                self.usize(*lhs);
+                self.usize(*rhs);
+            }
+            Instruction::UnaryMinus(dst, src) => {
+                self.u8(39);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::UnaryBitwiseNot(dst, src) => {
+                self.u8(40);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::UnaryLength(dst, src) => {
+                self.u8(41);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::UnaryLogicalNot(dst, src) => {
+                self.u8(42);
+                self.usize(*dst);
+                self.usize(*src);
+            }
+            Instruction::ForPrep(base, label) => {
+                self.u8(43);
+                self.usize(*base);
+                self.usize(*label);
+            }
+            Instruction::ForLoop(base, label) => {
+                self.u8(44);
+                self.usize(*base);
+                self.usize(*label);
+            }
+            Instruction::FunctionCall(func, args, expected) => {
+                self.u8(45);
+                self.usize(*func);
+                self.option_usize(*args);
+                self.option_usize(*expected);
+            }
+            Instruction::TailCall(func, args) => {
+                self.u8(46);
+                self.usize(*func);
+                self.option_usize(*args);
+            }
+            Instruction::Return(base, count) => {
+                self.u8(47);
+                self.usize(*base);
+                self.option_usize(*count);
+            }
+            Instruction::GetVariadic(dst, expected) => {
+                self.u8(48);
+                self.usize(*dst);
+                self.option_usize(*expected);
+            }
+            Instruction::GetField(dst, table, key, cache) => {
+                self.u8(49);
+                self.usize(*dst);
+                self.usize(*table);
+                self.usize(*key);
+                self.usize(*cache);
+            }
+            Instruction::SetField(table, key, value, cache) => {
+                self.u8(50);
+                self.usize(*table);
+                self.usize(*key);
+                self.usize(*value);
+                self.usize(*cache);
+            }
+        }
+    }
+}
+
+/// Reads what [`Writer`] wrote; never panics on malformed input.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+    /// Number of bytes left; upper bound of any length read from the input.
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        if len > self.buf.len() {
+            return Err(Malformed);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Malformed> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+    pub(crate) fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.array::<1>()?[0])
+    }
+    pub(crate) fn bool(&mut self) -> Result<bool, Malformed> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Malformed),
+        }
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, Malformed> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn usize(&mut self) -> Result<usize, Malformed> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value).map_err(|_| Malformed);
+            }
+        }
+        Err(Malformed)
+    }
+    /// Length of a sequence whose elements take at least one byte each.
+    pub(crate) fn len(&mut self) -> Result<usize, Malformed> {
+        let len = self.usize()?;
+        if len > self.remaining() {
+            return Err(Malformed);
+        }
+        Ok(len)
+    }
+    pub(crate) fn option_usize(&mut self) -> Result<Option<usize>, Malformed> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.usize()?)),
+            _ => Err(Malformed),
+        }
+    }
+    pub(crate) fn int(&mut self) -> Result<IntType, Malformed> {
+        Ok(IntType::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn float(&mut self) -> Result<FloatType, Malformed> {
+        Ok(FloatType::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn number(&mut self) -> Result<LuaNumber, Malformed> {
+        match self.u8()? {
+            0 => Ok(LuaNumber::Int(self.int()?)),
+            1 => Ok(LuaNumber::Float(self.float()?)),
+            _ => Err(Malformed),
+        }
+    }
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+    /// name of variable; empty if stripped
+    fn name(&mut self) -> Result<String, Malformed> {
+        match self.bytes()? {
+            [] => Ok(STRIPPED_NAME.to_string()),
+            name => String::from_utf8(name.to_vec()).map_err(|_| Malformed),
+        }
+    }
+
+    pub(crate) fn prototype(&mut self) -> Result<Prototype, Malformed> {
+        self.prototype_nested(0)
+    }
+    fn prototype_nested(&mut self, depth: usize) -> Result<Prototype, Malformed> {
+        if depth >= MAX_PROTOTYPE_DEPTH {
+            return Err(Malformed);
+        }
+        let args = self.usize()?;
+        let is_variadic = self.bool()?;
+        let upvalues = self.usize()?;
+        // upvalues are allocated when a closure is created
+        if upvalues > self.remaining() {
+            return Err(Malformed);
+        }
+        let chunk = self.chunk(depth)?;
+        Ok(Prototype {
+            args,
+            is_variadic,
+            upvalues,
+            chunk,
+        })
+    }
+    fn chunk(&mut self, depth: usize) -> Result<Chunk, Malformed> {
+        let len = self.len()?;
+        let mut instructions = Vec::with_capacity(len);
+        for _ in 0..len {
+            instructions.push(self.instruction()?);
+        }
+        // at most one cache for each instruction
+        let caches = self.usize()?;
+        if caches > instructions.len() {
+            return Err(Malformed);
+        }
+        let caches = vec![FieldCache::default(); caches];
+        let stack_size = self.usize()?;
+        let len = self.len()?;
+        let mut constants = Vec::with_capacity(len);
+        for _ in 0..len {
+            constants.push(LuaString::from_slice(self.bytes()?));
+        }
+        let source = Rc::from(self.name()?);
+        let len = self.len()?;
+        let mut lines = Vec::with_capacity(len);
+        for _ in 0..len {
+            lines.push(u32::try_from(self.usize()?).map_err(|_| Malformed)?);
+        }
+        let local_names = self.names()?;
+        let upvalue_names = self.names()?;
+        let len = self.len()?;
+        let mut prototypes = Vec::with_capacity(len);
+        for _ in 0..len {
+            prototypes.push(Rc::new(self.prototype_nested(depth + 1)?));
+        }
+        Ok(Chunk {
+            instructions,
+            stack_size,
+            lines,
+            constants,
+            caches,
+            local_names,
+            upvalue_names,
+            source,
+            prototypes,
+        })
+    }
+    /// names of variables in debug information
+    fn names(&mut self) -> Result<Vec<String>, Malformed> {
+        let len = self.len()?;
+        let mut names = Vec::with_capacity(len);
+        for _ in 0..len {
+            names.push(String::from_utf8(self.bytes()?.to_vec()).map_err(|_| Malformed)?);
+        }
+        Ok(names)
+    }
+    fn instruction(&mut self) -> Result<Instruction, Malformed> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Move(self.usize()?, self.usize()?),
+            1 => Instruction::Jump(self.usize()?),
+            2 => Instruction::JumpTrue(self.usize()?, self.usize()?),
+            3 => Instruction::JumpFalse(self.usize()?, self.usize()?),
+            4 => Instruction::InitCell(self.usize()?),
+            5 => Instruction::GetCell(self.usize()?, self.usize()?),
+            6 => Instruction::SetCell(self.usize()?, self.usize()?),
+            7 => Instruction::IsNil(self.usize()?, self.usize()?),
+            8 => Instruction::Nil(self.usize()?, self.usize()?),
+            9 => Instruction::Boolean(self.usize()?, self.bool()?),
+            10 => Instruction::Numeric(self.usize()?, self.number()?),
+            11 => Instruction::String(self.usize()?, self.usize()?),
+            12 => Instruction::GetEnv(self.usize()?),
+            13 => Instruction::TableInit(self.usize()?, self.usize()?),
+            14 => Instruction::TableIndexInit(self.usize()?, self.usize()?, self.usize()?),
+            15 => Instruction::TableInitLast(
+                self.usize()?,
+                self.usize()?,
+                self.option_usize()?,
+                self.int()?,
+            ),
+            16 => Instruction::TableIndex(self.usize()?, self.usize()?, self.usize()?),
+            17 => Instruction::TableIndexSet(self.usize()?, self.usize()?, self.usize()?),
+            18 => Instruction::FunctionInit(self.usize()?, self.usize()?),
+            19 => Instruction::FunctionInitUpvalueFromLocalVar(self.usize()?, self.usize()?),
+            20 => Instruction::FunctionInitUpvalueFromUpvalue(self.usize()?, self.usize()?),
+            21 => Instruction::FunctionUpvalue(self.usize()?, self.usize()?),
+            22 => Instruction::FunctionUpvalueSet(self.usize()?, self.usize()?),
+            23 => Instruction::BinaryAdd(self.usize()?, self.usize()?, self.usize()?),
+            24 => Instruction::BinarySub(self.usize()?, self.usize()?, self.usize()?),
+            25 => Instruction::BinaryMul(self.usize()?, self.usize()?, self.usize()?),
+            26 => Instruction::BinaryDiv(self.usize()?, self.usize()?, self.usize()?),
+            27 => Instruction::BinaryFloorDiv(self.usize()?, self.usize()?, self.usize()?),
+            28 => Instruction::BinaryMod(self.usize()?, self.usize()?, self.usize()?),
+            29 => Instruction::BinaryPow(self.usize()?, self.usize()?, self.usize()?),
+            30 => Instruction::BinaryConcat(self.usize()?, self.usize()?, self.usize()?),
+            31 => Instruction::BinaryBitwiseAnd(self.usize()?, self.usize()?, self.usize()?),
+            32 => Instruction::BinaryBitwiseOr(self.usize()?, self.usize()?, self.usize()?),
+            33 => Instruction::BinaryBitwiseXor(self.usize()?, self.usize()?, self.usize()?),
+            34 => Instruction::BinaryShiftLeft(self.usize()?, self.usize()?, self.usize()?),
+            35 => Instruction::BinaryShiftRight(self.usize()?, self.usize()?, self.usize()?),
+            36 => Instruction::BinaryEqual(self.usize()?, self.usize()?, self.usize()?),
+            37 => Instruction::BinaryLessThan(self.usize()?, self.usize()?, self.usize()?),
+            38 => Instruction::BinaryLessEqual(self.usize()?, self.usize()?, self.usize()?),
+            39 => Instruction::UnaryMinus(self.usize()?, self.usize()?),
+            40 => Instruction::UnaryBitwiseNot(self.usize()?, self.usize()?),
+            41 => Instruction::UnaryLength(self.usize()?, self.usize()?),
+            42 => Instruction::UnaryLogicalNot(self.usize()?, self.usize()?),
+            43 => Instruction::ForPrep(self.usize()?, self.usize()?),
+            44 => Instruction::ForLoop(self.usize()?, self.usize()?),
+            45 => {
+                Instruction::FunctionCall(self.usize()?, self.option_usize()?, self.option_usize()?)
+            }
+            46 => Instruction::TailCall(self.usize()?, self.option_usize()?),
+            47 => Instruction::Return(self.usize()?, self.option_usize()?),
+            48 => Instruction::GetVariadic(self.usize()?, self.option_usize()?),
+            49 => Instruction::GetField(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            50 => Instruction::SetField(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            _ => return Err(Malformed),
+        };
+        Ok(instruction)
+    }
+}
+
+/// Start of the values an instruction leaves up to the top of the data stack,
+/// like the results of `f()` in `g(f())`; its registers above them are not restored.
+fn open_results(instruction: &Instruction) -> Option<usize> {
+    match *instruction {
+        Instruction::FunctionCall(func, _, None) | Instruction::TailCall(func, _) => Some(func),
+        Instruction::GetVariadic(dst, None) => Some(dst),
+        _ => None,
+    }
+}
+/// Check that `instruction` takes the values left up to the top of the data stack from `start`
+/// by the previous instruction; `None` if it does not take them.
+fn takes_open_results(instruction: &Instruction, start: usize) -> Option<bool> {
+    match *instruction {
+        Instruction::FunctionCall(func, None, _) | Instruction::TailCall(func, None) => {
+            Some(func < start)
+        }
+        Instruction::Return(base, None) | Instruction::TableInitLast(_, base, None, _) => {
+            Some(base <= start)
+        }
+        _ => None,
+    }
+}
+
+/// Check that every index in the code of `prototype` and of its nested prototypes is in range,
+/// and that the code keeps the registers of the running function on the data stack,
+/// so the VM can run it without indexing out of bounds.
+///
+/// Values left up to the top of the data stack must be taken by the very next instruction,
+/// which no jump may land on; so the registers are restored before any other instruction runs.
+/// The types of values in registers are not checked; the VM raises an error on unexpected ones.
+pub(crate) fn validate(prototype: &Prototype) -> Result<(), Malformed> {
+    let chunk = &prototype.chunk;
+    let len = chunk.instructions.len();
+    if chunk.stack_size > MAX_STACK_SIZE || prototype.args > chunk.stack_size {
+        return Err(Malformed);
+    }
+    // every path ends with `Return`; the counter never runs past the end
+    if !matches!(chunk.instructions.last(), Some(Instruction::Return(..))) {
+        return Err(Malformed);
+    }
+    if !chunk.lines.is_empty() && chunk.lines.len() != len {
+        return Err(Malformed);
+    }
+    if chunk.local_names.len() > chunk.stack_size || chunk.upvalue_names.len() > prototype.upvalues
+    {
+        return Err(Malformed);
+    }
+    let target = |target: usize| {
+        if target < len {
+            Ok(())
+        } else {
+            Err(Malformed)
+        }
+    };
+    // `count` registers from `base`
+    let registers = |base: usize, count: usize| match base.checked_add(count) {
+        Some(end) if end <= chunk.stack_size => Ok(()),
+        _ => Err(Malformed),
+    };
+    let register = |index: usize| registers(index, 1);
+    let upvalue = |index: usize| {
+        if index < prototype.upvalues {
+            Ok(())
+        } else {
+            Err(Malformed)
+        }
+    };
+    let constant = |index: usize| {
+        if index < chunk.constants.len() {
+            Ok(())
+        } else {
+            Err(Malformed)
+        }
+    };
+    let cache = |index: usize| {
+        if index < chunk.caches.len() {
+            Ok(())
+        } else {
+            Err(Malformed)
+        }
+    };
+
+    let mut jump_targets = vec![false; len];
+    for instruction in &chunk.instructions {
+        match instruction {
+            Instruction::Jump(t)
+            | Instruction::JumpTrue(_, t)
+            | Instruction::JumpFalse(_, t)
+            | Instruction::ForPrep(_, t)
+            | Instruction::ForLoop(_, t) => {
+                target(*t)?;
+                jump_targets[*t] = true;
+            }
+            _ => {}
+        }
+    }
+
+    // register of the new function while the instructions initializing its upvalues are checked
+    let mut initializing_upvalues = None;
+    // start of the values left up to the top of the data stack by the previous instruction
+    let mut open = None;
+    for (i, instruction) in chunk.instructions.iter().enumerate() {
+        match (open, takes_open_results(instruction, open.unwrap_or(0))) {
+            (Some(_), Some(true)) if !jump_targets[i] => {}
+            (None, None) => {}
+            _ => return Err(Malformed),
+        }
+        open = open_results(instruction);
+        match instruction {
+            Instruction::Jump(t) => target(*t)?,
+            Instruction::JumpTrue(src, t) | Instruction::JumpFalse(src, t) => {
+                register(*src)?;
+                target(*t)?;
+            }
+            Instruction::InitCell(index)
+            | Instruction::GetEnv(index)
+            | Instruction::Boolean(index, _)
+            | Instruction::Numeric(index, _) => register(*index)?,
+            Instruction::String(dst, index) => {
+                register(*dst)?;
+                constant(*index)?;
+            }
+            Instruction::GetField(a, b, key, index) | Instruction::SetField(a, key, b, index) => {
+                register(*a)?;
+                register(*b)?;
+                constant(*key)?;
+                cache(*index)?;
+            }
+            Instruction::Move(a, b)
+            | Instruction::GetCell(a, b)
+            | Instruction::SetCell(a, b)
+            | Instruction::IsNil(a, b)
+            | Instruction::UnaryMinus(a, b)
+            | Instruction::UnaryBitwiseNot(a, b)
+            | Instruction::UnaryLength(a, b)
+            | Instruction::UnaryLogicalNot(a, b) => {
+                register(*a)?;
+                register(*b)?;
+            }
+            Instruction::Nil(dst, count) => registers(*dst, *count)?,
+            Instruction::TableIndexInit(a, b, c)
+            | Instruction::TableIndex(a, b, c)
+            | Instruction::TableIndexSet(a, b, c)
+            | Instruction::BinaryAdd(a, b, c)
+            | Instruction::BinarySub(a, b, c)
+            | Instruction::BinaryMul(a, b, c)
+            | Instruction::BinaryDiv(a, b, c)
+            | Instruction::BinaryFloorDiv(a, b, c)
+            | Instruction::BinaryMod(a, b, c)
+            | Instruction::BinaryPow(a, b, c)
+            | Instruction::BinaryConcat(a, b, c)
+            | Instruction::BinaryBitwiseAnd(a, b, c)
+            | Instruction::BinaryBitwiseOr(a, b, c)
+            | Instruction::BinaryBitwiseXor(a, b, c)
+            | Instruction::BinaryShiftLeft(a, b, c)
+            | Instruction::BinaryShiftRight(a, b, c)
+            | Instruction::BinaryEqual(a, b, c)
+            | Instruction::BinaryLessThan(a, b, c)
+            | Instruction::BinaryLessEqual(a, b, c) => {
+                register(*a)?;
+                register(*b)?;
+                register(*c)?;
+            }
+            Instruction::TableInit(dst, capacity) => {
+                register(*dst)?;
+                if *capacity > len {
+                    return Err(Malformed);
+                }
+            }
+            Instruction::TableInitLast(table, base, count, _) => {
+                register(*table)?;
+                registers(*base, count.unwrap_or(1))?;
+            }
+            Instruction::ForPrep(base, t) | Instruction::ForLoop(base, t) => {
+                registers(*base, 3)?;
+                target(*t)?;
+            }
+            Instruction::FunctionCall(func, args, expected) => {
+                registers(*func, 1 + args.unwrap_or(0))?;
+                registers(*func, expected.unwrap_or(0))?;
+            }
+            Instruction::TailCall(func, args) => registers(*func, 1 + args.unwrap_or(0))?,
+            Instruction::Return(base, count) => registers(*base, count.unwrap_or(0))?,
+            Instruction::GetVariadic(dst, count) => registers(*dst, count.unwrap_or(1))?,
+            Instruction::FunctionUpvalue(dst, index) => {
+                register(*dst)?;
+                upvalue(*index)?;
+            }
+            Instruction::FunctionUpvalueSet(index, src) => {
+                upvalue(*index)?;
+                register(*src)?;
+            }
+            Instruction::FunctionInit(dst, index) => {
+                register(*dst)?;
+                let function = chunk.prototypes.get(*index).ok_or(Malformed)?;
+                // upvalues of the new function are initialized by the instructions right after it
+                let mut count = 0;
+                for next in &chunk.instructions[i + 1..] {
+                    match next {
+                        Instruction::FunctionInitUpvalueFromLocalVar(func, cell) if func == dst => {
+                            register(*cell)?
+                        }
+                        Instruction::FunctionInitUpvalueFromUpvalue(func, index) if func == dst => {
+                            upvalue(*index)?
+                        }
+                        _ => break,
+                    }
+                    count += 1;
+                }
+                if count != function.upvalues {
+                    return Err(Malformed);
+                }
+            }
+            // checked with `FunctionInit` above
+            Instruction::FunctionInitUpvalueFromLocalVar(func, _)
+            | Instruction::FunctionInitUpvalueFromUpvalue(func, _) => {
+                if initializing_upvalues != Some(*func) || jump_targets[i] {
+                    return Err(Malformed);
+                }
+            }
+        }
+        initializing_upvalues = match instruction {
+            Instruction::FunctionInit(dst, _) => Some(*dst),
+            Instruction::FunctionInitUpvalueFromLocalVar(..)
+            | Instruction::FunctionInitUpvalueFromUpvalue(..) => initializing_upvalues,
+            _ => None,
+        };
+    }
+    chunk
+        .prototypes
+        .iter()
+        .try_for_each(|prototype| validate(prototype))
+}
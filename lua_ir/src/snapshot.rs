@@ -0,0 +1,799 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+
+use crate::random::Xoshiro256;
+use crate::serialize::Malformed;
+use crate::serialize::Reader;
+use crate::serialize::Writer;
+use crate::vm::CallStackFrame;
+use crate::Chunk;
+use crate::FloatType;
+use crate::IntType;
+use crate::LuaEnv;
+use crate::LuaFunction;
+use crate::LuaFunctionLua;
+use crate::LuaNumber;
+use crate::LuaString;
+use crate::LuaTable;
+use crate::LuaThread;
+use crate::LuaUserData;
+use crate::LuaValue;
+use crate::Prototype;
+use crate::RuntimeError;
+use crate::ThreadStatus;
+
+/// first bytes of every snapshot
+const MAGIC: &[u8] = b"\x1bLuaSnapshot";
+/// version of the layout; snapshots of other versions are rejected
+const VERSION: u8 = 9;
+
+/// depth of nested tables searched for permanents, from the global table
+const PERMANENT_DEPTH: usize = 4;
+
+// kinds of objects in the object table
+const OBJECT_PERMANENT: u8 = 0;
+const OBJECT_TABLE: u8 = 1;
+const OBJECT_FUNCTION: u8 = 2;
+const OBJECT_UPVALUE: u8 = 3;
+const OBJECT_THREAD: u8 = 4;
+
+// tags of values
+const VALUE_NIL: u8 = 0;
+const VALUE_FALSE: u8 = 1;
+const VALUE_TRUE: u8 = 2;
+const VALUE_INT: u8 = 3;
+const VALUE_FLOAT: u8 = 4;
+const VALUE_STRING: u8 = 5;
+const VALUE_TABLE: u8 = 6;
+const VALUE_FUNCTION: u8 = 7;
+const VALUE_USERDATA: u8 = 8;
+const VALUE_THREAD: u8 = 9;
+
+/// Name every Rust function and userdata reachable from the global table by its path, like `string.format`;
+/// so a `LuaEnv` built the same way gives the same names.
+/// Metatables of userdata are permanents too, named like `io.stdout#metatable`.
+pub(crate) fn collect_permanents(
+    globals: &Rc<RefCell<LuaTable>>,
+    string_metatable: &Rc<RefCell<LuaTable>>,
+) -> IndexMap<String, LuaValue> {
+    let mut permanents = IndexMap::new();
+    let mut visited = HashSet::new();
+    visited.insert(Rc::as_ptr(globals) as *const ());
+    collect_from(globals, "", 0, &mut visited, &mut permanents);
+    collect_from(
+        string_metatable,
+        "#string_metatable",
+        1,
+        &mut visited,
+        &mut permanents,
+    );
+    permanents
+}
+fn collect_from(
+    table: &Rc<RefCell<LuaTable>>,
+    path: &str,
+    depth: usize,
+    visited: &mut HashSet<*const ()>,
+    permanents: &mut IndexMap<String, LuaValue>,
+) {
+    if depth >= PERMANENT_DEPTH {
+        return;
+    }
+    for (key, value) in table.borrow().map.iter() {
+        let LuaValue::String(key) = key else {
+            continue;
+        };
+        let ptr = match value {
+            LuaValue::Table(t) => Rc::as_ptr(t) as *const (),
+            LuaValue::Function(f) => Rc::as_ptr(f) as *const (),
+            LuaValue::UserData(u) => Rc::as_ptr(u) as *const (),
+            _ => continue,
+        };
+        if !visited.insert(ptr) {
+            continue;
+        }
+        let name = if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        match value {
+            LuaValue::Table(t) => collect_from(t, &name, depth + 1, visited, permanents),
+            LuaValue::Function(f) => {
+                if let LuaFunction::RustFunc(_) = &*f.borrow() {
+                    permanents.insert(name, value.clone());
+                }
+            }
+            LuaValue::UserData(u) => {
+                if let Some(meta) = u.borrow().metatable() {
+                    if visited.insert(Rc::as_ptr(meta) as *const ()) {
+                        let meta_name = format!("{}#metatable", name);
+                        permanents.insert(meta_name.clone(), LuaValue::Table(Rc::clone(meta)));
+                        collect_from(meta, &meta_name, depth + 1, visited, permanents);
+                    }
+                }
+                permanents.insert(name, value.clone());
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn error(message: &str) -> RuntimeError {
+    RuntimeError::Custom(format!("snapshot: {}", message).into())
+}
+impl From<Malformed> for RuntimeError {
+    fn from(_: Malformed) -> Self {
+        error("malformed data")
+    }
+}
+
+/// Object with identity, written once in the snapshot and referenced by its index.
+enum Object {
+    Table(Rc<RefCell<LuaTable>>),
+    Function(Rc<RefCell<LuaFunction>>),
+    UserData(Rc<RefCell<LuaUserData>>),
+    Upvalue(Rc<RefCell<LuaValue>>),
+    Thread(Rc<RefCell<LuaThread>>),
+}
+impl Object {
+    fn as_ptr(&self) -> *const () {
+        match self {
+            Object::Table(t) => Rc::as_ptr(t) as *const (),
+            Object::Function(f) => Rc::as_ptr(f) as *const (),
+            Object::UserData(u) => Rc::as_ptr(u) as *const (),
+            Object::Upvalue(v) => Rc::as_ptr(v) as *const (),
+            Object::Thread(t) => Rc::as_ptr(t) as *const (),
+        }
+    }
+}
+
+/// Writes the object graph breadth-first, so deeply nested tables do not overflow the Rust stack.
+///
+/// Layout: header, object table ( kind of every object, name of permanents ), roots, contents of non-permanent objects.
+struct Encoder<'a> {
+    permanents: HashMap<*const (), &'a str>,
+    ids: HashMap<*const (), usize>,
+    /// function prototypes written so far, including nested ones; shared by functions written later
+    prototypes: HashMap<*const Prototype, usize>,
+    objects: Writer,
+    body: Writer,
+    queue: VecDeque<Object>,
+}
+impl Encoder<'_> {
+    fn id(&mut self, object: Object) -> Result<usize, RuntimeError> {
+        let ptr = object.as_ptr();
+        if let Some(&id) = self.ids.get(&ptr) {
+            return Ok(id);
+        }
+        if let Some(name) = self.permanents.get(&ptr) {
+            self.objects.u8(OBJECT_PERMANENT);
+            self.objects.bytes(name.as_bytes());
+        } else {
+            let kind = match &object {
+                Object::Table(_) => OBJECT_TABLE,
+                Object::Function(f) => match &*f.borrow() {
+                    LuaFunction::LuaFunc(_) => OBJECT_FUNCTION,
+                    LuaFunction::RustFunc(_) => {
+                        return Err(error("Rust function is not a permanent"));
+                    }
+                },
+                Object::UserData(_) => return Err(error("userdata is not a permanent")),
+                Object::Upvalue(_) => OBJECT_UPVALUE,
+                Object::Thread(_) => OBJECT_THREAD,
+            };
+            self.objects.u8(kind);
+            self.queue.push_back(object);
+        }
+        let id = self.ids.len();
+        self.ids.insert(ptr, id);
+        Ok(id)
+    }
+
+    fn value(&mut self, value: &LuaValue) -> Result<(), RuntimeError> {
+        let (tag, object) = match value {
+            LuaValue::Nil => {
+                self.body.u8(VALUE_NIL);
+                return Ok(());
+            }
+            LuaValue::Boolean(b) => {
+                self.body.u8(if *b { VALUE_TRUE } else { VALUE_FALSE });
+                return Ok(());
+            }
+            LuaValue::Number(LuaNumber::Int(i)) => {
+                self.body.u8(VALUE_INT);
+                self.body.int(*i);
+                return Ok(());
+            }
+            LuaValue::Number(LuaNumber::Float(f)) => {
+                self.body.u8(VALUE_FLOAT);
+                self.body.float(*f);
+                return Ok(());
+            }
+            LuaValue::String(s) => {
+                self.body.u8(VALUE_STRING);
+                self.body.bytes(s.as_bytes());
+                return Ok(());
+            }
+            LuaValue::Table(t) => (VALUE_TABLE, Object::Table(Rc::clone(t))),
+            LuaValue::Function(f) => (VALUE_FUNCTION, Object::Function(Rc::clone(f))),
+            LuaValue::UserData(u) => (VALUE_USERDATA, Object::UserData(Rc::clone(u))),
+            LuaValue::Thread(t) => (VALUE_THREAD, Object::Thread(Rc::clone(t))),
+        };
+        let id = self.id(object)?;
+        self.body.u8(tag);
+        self.body.usize(id);
+        Ok(())
+    }
+    fn upvalue(&mut self, upvalue: &Rc<RefCell<LuaValue>>) -> Result<(), RuntimeError> {
+        let id = self.id(Object::Upvalue(Rc::clone(upvalue)))?;
+        self.body.usize(id);
+        Ok(())
+    }
+    fn table(&mut self, table: &Rc<RefCell<LuaTable>>) -> Result<(), RuntimeError> {
+        let id = self.id(Object::Table(Rc::clone(table)))?;
+        self.body.usize(id);
+        Ok(())
+    }
+    fn option_table(&mut self, table: Option<&Rc<RefCell<LuaTable>>>) -> Result<(), RuntimeError> {
+        match table {
+            Some(table) => {
+                self.body.u8(1);
+                self.table(table)
+            }
+            None => {
+                self.body.u8(0);
+                Ok(())
+            }
+        }
+    }
+    fn values(&mut self, values: &[LuaValue]) -> Result<(), RuntimeError> {
+        self.body.usize(values.len());
+        for value in values {
+            self.value(value)?;
+        }
+        Ok(())
+    }
+    /// index of a prototype written before, or a new prototype
+    fn prototype(&mut self, prototype: &Rc<Prototype>) {
+        if let Some(&index) = self.prototypes.get(&Rc::as_ptr(prototype)) {
+            self.body.u8(1);
+            self.body.usize(index);
+        } else {
+            self.body.u8(0);
+            self.body.prototype(prototype);
+            self.add_prototype(prototype);
+        }
+    }
+    /// number `prototype` and its nested prototypes, depth first
+    fn add_prototype(&mut self, prototype: &Rc<Prototype>) {
+        let index = self.prototypes.len();
+        self.prototypes.insert(Rc::as_ptr(prototype), index);
+        for nested in &prototype.chunk.prototypes {
+            self.add_prototype(nested);
+        }
+    }
+
+    fn contents(&mut self, object: Object) -> Result<(), RuntimeError> {
+        match object {
+            Object::Table(table) => {
+                let table = table.borrow();
+                self.option_table(table.meta.as_ref())?;
+                // nil in the array part is kept, so the border stays the same
+                self.body.usize(table.arr.len());
+                for value in &table.arr {
+                    self.value(value)?;
+                }
+                let live = || table.map.iter().filter(|(_, value)| !value.is_nil());
+                self.body.usize(live().count());
+                for (key, value) in live() {
+                    self.value(key)?;
+                    self.value(value)?;
+                }
+            }
+            Object::Function(function) => {
+                let function = function.borrow();
+                let LuaFunction::LuaFunc(function) = &*function else {
+                    unreachable!("Rust functions are permanents");
+                };
+                self.body.usize(function.upvalues.len());
+                for upvalue in &function.upvalues {
+                    self.upvalue(upvalue)?;
+                }
+                self.option_table(function.env.as_ref())?;
+                self.prototype(&function.prototype);
+            }
+            Object::Upvalue(upvalue) => {
+                self.value(&upvalue.borrow())?;
+            }
+            Object::Thread(thread) => self.thread(&thread.borrow())?,
+            Object::UserData(_) => unreachable!("userdata are permanents"),
+        }
+        Ok(())
+    }
+    fn thread(&mut self, thread: &LuaThread) -> Result<(), RuntimeError> {
+        // a thread left running by an error can never run again; it is saved as dead
+        if thread.status == ThreadStatus::Running {
+            self.body.usize(0);
+            self.values(&[])?;
+            self.body.usize(0);
+        } else {
+            self.body.usize(thread.bp);
+            self.values(&thread.data_stack)?;
+            self.frames(&thread.call_stack)?;
+        }
+        match thread.status {
+            ThreadStatus::NotStarted => self.body.u8(0),
+            ThreadStatus::ResumePending(expected) => {
+                self.body.u8(2);
+                self.body.option_usize(expected);
+            }
+            ThreadStatus::YieldPending(expected) => {
+                self.body.u8(3);
+                self.body.option_usize(expected);
+            }
+            ThreadStatus::Running | ThreadStatus::Dead => self.body.u8(4),
+        }
+        match &thread.function {
+            Some(function) => {
+                self.body.u8(1);
+                self.value(&LuaValue::Function(Rc::clone(function)))?;
+            }
+            None => self.body.u8(0),
+        }
+        let boundaries = match thread.status {
+            ThreadStatus::Running => &[][..],
+            _ => &thread.boundaries,
+        };
+        self.body.usize(boundaries.len());
+        for &boundary in boundaries {
+            self.body.usize(boundary);
+        }
+        Ok(())
+    }
+    fn frames(&mut self, call_stack: &[CallStackFrame]) -> Result<(), RuntimeError> {
+        self.body.usize(call_stack.len());
+        for frame in call_stack {
+            if frame.continuation.is_some() {
+                return Err(error(
+                    "coroutine is suspended inside a Rust function, like `pcall`",
+                ));
+            }
+            self.value(&LuaValue::Function(Rc::clone(&frame.function)))?;
+            self.body.usize(frame.counter);
+            self.body.option_usize(frame.return_expected);
+            self.values(&frame.variadic)?;
+            self.body.usize(frame.data_stack);
+            self.body.usize(frame.bp);
+            self.body.usize(frame.cells.len());
+            for cell in &frame.cells {
+                self.upvalue(cell)?;
+            }
+            self.body.option_usize(frame.pending_result);
+            self.body.bool(frame.tail_call);
+        }
+        Ok(())
+    }
+}
+
+/// Object created before its contents are read, so references to it can be resolved.
+enum Slot {
+    Table(Rc<RefCell<LuaTable>>),
+    Function(Rc<RefCell<LuaFunction>>),
+    UserData(Rc<RefCell<LuaUserData>>),
+    Upvalue(Rc<RefCell<LuaValue>>),
+    Thread(Rc<RefCell<LuaThread>>),
+}
+
+struct Decoder<'a> {
+    reader: Reader<'a>,
+    objects: Vec<Slot>,
+    /// function prototypes read so far, numbered like [`Encoder::add_prototype`]
+    prototypes: Vec<Rc<Prototype>>,
+}
+impl Decoder<'_> {
+    fn value(&mut self) -> Result<LuaValue, RuntimeError> {
+        let value = match self.reader.u8()? {
+            VALUE_NIL => LuaValue::Nil,
+            VALUE_FALSE => LuaValue::Boolean(false),
+            VALUE_TRUE => LuaValue::Boolean(true),
+            VALUE_INT => LuaValue::Number(self.reader.int()?.into()),
+            VALUE_FLOAT => LuaValue::Number(self.reader.float()?.into()),
+            VALUE_STRING => LuaValue::String(LuaString::from_slice(self.reader.bytes()?)),
+            tag => {
+                let id = self.reader.usize()?;
+                match (tag, self.objects.get(id)) {
+                    (VALUE_TABLE, Some(Slot::Table(t))) => LuaValue::Table(Rc::clone(t)),
+                    (VALUE_FUNCTION, Some(Slot::Function(f))) => LuaValue::Function(Rc::clone(f)),
+                    (VALUE_USERDATA, Some(Slot::UserData(u))) => LuaValue::UserData(Rc::clone(u)),
+                    (VALUE_THREAD, Some(Slot::Thread(t))) => LuaValue::Thread(Rc::clone(t)),
+                    _ => return Err(Malformed.into()),
+                }
+            }
+        };
+        Ok(value)
+    }
+    fn upvalue(&mut self) -> Result<Rc<RefCell<LuaValue>>, RuntimeError> {
+        match self.objects.get(self.reader.usize()?) {
+            Some(Slot::Upvalue(v)) => Ok(Rc::clone(v)),
+            _ => Err(Malformed.into()),
+        }
+    }
+    fn cells(&mut self) -> Result<Vec<Rc<RefCell<LuaValue>>>, RuntimeError> {
+        let len = self.reader.len()?;
+        let mut cells = Vec::with_capacity(len);
+        for _ in 0..len {
+            cells.push(self.upvalue()?);
+        }
+        Ok(cells)
+    }
+    fn table(&mut self) -> Result<Rc<RefCell<LuaTable>>, RuntimeError> {
+        match self.objects.get(self.reader.usize()?) {
+            Some(Slot::Table(t)) => Ok(Rc::clone(t)),
+            _ => Err(Malformed.into()),
+        }
+    }
+    fn option_table(&mut self) -> Result<Option<Rc<RefCell<LuaTable>>>, RuntimeError> {
+        match self.reader.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.table()?)),
+            _ => Err(Malformed.into()),
+        }
+    }
+    fn function(&mut self) -> Result<Rc<RefCell<LuaFunction>>, RuntimeError> {
+        match self.value()? {
+            LuaValue::Function(f) => Ok(f),
+            _ => Err(Malformed.into()),
+        }
+    }
+    fn values(&mut self) -> Result<Vec<LuaValue>, RuntimeError> {
+        let len = self.reader.len()?;
+        let mut values = Vec::with_capacity(len);
+        for _ in 0..len {
+            values.push(self.value()?);
+        }
+        Ok(values)
+    }
+    fn usizes(&mut self) -> Result<Vec<usize>, RuntimeError> {
+        let len = self.reader.len()?;
+        let mut values = Vec::with_capacity(len);
+        for _ in 0..len {
+            values.push(self.reader.usize()?);
+        }
+        Ok(values)
+    }
+
+    fn contents(&mut self, id: usize) -> Result<(), RuntimeError> {
+        match &self.objects[id] {
+            Slot::Table(table) => {
+                let table = Rc::clone(table);
+                let meta = self.option_table()?;
+                let mut contents = LuaTable::new();
+                contents.meta = meta;
+                for _ in 0..self.reader.len()? {
+                    let value = self.value()?;
+                    contents.arr.push(value);
+                }
+                for _ in 0..self.reader.len()? {
+                    let key = self.value()?;
+                    let value = self.value()?;
+                    if key.is_nil() || key.is_nan() {
+                        return Err(Malformed.into());
+                    }
+                    contents.insert(key, value);
+                }
+                *table.borrow_mut() = contents;
+            }
+            Slot::Function(function) => {
+                let function = Rc::clone(function);
+                let len = self.reader.len()?;
+                let mut upvalues = Vec::with_capacity(len);
+                for _ in 0..len {
+                    upvalues.push(self.upvalue()?);
+                }
+                let env = self.option_table()?;
+                let prototype = self.prototype()?;
+                if upvalues.len() != prototype.upvalues {
+                    return Err(Malformed.into());
+                }
+                *function.borrow_mut() = LuaFunction::LuaFunc(LuaFunctionLua {
+                    upvalues,
+                    env,
+                    prototype,
+                });
+            }
+            Slot::Upvalue(upvalue) => {
+                let upvalue = Rc::clone(upvalue);
+                *upvalue.borrow_mut() = self.value()?;
+            }
+            Slot::Thread(thread) => {
+                let thread = Rc::clone(thread);
+                *thread.borrow_mut() = self.thread()?;
+            }
+            Slot::UserData(_) => unreachable!("userdata are permanents"),
+        }
+        Ok(())
+    }
+    fn prototype(&mut self) -> Result<Rc<Prototype>, RuntimeError> {
+        match self.reader.u8()? {
+            0 => {
+                let prototype = Rc::new(self.reader.prototype()?);
+                crate::serialize::validate(&prototype)?;
+                self.add_prototype(&prototype);
+                Ok(prototype)
+            }
+            1 => {
+                let index = self.reader.usize()?;
+                Ok(Rc::clone(self.prototypes.get(index).ok_or(Malformed)?))
+            }
+            _ => Err(Malformed.into()),
+        }
+    }
+    fn add_prototype(&mut self, prototype: &Rc<Prototype>) {
+        self.prototypes.push(Rc::clone(prototype));
+        for nested in &prototype.chunk.prototypes {
+            self.add_prototype(nested);
+        }
+    }
+    fn thread(&mut self) -> Result<LuaThread, RuntimeError> {
+        let bp = self.reader.usize()?;
+        let data_stack = self.values()?;
+        let len = self.reader.len()?;
+        let mut call_stack = Vec::with_capacity(len);
+        for _ in 0..len {
+            call_stack.push(CallStackFrame {
+                function: self.function()?,
+                counter: self.reader.usize()?,
+                return_expected: self.reader.option_usize()?,
+                variadic: self.values()?,
+                data_stack: self.reader.usize()?,
+                bp: self.reader.usize()?,
+                cells: self.cells()?,
+                pending_result: self.reader.option_usize()?,
+                continuation: None,
+                tail_call: self.reader.bool()?,
+            });
+        }
+        let status = match self.reader.u8()? {
+            0 => ThreadStatus::NotStarted,
+            2 => ThreadStatus::ResumePending(self.reader.option_usize()?),
+            3 => ThreadStatus::YieldPending(self.reader.option_usize()?),
+            4 => ThreadStatus::Dead,
+            _ => return Err(Malformed.into()),
+        };
+        let function = match self.reader.u8()? {
+            0 => None,
+            1 => Some(self.function()?),
+            _ => return Err(Malformed.into()),
+        };
+        let boundaries = self.usizes()?;
+        Ok(LuaThread {
+            bp,
+            data_stack,
+            call_stack,
+            status,
+            function,
+            boundaries,
+            stack_charged: (0, 0),
+        })
+    }
+}
+
+/// Check that the frames of a restored thread fit in its data stack and in the code of their functions,
+/// so resuming it cannot index out of the registers.
+fn check_thread(thread: &LuaThread) -> Result<(), Malformed> {
+    if thread.status == ThreadStatus::NotStarted
+        && (thread.function.is_none() || !thread.call_stack.is_empty())
+    {
+        return Err(Malformed);
+    }
+    if !thread.boundaries.is_sorted()
+        || thread
+            .boundaries
+            .last()
+            .is_some_and(|&boundary| boundary > thread.call_stack.len())
+    {
+        return Err(Malformed);
+    }
+    // registers of the caller of each frame; the first frame is called from the bottom of the stack
+    let mut caller = None;
+    for frame in &thread.call_stack {
+        let function = frame.function.borrow();
+        let LuaFunction::LuaFunc(function) = &*function else {
+            return Err(Malformed);
+        };
+        let prototype = &function.prototype;
+        let stack_size = prototype.chunk.stack_size;
+        let valid = frame.counter <= prototype.chunk.instructions.len()
+            && (prototype.is_variadic || frame.variadic.is_empty())
+            && frame.cells.len() <= stack_size
+            && frame.pending_result.is_none_or(|dst| dst < stack_size)
+            && match caller {
+                Some(caller) => frame.bp == caller && frame.data_stack >= caller,
+                None => frame.bp <= frame.data_stack,
+            }
+            && frame.data_stack <= thread.data_stack.len();
+        if !valid {
+            return Err(Malformed);
+        }
+        caller = Some(frame.data_stack);
+    }
+    match caller {
+        Some(bp) if thread.bp == bp => Ok(()),
+        None if thread.bp <= thread.data_stack.len() => Ok(()),
+        _ => Err(Malformed),
+    }
+}
+
+impl LuaEnv {
+    /// Register `value` as a permanent named `name`, replacing the one with the same name.
+    ///
+    /// Permanents are not written by [`LuaEnv::snapshot`], only their names are;
+    /// [`LuaEnv::restore`] puts back the permanents registered under those names.
+    /// Rust functions and userdata are saved only as permanents.
+    /// Every Rust function and userdata of the standard library is registered when `LuaEnv` is built,
+    /// named by its path like `string.format`.
+    pub fn add_permanent(&mut self, name: impl Into<String>, value: LuaValue) {
+        self.permanents.insert(name.into(), value);
+    }
+
+    /// Serialize the state of this `LuaEnv` to bytes, to be loaded by [`LuaEnv::restore`];
+    /// for saving games, or resuming after a crash.
+    ///
+    /// The snapshot contains the global table, the metatable for strings, the state of `math.random`,
+    /// and every value reachable from them: tables with their metatables, Lua functions with their upvalues,
+    /// and coroutines suspended by `coroutine.yield`.
+    /// Shared references and cycles are kept.
+    ///
+    /// Fails if Lua code is running, if a Rust function or userdata is reachable but not a permanent
+    /// ( see [`LuaEnv::add_permanent`] ), or if a coroutine is suspended inside a Rust function, like yielding inside `pcall`.
+    pub fn snapshot(&self) -> Result<Vec<u8>, RuntimeError> {
+        if !self.coroutines.is_empty() {
+            return Err(error("cannot save while Lua code is running"));
+        }
+        let mut permanents = HashMap::new();
+        for (name, value) in &self.permanents {
+            let ptr = match value {
+                LuaValue::Table(t) => Rc::as_ptr(t) as *const (),
+                LuaValue::Function(f) => Rc::as_ptr(f) as *const (),
+                LuaValue::UserData(u) => Rc::as_ptr(u) as *const (),
+                LuaValue::Thread(t) => Rc::as_ptr(t) as *const (),
+                _ => continue,
+            };
+            permanents.entry(ptr).or_insert(name.as_str());
+        }
+        let mut encoder = Encoder {
+            permanents,
+            ids: HashMap::new(),
+            prototypes: HashMap::new(),
+            objects: Writer::default(),
+            body: Writer::default(),
+            queue: VecDeque::new(),
+        };
+
+        encoder.table(&self.env)?;
+        encoder.table(&self.string_metatable)?;
+        for word in self.rng.state() {
+            encoder.body.u64(word);
+        }
+        while let Some(object) = encoder.queue.pop_front() {
+            encoder.contents(object)?;
+        }
+
+        let mut out = Writer::default();
+        out.buf.extend_from_slice(MAGIC);
+        out.u8(VERSION);
+        out.u8(std::mem::size_of::<IntType>() as u8);
+        out.u8(std::mem::size_of::<FloatType>() as u8);
+        out.usize(encoder.ids.len());
+        out.buf.extend_from_slice(&encoder.objects.buf);
+        out.buf.extend_from_slice(&encoder.body.buf);
+        Ok(out.buf)
+    }
+
+    /// Replace the state of this `LuaEnv` with `snapshot` taken by [`LuaEnv::snapshot`].
+    ///
+    /// This `LuaEnv` must be built with the same standard libraries,
+    /// and have the same permanents added by [`LuaEnv::add_permanent`].
+    /// On failure, the state is left unchanged.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), RuntimeError> {
+        if !self.coroutines.is_empty() {
+            return Err(error("cannot restore while Lua code is running"));
+        }
+        let mut reader = Reader::new(snapshot);
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(error("not a snapshot"));
+        }
+        if reader.u8()? != VERSION {
+            return Err(error("version mismatch"));
+        }
+        if reader.u8()? != std::mem::size_of::<IntType>() as u8
+            || reader.u8()? != std::mem::size_of::<FloatType>() as u8
+        {
+            return Err(error("number format mismatch"));
+        }
+
+        let len = reader.len()?;
+        let mut objects = Vec::with_capacity(len);
+        // permanents are not followed by their contents
+        let mut with_contents = Vec::new();
+        let placeholder = Rc::new(Prototype {
+            args: 0,
+            is_variadic: false,
+            upvalues: 0,
+            chunk: Chunk::new(),
+        });
+        for id in 0..len {
+            let slot = match reader.u8()? {
+                OBJECT_PERMANENT => {
+                    let name = String::from_utf8_lossy(reader.bytes()?);
+                    match self.permanents.get(name.as_ref()) {
+                        Some(LuaValue::Table(t)) => Slot::Table(Rc::clone(t)),
+                        Some(LuaValue::Function(f)) => Slot::Function(Rc::clone(f)),
+                        Some(LuaValue::UserData(u)) => Slot::UserData(Rc::clone(u)),
+                        Some(LuaValue::Thread(t)) => Slot::Thread(Rc::clone(t)),
+                        _ => return Err(error(&format!("permanent '{}' not found", name))),
+                    }
+                }
+                kind => {
+                    with_contents.push(id);
+                    match kind {
+                        OBJECT_TABLE => Slot::Table(Rc::new(RefCell::new(LuaTable::new()))),
+                        OBJECT_FUNCTION => Slot::Function(Rc::new(RefCell::new(
+                            LuaFunction::LuaFunc(LuaFunctionLua {
+                                upvalues: Vec::new(),
+                                env: None,
+                                prototype: Rc::clone(&placeholder),
+                            }),
+                        ))),
+                        OBJECT_UPVALUE => Slot::Upvalue(Rc::new(RefCell::new(LuaValue::Nil))),
+                        OBJECT_THREAD => Slot::Thread(Rc::new(RefCell::new(LuaThread {
+                            bp: 0,
+                            data_stack: Vec::new(),
+                            call_stack: Vec::new(),
+                            status: ThreadStatus::Dead,
+                            function: None,
+                            boundaries: Vec::new(),
+                            stack_charged: (0, 0),
+                        }))),
+                        _ => return Err(Malformed.into()),
+                    }
+                }
+            };
+            objects.push(slot);
+        }
+        let mut decoder = Decoder {
+            reader,
+            objects,
+            prototypes: Vec::new(),
+        };
+        let globals = decoder.table()?;
+        let string_metatable = decoder.table()?;
+        let mut state = [0; 4];
+        for word in &mut state {
+            *word = decoder.reader.u64()?;
+        }
+        for &id in &with_contents {
+            decoder.contents(id)?;
+        }
+        // frames are checked against the code of their functions, which may be read after the thread
+        for id in with_contents {
+            if let Slot::Thread(thread) = &decoder.objects[id] {
+                check_thread(&thread.borrow())?;
+            }
+        }
+        if !decoder.reader.is_empty() {
+            return Err(Malformed.into());
+        }
+
+        self.env = globals;
+        self.string_metatable = string_metatable;
+        self.rng = Xoshiro256::from_state(state);
+        Ok(())
+    }
+}
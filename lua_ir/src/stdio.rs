@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::LuaEnv;
+use crate::RuntimeError;
+
+/// Input stream shared by `LuaEnv` and `io.stdin`.
+/// The stream is replaced in place, so handles already given to Lua follow [`LuaEnv::set_stdin`].
+pub(crate) type InputStream = Rc<RefCell<Box<dyn BufRead>>>;
+/// Output stream shared by `LuaEnv` and `io.stdout`, `io.stderr`.
+pub(crate) type OutputStream = Rc<RefCell<Box<dyn Write>>>;
+
+/// Standard streams of [`LuaEnv`].
+#[derive(Clone)]
+pub(crate) struct StdStreams {
+    pub(crate) stdin: InputStream,
+    pub(crate) stdout: OutputStream,
+    pub(crate) stderr: OutputStream,
+}
+impl Default for StdStreams {
+    /// streams of the process
+    fn default() -> Self {
+        StdStreams {
+            stdin: Rc::new(RefCell::new(Box::new(std::io::BufReader::new(
+                std::io::stdin(),
+            )))),
+            stdout: Rc::new(RefCell::new(Box::new(std::io::stdout()))),
+            stderr: Rc::new(RefCell::new(Box::new(std::io::stderr()))),
+        }
+    }
+}
+
+impl LuaEnv {
+    /// Redirect standard output; `print`, `io.write` and `io.stdout`.
+    /// Default is stdout of the process.
+    pub fn set_stdout(&mut self, stdout: impl Write + 'static) {
+        *self.streams.stdout.borrow_mut() = Box::new(stdout);
+    }
+    /// Redirect standard error; `warn` and `io.stderr`.
+    /// Default is stderr of the process.
+    pub fn set_stderr(&mut self, stderr: impl Write + 'static) {
+        *self.streams.stderr.borrow_mut() = Box::new(stderr);
+    }
+    /// Redirect standard input; `io.read`, `io.stdin` and `dofile()` without filename.
+    /// Default is stdin of the process.
+    pub fn set_stdin(&mut self, stdin: impl BufRead + 'static) {
+        *self.streams.stdin.borrow_mut() = Box::new(stdin);
+    }
+
+    /// Write `buf` to the standard output.
+    pub(crate) fn write_stdout(&self, buf: &[u8]) -> Result<(), RuntimeError> {
+        write_stream(&self.streams.stdout, buf)
+    }
+    /// Write `buf` to the standard error.
+    pub(crate) fn write_stderr(&self, buf: &[u8]) -> Result<(), RuntimeError> {
+        write_stream(&self.streams.stderr, buf)
+    }
+    /// Read the whole standard input.
+    pub(crate) fn read_stdin(&self) -> Result<Vec<u8>, RuntimeError> {
+        let mut buf = Vec::new();
+        self.streams
+            .stdin
+            .borrow_mut()
+            .read_to_end(&mut buf)
+            .map_err(|e| {
+                RuntimeError::Custom(format!("failed to read from stdin: {}", e).into())
+            })?;
+        Ok(buf)
+    }
+}
+
+fn write_stream(stream: &OutputStream, buf: &[u8]) -> Result<(), RuntimeError> {
+    let mut stream = stream.borrow_mut();
+    stream
+        .write_all(buf)
+        .and_then(|_| stream.flush())
+        .map_err(|e| RuntimeError::Custom(e.to_string().into()))
+}
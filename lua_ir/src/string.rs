@@ -1,6 +1,14 @@
 use crate::{LuaNumber, RuntimeError};
 
+#[cfg(feature = "compact")]
+use std::alloc::Layout;
+#[cfg(feature = "compact")]
+use std::cell::Cell;
+#[cfg(feature = "compact")]
+use std::ptr::NonNull;
+
 /// A Lua string, which can be stored on the heap, stack, or as a static reference, based on its length.
+#[cfg(not(feature = "compact"))]
 #[derive(Clone)]
 pub enum LuaString {
     Heap(Vec<u8>),
@@ -8,6 +16,24 @@ pub enum LuaString {
     Stack([u8; 23], u8),
 }
 
+/// A Lua string, shared by reference counting.
+///
+/// With `compact` feature, a string is a single pointer, so `LuaValue` fits in 16 bytes
+/// and cloning a string never copies its bytes.
+/// The reference count and the length are stored in the same allocation, right before the bytes;
+/// `Rc<[u8]>` would be a pointer and a length, and `Rc<Vec<u8>>` would need a second indirection.
+#[cfg(feature = "compact")]
+pub struct LuaString(NonNull<Header>);
+
+/// Start of the allocation of a compact `LuaString`; the bytes of the string follow it.
+#[cfg(feature = "compact")]
+#[repr(C)]
+struct Header {
+    count: Cell<usize>,
+    len: usize,
+}
+
+#[cfg(not(feature = "compact"))]
 impl LuaString {
     pub fn from_static_str(s: &'static str) -> Self {
         LuaString::Static(s.as_bytes())
@@ -40,9 +66,6 @@ impl LuaString {
             LuaString::Heap(s.to_vec())
         }
     }
-    pub fn from_str(s: &str) -> Self {
-        LuaString::from_string(s.to_string())
-    }
 
     pub fn len(&self) -> usize {
         match self {
@@ -82,6 +105,120 @@ impl LuaString {
         }
     }
 
+    /// true if both strings are known to be equal without comparing their bytes.
+    fn same(&self, _other: &Self) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "compact")]
+impl LuaString {
+    pub fn from_static_str(s: &'static str) -> Self {
+        LuaString::from_slice(s.as_bytes())
+    }
+    pub fn from_static(s: &'static [u8]) -> Self {
+        LuaString::from_slice(s)
+    }
+    pub fn from_vec(v: Vec<u8>) -> Self {
+        LuaString::from_slice(&v)
+    }
+    pub fn from_string(s: String) -> Self {
+        LuaString::from_slice(s.as_bytes())
+    }
+    pub fn from_slice(s: &[u8]) -> Self {
+        let layout = Self::layout(s.len());
+        // SAFETY: `layout` has non-zero size, since it contains the header
+        let ptr = unsafe { std::alloc::alloc(layout) } as *mut Header;
+        let Some(ptr) = NonNull::new(ptr) else {
+            std::alloc::handle_alloc_error(layout);
+        };
+        // SAFETY: the allocation is big enough for the header followed by `s.len()` bytes
+        unsafe {
+            ptr.as_ptr().write(Header {
+                count: Cell::new(1),
+                len: s.len(),
+            });
+            std::ptr::copy_nonoverlapping(s.as_ptr(), ptr.as_ptr().add(1) as *mut u8, s.len());
+        }
+        LuaString(ptr)
+    }
+
+    /// layout of the allocation for a string of `len` bytes
+    fn layout(len: usize) -> Layout {
+        Layout::new::<Header>()
+            .extend(Layout::array::<u8>(len).expect("string too long"))
+            .expect("string too long")
+            .0
+            .pad_to_align()
+    }
+    fn header(&self) -> &Header {
+        // SAFETY: the header is alive as long as any reference to it
+        unsafe { self.0.as_ref() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.header().len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: `len` bytes follow the header, and are not mutated while shared
+        unsafe { std::slice::from_raw_parts(self.0.as_ptr().add(1) as *const u8, self.len()) }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    pub fn into_mapped(self, f: impl Fn(u8) -> u8) -> Self {
+        if self.header().count.get() != 1 {
+            return LuaString::from_vec(self.as_bytes().iter().map(|&c| f(c)).collect());
+        }
+        // no copy if this is the only reference
+        let len = self.len();
+        // SAFETY: this is the only reference, so no one else can see the bytes change
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(self.0.as_ptr().add(1) as *mut u8, len) };
+        for c in bytes {
+            *c = f(*c);
+        }
+        self
+    }
+
+    /// true if both strings are known to be equal without comparing their bytes.
+    fn same(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+#[cfg(feature = "compact")]
+impl Clone for LuaString {
+    fn clone(&self) -> Self {
+        let count = &self.header().count;
+        // leaking a string is safe; wrapping the count to 0 is not
+        if count.get() == usize::MAX {
+            std::process::abort();
+        }
+        count.set(count.get() + 1);
+        LuaString(self.0)
+    }
+}
+#[cfg(feature = "compact")]
+impl Drop for LuaString {
+    fn drop(&mut self) {
+        let count = &self.header().count;
+        count.set(count.get() - 1);
+        if count.get() == 0 {
+            let layout = Self::layout(self.len());
+            // SAFETY: this was the last reference, and the allocation was made with `layout`
+            unsafe { std::alloc::dealloc(self.0.as_ptr() as *mut u8, layout) };
+        }
+    }
+}
+
+impl LuaString {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn try_to_number(&self) -> Result<LuaNumber, RuntimeError> {
         // use `lua_tokenizer` to parse the string into a number
         let mut tokenizer = lua_tokenizer::Tokenizer::from_bytes(self.as_bytes());
@@ -133,6 +270,18 @@ impl LuaString {
     }
 }
 
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString::from_slice(s.as_bytes())
+    }
+}
+impl std::str::FromStr for LuaString {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(LuaString::from(s))
+    }
+}
+
 impl std::hash::Hash for LuaString {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.as_bytes().hash(state);
@@ -140,7 +289,7 @@ impl std::hash::Hash for LuaString {
 }
 impl std::cmp::PartialEq for LuaString {
     fn eq(&self, other: &Self) -> bool {
-        self.as_bytes() == other.as_bytes()
+        self.same(other) || self.as_bytes() == other.as_bytes()
     }
 }
 impl std::cmp::Eq for LuaString {}
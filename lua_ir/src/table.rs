@@ -1,20 +1,26 @@
 use indexmap::IndexMap;
 
+use std::cell::Cell;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
 use std::rc::Rc;
 
+use crate::FloatType;
 use crate::IntType;
 use crate::LuaNumber;
 use crate::LuaString;
 use crate::LuaValue;
 
+/// A Lua table, split into an array part and a hash part like the reference implementation.
+///
+/// Integer key `k` is in the array part if `1 <= k <= arr.len()`, and in the hash part otherwise.
+/// Nil in either part means the key is not in the table; keys removed from the hash part stay there as nil
+/// so that `next` can continue from them, until the hash part is rehashed.
 #[derive(Debug, Clone)]
 pub struct LuaTable {
-    /// every key except number goes here
+    /// every key not in the array part goes here
     pub(crate) map: IndexMap<LuaValue, LuaValue>,
-    /// every key with number goes here
-    pub(crate) arr: BTreeMap<IntType, LuaValue>,
+    /// values of integer keys `1..=arr.len()`
+    pub(crate) arr: Vec<LuaValue>,
 
     /// metatable
     pub(crate) meta: Option<Rc<RefCell<LuaTable>>>,
@@ -23,22 +29,33 @@ impl LuaTable {
     pub fn with_capacity(capacity: usize) -> Self {
         LuaTable {
             map: IndexMap::with_capacity(capacity),
-            arr: BTreeMap::new(),
+            arr: Vec::new(),
             meta: None,
         }
     }
     pub fn new() -> Self {
         LuaTable {
             map: IndexMap::new(),
-            arr: BTreeMap::new(),
+            arr: Vec::new(),
             meta: None,
         }
     }
+    /// get metatable of this table.
+    pub fn metatable(&self) -> Option<&Rc<RefCell<LuaTable>>> {
+        self.meta.as_ref()
+    }
+    /// set metatable of this table, `None` to remove it.
+    /// Returns the old metatable.
+    pub fn set_metatable(
+        &mut self,
+        meta: Option<Rc<RefCell<LuaTable>>>,
+    ) -> Option<Rc<RefCell<LuaTable>>> {
+        std::mem::replace(&mut self.meta, meta)
+    }
     pub fn get_metavalue(&self, key: &'static str) -> Option<LuaValue> {
         if let Some(meta) = &self.meta {
             meta.borrow()
-                .map
-                .get(&LuaValue::String(LuaString::from_static_str(key)))
+                .get_table(&LuaValue::String(LuaString::from_static_str(key)))
                 .cloned()
         } else {
             None
@@ -47,71 +64,352 @@ impl LuaTable {
     /// get value from table.
     /// key can be any lua value.
     pub fn get(&self, key: &LuaValue) -> Option<&LuaValue> {
-        match key {
-            LuaValue::Number(LuaNumber::Int(n)) => self.arr.get(n),
-            _ => self.map.get(key),
+        match int_key(key) {
+            Some(n) => self.get_arr(n),
+            None => self.get_table(key),
         }
     }
 
     /// get value from table.
     /// key can be any lua value.
     pub fn get_mut(&mut self, key: &LuaValue) -> Option<&mut LuaValue> {
-        match key {
-            LuaValue::Number(LuaNumber::Int(n)) => self.arr.get_mut(n),
-            _ => self.map.get_mut(key),
+        match int_key(key) {
+            Some(n) => self.get_arr_mut(n),
+            None => self.get_table_mut(key),
         }
     }
 
-    /// get value from array part of table.
+    /// index of string key `key` in the hash part.
+    /// The index cached in `cache` is checked first, and the key is hashed only if it is not there.
+    fn field_index(&self, key: &LuaString, cache: &FieldCache) -> Option<usize> {
+        let index = cache.0.get();
+        if let Some((LuaValue::String(cached), _)) = self.map.get_index(index) {
+            if cached == key {
+                return Some(index);
+            }
+        }
+        let index = self.map.get_index_of(&LuaValue::String(key.clone()))?;
+        cache.0.set(index);
+        Some(index)
+    }
+    /// get value of string key `key`, looked up through `cache`.
+    pub(crate) fn get_field(&self, key: &LuaString, cache: &FieldCache) -> Option<&LuaValue> {
+        let index = self.field_index(key, cache)?;
+        self.map
+            .get_index(index)
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_nil())
+    }
+    /// get value of string key `key`, looked up through `cache`.
+    pub(crate) fn get_field_mut(
+        &mut self,
+        key: &LuaString,
+        cache: &FieldCache,
+    ) -> Option<&mut LuaValue> {
+        let index = self.field_index(key, cache)?;
+        self.map
+            .get_index_mut(index)
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_nil())
+    }
+    /// insert new string key `key`, and cache its index in `cache`.
+    pub(crate) fn insert_field(&mut self, key: LuaString, value: LuaValue, cache: &FieldCache) {
+        let key = LuaValue::String(key);
+        self.reserve_table(&key);
+        let (index, _) = self.map.insert_full(key, value);
+        cache.0.set(index);
+    }
+
+    /// index in the array part of integer key `key`.
+    fn arr_index(&self, key: IntType) -> Option<usize> {
+        let index = (key as usize).wrapping_sub(1);
+        if key > 0 && index < self.arr.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+    /// get value of integer key.
     pub fn get_arr(&self, key: IntType) -> Option<&LuaValue> {
-        self.arr.get(&key)
+        match self.arr_index(key) {
+            Some(index) => Some(&self.arr[index]).filter(|value| !value.is_nil()),
+            None => self.get_table(&LuaValue::Number(LuaNumber::Int(key))),
+        }
     }
-    /// get value from array part of table.
+    /// get value of integer key.
     pub fn get_arr_mut(&mut self, key: IntType) -> Option<&mut LuaValue> {
-        self.arr.get_mut(&key)
+        match self.arr_index(key) {
+            Some(index) => Some(&mut self.arr[index]).filter(|value| !value.is_nil()),
+            None => self.get_table_mut(&LuaValue::Number(LuaNumber::Int(key))),
+        }
     }
 
     /// get value from hash part of table.
+    /// `nil` is never a key; it is not looked up, since it cannot be hashed.
     pub fn get_table(&self, key: &LuaValue) -> Option<&LuaValue> {
-        self.map.get(key)
+        if key.is_nil() {
+            return None;
+        }
+        self.map.get(key).filter(|value| !value.is_nil())
     }
     /// get value from hash part of table.
     pub fn get_table_mut(&mut self, key: &LuaValue) -> Option<&mut LuaValue> {
-        self.map.get_mut(key)
+        if key.is_nil() {
+            return None;
+        }
+        self.map.get_mut(key).filter(|value| !value.is_nil())
     }
 
     /// remove value from table
     pub fn remove(&mut self, key: &LuaValue) -> Option<LuaValue> {
-        match key {
-            LuaValue::Number(LuaNumber::Int(n)) => self.arr.remove(n),
-            _ => self.map.swap_remove(key),
-        }
+        Some(std::mem::take(self.get_mut(key)?))
     }
 
-    /// insert value to table.
+    /// insert value to table; nil value removes the key.
     /// key can be any lua value.
     pub fn insert(&mut self, key: LuaValue, value: LuaValue) -> Option<LuaValue> {
-        match key {
-            LuaValue::Nil => None,
-            LuaValue::Number(LuaNumber::Int(n)) => self.arr.insert(n, value),
-            _ => self.map.insert(key, value),
+        match (int_key(&key), key) {
+            (_, LuaValue::Nil) => None,
+            (Some(n), _) => self.insert_arr(n, value),
+            (None, key) => self.insert_table(key, value),
         }
     }
-    /// insert value to array part of table.
+    /// insert value of integer key; nil value removes the key.
     pub fn insert_arr(&mut self, key: IntType, value: LuaValue) -> Option<LuaValue> {
-        self.arr.insert(key, value)
+        if let Some(index) = self.arr_index(key) {
+            let old = std::mem::replace(&mut self.arr[index], value);
+            return Some(old).filter(|old| !old.is_nil());
+        }
+        if value.is_nil() {
+            return self.remove(&LuaValue::Number(LuaNumber::Int(key)));
+        }
+        if key > 0 && key as usize == self.arr.len() + 1 {
+            self.push_arr(value);
+            return None;
+        }
+        let map_key = LuaValue::Number(LuaNumber::Int(key));
+        if self.reserve_table(&map_key) {
+            // array part might have grown to contain the key
+            if let Some(index) = self.arr_index(key) {
+                self.arr[index] = value;
+                return None;
+            }
+            if key > 0 && key as usize == self.arr.len() + 1 {
+                self.push_arr(value);
+                return None;
+            }
+        }
+        self.map.insert(map_key, value).filter(|old| !old.is_nil())
     }
-    /// insert value to hash part of table.
+    /// insert value to hash part of table; nil value removes the key.
     pub fn insert_table(&mut self, key: LuaValue, value: LuaValue) -> Option<LuaValue> {
         match key {
             LuaValue::Nil => None,
             LuaValue::Number(LuaNumber::Int(_)) => panic!("insert_table with integer key"),
-            _ => self.map.insert(key, value),
+            _ if value.is_nil() => self.remove(&key),
+            _ => {
+                self.reserve_table(&key);
+                self.map.insert(key, value).filter(|old| !old.is_nil())
+            }
+        }
+    }
+    /// insert value of the key in a table constructor.
+    /// Positional values are kept in the array part even if they are nil, like reference Lua does.
+    pub(crate) fn insert_init(&mut self, key: LuaValue, value: LuaValue) {
+        match int_key(&key) {
+            Some(n) if n > 0 && n as usize == self.arr.len() + 1 => self.push_arr(value),
+            _ => {
+                self.insert(key, value);
+            }
         }
     }
 
-    /// get length of array part of table.
+    /// append value to the array part,
+    /// and move the keys following it from the hash part.
+    pub(crate) fn push_arr(&mut self, value: LuaValue) {
+        self.arr.push(value);
+        while !self.map.is_empty() {
+            let next = LuaValue::Number(LuaNumber::Int(self.arr.len() as IntType + 1));
+            match self.map.swap_remove(&next) {
+                Some(value) => self.arr.push(value),
+                None => break,
+            }
+        }
+    }
+    /// make room for inserting `key` to the hash part.
+    /// If the hash part is full, both parts are resized like reference Lua does,
+    /// and `true` is returned; `key` might have been moved into the array part.
+    fn reserve_table(&mut self, key: &LuaValue) -> bool {
+        if self.map.len() < self.map.capacity() || self.map.contains_key(key) {
+            return false;
+        }
+        self.rehash(int_key(key));
+        true
+    }
+    /// drop removed keys from the hash part,
+    /// and resize the array part to the largest `n` such that more than half of the keys `1..=n` are in the table.
+    /// `extra` is the integer key about to be inserted.
+    fn rehash(&mut self, extra: Option<IntType>) {
+        self.map.retain(|_, value| !value.is_nil());
+
+        // nums[i]: number of keys `k` with 2^(i-1) < k <= 2^i
+        let mut nums = [0usize; IntType::BITS as usize + 1];
+        let mut count = |key: IntType| {
+            if key > 0 {
+                nums[(IntType::BITS - (key - 1).leading_zeros()) as usize] += 1;
+            }
+        };
+        for (index, value) in self.arr.iter().enumerate() {
+            if !value.is_nil() {
+                count(index as IntType + 1);
+            }
+        }
+        for key in self.map.keys() {
+            if let LuaValue::Number(LuaNumber::Int(key)) = key {
+                count(*key);
+            }
+        }
+        if let Some(key) = extra {
+            count(key);
+        }
+
+        let total: usize = nums.iter().sum();
+        let mut size = 0;
+        let mut keys = 0;
+        for (i, &num) in nums.iter().enumerate() {
+            let two_to_i = 1usize << i;
+            if two_to_i / 2 >= total {
+                break;
+            }
+            keys += num;
+            if keys > two_to_i / 2 {
+                size = two_to_i;
+            }
+        }
+        self.resize_arr(size);
+    }
+    /// resize the array part to `size`, moving keys between the parts.
+    fn resize_arr(&mut self, size: usize) {
+        let old_size = self.arr.len();
+        if size > old_size {
+            self.arr.resize(size, LuaValue::Nil);
+            let moved: Vec<usize> = self
+                .map
+                .keys()
+                .filter_map(|key| int_key(key).and_then(|key| self.arr_index(key)))
+                .collect();
+            for index in moved {
+                let key = LuaValue::Number(LuaNumber::Int(index as IntType + 1));
+                self.arr[index] = self.map.swap_remove(&key).unwrap();
+            }
+        } else if size < old_size {
+            for (index, value) in self.arr.split_off(size).into_iter().enumerate() {
+                if !value.is_nil() {
+                    let key = LuaValue::Number(LuaNumber::Int((size + index) as IntType + 1));
+                    self.map.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// key and value following `key` in the traversal of `next`; the array part first, then the hash part.
+    /// `None` if `key` is not in the table, `Some(None)` if `key` is the last one.
+    pub fn next(&self, key: &LuaValue) -> Option<Option<(LuaValue, LuaValue)>> {
+        // position in the traversal where the search starts
+        let start = match key {
+            LuaValue::Nil => 0,
+            key => match int_key(key).and_then(|key| self.arr_index(key)) {
+                Some(index) => index + 1,
+                None => self.arr.len() + self.map.get_index_of(key)? + 1,
+            },
+        };
+        if let Some(tail) = self.arr.get(start..) {
+            for (index, value) in tail.iter().enumerate() {
+                if !value.is_nil() {
+                    let key = (start + index) as IntType + 1;
+                    return Some(Some((key.into(), value.clone())));
+                }
+            }
+        }
+        let start = start.saturating_sub(self.arr.len());
+        let next = (start..self.map.len())
+            .filter_map(|index| self.map.get_index(index))
+            .find(|(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.clone(), value.clone()));
+        Some(next)
+    }
+
+    /// get the border of the table; `n` where `t[n]` is not nil and `t[n + 1]` is nil, or 0 if `t[1]` is nil.
     pub fn len(&self) -> IntType {
-        self.arr.last_key_value().map_or(0, |(k, _)| *k).max(0)
+        let size = self.arr.len();
+        if size > 0 && self.arr[size - 1].is_nil() {
+            // binary search in the array part; `t[lo]` is not nil (or `lo` is 0), `t[hi]` is nil
+            let (mut lo, mut hi) = (0, size);
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                if self.arr[mid - 1].is_nil() {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            return lo as IntType;
+        }
+        // the array part is full; continue in the hash part
+        let mut lo = size as IntType;
+        if self.map.is_empty() || self.get_arr(lo + 1).is_none() {
+            return lo;
+        }
+        // find `hi` with nil by doubling, then binary search between them
+        let mut hi = lo + 1;
+        while self.get_arr(hi).is_some() {
+            lo = hi;
+            if hi > IntType::MAX / 2 {
+                // table built to defeat the search; resort to linear search
+                let mut n = 1;
+                while self.get_arr(n).is_some() {
+                    n += 1;
+                }
+                return n - 1;
+            }
+            hi *= 2;
+        }
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if self.get_arr(mid).is_some() {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+/// integer value of key, for integers and floats with exact integer value.
+fn int_key(key: &LuaValue) -> Option<IntType> {
+    match key {
+        LuaValue::Number(LuaNumber::Int(n)) => Some(*n),
+        LuaValue::Number(LuaNumber::Float(f))
+            if f.fract() == 0.0
+                && *f >= IntType::MIN as FloatType
+                && *f < -(IntType::MIN as FloatType) =>
+        {
+            Some(*f as IntType)
+        }
+        _ => None,
+    }
+}
+
+/// Inline cache of an instruction accessing a field by a constant string key;
+/// the index in the hash part of the table where the key was found last time.
+///
+/// Tables with the same keys inserted in the same order, like objects made by the same constructor,
+/// have the key at the same index.
+#[derive(Debug, Clone)]
+pub(crate) struct FieldCache(Cell<usize>);
+impl Default for FieldCache {
+    fn default() -> Self {
+        FieldCache(Cell::new(usize::MAX))
     }
 }
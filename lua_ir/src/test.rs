@@ -0,0 +1,2248 @@
+use crate::IntType;
+use crate::LuaEnv;
+use crate::LuaValue;
+
+#[test]
+fn compile_run_returns_values() {
+    let mut env = LuaEnv::new();
+    let chunk = env
+        .compile(b"local a, b = ... return a + b, 'done'", "add")
+        .unwrap();
+    assert_eq!(chunk.name(), "add");
+
+    let ret = env
+        .run(
+            &chunk,
+            vec![LuaValue::from(1 as IntType), LuaValue::from(2 as IntType)],
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![LuaValue::from(3 as IntType), LuaValue::from("done")]
+    );
+
+    // run the same chunk again
+    let ret = env
+        .run(
+            &chunk,
+            vec![LuaValue::from(10 as IntType), LuaValue::from(20 as IntType)],
+        )
+        .unwrap();
+    assert_eq!(ret[0], LuaValue::from(30 as IntType));
+}
+
+#[test]
+fn compiled_chunk_shared_across_envs() {
+    let chunk = LuaEnv::new()
+        .compile(b"counter = (counter or 0) + 1 return counter", "counter")
+        .unwrap();
+
+    let mut env1 = LuaEnv::new();
+    let mut env2 = LuaEnv::new();
+    env1.run(&chunk, Vec::new()).unwrap();
+    let ret1 = env1.run(&chunk, Vec::new()).unwrap();
+    let ret2 = env2.run(&chunk, Vec::new()).unwrap();
+    assert_eq!(ret1, vec![LuaValue::from(2 as IntType)]);
+    assert_eq!(ret2, vec![LuaValue::from(1 as IntType)]);
+}
+
+#[test]
+fn compiled_chunk_with_coroutine() {
+    let mut env = LuaEnv::new();
+    let chunk = env
+        .compile(
+            b"
+            local co = coroutine.create(function(x)
+                local y = coroutine.yield(x + 1)
+                return y * 2
+            end)
+            local _, a = coroutine.resume(co, 1)
+            local _, b = coroutine.resume(co, 5)
+            return a, b
+            ",
+            "coroutine",
+        )
+        .unwrap();
+    let ret = env.run(&chunk, Vec::new()).unwrap();
+    assert_eq!(
+        ret,
+        vec![LuaValue::from(2 as IntType), LuaValue::from(10 as IntType)]
+    );
+}
+
+#[test]
+fn run_error_keeps_env_usable() {
+    let mut env = LuaEnv::new();
+    let bad = env.compile(b"error('boom')", "bad").unwrap();
+    assert!(env.run(&bad, Vec::new()).is_err());
+
+    let good = env.compile(b"return 1", "good").unwrap();
+    assert_eq!(
+        env.run(&good, Vec::new()).unwrap(),
+        vec![LuaValue::from(1 as IntType)]
+    );
+}
+
+#[test]
+fn compile_error() {
+    let env = LuaEnv::new();
+    assert!(env.compile(b"local = 1", "invalid").is_err());
+}
+
+#[test]
+fn eval_chunk_with_custom_env() {
+    use crate::LuaTable;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut env = LuaEnv::new();
+
+    // shared stdlib as fallback of each tenant's globals
+    let mut fallback = LuaTable::new();
+    fallback.insert("__index".into(), LuaValue::Table(env.globals()));
+    let fallback = Rc::new(RefCell::new(fallback));
+
+    let mut tenant1 = LuaTable::new();
+    tenant1.set_metatable(Some(Rc::clone(&fallback)));
+    let tenant1 = Rc::new(RefCell::new(tenant1));
+    let mut tenant2 = LuaTable::new();
+    tenant2.set_metatable(Some(Rc::clone(&fallback)));
+    let tenant2 = Rc::new(RefCell::new(tenant2));
+
+    env.eval_chunk_with_env(
+        b"name = 'one'; function get_name() return name end",
+        Rc::clone(&tenant1),
+    )
+    .unwrap();
+    env.eval_chunk_with_env(b"name = 'two'", Rc::clone(&tenant2))
+        .unwrap();
+
+    // globals of tenants are separated, and the global table is untouched
+    assert_eq!(tenant1.borrow().get(&"name".into()), Some(&"one".into()));
+    assert_eq!(tenant2.borrow().get(&"name".into()), Some(&"two".into()));
+    assert_eq!(env.get_global("name"), LuaValue::Nil);
+
+    // functions keep `_ENV` of the chunk they were defined in
+    let get_name = tenant1.borrow().get(&"get_name".into()).unwrap().clone();
+    env.set_global("get_name", get_name);
+    let chunk = env
+        .compile(b"return get_name(), string.upper(name or 'none')", "call")
+        .unwrap();
+    assert_eq!(
+        env.run(&chunk, Vec::new()).unwrap(),
+        vec![LuaValue::from("one"), LuaValue::from("NONE")]
+    );
+    assert_eq!(
+        env.run_with_env(&chunk, Rc::clone(&tenant2), Vec::new())
+            .unwrap(),
+        vec![LuaValue::from("one"), LuaValue::from("TWO")]
+    );
+}
+
+#[test]
+fn eval_expressions_and_chunk() {
+    let mut env = LuaEnv::new();
+    assert_eq!(
+        env.eval(b"1 + 2, 'a' .. 'b'").unwrap(),
+        vec![LuaValue::from(3 as IntType), LuaValue::from("ab")]
+    );
+    assert_eq!(
+        env.eval(b"local x = 10 return x * 2").unwrap(),
+        vec![LuaValue::from(20 as IntType)]
+    );
+    assert_eq!(env.eval(b"x = 1").unwrap(), vec![]);
+    assert_eq!(
+        env.eval(b"math.max(x, 5)").unwrap(),
+        vec![LuaValue::from(5 as IntType)]
+    );
+}
+
+#[test]
+fn eval_as_typed() {
+    let mut env = LuaEnv::new();
+    assert_eq!(env.eval_as::<IntType>(b"2 ^ 10").unwrap(), 1024);
+    assert_eq!(env.eval_as::<crate::FloatType>(b"1 / 4").unwrap(), 0.25);
+    assert_eq!(
+        env.eval_as::<String>(b"string.rep('ab', 2)").unwrap(),
+        "abab"
+    );
+    assert!(env.eval_as::<bool>(b"1 < 2").unwrap());
+    assert_eq!(env.eval_as::<Option<IntType>>(b"nil").unwrap(), None);
+    assert_eq!(env.eval_as::<Option<IntType>>(b"").unwrap(), None);
+    assert!(env.eval_as::<IntType>(b"{}").is_err());
+    assert!(env.eval_as::<IntType>(b"1.5").is_err());
+}
+
+#[test]
+fn pcall_results_and_errors() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local ok1, a, b = pcall(function(x, y) return x + y, x * y end, 3, 4)
+            local ok2, err = pcall(error, 'boom')
+            local ok3 = pcall(function() local t = nil; return t.x end)
+            local ok4, inner_ok, inner_err = pcall(pcall, error, 'nested')
+            return ok1, a, b, ok2, err, ok3, ok4, inner_ok, inner_err
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            true.into(),
+            LuaValue::from(7 as IntType),
+            LuaValue::from(12 as IntType),
+            false.into(),
+            "boom".into(),
+            false.into(),
+            true.into(),
+            false.into(),
+            "nested".into(),
+        ]
+    );
+}
+
+#[test]
+fn yield_inside_pcall() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local co = coroutine.create(function(a)
+                local ok, v = pcall(function()
+                    local x = coroutine.yield(a + 1)
+                    return x * 2
+                end)
+                local ok2, err = pcall(function()
+                    coroutine.yield('again')
+                    error('after yield')
+                end)
+                return ok, v, ok2, err
+            end)
+            local _, y1 = coroutine.resume(co, 1)
+            local _, y2 = coroutine.resume(co, 21)
+            local _, ok, v, ok2, err = coroutine.resume(co)
+            return y1, y2, ok, v, ok2, err, coroutine.status(co)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(2 as IntType),
+            "again".into(),
+            true.into(),
+            LuaValue::from(42 as IntType),
+            false.into(),
+            "after yield".into(),
+            "dead".into(),
+        ]
+    );
+}
+
+#[test]
+fn yield_inside_sort_comparator() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local t = { 5, 3, 8, 1, 9, 2, 7 }
+            local compared = { count = 0 }
+            local co = coroutine.wrap(function()
+                table.sort(t, function(a, b)
+                    coroutine.yield()
+                    compared.count = compared.count + 1
+                    return a > b
+                end)
+                return 'done'
+            end)
+            local res = co()
+            while res ~= 'done' do res = co() end
+            return table.concat(t, ','), compared.count > 0
+            ",
+        )
+        .unwrap();
+    assert_eq!(ret, vec!["9,8,7,5,3,2,1".into(), true.into()]);
+
+    let ret = env
+        .eval(b"local t = { 'b', 'c', 'a' } table.sort(t) return table.concat(t)")
+        .unwrap();
+    assert_eq!(ret, vec!["abc".into()]);
+}
+
+#[test]
+fn yield_inside_metamethods() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local mt = {
+                __index = function(t, k) return coroutine.yield(k) end,
+                __add = function(a, b) return coroutine.yield('add') end,
+                __lt = function(a, b) return coroutine.yield('lt') end,
+            }
+            local obj = setmetatable({}, mt)
+            local co = coroutine.wrap(function()
+                local v = obj.key
+                local s = obj + 1
+                local l = obj < obj
+                return v, s, l
+            end)
+            local k = co()
+            local add = co('value')
+            local lt = co(10)
+            local v, s, l = co(true)
+            return k, add, lt, v, s, l
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "key".into(),
+            "add".into(),
+            "lt".into(),
+            "value".into(),
+            LuaValue::from(10 as IntType),
+            true.into(),
+        ]
+    );
+}
+
+#[test]
+fn yield_across_nested_call_fails() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local obj = setmetatable({}, {
+                __tostring = function() coroutine.yield() return 'obj' end,
+            })
+            local co = coroutine.create(function() return tostring(obj) end)
+            return coroutine.resume(co)
+            ",
+        )
+        .unwrap();
+    assert_eq!(ret[0], false.into());
+    assert_eq!(ret[1], "attempt to yield across a C-call boundary".into());
+}
+
+#[test]
+fn host_function_continuation() {
+    use crate::LuaFunction;
+
+    let mut env = LuaEnv::new();
+    // calls given function with given argument, and adds 1 to its result
+    env.set_global(
+        "call_add1",
+        LuaFunction::from_func_with_expected(|env, args, expected| {
+            assert_eq!(args, 2);
+            let arg = env.pop();
+            let func = env.pop();
+            env.push(arg);
+            env.call_k(1, func, Some(1), move |env, res| {
+                assert_eq!(res.unwrap(), 1);
+                let value = env.pop().try_to_int()?;
+                env.push((value + 1).into());
+                if expected == Some(0) {
+                    env.pop();
+                }
+                Ok(())
+            })
+        })
+        .into(),
+    );
+    // yields given value, and returns value passed to resume as string
+    env.set_global(
+        "yield_tostring",
+        LuaFunction::from_func_with_expected(|env, args, _expected| {
+            assert_eq!(args, 1);
+            env.yield_k(1, |env, res| {
+                assert_eq!(res.unwrap(), 1);
+                let value = env.pop();
+                env.push(value.to_string().into());
+                Ok(())
+            })
+        })
+        .into(),
+    );
+    let ret = env
+        .eval(
+            b"
+            local co = coroutine.wrap(function(x)
+                local a = call_add1(function(v) return coroutine.yield(v) end, x)
+                local b = yield_tostring(a)
+                return a, b
+            end)
+            local y1 = co(10)
+            local y2 = co(100)
+            local a, b = co(12.5)
+            return y1, y2, a, b, call_add1(function(v) return v * 2 end, 4)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(10 as IntType),
+            LuaValue::from(101 as IntType),
+            LuaValue::from(101 as IntType),
+            "12.5".into(),
+            LuaValue::from(9 as IntType),
+        ]
+    );
+}
+
+#[test]
+fn instruction_limit() {
+    use crate::Limit;
+    use crate::Limits;
+    use crate::RuntimeError;
+
+    let mut env = LuaEnv::new();
+    env.set_limits(Limits {
+        instructions: Some(10_000),
+        ..Default::default()
+    });
+    // uncatchable limit unwinds pcall and coroutines
+    let res = env.eval(
+        b"
+        pcall(coroutine.wrap(function() while true do end end))
+        return 'caught'
+        ",
+    );
+    assert!(matches!(
+        res,
+        Err(RuntimeError::LimitExceeded(Limit::Instructions, false))
+    ));
+    assert!(env.instructions_executed() > 10_000);
+
+    env.set_limits(Limits {
+        instructions: Some(10_000),
+        instructions_catchable: true,
+        ..Default::default()
+    });
+    let ret = env
+        .eval(b"return pcall(function() while true do end end)")
+        .unwrap();
+    assert_eq!(ret, vec![false.into(), "instruction limit exceeded".into()]);
+
+    // limit stays armed after the catchable error; the handler gets a small extra budget
+    for source in [
+        &b"pcall(function() while true do end end) while true do end"[..],
+        b"while true do pcall(function() while true do end end) end",
+    ] {
+        env.set_limits(Limits {
+            instructions: Some(10_000),
+            instructions_catchable: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            env.eval(source),
+            Err(RuntimeError::LimitExceeded(Limit::Instructions, false))
+        ));
+        assert!(env.instructions_executed() <= 10_000 + crate::HANDLER_INSTRUCTIONS + 1024);
+    }
+}
+
+#[test]
+fn memory_limit() {
+    use crate::Limit;
+    use crate::Limits;
+    use crate::RuntimeError;
+
+    let mut env = LuaEnv::new();
+    let catchable = Limits {
+        allocation: Some(1 << 20),
+        allocation_catchable: true,
+        ..Default::default()
+    };
+    let sources: [&[u8]; 3] = [
+        b"return pcall(string.rep, 'x', 1 << 30)",
+        b"
+        return pcall(function()
+            local s = ''
+            for i = 1, 1000000 do s = s .. 'xxxxxxxxxx' end
+        end)
+        ",
+        b"
+        return pcall(function()
+            local t = {}
+            for i = 1, 1000000 do t[i] = i end
+        end)
+        ",
+    ];
+    for source in sources {
+        env.set_limits(catchable.clone());
+        let ret = env.eval(source).unwrap();
+        assert_eq!(ret, vec![false.into(), "not enough memory".into()]);
+    }
+    // strings made by library functions, functions, threads and stacks are charged too
+    let sources = [
+        "local s = string.rep('x', 100000) for i = 1, 3000 do local u = s:upper() end",
+        "local s = string.rep('x', 100000) for i = 1, 3000 do local u = s:lower() end",
+        "local s = string.rep('x', 100000) for i = 1, 3000 do local u = s:reverse() end",
+        "local s = string.rep('x', 100000) for i = 1, 3000 do local u = s:sub(2) end",
+        "for i = 1, 1000000 do local f = function() return i end end",
+        "for i = 1, 100000 do coroutine.create(print) end",
+        "local function f(n) if n > 0 then return 1 + f(n - 1) end return 0 end f(100000)",
+    ];
+    for source in sources {
+        env.set_limits(catchable.clone());
+        let ret = env
+            .eval(format!("return pcall(function() {} end)", source).as_bytes())
+            .unwrap();
+        assert_eq!(
+            ret,
+            vec![false.into(), "not enough memory".into()],
+            "{}",
+            source
+        );
+    }
+    // limit stays armed after raising catchable error
+    env.set_limits(catchable);
+    let res = env.eval(
+        b"
+        pcall(string.rep, 'x', 1 << 30)
+        local t = {}
+        for i = 1, 1000000 do t[i] = { i } end
+        ",
+    );
+    assert!(matches!(
+        res,
+        Err(RuntimeError::LimitExceeded(Limit::Allocation, false))
+    ));
+
+    env.set_limits(Limits {
+        allocation: Some(1 << 20),
+        ..Default::default()
+    });
+    let res = env.eval(b"local t = {} for i = 1, 1000000 do t[i] = { i } end");
+    assert!(matches!(
+        res,
+        Err(RuntimeError::LimitExceeded(Limit::Allocation, false))
+    ));
+    assert!(env.memory_allocated() > 1 << 19);
+}
+
+#[test]
+fn deadline_and_interrupt() {
+    use crate::Limit;
+    use crate::Limits;
+    use crate::RuntimeError;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    let mut env = LuaEnv::new();
+    env.set_limits(Limits {
+        deadline: Some(Instant::now() + Duration::from_millis(50)),
+        ..Default::default()
+    });
+    assert!(matches!(
+        env.eval(b"while true do end"),
+        Err(RuntimeError::LimitExceeded(Limit::Deadline, false))
+    ));
+
+    env.set_limits(Limits {
+        deadline: Some(Instant::now() + Duration::from_millis(50)),
+        deadline_catchable: true,
+        ..Default::default()
+    });
+    let ret = env
+        .eval(b"return pcall(function() while true do end end)")
+        .unwrap();
+    assert_eq!(ret, vec![false.into(), "deadline exceeded".into()]);
+    assert!(matches!(
+        env.eval(b"while true do end"),
+        Err(RuntimeError::LimitExceeded(Limit::Deadline, false))
+    ));
+
+    env.set_limits(Limits {
+        interrupt_catchable: true,
+        ..Default::default()
+    });
+    let handle = env.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let ret = env
+        .eval(b"return pcall(function() while true do end end)")
+        .unwrap();
+    interrupter.join().unwrap();
+    assert_eq!(ret, vec![false.into(), "interrupted".into()]);
+
+    // interrupt request is consumed
+    assert_eq!(
+        env.eval(b"return 1 + 1").unwrap(),
+        vec![LuaValue::from(2 as IntType)]
+    );
+}
+
+#[test]
+fn builder_stdlib_selection() {
+    use crate::StdLib;
+
+    let mut env = LuaEnv::builder().safe().build();
+    assert!(!env.binary_chunks_allowed());
+    let ret = env
+        .eval(
+            b"
+            type(dofile), type(loadfile), type(io), type(os),
+            type(load), type(string), type(coroutine), _G.string == string, ('ab'):rep(2)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "nil".into(),
+            "nil".into(),
+            "nil".into(),
+            "nil".into(),
+            "function".into(),
+            "table".into(),
+            "table".into(),
+            true.into(),
+            "abab".into(),
+        ]
+    );
+
+    let mut env = LuaEnv::builder()
+        .stdlib(StdLib::MATH | StdLib::TABLE)
+        .build();
+    assert!(env.binary_chunks_allowed());
+    assert_eq!(env.get_global("print"), LuaValue::Nil);
+    assert_eq!(env.get_global("_G"), LuaValue::Nil);
+    assert_eq!(
+        env.eval(b"math.max(1, 2), table.unpack({ 3 })").unwrap(),
+        vec![LuaValue::from(2 as IntType), LuaValue::from(3 as IntType)]
+    );
+    assert!(env.eval(b"('a'):rep(2)").is_err());
+}
+
+#[test]
+fn memory_file_system() {
+    use crate::MemoryFileSystem;
+
+    let fs = MemoryFileSystem::new();
+    fs.insert("lib/util.lua", "return ... or 'none', 42");
+    fs.insert("data.txt", "first line\n12 0x10 rest\nlast");
+    let mut env = LuaEnv::builder().file_system(fs.clone()).build();
+
+    let ret = env
+        .eval(
+            b"
+            local a, b = dofile('lib/util.lua')
+            local f = loadfile('./lib/../lib/util.lua')
+            local c = f('arg')
+            local g, err = loadfile('missing.lua')
+            return a, b, c, g, err
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "none".into(),
+            LuaValue::from(42 as IntType),
+            "arg".into(),
+            LuaValue::Nil,
+            "missing.lua: No such file or directory".into(),
+        ]
+    );
+
+    let ret = env
+        .eval(
+            b"
+            local f = io.open('data.txt')
+            local line, n, m = f:read('l', 'n', 'n')
+            local rest, last, eof = f:read('L', 'a', 'l')
+            f:close()
+            return line, n, m, rest, last, eof, io.type(f), io.type(1)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "first line".into(),
+            LuaValue::from(12 as IntType),
+            LuaValue::from(16 as IntType),
+            " rest\n".into(),
+            "last".into(),
+            LuaValue::Nil,
+            "closed file".into(),
+            LuaValue::Nil,
+        ]
+    );
+
+    env.eval(
+        b"
+        local f = io.open('out/log.txt', 'w')
+        f:write('a', 1, '\\n'):write('b\\n')
+        f:close()
+        f = io.open('out/log.txt', 'a+')
+        f:write('c\\n')
+        f:seek('set')
+        local lines = {}
+        for line in f:lines() do lines[#lines + 1] = line end
+        f:close()
+        for line in io.lines('out/log.txt', 'L') do lines[#lines + 1] = line end
+        assert(table.concat(lines, ',') == 'a1,b,c,a1\\n,b\\n,c\\n')
+        assert(os.rename('out/log.txt', 'log.txt') == true)
+        assert(os.remove('log.txt') == true)
+        local ok, err = os.remove('log.txt')
+        assert(ok == nil and err == 'log.txt: No such file or directory')
+        assert(io.open('log.txt') == nil)
+        assert(not pcall(io.open, 'data.txt', 'rw'))
+
+        -- the gap before a far offset is never allocated
+        f = io.open('big.bin', 'w')
+        assert(f:seek('set', (1 << 30) + 1) == nil)
+        assert(f:seek('set', 1 << 30) == 1 << 30)
+        local ok, err = f:write('x')
+        assert(ok == nil and err == 'File too large')
+        f:close()
+        assert(os.remove('big.bin') == true)
+        ",
+    )
+    .unwrap();
+    assert_eq!(fs.get("out/log.txt"), None);
+    assert_eq!(fs.get("log.txt"), None);
+}
+
+/// `Write` appending to a shared buffer, to capture output of `LuaEnv`
+#[derive(Clone, Default)]
+struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl SharedBuffer {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.borrow_mut())).unwrap()
+    }
+}
+
+#[test]
+fn redirect_std_streams() {
+    let stdout = SharedBuffer::default();
+    let stderr = SharedBuffer::default();
+    let mut env = LuaEnv::new();
+    env.set_stdout(stdout.clone());
+    env.set_stderr(stderr.clone());
+    env.set_stdin(&b"10 20\nsecond line\nthird"[..]);
+
+    env.eval_chunk(
+        b"
+        print('hello', 1, nil, true)
+        io.write('a', 2, '\\n')
+        io.stdout:write('b'):write('c\\n')
+        io.stderr:write('error\\n')
+        warn('not shown')
+        warn('@on')
+        warn('shown ', 1)
+        local a, b = io.read('n', 'n')
+        print(a + b, io.read('l'), io.read('L'), io.stdin:read('a'), io.read('l'))
+        assert(io.stdout:close() == nil)
+        assert(io.type(io.stdout) == 'file')
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        stdout.take(),
+        "hello\t1\tnil\ttrue\na2\nbc\n30\t\tsecond line\n\tthird\tnil\n"
+    );
+    assert_eq!(stderr.take(), "error\nLua warning: shown 1\n");
+
+    // redirection applies to handles given to Lua before
+    let other = SharedBuffer::default();
+    env.set_stdout(other.clone());
+    env.set_stdin(&b"return 1 + 2"[..]);
+    let ret = env.eval(b"io.write('x'), dofile()").unwrap();
+    assert_eq!(ret.last(), Some(&LuaValue::from(3 as IntType)));
+    assert_eq!(other.take(), "x");
+    assert_eq!(stdout.take(), "");
+}
+
+#[test]
+fn deterministic_mode() {
+    use crate::VirtualClock;
+
+    let source = b"
+        local t = {}
+        local keys = { {}, {}, 'x', 1.5, function() end }
+        for i, key in ipairs(keys) do t[key] = i end
+        t[keys[2]] = nil
+        local order = {}
+        for k, v in pairs(t) do order[#order + 1] = v end
+        return tostring(keys[1]), tostring(print), tostring(keys[1]),
+            table.concat(order, ','), math.random(1000000), math.random(),
+            os.time(), os.clock(), os.date('%Y-%m-%d %H:%M:%S')
+    ";
+    let run = || {
+        let clock = VirtualClock::new(86400.0 * 365.0);
+        let mut env = LuaEnv::builder().deterministic(42, clock.clone()).build();
+        assert!(env.is_deterministic());
+        clock.advance(1.5);
+        env.eval(source).unwrap()
+    };
+    let ret = run();
+    assert_eq!(ret, run());
+    assert_eq!(ret[0], "table: 0x00000001".into());
+    assert_eq!(ret[1], "function: 0x00000002".into());
+    assert_eq!(ret[2], ret[0]);
+    assert_eq!(ret[6], LuaValue::from(31536001 as IntType));
+    assert_eq!(ret[7], LuaValue::from(1.5));
+    assert_eq!(ret[8], "1971-01-01 00:00:01".into());
+
+    let mut env = LuaEnv::builder()
+        .deterministic(1, VirtualClock::new(0.0))
+        .build();
+    let ret = env
+        .eval(
+            b"
+            os.date('%c', 1700000000), os.date('!%x %X %j %a %B %p', 951782400),
+            os.time({ year = 2000, month = 13, day = 0, hour = 0 }),
+            os.date('*t', 0).wday, os.difftime(10, 4)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "Tue Nov 14 22:13:20 2023".into(),
+            "02/29/00 00:00:00 060 Tue February AM".into(),
+            LuaValue::from(978220800 as IntType),
+            LuaValue::from(5 as IntType),
+            LuaValue::from(6.0),
+        ]
+    );
+}
+
+#[test]
+fn xoshiro256_random() {
+    use crate::random::Xoshiro256;
+
+    // reference implementation of xoshiro256**, seeded with state [1, 2, 3, 4]
+    let mut rng = Xoshiro256::from_state([1, 2, 3, 4]);
+    let expected: [u64; 10] = [
+        11520,
+        0,
+        1509978240,
+        1215971899390074240,
+        1216172134540287360,
+        607988272756665600,
+        16172922978634559625,
+        8476171486693032832,
+        10595114339597558777,
+        2904607092377533576,
+    ];
+    for e in expected {
+        assert_eq!(rng.next_u64(), e);
+    }
+
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local a, b = math.randomseed(7, 3)
+            local c, d = math.randomseed(-1)
+            return a, b, c, d, math.randomseed()
+            ",
+        )
+        .unwrap();
+    assert_eq!(ret.len(), 6);
+    assert_eq!(ret[..4], [7.into(), 3.into(), (-1).into(), 0.into()]);
+
+    // seeded sequences are reproducible, and stay in range
+    let ret = env
+        .eval(
+            b"
+            local function run(...)
+                local seed1, seed2 = math.randomseed(...)
+                local t = {}
+                for i = 1, 100 do
+                    local f, n = math.random(), math.random(-3, 3)
+                    assert(f >= 0 and f < 1 and n >= -3 and n <= 3)
+                    t[#t + 1] = f
+                    t[#t + 1] = n
+                end
+                t[#t + 1] = math.random(0)
+                t[#t + 1] = math.random(math.mininteger, math.maxinteger)
+                return table.concat(t, ','), seed1, seed2
+            end
+            local a = run(42)
+            local b, s1, s2 = run()
+            return a == run(42), b == run(s1, s2), math.random(3, 3), pcall(math.random, 2, 1)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret[..4],
+        [
+            true.into(),
+            true.into(),
+            LuaValue::from(3 as IntType),
+            false.into(),
+        ]
+    );
+    assert!(ret[4].to_string().ends_with("(interval is empty)"));
+}
+
+#[test]
+fn snapshot_restore() {
+    use crate::LuaFunction;
+
+    let new_env = || {
+        let mut env = LuaEnv::new();
+        let double: LuaValue = LuaFunction::from_func(|env, args| {
+            env.pop_n(args.saturating_sub(1));
+            let n = if args > 0 { env.pop() } else { LuaValue::Nil };
+            env.push((n.try_to_int()? * 2).into());
+            Ok(1)
+        })
+        .into();
+        env.add_permanent("host.double", double.clone());
+        env.set_global("double", double);
+        env
+    };
+
+    let mut env = new_env();
+    env.eval_chunk(
+        b"
+        shared = { name = 'shared' }
+        a = { x = shared }
+        b = { x = shared, 10, 20, 30 }
+        a.self = a
+        local count = 0
+        function inc() count = count + 1 return count end
+        function get() return count end
+        inc() inc()
+        vec = setmetatable({ 1, 2 }, {
+            __index = { sum = function(self) return self[1] + self[2] end },
+            __tostring = function(self) return 'vec' end,
+        })
+        co = coroutine.create(function(a)
+            local b = coroutine.yield(a + 1)
+            local c = coroutine.yield(a + b)
+            return a + b + c
+        end)
+        first = select(2, coroutine.resume(co, 1))
+        math.randomseed(7)
+    ",
+    )
+    .unwrap();
+    let snapshot = env.snapshot().unwrap();
+    let expected = env
+        .eval(b"math.random(1000000), select(2, coroutine.resume(co, 10))")
+        .unwrap();
+
+    let mut restored = new_env();
+    restored.restore(&snapshot).unwrap();
+    let ret = restored
+        .eval(
+            b"
+            local values = {
+                a.x == b.x, a.self == a, b[3], #b, inc(), get(),
+                vec:sum(), tostring(vec), first, ('abc'):upper(), double(21),
+                type(print),
+            }
+            return table.unpack(values)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            true.into(),
+            true.into(),
+            LuaValue::from(30 as IntType),
+            LuaValue::from(3 as IntType),
+            LuaValue::from(3 as IntType),
+            LuaValue::from(3 as IntType),
+            LuaValue::from(3 as IntType),
+            "vec".into(),
+            LuaValue::from(2 as IntType),
+            "ABC".into(),
+            LuaValue::from(42 as IntType),
+            "function".into(),
+        ]
+    );
+    // suspended coroutine and random state continue as in the original
+    let ret = restored
+        .eval(b"math.random(1000000), select(2, coroutine.resume(co, 10))")
+        .unwrap();
+    assert_eq!(ret, expected);
+    assert_eq!(
+        restored
+            .eval(b"local ok, sum = coroutine.resume(co, 100) return ok, sum, coroutine.status(co)")
+            .unwrap(),
+        vec![true.into(), LuaValue::from(111 as IntType), "dead".into()]
+    );
+
+    // the permanent must be registered in the restoring `LuaEnv` too
+    assert!(LuaEnv::new().restore(&snapshot).is_err());
+    // malformed snapshot leaves the state unchanged
+    assert!(restored.restore(&snapshot[..snapshot.len() / 2]).is_err());
+    assert_eq!(
+        restored.eval(b"get()").unwrap(),
+        vec![LuaValue::from(3 as IntType)]
+    );
+
+    // Rust functions which are not permanents cannot be saved
+    let mut env = LuaEnv::new();
+    env.eval_chunk(b"wrapped = coroutine.wrap(function() end)")
+        .unwrap();
+    assert!(env.snapshot().is_err());
+    env.eval_chunk(b"wrapped = nil").unwrap();
+    assert!(env.snapshot().is_ok());
+}
+
+#[test]
+fn corrupted_snapshot() {
+    use crate::LuaThread;
+    use crate::ThreadStatus;
+
+    // coroutine suspended in a nested call, with a captured local
+    let suspended = || {
+        let mut env = LuaEnv::new();
+        env.eval_chunk(
+            b"
+            co = coroutine.create(function(a)
+                local function inner(b)
+                    local c = b
+                    local get = function() return c end
+                    return coroutine.yield(a + b) + get()
+                end
+                local r = inner(a * 2)
+                return r
+            end)
+            coroutine.resume(co, 1)
+        ",
+        )
+        .unwrap();
+        env
+    };
+    let corrupt = |f: fn(&mut LuaThread)| {
+        let env = suspended();
+        let LuaValue::Thread(co) = env.get_global("co") else {
+            panic!("co is not a thread");
+        };
+        f(&mut co.borrow_mut());
+        env.snapshot().unwrap()
+    };
+
+    let mut restored = LuaEnv::new();
+    restored.restore(&corrupt(|_| {})).unwrap();
+    assert_eq!(
+        restored
+            .eval(b"return select(2, coroutine.resume(co, 10))")
+            .unwrap(),
+        vec![LuaValue::from(12 as IntType)]
+    );
+
+    let corruptions: [fn(&mut LuaThread); 10] = [
+        |thread| thread.bp += 1,
+        |thread| thread.data_stack.clear(),
+        |thread| thread.boundaries.push(3),
+        |thread| thread.status = ThreadStatus::NotStarted,
+        |thread| thread.call_stack[1].bp += 1,
+        |thread| thread.call_stack[1].counter = 1 << 20,
+        |thread| thread.call_stack[1].pending_result = Some(1 << 20),
+        |thread| thread.call_stack[1].variadic.push(LuaValue::Nil),
+        |thread| {
+            thread.call_stack[1]
+                .cells
+                .resize_with(1 << 10, Default::default)
+        },
+        |thread| {
+            let top = thread.data_stack.len() + 1;
+            thread.call_stack[1].data_stack = top;
+            thread.bp = top;
+        },
+    ];
+    for (idx, f) in corruptions.into_iter().enumerate() {
+        assert!(restored.restore(&corrupt(f)).is_err(), "corruption {}", idx);
+    }
+
+    // a thread left running by an error cannot run again, and is restored as dead
+    restored
+        .restore(&corrupt(|thread| thread.status = ThreadStatus::Running))
+        .unwrap();
+    assert_eq!(
+        restored.eval(b"return coroutine.status(co)").unwrap(),
+        vec!["dead".into()]
+    );
+
+    // no single byte changed makes restoring panic
+    let snapshot = corrupt(|_| {});
+    for idx in 0..snapshot.len() {
+        for byte in [0, 1, 2, 0x7f, 0xff, snapshot[idx] ^ 1] {
+            let mut mutated = snapshot.clone();
+            mutated[idx] = byte;
+            let _ = LuaEnv::new().restore(&mutated);
+        }
+    }
+}
+
+#[test]
+fn shared_prototypes() {
+    use crate::LuaFunction;
+    use crate::Prototype;
+    use std::rc::Rc;
+
+    let prototype = |env: &LuaEnv, name: &str| -> Rc<Prototype> {
+        let LuaValue::Function(f) = env.get_global(name) else {
+            panic!("{} is not a function", name);
+        };
+        let prototype = match &*f.borrow() {
+            LuaFunction::LuaFunc(f) => Rc::clone(&f.prototype),
+            LuaFunction::RustFunc(_) => panic!("{} is not a Lua function", name),
+        };
+        prototype
+    };
+    let mut env = LuaEnv::new();
+    env.eval_chunk(
+        b"
+        local function counter(n)
+            return function() n = n + 1 return n end
+        end
+        c1, c2 = counter(0), counter(10)
+        c1() c1()
+    ",
+    )
+    .unwrap();
+    // closures of the same function share the code, but not the upvalues
+    assert!(Rc::ptr_eq(&prototype(&env, "c1"), &prototype(&env, "c2")));
+    assert_eq!(
+        env.eval(b"c1(), c2()").unwrap(),
+        vec![LuaValue::from(3 as IntType), LuaValue::from(11 as IntType)]
+    );
+
+    let mut restored = LuaEnv::new();
+    restored.restore(&env.snapshot().unwrap()).unwrap();
+    assert!(Rc::ptr_eq(
+        &prototype(&restored, "c1"),
+        &prototype(&restored, "c2")
+    ));
+    assert_eq!(
+        restored.eval(b"c1(), c2()").unwrap(),
+        vec![LuaValue::from(4 as IntType), LuaValue::from(12 as IntType)]
+    );
+}
+
+#[test]
+fn binary_chunk() {
+    use crate::CompiledChunk;
+
+    let mut env = LuaEnv::new();
+    let source = b"
+        local n = ...
+        local function counter()
+            local count = 0
+            return function() count = count + 1 return count end
+        end
+        local c = counter()
+        c() c()
+        return c() + n, undefined_global
+    ";
+    let chunk = env.compile(source, "counter").unwrap();
+    for strip in [false, true] {
+        let binary = chunk.dump(strip);
+        let loaded = CompiledChunk::undump(&binary, "loaded").unwrap();
+        assert_eq!(loaded.name(), if strip { "loaded" } else { "counter" });
+        assert_eq!(
+            env.run(&loaded, vec![LuaValue::from(10 as IntType)])
+                .unwrap(),
+            vec![LuaValue::from(13 as IntType), LuaValue::Nil]
+        );
+
+        // truncated or corrupted input is rejected without panic
+        for len in 0..binary.len() {
+            assert!(CompiledChunk::undump(&binary[..len], "truncated").is_err());
+        }
+        for i in 0..binary.len() {
+            let mut corrupted = binary.clone();
+            corrupted[i] ^= 0xff;
+            let _ = CompiledChunk::undump(&corrupted, "corrupted");
+        }
+    }
+
+    let ret = env
+        .eval(
+            b"
+            local x = 1
+            local function add(a, b) return a + b + (x or 100) end
+            local loaded = load(string.dump(add, true), 'add', 'b')
+            local ok, message = load(string.dump(add), 'add', 't')
+            local pieces = { 'return ', '1 ', '+ 2' }
+            local i = 0
+            local reader = load(function() i = i + 1 return pieces[i] end)
+            return add(1, 2), loaded(1, 2), ok, message, reader(), pcall(string.dump, print)
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(4 as IntType),
+            // upvalues of dumped function are fresh `nil`
+            LuaValue::from(103 as IntType),
+            LuaValue::Nil,
+            "attempt to load a binary chunk (mode is 't')".into(),
+            LuaValue::from(3 as IntType),
+            false.into(),
+            "unable to dump given function".into(),
+        ]
+    );
+
+    let mut env = LuaEnv::builder().binary_chunks(false).build();
+    let ret = env.eval(b"load(string.dump(function() end))").unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::Nil,
+            "attempt to load a binary chunk (binary chunks are not allowed)".into()
+        ]
+    );
+}
+
+#[test]
+fn mutated_dumps() {
+    use crate::random::Xoshiro256;
+    use crate::Limits;
+
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            return string.dump(function(...)
+                local t = { 1, 2, x = 'a', ... }
+                local function sum(...)
+                    local s = 0
+                    for i, v in ipairs({ ... }) do s = s + v * i end
+                    for i = 1, select('#', ...) do s = s + (select(i, ...)) end
+                    return s, ...
+                end
+                local up = 0
+                local function f(a, b, ...)
+                    up = up + 1
+                    local c = a .. b
+                    while #c > up do up = up + 1 end
+                    t.x = t[1] // 2 % 3
+                    t[#t + 1] = { sum(1, 2, ...) }
+                    return string.format('%d', up & 7 | 1 << 2), sum(...)
+                end
+                return f('a', 'b', 1, 2), setmetatable({}, { __index = t }).x, t[nil]
+            end)
+            ",
+        )
+        .unwrap();
+    let LuaValue::String(dump) = &ret[0] else {
+        panic!("string.dump returned {:?}", ret);
+    };
+    let dump = dump.as_bytes().to_vec();
+
+    // a few bytes of the dump are replaced or nudged; loading and running it raises errors, not panics
+    let mut rng = Xoshiro256::from_seed(38, 0);
+    for _ in 0..20_000 {
+        let mut mutated = dump.clone();
+        for _ in 0..1 + rng.next_u64() % 4 {
+            let idx = (rng.next_u64() % mutated.len() as u64) as usize;
+            let r = rng.next_u64();
+            mutated[idx] = match r % 3 {
+                0 => (r >> 8) as u8,
+                1 => mutated[idx].wrapping_add(1),
+                _ => mutated[idx].wrapping_sub(1),
+            };
+        }
+        env.set_limits(Limits {
+            instructions: Some(10_000),
+            allocation: Some(1 << 24),
+            ..Default::default()
+        });
+        env.set_global("mutated", mutated.into());
+        // globals written by the mutated code do not leak into the next ones
+        let _ = env.eval(
+            b"return pcall(load(mutated, 'mutated', 'b', setmetatable({}, { __index = _G })))",
+        );
+    }
+    env.set_limits(Limits::default());
+    assert_eq!(
+        env.eval(b"1 + 1").unwrap(),
+        vec![LuaValue::from(2 as IntType)]
+    );
+}
+
+#[test]
+fn disassemble() {
+    use crate::CompiledChunk;
+
+    let env = LuaEnv::new();
+    let source = b"local x = 1
+local function add(a, ...)
+    if a then
+        return x + a
+    end
+end
+return add(2), 'done', 'done'";
+    let chunk = env.compile(source, "add").unwrap();
+    let listing = chunk.disassemble();
+    assert!(listing.starts_with("main <add> ("));
+    assert!(listing.contains("0+ params, 5 registers, 0 upvalues"));
+    assert!(listing.contains("function #1 <add:2> ("));
+    assert!(listing.contains("1+ params, 3 registers, 1 upvalues"));
+    // source lines, resolved jump targets and names of variables
+    assert!(listing.contains("\t[3]\tJumpFalse"));
+    assert!(listing.contains("\t[4]\tFunctionUpvalue     \t2 0     \t; x"));
+    assert!(listing.contains("\t0\tx\tlocal 0"));
+    assert!(listing.contains("registers (3):\n\t0\ta\n"));
+    // equal string constants share a slot in the constant pool
+    assert!(listing.contains("\t8\t[7]\tString              \t3 0     \t; \"done\""));
+    assert!(listing.contains("\t9\t[7]\tString              \t4 0     \t; \"done\""));
+    let jump = listing
+        .lines()
+        .find(|line| line.contains("JumpFalse"))
+        .unwrap();
+    let target = jump.rsplit("to ").next().unwrap();
+    assert!(listing.contains(&format!("\t{}\t[", target)));
+
+    // line numbers are debug information, stripped from binary chunks
+    let stripped = CompiledChunk::undump(&chunk.dump(true), "stripped").unwrap();
+    let stripped = stripped.disassemble();
+    assert!(stripped.contains("\t[-]\tJumpFalse"));
+    assert!(stripped.contains("\t[-]\tFunctionUpvalue     \t2 0\n"));
+    assert!(stripped.contains("; \"done\""));
+    let loaded = CompiledChunk::undump(&chunk.dump(false), "loaded").unwrap();
+    assert_eq!(loaded.disassemble(), listing);
+}
+
+#[test]
+fn tail_call() {
+    let mut env = LuaEnv::new();
+    let source = b"
+local function count(n, acc)
+    if n == 0 then
+        depth = #debug.traceback()
+        return acc
+    end
+    return count(n - 1, acc + 1)
+end
+local even, odd
+function even(n) if n == 0 then return true end return odd(n - 1) end
+function odd(n) if n == 0 then return false end return even(n - 1) end
+return count(200000, 0), even(100001), select('#', count(0, nil))";
+    let chunk = env.compile(source, "tail").unwrap();
+    assert_eq!(
+        env.run(&chunk, Vec::new()).unwrap(),
+        vec![
+            LuaValue::from(200000 as IntType),
+            false.into(),
+            LuaValue::from(1 as IntType)
+        ]
+    );
+    // the stack did not grow while recursing
+    assert!(env.get_global("depth").try_to_int().unwrap() < 200);
+
+    let traceback = env
+        .eval(
+            b"local function f(n) if n == 0 then return debug.traceback('msg') end return f(n - 1) end
+            local function g() local t = f(10) return t end
+            local t = g() return t",
+        )
+        .unwrap();
+    assert_eq!(
+        traceback,
+        vec![LuaValue::from(
+            "msg
+stack traceback:
+\teval:1: in function <eval:1>
+\t(...tail calls...)
+\teval:2: in function <eval:2>
+\teval:3: in main chunk"
+        )]
+    );
+}
+
+#[test]
+fn stack_overflow() {
+    let mut env = LuaEnv::new();
+    let source = b"
+local function rec() return 1 + rec() end
+local function cmp(a, b) table.sort({2, 1}, cmp) return a < b end
+local t = setmetatable({}, {})
+getmetatable(t).__index = t
+local callable = setmetatable({}, {})
+getmetatable(callable).__call = callable
+local function deep(n) if n == 0 then error(debug.traceback('deep')) end deep(n - 1) end
+local _, a = pcall(rec)
+local _, b = pcall(table.sort, {2, 1}, cmp)
+local _, c = pcall(function() return t.x end)
+local _, d = pcall(callable)
+local _, e = pcall(deep, 100)
+return a, b, c, d, e";
+    let chunk = env.compile(source, "overflow").unwrap();
+    let ret = env.run(&chunk, Vec::new()).unwrap();
+    assert_eq!(ret[0], LuaValue::from("stack overflow"));
+    assert_eq!(ret[1], LuaValue::from("stack overflow"));
+    assert_eq!(
+        ret[2],
+        LuaValue::from("'__index' chain too long; possible loop")
+    );
+    assert_eq!(ret[3], LuaValue::from("stack overflow"));
+    // long traceback is truncated
+    let traceback = ret[4].to_string();
+    assert_eq!(traceback.lines().count(), 2 + 10 + 1 + 11);
+    assert!(traceback.contains("\n\t...\t(skipping "));
+
+    // limits are configurable
+    let mut limits = env.limits().clone();
+    limits.call_depth = 50;
+    env.set_limits(limits);
+    let ret = env
+        .eval(b"local function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end return pcall(f, 40), pcall(f, 60)")
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![true.into(), false.into(), LuaValue::from("stack overflow")]
+    );
+}
+
+#[test]
+fn scripts_do_not_panic() {
+    // each of them used to panic the host; now it returns or raises a Lua error
+    let sources: &[&[u8]] = &[
+        b"return 1 % 0",
+        b"return math.mininteger % -1, -math.mininteger, math.abs(math.mininteger)",
+        b"return math.fmod(1, 0)",
+        b"return select(math.mininteger, 1)",
+        b"return select('', 1)",
+        b"return table.unpack({}, math.mininteger, math.maxinteger)",
+        b"return string.rep('x', math.maxinteger)",
+        b"return string.rep('', math.maxinteger, '')",
+        b"return coroutine.resume(coroutine.running())",
+        b"return os.time({ year = 1e18, month = 1, day = 1 })",
+        b"return string.format('%d', 1)",
+        b"return xpcall(print, print)",
+        b"return collectgarbage()",
+        b"goto nowhere",
+        b"::top:: local function f() goto top end",
+    ];
+    for source in sources {
+        let mut env = LuaEnv::new();
+        let _ = env.eval_chunk(source);
+        // env is still usable
+        assert_eq!(
+            env.eval(b"1 + 1").unwrap(),
+            vec![LuaValue::from(2 as IntType)]
+        );
+    }
+
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(b"tonumber('ff', 16), tonumber('-z', 36), tonumber('8', 8), pcall(function() return math.fmod(1, 0) end)")
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(255 as IntType),
+            LuaValue::from(-35 as IntType),
+            LuaValue::Nil,
+            false.into(),
+            "bad argument #2 to 'fmod' (zero)".into(),
+        ]
+    );
+
+    // failed lines do not break the interpreter
+    let mut env = LuaEnv::new();
+    for line in [
+        "local f = function() local x = ... end",
+        "goto nowhere",
+        "local a <const> = 1",
+        "a = 2",
+        "x = 1",
+    ] {
+        let _ = env.feed_line(line.as_bytes());
+    }
+    assert_eq!(env.get_global("x"), LuaValue::from(1 as IntType));
+}
+
+#[test]
+fn generated_scripts_do_not_panic() {
+    use crate::random::Xoshiro256;
+    use crate::Limits;
+    use crate::RuntimeError;
+    use crate::StdLib;
+
+    let limits = || Limits {
+        instructions: Some(100_000),
+        allocation: Some(1 << 24),
+        ..Default::default()
+    };
+    let new_env = || {
+        LuaEnv::builder()
+            .stdlib(StdLib::SAFE | StdLib::OS)
+            .file_loading(false)
+            .build()
+    };
+    // every library function, except those printing or reaching out of the process
+    let functions: Vec<String> = new_env()
+        .eval(
+            b"
+            local skip = {
+                print = true, ['os.exit'] = true, ['os.execute'] = true,
+                ['os.remove'] = true, ['os.rename'] = true, ['os.tmpname'] = true,
+            }
+            local names = {}
+            for name, value in pairs(_G) do
+                if type(value) == 'function' then
+                    names[#names + 1] = name
+                elseif type(value) == 'table' and name ~= '_G' then
+                    for field, f in pairs(value) do
+                        if type(f) == 'function' then names[#names + 1] = name .. '.' .. field end
+                    end
+                end
+            end
+            local functions = {}
+            for _, name in ipairs(names) do
+                if not skip[name] then functions[#functions + 1] = name end
+            end
+            table.sort(functions)
+            return table.unpack(functions)
+            ",
+        )
+        .unwrap()
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    assert!(functions.len() > 50);
+
+    let operands = [
+        "nil",
+        "true",
+        "0",
+        "-1",
+        "3",
+        "2.5",
+        "-0.0",
+        "0/0",
+        "1/0",
+        "math.mininteger",
+        "math.maxinteger",
+        "2^63",
+        "''",
+        "'x'",
+        "'10'",
+        "'0x7fffffffffffffff'",
+        "'%d%s%q'",
+        "'%'",
+        "'(['",
+        "'%f[%a]'",
+        "'\\0\\255'",
+        "{}",
+        "{ 1, 2, nil, 4 }",
+        "{ n = -1, [1.5] = 1 }",
+        "setmetatable({}, { __index = error, __len = error, __lt = error })",
+        "tostring",
+        "function(...) return ... end",
+        "coroutine.running()",
+        "coroutine.create(type)",
+    ];
+    let operators = [
+        "+", "-", "*", "/", "//", "%", "^", "..", "&", "|", "~", "<<", ">>", "==", "<", "<=", "and",
+    ];
+    let templates = [
+        "return {f}()",
+        "return {f}({a})",
+        "return {f}({a}, {b})",
+        "return {f}({a}, {b}, {c})",
+        "return {f}({a}, {b}, {c}, {a})",
+        "return {a} {op} {b}",
+        "return -{a}, ~{a}, #{a}, not {a}",
+        "local t = {a} t[{b}] = {c} return t[{b}], t.x",
+        "for i = {a}, {b}, {c} do end",
+        "for k, v in {a}, {b}, {c} do end",
+        "return {a}({b}, {c})",
+        "local s = {a} return s:{m}({b}, {c})",
+        "return table.unpack({ {a}, {b}, {c} }, {a}, {b})",
+    ];
+
+    let mut rng = Xoshiro256::from_seed(42, 0);
+    let mut pick =
+        |items: &[&str]| items[(rng.next_u64() % items.len() as u64) as usize].to_string();
+    let mut env = new_env();
+    for round in 0..4_000 {
+        // functions may change the state of the environment in any way
+        if round % 100 == 0 {
+            env = new_env();
+        }
+        let function = &functions[round % functions.len()];
+        let method = function.rsplit('.').next().unwrap();
+        let source = pick(&templates)
+            .replace("{f}", function)
+            .replace("{m}", method)
+            .replace("{op}", &pick(&operators))
+            .replace("{a}", &pick(&operands))
+            .replace("{b}", &pick(&operands))
+            .replace("{c}", &pick(&operands));
+        env.set_limits(limits());
+        let res = env.eval(source.as_bytes());
+        assert!(
+            !matches!(res, Err(RuntimeError::Panic(_))),
+            "{} panicked: {:?}",
+            source,
+            res
+        );
+    }
+}
+
+#[test]
+fn panic_in_rust_function() {
+    use crate::LuaFunction;
+
+    let mut env = LuaEnv::new();
+    env.set_global(
+        "explode",
+        LuaFunction::from_func(|_env, _args| panic!("kaboom")).into(),
+    );
+    let ret = env
+        .eval(b"local ok, err = pcall(explode, 1, 2) return ok, err, 1 + 1")
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            false.into(),
+            "Rust function panicked: kaboom".into(),
+            LuaValue::from(2 as IntType)
+        ]
+    );
+    assert!(matches!(
+        env.eval(b"explode()"),
+        Err(crate::RuntimeError::Panic(msg)) if msg == "kaboom"
+    ));
+}
+
+#[test]
+fn registers() {
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"
+            local a, b, c = 1, 2
+            a, b = b, a
+            local fs = {}
+            for i = 1, 3 do fs[i] = function() return i end end
+            local function pack(...) return select('#', ...), ... end
+            local n, x, y = pack(a, b, c)
+            local t = { pack(1, 2), pack(3, 4) }
+            local s = ('-'):rep(fs[1]() + fs[3](), #t)
+            local steps = 0
+            for f = 1.0, 2.0, 0.5 do steps = steps + f end
+            return a, b, c, n, x, y, s, steps",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(2 as IntType),
+            LuaValue::from(1 as IntType),
+            LuaValue::Nil,
+            LuaValue::from(3 as IntType),
+            LuaValue::from(2 as IntType),
+            LuaValue::from(1 as IntType),
+            LuaValue::from("-4-4-4-"),
+            LuaValue::from(4.5),
+        ]
+    );
+    assert!(matches!(
+        env.eval(b"for i = 1, 10, 0 do end"),
+        Err(crate::RuntimeError::Custom(msg)) if msg == "'for' step is zero".into()
+    ));
+}
+
+#[test]
+fn constant_folding() {
+    let mut env = LuaEnv::new();
+    let binary = [
+        ("2", "^", "10"),
+        ("7", "/", "2"),
+        ("7", "//", "2"),
+        ("7.5", "//", "2"),
+        ("7", "%", "-3"),
+        ("-7.5", "%", "2"),
+        ("5.0", "%", "0"),
+        ("1", "/", "0"),
+        ("9223372036854775807", "+", "1"),
+        ("3", "&", "5.0"),
+        ("1", "<<", "63"),
+        ("1", "<<", "64"),
+        ("-1", ">>", "1"),
+        ("8", ">>", "-2"),
+        ("'a'", "..", "'b'"),
+        ("1", "..", "2.0"),
+        ("'x'", "..", "1.5"),
+        ("1", "==", "1.0"),
+        ("1", "~=", "'1'"),
+        ("'a'", "<", "'b'"),
+        ("2", ">=", "2.5"),
+        ("nil", "and", "1"),
+        ("false", "or", "'y'"),
+    ];
+    for (lhs, op, rhs) in binary {
+        let folded = format!("return {} {} {}", lhs, op, rhs);
+        let chunk = env.compile(folded.as_bytes(), "folded").unwrap();
+        assert!(!chunk.disassemble().contains("\tBinary"), "{}", folded);
+        let runtime = format!("local a, b = {}, {} return a {} b", lhs, rhs, op);
+        assert_eq!(
+            format!("{:?}", env.eval(folded.as_bytes()).unwrap()),
+            format!("{:?}", env.eval(runtime.as_bytes()).unwrap()),
+            "{}",
+            folded
+        );
+    }
+    let unary = [
+        ("-", "1"),
+        ("-", "2.5"),
+        ("not", "true"),
+        ("#", "'abc'"),
+        ("~", "5"),
+    ];
+    for (op, value) in unary {
+        let folded = format!("return {} {}", op, value);
+        let chunk = env.compile(folded.as_bytes(), "folded").unwrap();
+        assert!(!chunk.disassemble().contains("\tUnary"), "{}", folded);
+        let runtime = format!("local a = {} return {} a", value, op);
+        assert_eq!(
+            format!("{:?}", env.eval(folded.as_bytes()).unwrap()),
+            format!("{:?}", env.eval(runtime.as_bytes()).unwrap()),
+            "{}",
+            folded
+        );
+    }
+
+    // constant lhs of `and`/`or` selects the operand without a jump
+    let chunk = env
+        .compile(b"local x = ... return true and x", "and")
+        .unwrap();
+    assert!(!chunk.disassemble().contains("Jump"));
+
+    // errors and string coercion are left for the runtime
+    for source in [
+        "return 1 % 0",
+        "return 1 // 0",
+        "return 1 < 'x'",
+        "return 1.5 | 1",
+        "return -{}",
+    ] {
+        let chunk = env.compile(source.as_bytes(), "error").unwrap();
+        assert!(env.run(&chunk, Vec::new()).is_err(), "{}", source);
+    }
+    let chunk = env.compile(b"return '10' + 1", "coercion").unwrap();
+    assert!(chunk.disassemble().contains("BinaryAdd"));
+
+    // integer division and modulo by zero raise the same errors as reference Lua
+    for (source, message) in [
+        (
+            "local a, b = 1, 0 return a // b",
+            "attempt to perform 'n//0'",
+        ),
+        (
+            "local a, b = '1', 0 return a // b",
+            "attempt to perform 'n//0'",
+        ),
+        (
+            "local a, b = 1, 0 return a % b",
+            "attempt to perform 'n%%0'",
+        ),
+        (
+            "local a, b = '1', 0 return a % b",
+            "attempt to perform 'n%%0'",
+        ),
+    ] {
+        let ret = env.eval(source.as_bytes());
+        assert!(
+            matches!(&ret, Err(crate::RuntimeError::Custom(msg)) if *msg == message.into()),
+            "{}: {:?}",
+            source,
+            ret
+        );
+    }
+    assert_eq!(
+        env.eval(b"local a, b = 1, 0.0 return a // b, -a // b")
+            .unwrap(),
+        vec![
+            LuaValue::from(crate::FloatType::INFINITY),
+            LuaValue::from(crate::FloatType::NEG_INFINITY)
+        ]
+    );
+}
+
+#[test]
+fn optimization_levels() {
+    use crate::OptLevel;
+
+    let sources: [&[u8]; 8] = [
+        b"local t, s = {}, ''
+        for i = 1, 10 do
+            if i % 2 == 0 and i > 3 then t[#t + 1] = i
+            elseif not (i < 5) then s = s .. i
+            else break end
+        end
+        return #t, s",
+        b"local s = ''
+        for i = 1, 5 do
+            for j = 1, 5 do
+                if j > i then goto continue end
+                if i * j > 8 then goto out end
+                s = s .. j
+                ::continue::
+            end
+        end
+        ::out::
+        return s",
+        b"local n, steps = 27, 0
+        while n ~= 1 do
+            if n % 2 == 0 then n = n // 2 else n = 3 * n + 1 end
+            steps = steps + 1
+        end
+        return steps",
+        b"local a, b = nil, false
+        return a or b, a and 1, not a and 'y', (1 < 2) and 'lt' or 'ge', not not b",
+        b"local n = 0
+        while true do
+            n = n + 1
+            repeat n = n + 2 until not (n < 10)
+            if n > 20 then break end
+        end
+        if nil then n = 0 elseif false then n = 1 end
+        return n",
+        b"local x = ...
+        local a = not x
+        if a then x = 1 end
+        local c = 1 < 2
+        if c then x = x + 1 end
+        return a, c, x",
+        b"local function find(t, x)
+            for i, v in ipairs(t) do
+                if v == x then return i end
+            end
+            return nil
+        end
+        return find({ 5, 6, 7 }, 7), find({}, 1)",
+        b"return pcall(function()
+            local t
+            if not t then error('boom') end
+            return t
+        end)",
+    ];
+    let mut sizes = [0; 3];
+    for source in sources {
+        let mut results = Vec::new();
+        for (level, size) in [OptLevel::None, OptLevel::Basic, OptLevel::Full]
+            .into_iter()
+            .zip(&mut sizes)
+        {
+            let mut env = LuaEnv::builder().opt_level(level).build();
+            let chunk = env.compile(source, "test").unwrap();
+            *size += chunk.prototype.chunk.instructions.len();
+            results.push(format!("{:?}", env.run(&chunk, Vec::new())));
+        }
+        assert!(results[0].starts_with("Ok("), "{}", results[0]);
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0], results[2]);
+    }
+    assert!(sizes[0] > sizes[1] && sizes[1] > sizes[2]);
+
+    // no jump to a jump is left, and constant conditions are gone
+    let env = LuaEnv::new();
+    let chunk = env.compile(sources[4], "test").unwrap();
+    let instructions = &chunk.prototype.chunk.instructions;
+    assert!(instructions.iter().all(|instruction| match instruction {
+        crate::Instruction::Jump(target)
+        | crate::Instruction::JumpTrue(_, target)
+        | crate::Instruction::JumpFalse(_, target) => {
+            !matches!(instructions[*target], crate::Instruction::Jump(_))
+        }
+        crate::Instruction::Boolean(..) | crate::Instruction::Nil(..) => false,
+        _ => true,
+    }));
+}
+
+#[test]
+fn field_inline_cache() {
+    let mut env = LuaEnv::new();
+    let chunk = env
+        .compile(
+            b"local Point = {}
+            Point.__index = Point
+            function Point.new(x, y) local p = {} p.x = x p.y = y return setmetatable(p, Point) end
+            function Point:sum() return self.x + self.y end
+
+            -- same shape, keys inserted in another order, and keys removed and inserted again
+            local points = { Point.new(1, 2), Point.new(10, 20), setmetatable({ y = 200, x = 100 }, Point) }
+            local q = Point.new(3, 4)
+            q.x = nil
+            q.z = 5
+            q.x = 30
+            points[#points + 1] = q
+            local sums = {}
+            for i = 1, #points do sums[i] = points[i]:sum() end
+
+            -- cached index must not be used for a missing key or a non-table
+            local r = {}
+            for _, t in ipairs({ { x = 1 }, { a = 0, x = 2 }, {}, { x = 4 } }) do r[#r + 1] = t.x or 'nil' end
+
+            -- metamethods on a miss, and a metatable set after the key was cached
+            local log = {}
+            local proxy = setmetatable({}, {
+                __index = function(_, k) return 'get ' .. k end,
+                __newindex = function(t, k, v) log[#log + 1] = k .. '=' .. v; rawset(t, k, v) end,
+            })
+            local gets, sets = {}, {}
+            for i = 1, 2 do gets[i] = proxy.field; proxy.field = i end
+            local late = { n = 1 }
+            for i = 1, 3 do
+                if i == 2 then late.n = nil; setmetatable(late, { __index = function() return 'meta' end }) end
+                sets[i] = late.n
+            end
+
+            -- globals
+            counter = 0
+            for i = 1, 5 do counter = counter + i end
+
+            local ok, msg = pcall(function() return ('x'):rep({}) end)
+            return table.concat(sums, ' '), table.concat(r, ' '), gets[1], gets[2],
+                table.concat(log, ' '), table.concat(sets, ' '), counter, msg",
+            "fields",
+        )
+        .unwrap();
+    assert!(chunk.disassemble().contains("\tGetField"));
+    assert!(chunk.disassemble().contains("\tSetField"));
+    let ret = env.run(&chunk, Vec::new()).unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "3 30 300 34".into(),
+            "1 2 nil 4".into(),
+            "get field".into(),
+            LuaValue::from(1 as IntType),
+            "field=1".into(),
+            "1 meta meta".into(),
+            LuaValue::from(15 as IntType),
+            "bad argument #2 to 'rep' (number expected, got table)".into(),
+        ]
+    );
+}
+
+#[test]
+fn hybrid_table() {
+    let mut env = LuaEnv::new();
+
+    // integer keys in both parts, checked against a table of string keys
+    let ret = env
+        .eval(
+            b"local seed = 7
+            local function random(n) seed = (seed * 1103515245 + 12345) % 2147483648 return seed % n end
+            local t, model = {}, {}
+            for step = 1, 3000 do
+                local k = random(80) - 5
+                if random(3) == 0 then
+                    t[k], model['k' .. k] = nil, nil
+                else
+                    t[k], model['k' .. k] = step, step
+                end
+                if step % 7 == 0 then t['s' .. step % 50] = step end
+                if step % 50 == 0 then
+                    for k = -5, 75 do
+                        if t[k] ~= model['k' .. k] then error('value of ' .. k) end
+                    end
+                    local n = #t
+                    if (n > 0 and t[n] == nil) or t[n + 1] ~= nil then error('border ' .. n) end
+                    local count, expected = 0, 0
+                    for k, v in pairs(t) do
+                        if math.type(k) == 'integer' then
+                            if model['k' .. k] ~= v then error('pairs ' .. k) end
+                            count = count + 1
+                        end
+                    end
+                    for _ in pairs(model) do expected = expected + 1 end
+                    if count ~= expected then error('count ' .. count) end
+                end
+            end
+            return true",
+        )
+        .unwrap();
+    assert_eq!(ret, vec![true.into()]);
+
+    let ret = env
+        .eval(
+            b"local lens = table.concat({ #{ 1, nil, 3 }, #{ nil, nil }, #{ 1, 2, nil }, #{ n = 1 }, #{ [2] = 2 } }, ' ')
+
+            -- float keys with integer value are integer keys
+            local f = {}
+            f[1.0], f[2] = 'a', 'b'
+            f[3.0] = 'c'
+            local floats = table.concat(f, '') .. #f .. math.type(next(f))
+
+            -- keys can be removed while traversing
+            local c = { 1, 2, 3, x = 1, y = 2, [10] = 10 }
+            for k in pairs(c) do c[k] = nil end
+            local cleared = next(c) == nil
+
+            local s = { 1, 2, 3, 4, 5 }
+            table.insert(s, 1, 0)
+            table.insert(s, 4, 'x')
+            table.insert(s, 'last')
+            local removed = table.remove(s, 2) .. table.remove(s) .. table.remove(s, 1)
+            table.move(s, 1, 4, 2)
+            local moved = table.concat(s, ',')
+            local packed = table.pack(1, nil, 3)
+            local sparse = { [1000] = 'a', [1001] = 'b' }
+            return lens, floats, cleared, removed, moved, packed.n, #packed,
+                select('#', table.unpack(sparse, 999, 1002)), table.unpack(sparse, 1000, 1001)",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            "3 0 2 0 0".into(),
+            "abc3integer".into(),
+            true.into(),
+            "1last0".into(),
+            "2,2,x,3,4".into(),
+            LuaValue::from(3 as IntType),
+            LuaValue::from(3 as IntType),
+            LuaValue::from(4 as IntType),
+            "a".into(),
+            "b".into(),
+        ]
+    );
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn compact_value() {
+    use crate::LuaString;
+
+    assert_eq!(std::mem::size_of::<LuaString>(), 8);
+    assert_eq!(std::mem::size_of::<LuaValue>(), 16);
+    assert_eq!(std::mem::size_of::<Option<LuaValue>>(), 16);
+
+    // clones share the bytes; mapping the last reference does not copy
+    let s = LuaString::from("shared");
+    let t = s.clone();
+    assert_eq!(s.as_bytes().as_ptr(), t.as_bytes().as_ptr());
+    let ptr = s.as_bytes().as_ptr();
+    let upper = t.into_mapped(|c| c.to_ascii_uppercase());
+    assert_ne!(upper.as_bytes().as_ptr(), ptr);
+    assert_eq!(s.as_bytes(), b"shared");
+    let upper = s.into_mapped(|c| c.to_ascii_uppercase());
+    assert_eq!(upper.as_bytes().as_ptr(), ptr);
+    assert_eq!(upper.as_bytes(), b"SHARED");
+    assert!(LuaString::from("").is_empty());
+
+    let mut env = LuaEnv::new();
+    let ret = env
+        .eval(
+            b"local t, s = {}, 'abc'
+            for i = 1, 100 do t[i] = i * 0.5; t[s .. i] = s:upper() end
+            return t[100], t.abc7, #t, s",
+        )
+        .unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            LuaValue::from(50.0 as crate::FloatType),
+            "ABC".into(),
+            LuaValue::from(100 as IntType),
+            "abc".into(),
+        ]
+    );
+}
+
+/// Future resolving to `values` after returning `Poll::Pending` `remaining` times.
+#[cfg(feature = "async")]
+struct Delay {
+    remaining: usize,
+    values: Vec<LuaValue>,
+}
+#[cfg(feature = "async")]
+impl std::future::Future for Delay {
+    type Output = Result<Vec<LuaValue>, crate::RuntimeError>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        if self.remaining == 0 {
+            std::task::Poll::Ready(Ok(std::mem::take(&mut self.values)))
+        } else {
+            self.remaining -= 1;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+}
+
+/// Run `future` to completion on the current thread.
+/// Returns its output and the number of times it was polled.
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> (F::Output, usize) {
+    struct NoopWaker;
+    impl std::task::Wake for NoopWaker {
+        fn wake(self: std::sync::Arc<Self>) {}
+    }
+    let waker = std::task::Waker::from(std::sync::Arc::new(NoopWaker));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    let mut polls = 0;
+    loop {
+        polls += 1;
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return (output, polls);
+        }
+    }
+}
+
+/// `sleep(v)` returns `v` after being pending twice;
+/// `fail(msg)` raises `msg` after being pending once.
+#[cfg(feature = "async")]
+fn env_with_async_functions() -> LuaEnv {
+    use crate::LuaFunction;
+    use crate::RuntimeError;
+
+    let mut env = LuaEnv::new();
+    env.set_global(
+        "sleep",
+        LuaFunction::from_async(|_env, args| Delay {
+            remaining: 2,
+            values: args.into_iter().take(1).collect(),
+        })
+        .into(),
+    );
+    env.set_global(
+        "fail",
+        LuaFunction::from_async(|_env, args| {
+            let msg = args.into_iter().next().unwrap_or_default();
+            async move {
+                Delay {
+                    remaining: 1,
+                    values: Vec::new(),
+                }
+                .await?;
+                Err(RuntimeError::Custom(msg))
+            }
+        })
+        .into(),
+    );
+    env
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn eval_async_suspends_on_async_function() {
+    let mut env = env_with_async_functions();
+    let (ret, polls) = block_on(env.eval_async(
+        b"
+        local a = sleep(1)
+        local b = sleep(2) + 10
+        return a, b, sleep(nil)
+        ",
+    ));
+    assert_eq!(
+        ret.unwrap(),
+        vec![
+            LuaValue::from(1 as IntType),
+            LuaValue::from(12 as IntType),
+            LuaValue::Nil
+        ]
+    );
+    assert_eq!(polls, 7);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_function_in_coroutine_pcall_and_sort() {
+    let mut env = env_with_async_functions();
+    let (ret, _) = block_on(env.eval_async(
+        b"
+        local co = coroutine.wrap(function(x)
+            local v = sleep(x)
+            coroutine.yield(v)
+            return sleep(v + 1)
+        end)
+        local ok, err = pcall(fail, 'boom')
+        local t = { 3, 1, 2 }
+        table.sort(t, function(a, b) return sleep(a) < sleep(b) end)
+        return co(1), co(), ok, err, t[1], t[2], t[3]
+        ",
+    ));
+    assert_eq!(
+        ret.unwrap(),
+        vec![
+            LuaValue::from(1 as IntType),
+            LuaValue::from(2 as IntType),
+            false.into(),
+            "boom".into(),
+            LuaValue::from(1 as IntType),
+            LuaValue::from(2 as IntType),
+            LuaValue::from(3 as IntType),
+        ]
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn call_async_function() {
+    let mut env = env_with_async_functions();
+    env.eval_chunk(b"function add(a, b) return sleep(a) + b end")
+        .unwrap();
+
+    let add = env.get_global("add");
+    let (ret, _) = block_on(env.call_async(
+        add,
+        vec![LuaValue::from(1 as IntType), LuaValue::from(2 as IntType)],
+    ));
+    assert_eq!(ret.unwrap(), vec![LuaValue::from(3 as IntType)]);
+
+    let sleep = env.get_global("sleep");
+    let (ret, _) = block_on(env.call_async(sleep, vec!["zzz".into()]));
+    assert_eq!(ret.unwrap(), vec![LuaValue::from("zzz")]);
+
+    let fail = env.get_global("fail");
+    let (ret, _) = block_on(env.call_async(fail, vec!["error".into()]));
+    assert!(matches!(ret, Err(crate::RuntimeError::Custom(msg)) if msg == "error".into()));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_function_outside_future_fails() {
+    use std::future::Future;
+
+    let mut env = env_with_async_functions();
+    assert!(matches!(
+        env.eval(b"return sleep(1)"),
+        Err(crate::RuntimeError::AsyncCallOutsideFuture)
+    ));
+
+    // dropping pending future aborts the execution
+    {
+        let future = env.eval_async(b"done = false sleep() done = true");
+        let waker = std::task::Waker::noop();
+        let mut cx = std::task::Context::from_waker(waker);
+        let mut future = std::pin::pin!(future);
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+    }
+    assert_eq!(
+        env.eval(b"return done, 1 + 1").unwrap(),
+        vec![false.into(), LuaValue::from(2 as IntType)]
+    );
+}
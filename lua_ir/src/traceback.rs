@@ -0,0 +1,76 @@
+use crate::LuaFunction;
+use crate::LuaThread;
+
+impl LuaThread {
+    /// Stack traceback of this thread, from the innermost function, skipping `level` entries.
+    ///
+    /// Each entry is a Lua function with its current line, or `[C]` for Rust functions.
+    /// Frames replaced by tail calls are shown as `(...tail calls...)`.
+    pub fn traceback(&self, level: usize) -> String {
+        let mut entries = self.traceback_entries();
+        entries.drain(..level.min(entries.len()));
+        format_traceback(entries)
+    }
+
+    /// Entries of [`LuaThread::traceback`], from the innermost function.
+    pub(crate) fn traceback_entries(&self) -> Vec<String> {
+        let mut entries = Vec::new();
+        for (idx, frame) in self.call_stack.iter().enumerate().rev() {
+            // Rust function waiting for a nested call, without its own frame
+            if self.boundaries.contains(&(idx + 1)) {
+                entries.push("[C]: in ?".to_string());
+            }
+            if frame.continuation.is_some() {
+                entries.push("[C]: in ?".to_string());
+                continue;
+            }
+            let function = frame.function.borrow();
+            let LuaFunction::LuaFunc(function) = &*function else {
+                entries.push("[C]: in ?".to_string());
+                continue;
+            };
+            let chunk = &function.prototype.chunk;
+            // the counter points to the instruction after the one being executed
+            let line = match chunk.lines.get(frame.counter.wrapping_sub(1)) {
+                Some(line) if *line > 0 => line.to_string(),
+                _ => "?".to_string(),
+            };
+            let what = if idx == 0 && !frame.tail_call && self.function.is_none() {
+                "main chunk".to_string()
+            } else {
+                match chunk.lines.first() {
+                    Some(defined) => format!("function <{}:{}>", chunk.source, defined),
+                    None => format!("function <{}>", chunk.source),
+                }
+            };
+            entries.push(format!("{}:{}: in {}", chunk.source, line, what));
+            if frame.tail_call {
+                entries.push("(...tail calls...)".to_string());
+            }
+        }
+
+        entries
+    }
+}
+
+/// number of innermost entries shown in a long traceback
+const LEVELS_TOP: usize = 10;
+/// number of outermost entries shown in a long traceback
+const LEVELS_BOTTOM: usize = 11;
+
+/// Format traceback `entries`; entries in the middle of a long traceback are skipped.
+pub(crate) fn format_traceback(entries: Vec<String>) -> String {
+    let mut traceback = "stack traceback:".to_string();
+    let skipped = entries.len().saturating_sub(LEVELS_TOP + LEVELS_BOTTOM);
+    for (idx, entry) in entries.iter().enumerate() {
+        if skipped > 0 && idx == LEVELS_TOP {
+            traceback.push_str(&format!("\n\t...\t(skipping {} levels)", skipped));
+        }
+        if skipped > 0 && (LEVELS_TOP..LEVELS_TOP + skipped).contains(&idx) {
+            continue;
+        }
+        traceback.push_str("\n\t");
+        traceback.push_str(entry);
+    }
+    traceback
+}
@@ -1,23 +1,47 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
 use std::rc::Rc;
 
-use rand::SeedableRng;
+use indexmap::IndexMap;
 
+use crate::builder::LuaEnvBuilder;
 use crate::builtin;
-use crate::luaval::RefOrValue;
+use crate::deterministic::Clock;
+use crate::deterministic::StableIds;
+use crate::error::catch_panic;
+use crate::limits::Usage;
+use crate::number::shift_left;
+use crate::number::shift_right;
+use crate::random::Xoshiro256;
+use crate::serialize::Malformed;
+use crate::stdio::StdStreams;
+use crate::table::FieldCache;
+use crate::FileSystem;
+use crate::FromLua;
 use crate::IntType;
+use crate::Limits;
 use crate::LuaFunction;
 use crate::LuaFunctionLua;
+use crate::LuaNumber;
 use crate::LuaString;
 use crate::LuaTable;
 use crate::LuaValue;
+use crate::OptLevel;
+use crate::Prototype;
+use crate::StdLib;
 
 use crate::Instruction;
 use crate::RuntimeError;
 
+/// maximum length of `__index` and `__newindex` chains of tables, to detect loops
+const MAX_TAG_LOOP: usize = 2000;
+
 /// Main entry for Lua runtime.
 /// It contains global environment, random number generator, coroutine stack, etc.
+///
+/// No Lua source code can panic `LuaEnv`; every failure is returned as a [`RuntimeError`].
+/// A panic in a Rust function called from Lua is caught and raised as [`RuntimeError::Panic`].
+/// Binary chunks are verified when they are loaded, so this holds for them too;
+/// see [`crate::LuaEnvBuilder::binary_chunks`].
 pub struct LuaEnv {
     /// _env
     pub(crate) env: Rc<RefCell<LuaTable>>,
@@ -25,41 +49,129 @@ pub struct LuaEnv {
     pub(crate) string_metatable: Rc<RefCell<LuaTable>>,
 
     /// random number generator
-    pub(crate) rng: rand::rngs::StdRng,
+    pub(crate) rng: Xoshiro256,
 
     /// coroutine stack
     pub(crate) coroutines: Vec<Rc<RefCell<LuaThread>>>,
 
     /// last operation (for error message)
-    pub(crate) last_op: String,
+    pub(crate) last_op: LuaString,
 
     pub(crate) parser_context: Option<lua_parser::Context>,
     pub(crate) semantic_context: lua_semantics::Context,
+
+    /// set while the dispatch loop is executing an instruction.
+    /// Function call in tail position of the instruction is run by the dispatch loop instead of a nested one.
+    pub(crate) dispatching: bool,
+    /// Rust function called directly by the dispatch loop, which can schedule continuation.
+    pub(crate) continuable: Option<Rc<RefCell<LuaFunction>>>,
+    /// set while the dispatch loop is driven by a future returned from `call_async` or `eval_async`.
+    /// Nested dispatch loops clear it, since only the outermost loop can be suspended.
+    pub(crate) awaitable: bool,
+    /// number of dispatch loops running on the native stack; checked against [`Limits::native_depth`]
+    pub(crate) native_depth: usize,
+    /// future of the async function the running thread is waiting for.
+    #[cfg(feature = "async")]
+    pub(crate) pending: Option<crate::future::PendingCall>,
+
+    /// execution limits
+    pub(crate) limits: Limits,
+    /// resources used, checked against `limits`
+    pub(crate) usage: Usage,
+
+    /// if false, loading precompiled binary chunks is not allowed
+    pub(crate) binary_chunks: bool,
+    /// optimization of compiled chunks
+    pub(crate) opt_level: OptLevel,
+    /// every file access goes through this
+    pub(crate) file_system: Rc<dyn FileSystem>,
+    /// stdin, stdout, stderr
+    pub(crate) streams: StdStreams,
+
+    /// time source of `os` library
+    pub(crate) clock: Clock,
+    /// IDs shown by `tostring` instead of addresses; `Some` in deterministic mode
+    pub(crate) stable_ids: Option<StableIds>,
+
+    /// Rust functions and userdata saved by name in snapshots; see [`LuaEnv::add_permanent`]
+    pub(crate) permanents: IndexMap<String, LuaValue>,
 }
 
 impl LuaEnv {
+    /// Create a new `LuaEnv` with every standard library loaded.
     pub fn new() -> LuaEnv {
-        let env = Rc::new(RefCell::new(builtin::init_env().unwrap()));
-        env.borrow_mut()
-            .insert("_G".into(), LuaValue::Table(Rc::clone(&env)));
+        Self::builder().build()
+    }
+
+    /// Builder to configure standard libraries and capabilities of `LuaEnv`.
+    pub fn builder() -> LuaEnvBuilder {
+        LuaEnvBuilder::default()
+    }
+
+    pub(crate) fn with_builder(builder: LuaEnvBuilder) -> LuaEnv {
+        let streams = StdStreams::default();
+        let env = Rc::new(RefCell::new(
+            builtin::init_env(builder.stdlib, builder.file_loading, &streams).unwrap(),
+        ));
+        if builder.stdlib.contains(StdLib::BASE) {
+            env.borrow_mut()
+                .insert("_G".into(), LuaValue::Table(Rc::clone(&env)));
+        }
 
         let string_metatable = builtin::init_string_metatable();
-        string_metatable.borrow_mut().insert(
-            "__index".into(),
-            env.borrow().get(&"string".into()).unwrap().clone(),
-        );
+        if let Some(string) = env.borrow().get(&"string".into()) {
+            string_metatable
+                .borrow_mut()
+                .insert("__index".into(), string.clone());
+        }
+        let permanents = crate::snapshot::collect_permanents(&env, &string_metatable);
         let mut semantic_context = lua_semantics::Context::new();
         semantic_context.begin_scope(false);
+        let (rng, clock, stable_ids) = match builder.deterministic {
+            Some((seed, clock)) => (
+                Xoshiro256::from_seed(seed as IntType, 0),
+                Clock::Virtual(clock),
+                Some(StableIds::default()),
+            ),
+            None => (
+                Xoshiro256::from_seed(
+                    rand::random::<u64>() as IntType,
+                    rand::random::<u64>() as IntType,
+                ),
+                Clock::System(std::time::Instant::now()),
+                None,
+            ),
+        };
         LuaEnv {
             env,
             string_metatable,
-            rng: rand::rngs::StdRng::from_entropy(),
+            rng,
 
             coroutines: vec![],
-            last_op: "last_op".to_string(),
+            last_op: LuaString::from_static_str("last_op"),
 
             parser_context: None,
             semantic_context,
+
+            dispatching: false,
+            continuable: None,
+            awaitable: false,
+            native_depth: 0,
+            #[cfg(feature = "async")]
+            pending: None,
+
+            limits: Limits::default(),
+            usage: Usage::default(),
+
+            binary_chunks: builder.binary_chunks,
+            opt_level: builder.opt_level,
+            file_system: builder.file_system,
+            streams,
+
+            clock,
+            stable_ids,
+
+            permanents,
         }
     }
 
@@ -113,13 +225,10 @@ impl LuaEnv {
             return Ok(());
         }
 
-        let res = self
-            .parser_context
-            .take()
-            .unwrap()
-            .accept_all()
-            .ok()
-            .unwrap();
+        let res = match self.parser_context.take().unwrap().accept_all() {
+            Ok(res) => res,
+            Err(err) => return Err(RuntimeError::Custom(err.to_string().into())),
+        };
 
         let mut matched_stmt = None;
         let mut matched_expr = None;
@@ -162,6 +271,8 @@ impl LuaEnv {
             matched_stmt = Some(blk);
         }
         if let Some(matched_stmt) = matched_stmt {
+            // scopes are left open on error; restore them for the next line
+            let semantic_context = self.semantic_context.clone();
             let processed_block =
                 match self
                     .semantic_context
@@ -169,12 +280,19 @@ impl LuaEnv {
                 {
                     Ok(res) => res,
                     Err(err) => {
+                        self.semantic_context = semantic_context;
                         return Err(RuntimeError::Custom(err.to_string().into()));
                     }
                 };
 
-            let ir_context = crate::Context::new();
-            let chunk = ir_context.emit(processed_block);
+            let ir_context = crate::Context::new().opt_level(self.opt_level);
+            let chunk = match ir_context.emit(processed_block) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.semantic_context = semantic_context;
+                    return Err(err);
+                }
+            };
             let thread = LuaThread::new_main(chunk);
             self.coroutines.push(Rc::new(RefCell::new(thread)));
 
@@ -194,99 +312,175 @@ impl LuaEnv {
 
     /// parse lua chunk from `source` and evaluate it.
     pub fn eval_chunk(&mut self, source: &[u8]) -> Result<(), RuntimeError> {
-        let chunk = self.load_chunk(source)?;
-        let thread = LuaThread::new_main(chunk);
-        self.coroutines.push(Rc::new(RefCell::new(thread)));
-
-        while !self.coroutines.is_empty() {
-            match self.cycle() {
-                Ok(_) => {}
-                Err(err) => {
-                    self.coroutines.clear();
-                    return Err(err);
-                }
-            }
-        }
+        let chunk = self.compile(source, "chunk")?;
+        self.run(&chunk, Vec::new())?;
         Ok(())
     }
 
-    pub(crate) fn load_chunk(&mut self, source: &[u8]) -> Result<Chunk, RuntimeError> {
-        self.parser_context = Some(lua_parser::Context::new(()));
+    /// Compile lua chunk from `source` without running it.
+    ///
+    /// `chunkname` is the name of the chunk, used for debug information.
+    /// The returned chunk does not depend on this `LuaEnv`,
+    /// it can be run multiple times, on any `LuaEnv` instance, with [`LuaEnv::run`].
+    pub fn compile(&self, source: &[u8], chunkname: &str) -> Result<CompiledChunk, RuntimeError> {
+        let block = parse_chunk(source)?;
+        Self::compile_block(block, source, chunkname, self.opt_level)
+    }
 
-        for token in lua_tokenizer::Tokenizer::from_bytes(source) {
-            match token {
-                Ok(token) => {
-                    match self
-                        .parser_context
-                        .as_mut()
-                        .unwrap()
-                        .feed(token)
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            self.clear_feed_pending();
-                            return Err(RuntimeError::Custom(err.to_string().into()));
-                        }
-                    }
-                }
-                Err(err) => {
-                    self.clear_feed_pending();
-                    return Err(RuntimeError::TokenizeError(err));
-                }
+    /// `source` is the source code of `block`, used for line numbers.
+    pub(crate) fn compile_block(
+        block: lua_parser::Block,
+        source: &[u8],
+        chunkname: &str,
+        opt_level: OptLevel,
+    ) -> Result<CompiledChunk, RuntimeError> {
+        // main chunk is treated as the body of a variadic function
+        let mut sem_context = lua_semantics::Context::new();
+        sem_context.begin_function_scope(true);
+        let processed_block = match sem_context.process_block(block, true, false) {
+            Ok(res) => res,
+            Err(err) => {
+                return Err(RuntimeError::Custom(err.to_string().into()));
             }
-        }
+        };
+        drop(sem_context);
+
+        let ir_context = crate::Context::with_source(source, chunkname).opt_level(opt_level);
+        let chunk = ir_context.emit(processed_block)?;
+        Ok(CompiledChunk {
+            prototype: Rc::new(Prototype {
+                args: 0,
+                is_variadic: true,
+                upvalues: 0,
+                chunk,
+            }),
+            name: Rc::from(chunkname),
+        })
+    }
 
-        match self
-            .parser_context
-            .take()
-            .unwrap()
-            .accept_all()
-        {
-            Ok(matched) => {
-                let mut matched_stmt = None;
-                for (m, _) in matched {
-                    match m {
-                        lua_parser::ChunkOrExpressions::Chunk(chunk) => {
-                            if matched_stmt.is_some() {
-                                return Err(RuntimeError::Custom("ambiguous statement".into()));
-                            }
-                            matched_stmt = Some(chunk);
-                        }
-                        lua_parser::ChunkOrExpressions::Expressions(_) => {}
-                    }
-                }
-                if let Some(matched_stmt) = matched_stmt {
-                    let mut sem_context = lua_semantics::Context::new();
-                    sem_context.begin_scope(false);
-                    let processed_block = match sem_context.process_block(matched_stmt, true, false)
-                    {
-                        Ok(res) => res,
-                        Err(err) => {
-                            return Err(RuntimeError::Custom(err.to_string().into()));
-                        }
-                    };
-                    drop(sem_context);
+    /// Evaluate `source` and return its values.
+    ///
+    /// `source` can be either a chunk, which returns the values of its `return` statement,
+    /// or a list of expressions, which returns the values of the expressions.
+    /// If `source` can be parsed as both, it is evaluated as expressions.
+    pub fn eval(&mut self, source: &[u8]) -> Result<Vec<LuaValue>, RuntimeError> {
+        let block = parse_chunk_or_expressions(source)?;
+        let chunk = Self::compile_block(block, source, "eval", self.opt_level)?;
+        self.run(&chunk, Vec::new())
+    }
 
-                    let ir_context = crate::Context::new();
-                    let chunk = ir_context.emit(processed_block);
-                    Ok(chunk)
-                } else {
-                    Err(RuntimeError::Custom("no statement found".into()))
+    /// Evaluate `source` and convert its first value to `T`.
+    /// If `source` returns no value, `nil` is converted.
+    pub fn eval_as<T: FromLua>(&mut self, source: &[u8]) -> Result<T, RuntimeError> {
+        let value = self.eval(source)?.into_iter().next().unwrap_or_default();
+        T::from_lua(value)
+    }
+
+    /// parse lua chunk from `source` and evaluate it with `env` as its `_ENV` table.
+    /// Global variables accessed in the chunk, and in functions defined in it, are looked up in `env`.
+    pub fn eval_chunk_with_env(
+        &mut self,
+        source: &[u8],
+        env: Rc<RefCell<LuaTable>>,
+    ) -> Result<(), RuntimeError> {
+        let chunk = self.compile(source, "chunk")?;
+        self.run_with_env(&chunk, env, Vec::new())?;
+        Ok(())
+    }
+
+    /// Run the compiled chunk on this `LuaEnv`.
+    ///
+    /// `args` are passed to the chunk as variadic arguments `...`.
+    /// Returns the values returned by the chunk.
+    pub fn run(
+        &mut self,
+        chunk: &CompiledChunk,
+        args: Vec<LuaValue>,
+    ) -> Result<Vec<LuaValue>, RuntimeError> {
+        self.run_chunk(chunk, None, args)
+    }
+
+    /// Run the compiled chunk on this `LuaEnv` with `env` as its `_ENV` table.
+    ///
+    /// `args` are passed to the chunk as variadic arguments `...`.
+    /// Returns the values returned by the chunk.
+    pub fn run_with_env(
+        &mut self,
+        chunk: &CompiledChunk,
+        env: Rc<RefCell<LuaTable>>,
+        args: Vec<LuaValue>,
+    ) -> Result<Vec<LuaValue>, RuntimeError> {
+        self.run_chunk(chunk, Some(env), args)
+    }
+
+    fn run_chunk(
+        &mut self,
+        chunk: &CompiledChunk,
+        env: Option<Rc<RefCell<LuaTable>>>,
+        args: Vec<LuaValue>,
+    ) -> Result<Vec<LuaValue>, RuntimeError> {
+        let thread = Rc::new(RefCell::new(LuaThread::new_chunk(chunk, env, args)));
+
+        self.enter_native()?;
+        let coroutine_len = self.coroutines.len();
+        self.coroutines.push(Rc::clone(&thread));
+        let awaitable = std::mem::take(&mut self.awaitable);
+        let mut res = Ok(());
+        while self.coroutines.len() > coroutine_len {
+            if let Err(err) = self.cycle() {
+                for thread in self.coroutines.drain(coroutine_len..) {
+                    thread.borrow_mut().set_dead();
                 }
+                res = Err(err);
+                break;
             }
-            Err(err) => Err(RuntimeError::Custom(err.to_string().into())),
         }
+        self.awaitable = awaitable;
+        self.native_depth -= 1;
+        res?;
+        let ret = std::mem::take(&mut thread.borrow_mut().data_stack);
+        Ok(ret)
     }
 
-    /// Read file from `path`.
-    /// Searches from current directory, then from `package.path`.
+    /// Read file from `path`, with the filesystem of this `LuaEnv`.
     pub(crate) fn read_file(&self, path: &str) -> Result<Vec<u8>, RuntimeError> {
-        std::fs::read(path).map_err(|e| RuntimeError::Custom(e.to_string().into()))
+        self.file_system
+            .read(path)
+            .map_err(|e| RuntimeError::Custom(crate::fs::error_message(path, &e).into()))
+    }
+
+    /// Filesystem used for every file access.
+    pub fn file_system(&self) -> &Rc<dyn FileSystem> {
+        &self.file_system
+    }
+    /// Replace filesystem used for every file access.
+    pub fn set_file_system(&mut self, file_system: impl FileSystem + 'static) {
+        self.file_system = Rc::new(file_system);
+    }
+
+    /// Returns `true` if loading precompiled binary chunks is allowed.
+    pub fn binary_chunks_allowed(&self) -> bool {
+        self.binary_chunks
+    }
+
+    /// Global environment table, `_G`.
+    pub fn globals(&self) -> Rc<RefCell<LuaTable>> {
+        Rc::clone(&self.env)
+    }
+
+    /// `_ENV` table of the running lua function.
+    fn current_function_env(&self) -> Rc<RefCell<LuaTable>> {
+        let thread = self.running_thread().borrow();
+        let func = thread.call_stack.last().unwrap().function.borrow();
+        match &*func {
+            LuaFunction::LuaFunc(LuaFunctionLua { env: Some(env), .. }) => Rc::clone(env),
+            _ => Rc::clone(&self.env),
+        }
     }
 
     /// Get global variable name `name`.
     pub fn get_global(&self, name: &str) -> LuaValue {
-        let name = LuaValue::String(LuaString::from_str(name));
+        let name = LuaValue::String(LuaString::from(name));
         self.env
             .borrow()
             .get(&name)
@@ -297,7 +491,7 @@ impl LuaEnv {
     /// Returns the old value of the variable, or `nil` if it doesn't exist.
     /// Settting a variable to `nil` is equivalent to deleting it.
     pub fn set_global(&mut self, name: &str, value: LuaValue) -> LuaValue {
-        let key = LuaValue::String(LuaString::from_str(name));
+        let key = LuaValue::String(LuaString::from(name));
         if value == LuaValue::Nil {
             self.env.borrow_mut().remove(&key).unwrap_or(LuaValue::Nil)
         } else {
@@ -369,6 +563,10 @@ impl LuaEnv {
             // @TODO: link `string` module here
             LuaValue::String(_) => self.string_metatable.borrow().get(&key.into()).cloned(),
             LuaValue::Table(table) => table.borrow().get_metavalue(key),
+            LuaValue::UserData(userdata) => userdata
+                .borrow()
+                .metatable()
+                .and_then(|meta| meta.borrow().get(&key.into()).cloned()),
             _ => None,
         }
     }
@@ -417,7 +615,11 @@ impl LuaEnv {
                     },
                     None => match top {
                         LuaValue::String(s) => LuaValue::String(s),
-                        top => top.to_string().into(),
+                        top => {
+                            let s = self.value_to_string(&top);
+                            self.charge_memory(s.len())?;
+                            s.into()
+                        }
                     },
                 };
                 self.push(s);
@@ -488,6 +690,9 @@ impl LuaEnv {
     pub fn mod_(&mut self) -> Result<(), RuntimeError> {
         let (lhs, rhs) = self.pop2();
         match (lhs, rhs) {
+            (LuaValue::Number(LuaNumber::Int(_)), LuaValue::Number(LuaNumber::Int(0))) => {
+                Err(RuntimeError::Custom("attempt to perform 'n%%0'".into()))
+            }
             (LuaValue::Number(lhs), LuaValue::Number(rhs)) => {
                 self.push((lhs % rhs).into());
                 Ok(())
@@ -537,6 +742,9 @@ impl LuaEnv {
     pub fn idiv(&mut self) -> Result<(), RuntimeError> {
         let (lhs, rhs) = self.pop2();
         match (lhs, rhs) {
+            (LuaValue::Number(LuaNumber::Int(_)), LuaValue::Number(LuaNumber::Int(0))) => {
+                Err(RuntimeError::Custom("attempt to perform 'n//0'".into()))
+            }
             (LuaValue::Number(lhs), LuaValue::Number(rhs)) => {
                 self.push((lhs.floor_div(rhs)).into());
                 Ok(())
@@ -620,18 +828,7 @@ impl LuaEnv {
             (LuaValue::Number(lhs_num), LuaValue::Number(rhs_num)) => {
                 match (lhs_num.try_to_int(), rhs_num.try_to_int()) {
                     (Ok(lhs), Ok(rhs)) => {
-                        self.push(
-                            if rhs <= -64 {
-                                0
-                            } else if rhs < 0 {
-                                lhs.wrapping_shr((-rhs) as u32)
-                            } else if rhs < 64 {
-                                lhs.wrapping_shl(rhs as u32)
-                            } else {
-                                0
-                            }
-                            .into(),
-                        );
+                        self.push(shift_left(lhs, rhs).into());
                         Ok(())
                     }
                     _ => self.try_call_metamethod(
@@ -653,18 +850,7 @@ impl LuaEnv {
             (LuaValue::Number(lhs_num), LuaValue::Number(rhs_num)) => {
                 match (lhs_num.try_to_int(), rhs_num.try_to_int()) {
                     (Ok(lhs), Ok(rhs)) => {
-                        self.push(
-                            if rhs <= -64 {
-                                0
-                            } else if rhs < 0 {
-                                lhs.wrapping_shl((-rhs) as u32)
-                            } else if rhs < 64 {
-                                lhs.wrapping_shr(rhs as u32)
-                            } else {
-                                0
-                            }
-                            .into(),
-                        );
+                        self.push(shift_right(lhs, rhs).into());
                         Ok(())
                     }
                     _ => self.try_call_metamethod(
@@ -721,12 +907,14 @@ impl LuaEnv {
                 LuaValue::Number(rhs_num) => {
                     let mut concated = lhs_num.to_string().into_bytes();
                     concated.append(&mut rhs_num.to_string().into_bytes());
+                    self.charge_memory(concated.len())?;
                     self.push(LuaString::from_vec(concated).into());
                     Ok(())
                 }
                 LuaValue::String(rhs) => {
                     let mut lhs = lhs_num.to_string().into_bytes();
                     lhs.extend_from_slice(rhs.as_bytes());
+                    self.charge_memory(lhs.len())?;
                     self.push(LuaString::from_vec(lhs).into());
                     Ok(())
                 }
@@ -742,12 +930,14 @@ impl LuaEnv {
                 LuaValue::Number(rhs_num) => {
                     let mut concated = lhs_str.into_vec();
                     concated.append(&mut rhs_num.to_string().into_bytes());
+                    self.charge_memory(concated.len())?;
                     self.push(LuaString::from_vec(concated).into());
                     Ok(())
                 }
                 LuaValue::String(rhs) => {
                     let mut lhs = lhs_str.into_vec();
                     lhs.extend_from_slice(rhs.as_bytes());
+                    self.charge_memory(lhs.len())?;
                     self.push(LuaString::from_vec(lhs).into());
                     Ok(())
                 }
@@ -802,106 +992,92 @@ impl LuaEnv {
     }
     /// table index get operation with __index metamethod
     pub fn index(&mut self) -> Result<(), RuntimeError> {
-        let (table, key) = self.pop2();
-        match table {
-            LuaValue::Table(table) => {
-                let get = table.borrow().get(&key).cloned();
-                if let Some(get) = get {
-                    self.push(get);
-                    Ok(())
-                } else {
+        let (mut table, key) = self.pop2();
+        // `__index` tables are followed in a loop, not by recursion
+        for _ in 0..MAX_TAG_LOOP {
+            let meta = match &table {
+                LuaValue::Table(table) => {
+                    let get = table.borrow().get(&key).cloned();
+                    if let Some(get) = get {
+                        self.push(get);
+                        return Ok(());
+                    }
                     let meta = table.borrow().get_metavalue("__index");
                     match meta {
-                        Some(LuaValue::Function(meta_func)) => {
-                            self.push2(LuaValue::Table(table), key);
-                            self.function_call(2, LuaValue::Function(meta_func), Some(1))?;
-                            Ok(())
-                        }
-                        Some(LuaValue::Table(meta_table)) => {
-                            self.push2(LuaValue::Table(meta_table), key);
-                            self.index()
-                        }
+                        Some(meta @ (LuaValue::Function(_) | LuaValue::Table(_))) => meta,
                         _ => {
                             self.push(LuaValue::Nil);
-                            Ok(())
+                            return Ok(());
                         }
                     }
                 }
-            }
-            table => {
-                let meta = self.get_metavalue(&table, "__index");
-                match meta {
-                    Some(LuaValue::Function(meta_func)) => {
-                        self.push2(table, key);
-                        self.function_call(2, LuaValue::Function(meta_func), Some(1))?;
-                        Ok(())
-                    }
-                    Some(LuaValue::Table(meta_table)) => {
-                        self.push2(LuaValue::Table(meta_table), key);
-                        self.index()
-                    }
-                    _ =>
+                table => match self.get_metavalue(table, "__index") {
+                    Some(meta @ (LuaValue::Function(_) | LuaValue::Table(_))) => meta,
                     // @TODO : error message
-                    {
-                        Err(RuntimeError::Custom("__index metamethod not found".into()))
-                    }
+                    _ => return Err(RuntimeError::Custom("__index metamethod not found".into())),
+                },
+            };
+            match meta {
+                LuaValue::Function(meta_func) => {
+                    self.push2(table, key);
+                    return self.function_call(2, LuaValue::Function(meta_func), Some(1));
                 }
+                meta_table => table = meta_table,
             }
         }
+        Err(RuntimeError::Custom(
+            "'__index' chain too long; possible loop".into(),
+        ))
     }
     /// table index set operation with __newindex metamethod
     pub fn newindex(&mut self) -> Result<(), RuntimeError> {
-        let (value, table, key) = self.pop3();
-
-        match table {
-            LuaValue::Table(table) => {
-                {
-                    let mut table_mut = table.borrow_mut();
-                    if let Some(val) = table_mut.get_mut(&key) {
-                        // if rhs is nil, remove the key
-                        if value.is_nil() {
-                            table_mut.remove(&key);
-                        } else {
-                            *val = value;
+        let (value, mut table, key) = self.pop3();
+        // `__newindex` tables are followed in a loop, not by recursion
+        for _ in 0..MAX_TAG_LOOP {
+            let meta = match &table {
+                LuaValue::Table(table) => {
+                    {
+                        let mut table_mut = table.borrow_mut();
+                        if let Some(val) = table_mut.get_mut(&key) {
+                            // if rhs is nil, remove the key
+                            if value.is_nil() {
+                                table_mut.remove(&key);
+                            } else {
+                                *val = value;
+                            }
+                            return Ok(());
                         }
-                        return Ok(());
-                    }
-                }
-                let meta = table.borrow().get_metavalue("__newindex");
-                match meta {
-                    Some(LuaValue::Function(meta_func)) => {
-                        self.push3(LuaValue::Table(table), key, value);
-                        self.function_call(3, LuaValue::Function(meta_func), Some(0))?;
-                        Ok(())
                     }
-                    Some(LuaValue::Table(meta_table)) => {
-                        self.push3(value, LuaValue::Table(meta_table), key);
-                        self.newindex()
-                    }
-                    _ => {
-                        table.borrow_mut().insert(key, value);
-                        Ok(())
+                    let meta = table.borrow().get_metavalue("__newindex");
+                    match meta {
+                        Some(meta @ (LuaValue::Function(_) | LuaValue::Table(_))) => meta,
+                        _ => {
+                            self.charge_table_entries(1)?;
+                            table.borrow_mut().insert(key, value);
+                            return Ok(());
+                        }
                     }
                 }
-            }
-            table => {
-                let meta = self.get_metavalue(&table, "__newindex");
-                match meta {
-                    Some(LuaValue::Function(meta_func)) => {
-                        self.push3(table, key, value);
-                        self.function_call(3, LuaValue::Function(meta_func), Some(0))?;
-                        Ok(())
-                    }
-                    Some(LuaValue::Table(meta_table)) => {
-                        self.push3(value, LuaValue::Table(meta_table), key);
-                        self.newindex()
+                table => match self.get_metavalue(table, "__newindex") {
+                    Some(meta @ (LuaValue::Function(_) | LuaValue::Table(_))) => meta,
+                    _ => {
+                        return Err(RuntimeError::Custom(
+                            "__newindex metamethod not found".into(),
+                        ))
                     }
-                    _ => Err(RuntimeError::Custom(
-                        "__newindex metamethod not found".into(),
-                    )),
+                },
+            };
+            match meta {
+                LuaValue::Function(meta_func) => {
+                    self.push3(table, key, value);
+                    return self.function_call(3, LuaValue::Function(meta_func), Some(0));
                 }
+                meta_table => table = meta_table,
             }
         }
+        Err(RuntimeError::Custom(
+            "'__newindex' chain too long; possible loop".into(),
+        ))
     }
     /// equality operation with __eq metamethod
     pub fn eq(&mut self) -> Result<(), RuntimeError> {
@@ -969,6 +1145,11 @@ impl LuaEnv {
 
     /// Function call with given function object.
     /// This could search for `__call` metamethod if the function object is not a function.
+    ///
+    /// The function is run until it returns, and its return values are left on the stack.
+    /// When called in tail position of an instruction being executed by the dispatch loop,
+    /// the call is only pushed to the call stack and run by the dispatch loop,
+    /// so it can yield.
     pub fn function_call(
         &mut self,
         // number of arguments actually passed
@@ -977,6 +1158,22 @@ impl LuaEnv {
         func: LuaValue,
         // number of return values expected; returned values will be adjusted to this number
         expected_ret: Option<usize>,
+    ) -> Result<(), RuntimeError> {
+        if std::mem::take(&mut self.dispatching) {
+            self.call_on_dispatch(args_num, func, expected_ret)
+        } else {
+            self.call_nested(args_num, func, expected_ret)
+        }
+    }
+
+    /// Push function call on the running thread, without waiting for it to return.
+    /// Frame of lua function will be run by the dispatch loop.
+    /// Rust function is called immediately, and it can schedule continuation with `call_k` or `yield_k`.
+    pub(crate) fn call_on_dispatch(
+        &mut self,
+        args_num: usize,
+        func: LuaValue,
+        expected_ret: Option<usize>,
     ) -> Result<(), RuntimeError> {
         match func {
             LuaValue::Function(func) => {
@@ -985,123 +1182,57 @@ impl LuaEnv {
                     LuaFunction::LuaFunc(lua_internal) => {
                         let mut thread_mut_ = self.running_thread().borrow_mut();
                         let thread_mut = &mut *thread_mut_;
+                        if thread_mut.call_stack.len() >= self.limits.call_depth {
+                            return Err(RuntimeError::StackOverflow);
+                        }
 
-                        // adjust function arguments
+                        // arguments are the first registers of the new frame
+                        let base = thread_mut.data_stack.len() - args_num;
                         // extract variadic arguments if needed
-                        if lua_internal.is_variadic {
-                            let (variadic, rest_args_num) = if args_num <= lua_internal.args {
-                                (Vec::new(), args_num)
-                            } else {
-                                (
-                                    thread_mut
-                                        .data_stack
-                                        .drain(
-                                            thread_mut.data_stack.len() - args_num
-                                                + lua_internal.args..,
-                                        )
-                                        .collect(),
-                                    lua_internal.args,
-                                )
-                            };
-
-                            // push call stack frame
-                            thread_mut.call_stack.push(CallStackFrame {
-                                function: Rc::clone(&func),
-                                usize_stack: thread_mut.usize_stack.len(),
-                                return_expected: expected_ret,
-                                variadic: variadic,
-                                bp: thread_mut.bp,
-                                counter: 0,
-                                data_stack: thread_mut.data_stack.len() - rest_args_num,
-                                local_variables: thread_mut.local_variables.len(),
-                            });
-
-                            // set base pointer to new stack frame
-                            thread_mut.bp = thread_mut.local_variables.len();
-                            // reserve stack space for local variables
-                            thread_mut.local_variables.resize_with(
-                                thread_mut.local_variables.len() + lua_internal.chunk.stack_size,
-                                Default::default,
-                            );
-
-                            // copy arguments to local variables
-                            for (idx, arg) in thread_mut
+                        let prototype = &lua_internal.prototype;
+                        let variadic = if prototype.is_variadic && args_num > prototype.args {
+                            thread_mut
                                 .data_stack
-                                .drain(thread_mut.data_stack.len() - rest_args_num..)
-                                .enumerate()
-                            {
-                                thread_mut.local_variables[thread_mut.bp + idx] =
-                                    RefOrValue::Value(arg);
-                            }
+                                .drain(base + prototype.args..)
+                                .collect()
                         } else {
-                            // push call stack frame
-                            thread_mut.call_stack.push(CallStackFrame {
-                                function: Rc::clone(&func),
-                                usize_stack: thread_mut.usize_stack.len(),
-                                return_expected: expected_ret,
-                                variadic: Vec::new(),
-                                bp: thread_mut.bp,
-                                counter: 0,
-                                data_stack: thread_mut.data_stack.len() - args_num,
-                                local_variables: thread_mut.local_variables.len(),
-                            });
-
-                            // set base pointer to new stack frame
-                            thread_mut.bp = thread_mut.local_variables.len();
-                            // reserve stack space for local variables
-                            thread_mut.local_variables.resize_with(
-                                thread_mut.local_variables.len() + lua_internal.chunk.stack_size,
-                                Default::default,
-                            );
-
-                            // copy arguments to local variables
-                            for (idx, arg) in thread_mut
-                                .data_stack
-                                .drain(thread_mut.data_stack.len() - args_num..)
-                                .take(lua_internal.args)
-                                .enumerate()
-                            {
-                                thread_mut.local_variables[thread_mut.bp + idx] =
-                                    RefOrValue::Value(arg);
-                            }
+                            Vec::new()
                         };
-                        drop(thread_mut_);
-                        drop(func_borrow);
-                        drop(func);
-
-                        let coroutine_len = self.coroutines.len();
-                        let call_stack_len = self.running_thread().borrow().call_stack.len();
-                        loop {
-                            if self.coroutines.len() < coroutine_len {
-                                break;
-                            } else if self.coroutines.len() == coroutine_len
-                                && self.coroutines[coroutine_len - 1].borrow().call_stack.len()
-                                    < call_stack_len
-                            {
-                                break;
-                            }
 
-                            self.cycle()?;
-                        }
-                        Ok(())
+                        // push call stack frame
+                        thread_mut.call_stack.push(CallStackFrame {
+                            function: Rc::clone(&func),
+                            return_expected: expected_ret,
+                            variadic,
+                            bp: thread_mut.bp,
+                            counter: 0,
+                            data_stack: base,
+                            cells: Vec::new(),
+                            pending_result: None,
+                            continuation: None,
+                            tail_call: false,
+                        });
+
+                        // set base pointer to new stack frame
+                        thread_mut.bp = base;
+                        // adjust arguments, and reserve registers
+                        thread_mut
+                            .data_stack
+                            .truncate(base + prototype.args.min(args_num));
+                        thread_mut
+                            .data_stack
+                            .resize_with(base + prototype.chunk.stack_size, Default::default);
+                        let grown = thread_mut.stack_growth(self.usage.epoch);
+                        drop(thread_mut_);
+                        self.charge_memory(grown)
                     }
                     LuaFunction::RustFunc(rust_internal) => {
-                        // self.running_thread()
-                        //     .borrow_mut()
-                        //     .call_stack
-                        //     .push(CallStackFrame {
-                        //         bp: 0,
-                        //         counter: 0,
-                        //         data_stack: 0,
-                        //         function: Rc::clone(&func),
-                        //         local_variables: 0,
-                        //         return_expected: expected_ret,
-                        //         usize_stack: 0,
-                        //         variadic: Vec::new(),
-                        //     });
-                        rust_internal(self, args_num, expected_ret)?;
-                        // self.running_thread().borrow_mut().call_stack.pop();
-                        Ok(())
+                        let continuable = self.continuable.replace(Rc::clone(&func));
+                        let res = catch_panic(|| rust_internal(self, args_num, expected_ret));
+                        self.continuable = continuable;
+                        // returned values are left on the stack
+                        res?;
+                        self.charge_stack()
                     }
                 }
             }
@@ -1117,7 +1248,11 @@ impl LuaEnv {
                             .data_stack
                             .insert(front_arg_pos, other);
                     }
-                    self.function_call(args_num + 1, meta, expected_ret)
+                    // `__call` of `__call` ... is resolved recursively
+                    self.enter_native()?;
+                    let res = self.call_on_dispatch(args_num + 1, meta, expected_ret);
+                    self.native_depth -= 1;
+                    res
                 } else {
                     // @TODO : error message
                     let msg = format!("__call metamethod not found for {}", other);
@@ -1127,351 +1262,981 @@ impl LuaEnv {
         }
     }
 
-    /// execute single instruction
-    pub fn run_instruction(&mut self, instruction: Instruction) -> Result<(), RuntimeError> {
-        debug_assert!(self.coroutines.is_empty() == false);
-        match instruction {
-            Instruction::Clone => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let top = thread_mut.data_stack.last().unwrap().clone();
-                thread_mut.data_stack.push(top);
-            }
-            Instruction::Sp => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let len = thread_mut.data_stack.len();
-                thread_mut.usize_stack.push(len);
-            }
-            Instruction::Pop => {
-                self.pop();
-            }
-            Instruction::Deref => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let sp = *thread_mut.usize_stack.last().unwrap();
-                let top = thread_mut.data_stack[sp].clone();
-                thread_mut.data_stack.push(top);
-            }
-            Instruction::Jump(label) => {
-                let mut thread = self.running_thread().borrow_mut();
-                let frame = thread.call_stack.last_mut().unwrap();
-                let func = frame.function.borrow();
-                let next_pc = match &*func {
-                    LuaFunction::LuaFunc(f) => f.chunk.label_map.get(label).unwrap(),
-                    _ => unreachable!("jump from non-lua function"),
-                };
-                frame.counter = *next_pc;
-            }
-            Instruction::JumpTrue(label) => {
-                let mut thread = self.running_thread().borrow_mut();
-                let top = thread.data_stack.pop().unwrap().to_bool();
-                if top {
-                    let frame = thread.call_stack.last_mut().unwrap();
-                    let func = frame.function.borrow();
-                    let next_pc = match &*func {
-                        LuaFunction::LuaFunc(f) => f.chunk.label_map.get(label).unwrap(),
-                        _ => unreachable!("jump from non-lua function"),
-                    };
-                    frame.counter = *next_pc;
-                }
-            }
-            Instruction::JumpFalse(label) => {
-                let mut thread = self.running_thread().borrow_mut();
-                let top = thread.data_stack.pop().unwrap().to_bool();
-                if !top {
-                    let frame = thread.call_stack.last_mut().unwrap();
-                    let func = frame.function.borrow();
-                    let next_pc = match &*func {
-                        LuaFunction::LuaFunc(f) => f.chunk.label_map.get(label).unwrap(),
-                        _ => unreachable!("jump from non-lua function"),
-                    };
-                    frame.counter = *next_pc;
-                }
-            }
-            Instruction::GetLocalVariable(local_id, name) => {
-                self.last_op = name;
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let local_idx = local_id + thread_mut.bp;
-                let val = match thread_mut.local_variables.get(local_idx).unwrap() {
-                    RefOrValue::Ref(val) => val.borrow().clone(),
-                    RefOrValue::Value(val) => val.clone(),
-                };
-                thread_mut.data_stack.push(val);
-            }
-            Instruction::SetLocalVariable(local_id) => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let top = thread_mut.data_stack.pop().unwrap();
-                let local_idx = local_id + thread_mut.bp;
-                match thread_mut.local_variables.get_mut(local_idx).unwrap() {
-                    RefOrValue::Ref(val) => {
-                        val.replace(top);
-                    }
-                    RefOrValue::Value(val) => {
-                        *val = top;
-                    }
-                }
-            }
-            Instruction::InitLocalVariable(local_id) => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let top = thread_mut.data_stack.pop().unwrap();
-                let local_idx = local_id + thread_mut.bp;
-                if thread_mut.local_variables.len() <= local_idx {
-                    let gap = local_idx - thread_mut.local_variables.len() + 1;
-                    let new_len = thread_mut.local_variables.len() + 2 * gap;
-                    thread_mut
-                        .local_variables
-                        .resize_with(new_len, Default::default);
+    /// Call `func` for `return func(args)`, replacing the frame of the running Lua function,
+    /// so tail-recursive functions run in constant stack space.
+    ///
+    /// Other functions are called as a normal call,
+    /// and the `Return` instruction following the tail call returns their values.
+    fn tail_call(&mut self, args_num: usize, func: LuaValue) -> Result<(), RuntimeError> {
+        let func = match func {
+            LuaValue::Function(func) if matches!(&*func.borrow(), LuaFunction::LuaFunc(_)) => func,
+            func => return self.function_call(args_num, func, None),
+        };
+        self.dispatching = false;
+
+        // pop the caller's frame, keeping the arguments on the data stack
+        let return_expected = {
+            let mut thread_mut = self.running_thread().borrow_mut();
+            let frame = thread_mut.call_stack.pop().unwrap();
+            let args_start = thread_mut.data_stack.len() - args_num;
+            let args: Vec<_> = thread_mut.data_stack.drain(args_start..).collect();
+            thread_mut.data_stack.truncate(frame.data_stack);
+            thread_mut.data_stack.extend(args);
+            thread_mut.bp = frame.bp;
+            frame.return_expected
+        };
+        self.call_on_dispatch(args_num, LuaValue::Function(func), return_expected)?;
+        self.running_thread()
+            .borrow_mut()
+            .call_stack
+            .last_mut()
+            .unwrap()
+            .tail_call = true;
+        Ok(())
+    }
+
+    /// Call function and run nested dispatch loop until it returns.
+    /// The running thread cannot yield until the call returns.
+    /// On error, the stack of the running thread is restored as if the function was never called.
+    fn call_nested(
+        &mut self,
+        args_num: usize,
+        func: LuaValue,
+        expected_ret: Option<usize>,
+    ) -> Result<(), RuntimeError> {
+        let thread = Rc::clone(self.running_thread());
+        let mut state = thread.borrow().to_state();
+        state.data_stack -= args_num;
+        if let Err(err) = self.enter_native() {
+            thread.borrow_mut().from_state(state);
+            return Err(err);
+        }
+        let base = state.call_stack;
+        thread.borrow_mut().boundaries.push(base);
+        let continuable = self.continuable.take();
+        let awaitable = std::mem::take(&mut self.awaitable);
+        let coroutine_len = self.coroutines.len();
+
+        let mut res = self.call_on_dispatch(args_num, func, expected_ret);
+        if res.is_ok() {
+            // coroutine resumed by the callee is run by this loop too
+            while self.coroutines.len() > coroutine_len || thread.borrow().call_stack.len() > base {
+                if let Err(err) = self.cycle() {
+                    res = Err(err);
+                    break;
                 }
-                *thread_mut.local_variables.get_mut(local_idx).unwrap() = RefOrValue::Value(top);
-            }
-            Instruction::IsNil => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let top = thread_mut.data_stack.pop().unwrap();
-                thread_mut.data_stack.push(LuaValue::Boolean(top.is_nil()));
             }
+        }
 
-            Instruction::Nil => {
-                self.push(LuaValue::Nil);
-            }
-            Instruction::Boolean(b) => {
-                self.push(LuaValue::Boolean(b));
-            }
-            Instruction::Numeric(n) => {
-                self.push(LuaValue::Number(n));
-            }
-            Instruction::String(s) => {
-                self.last_op = String::from_utf8_lossy(&s).to_string();
-                self.push(LuaString::from_vec(s).into());
-            }
-            Instruction::GetEnv => {
-                let env = Rc::clone(&self.env);
-                self.push(LuaValue::Table(env));
-            }
-            Instruction::TableInit(cap) => {
-                let table = LuaTable::with_capacity(cap);
-                self.push(table.into());
-            }
-            Instruction::TableIndexInit => {
-                let (table, index, value) = self.pop3();
-                if let LuaValue::Table(table) = table {
-                    table.borrow_mut().insert(index, value);
-                    self.push(LuaValue::Table(table));
-                } else {
-                    unreachable!("table must be on top of stack");
-                }
+        thread.borrow_mut().boundaries.pop();
+        if res.is_err() {
+            thread.borrow_mut().from_state(state);
+        }
+        self.continuable = continuable;
+        self.awaitable = awaitable;
+        self.native_depth -= 1;
+        res
+    }
+
+    /// Returns `true` if the running Rust function was called directly by the dispatch loop,
+    /// so `call_k`, `pcall_k` can schedule their continuation instead of calling it immediately.
+    pub fn is_continuable(&self) -> bool {
+        self.continuable.is_some()
+    }
+
+    /// Call function `func` with `args_num` arguments on the top of the stack,
+    /// and then call continuation `k` with the number of returned values.
+    ///
+    /// This must be called in tail position of a Rust function,
+    /// since if the Rust function was called by the dispatch loop,
+    /// the function call is scheduled and `k` is called later, after `func` returns.
+    /// It allows `func` to yield, resuming at `k` after the coroutine is resumed.
+    /// Otherwise, `func` is called immediately and `k` is called before this returns.
+    ///
+    /// Error raised by `func` is propagated without calling `k`.
+    pub fn call_k(
+        &mut self,
+        args_num: usize,
+        func: LuaValue,
+        expected_ret: Option<usize>,
+        k: impl FnOnce(&mut LuaEnv, Result<usize, RuntimeError>) -> Result<(), RuntimeError> + 'static,
+    ) -> Result<(), RuntimeError> {
+        self.call_k_impl(args_num, func, expected_ret, false, Box::new(k))
+    }
+
+    /// Protected version of [`LuaEnv::call_k`].
+    /// If `func` raises an error, the stack is restored as before the call,
+    /// and `k` is called with the error.
+    pub fn pcall_k(
+        &mut self,
+        args_num: usize,
+        func: LuaValue,
+        expected_ret: Option<usize>,
+        k: impl FnOnce(&mut LuaEnv, Result<usize, RuntimeError>) -> Result<(), RuntimeError> + 'static,
+    ) -> Result<(), RuntimeError> {
+        self.call_k_impl(args_num, func, expected_ret, true, Box::new(k))
+    }
+
+    fn call_k_impl(
+        &mut self,
+        args_num: usize,
+        func: LuaValue,
+        expected_ret: Option<usize>,
+        protected: bool,
+        k: ContinuationFn,
+    ) -> Result<(), RuntimeError> {
+        let base = self.running_thread().borrow().data_stack.len() - args_num;
+        if let Some(function) = self.continuable.take() {
+            // push continuation frame below the callee's frame;
+            // the dispatch loop will call `k` after the callee returns.
+            self.push_continuation_frame(function, base, protected, k);
+            return self.call_on_dispatch(args_num, func, expected_ret);
+        }
+
+        match self.call_nested(args_num, func, expected_ret) {
+            Ok(_) => {
+                let returned = self.running_thread().borrow().data_stack.len() - base;
+                k(self, Ok(returned))
             }
-            Instruction::TableInitLast(start_key) => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let sp = thread_mut.usize_stack.pop().unwrap();
-                let mut values: BTreeMap<_, _> = thread_mut
-                    .data_stack
-                    .drain(sp..)
-                    .enumerate()
-                    .map(|(idx, value)| {
-                        let index = idx as IntType + start_key;
-                        (index.into(), value)
-                    })
-                    .collect();
-                if let LuaValue::Table(table) = thread_mut.data_stack.last().unwrap() {
-                    table.borrow_mut().arr.append(&mut values);
+            Err(err) => {
+                if protected && err.is_catchable() {
+                    k(self, Err(err))
                 } else {
-                    unreachable!("table must be on top of stack");
+                    Err(err)
                 }
             }
+        }
+    }
 
-            Instruction::TableIndex => {
-                self.index()?;
-            }
-            Instruction::TableIndexSet => {
-                self.newindex()?;
-            }
-
-            Instruction::FunctionInit(func) => {
-                self.push(LuaFunction::LuaFunc(*func).into());
-            }
-            Instruction::FunctionInitUpvalueFromLocalVar(src_local_id) => {
-                let mut thread_mut = self.running_thread().borrow_mut();
-                let local_idx = src_local_id + thread_mut.bp;
-                let local_var = thread_mut.local_variables.get_mut(local_idx).unwrap();
-                // upvalue must be reference.
-                let local_var = match local_var {
-                    RefOrValue::Ref(r) => Rc::clone(r),
-                    RefOrValue::Value(v) => {
-                        let reffed_var = Rc::new(RefCell::new(v.clone()));
-                        *local_var = RefOrValue::Ref(Rc::clone(&reffed_var));
-                        reffed_var
-                    }
-                };
-                match thread_mut.data_stack.last().unwrap() {
-                    LuaValue::Function(func) => match &mut *func.borrow_mut() {
-                        LuaFunction::LuaFunc(f) => {
-                            f.upvalues.push(local_var);
-                        }
-                        _ => unreachable!("stack top must be function"),
-                    },
-                    _ => unreachable!("stack top must be function"),
+    /// Yield from the running coroutine with `args_num` values on the top of the stack,
+    /// and call continuation `k` with the number of values passed to `resume` when the coroutine is resumed.
+    ///
+    /// This must be called in tail position of a Rust function called by the dispatch loop.
+    pub fn yield_k(
+        &mut self,
+        args_num: usize,
+        k: impl FnOnce(&mut LuaEnv, Result<usize, RuntimeError>) -> Result<(), RuntimeError> + 'static,
+    ) -> Result<(), RuntimeError> {
+        self.check_yieldable()?;
+        let function = match self.continuable.take() {
+            Some(function) => function,
+            None => return Err(RuntimeError::YieldAcrossBoundary),
+        };
+        let base = self.running_thread().borrow().data_stack.len() - args_num;
+        self.push_continuation_frame(function, base, false, Box::new(k));
+        builtin::coroutine::yield_(self, args_num, None)
+    }
+
+    /// Check if the running thread can yield.
+    pub(crate) fn check_yieldable(&self) -> Result<(), RuntimeError> {
+        let thread = self.running_thread().borrow();
+        if thread.function.is_none() {
+            Err(RuntimeError::YieldOutsideCoroutine)
+        } else if !thread.boundaries.is_empty() {
+            Err(RuntimeError::YieldAcrossBoundary)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn push_continuation_frame(
+        &mut self,
+        function: Rc<RefCell<LuaFunction>>,
+        data_stack: usize,
+        protected: bool,
+        k: ContinuationFn,
+    ) {
+        let mut thread_mut = self.running_thread().borrow_mut();
+        let frame = CallStackFrame {
+            function,
+            counter: 0,
+            return_expected: None,
+            variadic: Vec::new(),
+            data_stack,
+            bp: thread_mut.bp,
+            cells: Vec::new(),
+            pending_result: None,
+            continuation: Some(Continuation { k, protected }),
+            tail_call: false,
+        };
+        thread_mut.call_stack.push(frame);
+    }
+
+    /// Call continuation of `frame`, which was popped from the call stack.
+    fn call_continuation(
+        &mut self,
+        frame: CallStackFrame,
+        res: Result<usize, RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        let k = frame.continuation.unwrap().k;
+        let continuable = self.continuable.replace(frame.function);
+        let ret = catch_panic(|| k(self, res));
+        self.continuable = continuable;
+        ret
+    }
+
+    /// Jump to instruction `target` of the running Lua function.
+    fn jump(thread: &mut LuaThread, target: usize) {
+        thread.call_stack.last_mut().unwrap().counter = target;
+    }
+
+    /// Run operation `op` on the operands pushed on the stack;
+    /// its result is stored into `R[dst]` before the next instruction.
+    fn pending_op(
+        &mut self,
+        dst: usize,
+        op: fn(&mut LuaEnv) -> Result<(), RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        self.running_thread()
+            .borrow_mut()
+            .call_stack
+            .last_mut()
+            .unwrap()
+            .pending_result = Some(dst);
+        op(self)
+    }
+
+    /// `R[dst] = R[lhs] op R[rhs]`.
+    /// `fast` computes the result of primitive operands in place;
+    /// if it returns `None`, the operands are passed to `slow`, which may call metamethods.
+    fn binary_op(
+        &mut self,
+        dst: usize,
+        lhs: usize,
+        rhs: usize,
+        fast: impl FnOnce(&LuaValue, &LuaValue) -> Option<LuaValue>,
+        slow: fn(&mut LuaEnv) -> Result<(), RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        let mut thread_mut = self.running_thread().borrow_mut();
+        let bp = thread_mut.bp;
+        let lhs = &thread_mut.data_stack[bp + lhs];
+        let rhs = &thread_mut.data_stack[bp + rhs];
+        match fast(lhs, rhs) {
+            Some(res) => {
+                thread_mut.data_stack[bp + dst] = res;
+                Ok(())
+            }
+            None => {
+                let (lhs, rhs) = (lhs.clone(), rhs.clone());
+                thread_mut.data_stack.push(lhs);
+                thread_mut.data_stack.push(rhs);
+                drop(thread_mut);
+                self.pending_op(dst, slow)
+            }
+        }
+    }
+
+    /// `R[dst] = op R[src]`, see [`LuaEnv::binary_op`].
+    fn unary_op(
+        &mut self,
+        dst: usize,
+        src: usize,
+        fast: impl FnOnce(&LuaValue) -> Option<LuaValue>,
+        slow: fn(&mut LuaEnv) -> Result<(), RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        let mut thread_mut = self.running_thread().borrow_mut();
+        let bp = thread_mut.bp;
+        let src = &thread_mut.data_stack[bp + src];
+        match fast(src) {
+            Some(res) => {
+                thread_mut.data_stack[bp + dst] = res;
+                Ok(())
+            }
+            None => {
+                let src = src.clone();
+                thread_mut.data_stack.push(src);
+                drop(thread_mut);
+                self.pending_op(dst, slow)
+            }
+        }
+    }
+
+    /// Closure in `R[func]`, to add upvalues to.
+    /// Only malformed code leaves anything else there.
+    fn with_closure(
+        thread: &LuaThread,
+        func: usize,
+        upvalue: Rc<RefCell<LuaValue>>,
+    ) -> Result<(), RuntimeError> {
+        match &thread.data_stack[thread.bp + func] {
+            LuaValue::Function(func) => match &mut *func.borrow_mut() {
+                LuaFunction::LuaFunc(f) => {
+                    f.upvalues.push(upvalue);
+                    Ok(())
                 }
+                _ => Err(Malformed.into()),
+            },
+            _ => Err(Malformed.into()),
+        }
+    }
+
+    /// `for` loop value must be a number; `what` is the name of the value for error message.
+    fn for_number(value: &LuaValue, what: &str) -> Result<LuaNumber, RuntimeError> {
+        match value {
+            LuaValue::Number(num) => Ok(*num),
+            _ => Err(RuntimeError::Custom(
+                format!("'for' {} must be a number", what).into(),
+            )),
+        }
+    }
+
+    /// execute single instruction
+    pub fn run_instruction(&mut self, instruction: &Instruction) -> Result<(), RuntimeError> {
+        debug_assert!(self.coroutines.is_empty() == false);
+        match *instruction {
+            Instruction::Move(dst, src) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                let value = thread_mut.data_stack[bp + src].clone();
+                thread_mut.data_stack[bp + dst] = value;
+            }
+            Instruction::Jump(target) => {
+                Self::jump(&mut self.running_thread().borrow_mut(), target);
+            }
+            Instruction::JumpTrue(src, target) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                if thread_mut.data_stack[bp + src].to_bool() {
+                    Self::jump(&mut thread_mut, target);
+                }
+            }
+            Instruction::JumpFalse(src, target) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                if !thread_mut.data_stack[bp + src].to_bool() {
+                    Self::jump(&mut thread_mut, target);
+                }
+            }
+            Instruction::InitCell(cell) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let thread_mut = &mut *thread_mut;
+                let value = thread_mut.data_stack[thread_mut.bp + cell].clone();
+                let frame = thread_mut.call_stack.last_mut().unwrap();
+                *frame.cell(cell) = Rc::new(RefCell::new(value));
+            }
+            Instruction::GetCell(dst, cell) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let thread_mut = &mut *thread_mut;
+                let frame = thread_mut.call_stack.last_mut().unwrap();
+                let value = frame.cell(cell).borrow().clone();
+                thread_mut.data_stack[thread_mut.bp + dst] = value;
+            }
+            Instruction::SetCell(cell, src) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let thread_mut = &mut *thread_mut;
+                let value = thread_mut.data_stack[thread_mut.bp + src].clone();
+                let frame = thread_mut.call_stack.last_mut().unwrap();
+                *frame.cell(cell).borrow_mut() = value;
+            }
+            Instruction::IsNil(dst, src) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                let is_nil = thread_mut.data_stack[bp + src].is_nil();
+                thread_mut.data_stack[bp + dst] = LuaValue::Boolean(is_nil);
+            }
+
+            Instruction::Nil(dst, count) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst..bp + dst + count].fill(LuaValue::Nil);
+            }
+            Instruction::Boolean(dst, b) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = LuaValue::Boolean(b);
             }
-            Instruction::FunctionInitUpvalueFromUpvalue(src_upvalue_id) => {
+            Instruction::Numeric(dst, n) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = LuaValue::Number(n);
+            }
+            Instruction::String(dst, index) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let s = match &*thread_mut.call_stack.last().unwrap().function.borrow() {
+                    LuaFunction::LuaFunc(f) => f.prototype.chunk.constants[index].clone(),
+                    _ => unreachable!("function must be LuaFunc"),
+                };
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = LuaValue::String(s.clone());
+                drop(thread_mut);
+                self.last_op = s;
+            }
+            Instruction::GetEnv(dst) => {
+                let env = self.current_function_env();
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = LuaValue::Table(env);
+            }
+            Instruction::TableInit(dst, cap) => {
+                self.charge_table(cap)?;
+                let table = LuaTable::with_capacity(cap);
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = table.into();
+            }
+            Instruction::TableIndexInit(table, key, value) => {
+                self.charge_table_entries(1)?;
                 let thread = self.running_thread().borrow();
-                let func = thread.call_stack.last().unwrap().function.borrow();
+                let bp = thread.bp;
+                if let LuaValue::Table(table) = &thread.data_stack[bp + table] {
+                    let key = thread.data_stack[bp + key].clone();
+                    let value = thread.data_stack[bp + value].clone();
+                    table.borrow_mut().insert_init(key, value);
+                } else {
+                    return Err(Malformed.into());
+                }
+            }
+            Instruction::TableInitLast(table, base, count, start_key) => {
+                let (from, count) = {
+                    let thread = self.running_thread().borrow();
+                    let from = thread.bp + base;
+                    (from, count.unwrap_or(thread.data_stack.len() - from))
+                };
+                self.charge_table_entries(count)?;
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let top = thread_mut.frame_top();
+                let values: Vec<_> = thread_mut.data_stack[from..from + count].to_vec();
+                // values up to the top of the stack are consumed
+                thread_mut.data_stack.resize_with(top, Default::default);
+                let bp = thread_mut.bp;
+                if let LuaValue::Table(table) = &thread_mut.data_stack[bp + table] {
+                    let mut table = table.borrow_mut();
+                    for (idx, value) in values.into_iter().enumerate() {
+                        table.insert_init((idx as IntType + start_key).into(), value);
+                    }
+                } else {
+                    return Err(Malformed.into());
+                }
+            }
 
-                let val = match &*func {
-                    LuaFunction::LuaFunc(f) => Rc::clone(&f.upvalues[src_upvalue_id]),
-                    _ => {
-                        unreachable!("function must be LuaFunc");
+            Instruction::TableIndex(dst, table, key) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                let table = &thread_mut.data_stack[bp + table];
+                let key = &thread_mut.data_stack[bp + key];
+                let value = match table {
+                    LuaValue::Table(table) => {
+                        let table = table.borrow();
+                        match table.get(key) {
+                            Some(value) => Some(value.clone()),
+                            None if table.meta.is_none() => Some(LuaValue::Nil),
+                            None => None,
+                        }
                     }
+                    _ => None,
                 };
-                drop(func);
-                drop(thread);
+                match value {
+                    Some(value) => {
+                        thread_mut.data_stack[bp + dst] = value;
+                    }
+                    None => {
+                        let (table, key) = (table.clone(), key.clone());
+                        thread_mut.data_stack.push(table);
+                        thread_mut.data_stack.push(key);
+                        drop(thread_mut);
+                        self.pending_op(dst, Self::index)?;
+                    }
+                }
+            }
+            Instruction::TableIndexSet(table, key, value) => {
+                // existing key, or new key of table without metatable
+                let new_key = {
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    match &thread.data_stack[bp + table] {
+                        LuaValue::Table(table) => {
+                            let mut table = table.borrow_mut();
+                            let key = &thread.data_stack[bp + key];
+                            let value = &thread.data_stack[bp + value];
+                            if let Some(slot) = table.get_mut(key) {
+                                // if rhs is nil, remove the key
+                                if value.is_nil() {
+                                    table.remove(key);
+                                } else {
+                                    *slot = value.clone();
+                                }
+                                return Ok(());
+                            }
+                            table.meta.is_none()
+                        }
+                        _ => false,
+                    }
+                };
+                if new_key {
+                    self.charge_table_entries(1)?;
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    if let LuaValue::Table(table) = &thread.data_stack[bp + table] {
+                        let key = thread.data_stack[bp + key].clone();
+                        let value = thread.data_stack[bp + value].clone();
+                        table.borrow_mut().insert(key, value);
+                    }
+                    return Ok(());
+                }
+                let (table, key, value) = {
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    (
+                        thread.data_stack[bp + table].clone(),
+                        thread.data_stack[bp + key].clone(),
+                        thread.data_stack[bp + value].clone(),
+                    )
+                };
+                self.push3(value, table, key);
+                self.newindex()?;
+            }
 
-                match self.running_thread().borrow().data_stack.last().unwrap() {
-                    LuaValue::Function(func) => match &mut *func.borrow_mut() {
-                        LuaFunction::LuaFunc(f) => {
-                            f.upvalues.push(val);
+            Instruction::GetField(dst, table, key, cache) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let thread = &mut *thread_mut;
+                let bp = thread.bp;
+                let function = thread.call_stack.last().unwrap().function.borrow();
+                let chunk = match &*function {
+                    LuaFunction::LuaFunc(f) => &f.prototype.chunk,
+                    _ => unreachable!("function must be LuaFunc"),
+                };
+                let key = &chunk.constants[key];
+                let table = &thread.data_stack[bp + table];
+                let value = match table {
+                    LuaValue::Table(table) => {
+                        let table = table.borrow();
+                        match table.get_field(key, &chunk.caches[cache]) {
+                            Some(value) => Some(value.clone()),
+                            None if table.meta.is_none() => Some(LuaValue::Nil),
+                            None => None,
                         }
-                        _ => unreachable!("stack top must be function"),
-                    },
-                    _ => unreachable!("stack top must be function"),
+                    }
+                    _ => None,
+                };
+                let key = key.clone();
+                match value {
+                    Some(value) => {
+                        drop(function);
+                        thread.data_stack[bp + dst] = value;
+                        drop(thread_mut);
+                    }
+                    None => {
+                        let table = table.clone();
+                        drop(function);
+                        thread.data_stack.push(table);
+                        thread.data_stack.push(LuaValue::String(key.clone()));
+                        drop(thread_mut);
+                        self.pending_op(dst, Self::index)?;
+                    }
+                }
+                self.last_op = key;
+            }
+            Instruction::SetField(table, key, value, cache) => {
+                let prototype = match &*self
+                    .running_thread()
+                    .borrow()
+                    .call_stack
+                    .last()
+                    .unwrap()
+                    .function
+                    .borrow()
+                {
+                    LuaFunction::LuaFunc(f) => Rc::clone(&f.prototype),
+                    _ => unreachable!("function must be LuaFunc"),
+                };
+                let key = &prototype.chunk.constants[key];
+                let cache = &prototype.chunk.caches[cache];
+                self.last_op = key.clone();
+                // existing key, or new key of table without metatable
+                let new_key = {
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    match &thread.data_stack[bp + table] {
+                        LuaValue::Table(table) => {
+                            let mut table = table.borrow_mut();
+                            let value = &thread.data_stack[bp + value];
+                            if let Some(slot) = table.get_field_mut(key, cache) {
+                                // if rhs is nil, remove the key
+                                if value.is_nil() {
+                                    table.remove(&LuaValue::String(key.clone()));
+                                } else {
+                                    *slot = value.clone();
+                                }
+                                return Ok(());
+                            }
+                            table.meta.is_none()
+                        }
+                        _ => false,
+                    }
+                };
+                if new_key {
+                    self.charge_table_entries(1)?;
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    if let LuaValue::Table(table) = &thread.data_stack[bp + table] {
+                        let value = thread.data_stack[bp + value].clone();
+                        table.borrow_mut().insert_field(key.clone(), value, cache);
+                    }
+                    return Ok(());
                 }
+                let (table, value) = {
+                    let thread = self.running_thread().borrow();
+                    let bp = thread.bp;
+                    (
+                        thread.data_stack[bp + table].clone(),
+                        thread.data_stack[bp + value].clone(),
+                    )
+                };
+                self.push3(value, table, LuaValue::String(key.clone()));
+                self.newindex()?;
             }
 
-            Instruction::FunctionUpvalue(upvalue_id, name) => {
-                self.last_op = name;
+            Instruction::FunctionInit(dst, index) => {
+                // nested functions share `_ENV` with the function that created them
+                let env = self.current_function_env();
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let prototype = match &*thread_mut.call_stack.last().unwrap().function.borrow() {
+                    LuaFunction::LuaFunc(f) => Rc::clone(&f.prototype.chunk.prototypes[index]),
+                    _ => unreachable!("function must be LuaFunc"),
+                };
+                let upvalues = prototype.upvalues;
+                let func = LuaFunctionLua {
+                    upvalues: Vec::with_capacity(upvalues),
+                    env: Some(env),
+                    prototype,
+                };
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = LuaFunction::LuaFunc(func).into();
+                drop(thread_mut);
+                self.charge_function(upvalues)?;
+            }
+            Instruction::FunctionInitUpvalueFromLocalVar(func, cell) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let upvalue = Rc::clone(thread_mut.call_stack.last_mut().unwrap().cell(cell));
+                Self::with_closure(&thread_mut, func, upvalue)?;
+            }
+            Instruction::FunctionInitUpvalueFromUpvalue(func, src_upvalue_id) => {
                 let thread = self.running_thread().borrow();
-                let func = thread.call_stack.last().unwrap().function.borrow();
+                let upvalue = match &*thread.call_stack.last().unwrap().function.borrow() {
+                    LuaFunction::LuaFunc(f) => Rc::clone(&f.upvalues[src_upvalue_id]),
+                    _ => {
+                        unreachable!("function must be LuaFunc");
+                    }
+                };
+                Self::with_closure(&thread, func, upvalue)?;
+            }
 
-                let val = match &*func {
-                    LuaFunction::LuaFunc(f) => RefCell::borrow(&f.upvalues[upvalue_id]).clone(),
+            Instruction::FunctionUpvalue(dst, upvalue_id) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let (value, name) = match &*thread_mut.call_stack.last().unwrap().function.borrow()
+                {
+                    LuaFunction::LuaFunc(f) => (
+                        RefCell::borrow(&f.upvalues[upvalue_id]).clone(),
+                        match f.prototype.chunk.upvalue_names.get(upvalue_id) {
+                            Some(name) if !name.is_empty() => {
+                                LuaString::from_slice(name.as_bytes())
+                            }
+                            _ => LuaString::from_static_str("?"),
+                        },
+                    ),
                     _ => {
                         unreachable!("function must be LuaFunc");
                     }
                 };
-                drop(func);
-                drop(thread);
-                self.push(val);
+                let bp = thread_mut.bp;
+                thread_mut.data_stack[bp + dst] = value;
+                drop(thread_mut);
+                self.last_op = name;
             }
-            Instruction::FunctionUpvalueSet(upvalue_id) => {
-                let top = self.pop();
+            Instruction::FunctionUpvalueSet(upvalue_id, src) => {
                 let thread = self.running_thread().borrow();
-                let mut func = thread.call_stack.last().unwrap().function.borrow_mut();
-                match &mut *func {
+                let value = thread.data_stack[thread.bp + src].clone();
+                match &*thread.call_stack.last().unwrap().function.borrow() {
                     LuaFunction::LuaFunc(f) => {
-                        f.upvalues[upvalue_id] = Rc::new(RefCell::new(top));
+                        // write into the shared cell; other closures and the enclosing local see the change
+                        *f.upvalues[upvalue_id].borrow_mut() = value;
                     }
                     _ => {
                         unreachable!("function must be LuaFunc");
                     }
-                }
+                };
             }
 
-            Instruction::BinaryAdd => {
-                self.add()?;
-            }
-            Instruction::BinarySub => {
-                self.sub()?;
-            }
-            Instruction::BinaryMul => {
-                self.mul()?;
-            }
-            Instruction::BinaryDiv => {
-                self.div()?;
-            }
-            Instruction::BinaryFloorDiv => {
-                self.idiv()?;
-            }
-            Instruction::BinaryMod => {
-                self.mod_()?;
-            }
-            Instruction::BinaryPow => {
-                self.pow()?;
-            }
-            Instruction::BinaryConcat => {
-                self.concat()?;
-            }
-            Instruction::BinaryBitwiseAnd => {
-                self.band()?;
+            Instruction::BinaryAdd(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => Some((*lhs + *rhs).into()),
+                    _ => None,
+                },
+                Self::add,
+            )?,
+            Instruction::BinarySub(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => Some((*lhs - *rhs).into()),
+                    _ => None,
+                },
+                Self::sub,
+            )?,
+            Instruction::BinaryMul(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => Some((*lhs * *rhs).into()),
+                    _ => None,
+                },
+                Self::mul,
+            )?,
+            Instruction::BinaryDiv(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => Some((*lhs / *rhs).into()),
+                    _ => None,
+                },
+                Self::div,
+            )?,
+            Instruction::BinaryFloorDiv(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::idiv)?
+            }
+            Instruction::BinaryMod(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    // integer modulo by zero raises error on the slow path
+                    (LuaValue::Number(_), LuaValue::Number(LuaNumber::Int(0))) => None,
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => Some((*lhs % *rhs).into()),
+                    _ => None,
+                },
+                Self::mod_,
+            )?,
+            Instruction::BinaryPow(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::pow)?
             }
-            Instruction::BinaryBitwiseOr => {
-                self.bor()?;
+            Instruction::BinaryConcat(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::concat)?
             }
-            Instruction::BinaryBitwiseXor => {
-                self.bxor()?;
+            Instruction::BinaryBitwiseAnd(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::band)?
             }
-            Instruction::BinaryShiftLeft => {
-                self.shl()?;
+            Instruction::BinaryBitwiseOr(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::bor)?
             }
-            Instruction::BinaryShiftRight => {
-                self.shr()?;
+            Instruction::BinaryBitwiseXor(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::bxor)?
             }
-            Instruction::BinaryEqual => {
-                self.eq()?;
+            Instruction::BinaryShiftLeft(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::shl)?
             }
-            Instruction::BinaryLessThan => {
-                self.lt()?;
+            Instruction::BinaryShiftRight(dst, lhs, rhs) => {
+                self.binary_op(dst, lhs, rhs, |_, _| None, Self::shr)?
             }
-            Instruction::BinaryLessEqual => {
-                self.le()?;
+            Instruction::BinaryEqual(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Table(lhs), LuaValue::Table(rhs)) => {
+                        Rc::ptr_eq(lhs, rhs).then_some(LuaValue::Boolean(true))
+                    }
+                    (lhs, rhs) => Some(LuaValue::Boolean(lhs == rhs)),
+                },
+                Self::eq,
+            )?,
+            Instruction::BinaryLessThan(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => {
+                        Some(LuaValue::Boolean(lhs < rhs))
+                    }
+                    _ => None,
+                },
+                Self::lt,
+            )?,
+            Instruction::BinaryLessEqual(dst, lhs, rhs) => self.binary_op(
+                dst,
+                lhs,
+                rhs,
+                |lhs, rhs| match (lhs, rhs) {
+                    (LuaValue::Number(lhs), LuaValue::Number(rhs)) => {
+                        Some(LuaValue::Boolean(lhs <= rhs))
+                    }
+                    _ => None,
+                },
+                Self::le,
+            )?,
+
+            Instruction::UnaryMinus(dst, src) => self.unary_op(
+                dst,
+                src,
+                |src| match src {
+                    LuaValue::Number(num) => Some((-*num).into()),
+                    _ => None,
+                },
+                Self::unm,
+            )?,
+            Instruction::UnaryBitwiseNot(dst, src) => {
+                self.unary_op(dst, src, |_| None, Self::bnot)?
+            }
+            Instruction::UnaryLength(dst, src) => self.unary_op(
+                dst,
+                src,
+                |src| match src {
+                    LuaValue::String(s) => Some((s.len() as IntType).into()),
+                    LuaValue::Table(table) => {
+                        let table = table.borrow();
+                        table
+                            .meta
+                            .is_none()
+                            .then(|| (table.len() as IntType).into())
+                    }
+                    _ => None,
+                },
+                Self::len,
+            )?,
+            Instruction::UnaryLogicalNot(dst, src) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let bp = thread_mut.bp;
+                let value = !thread_mut.data_stack[bp + src].to_bool();
+                thread_mut.data_stack[bp + dst] = value.into();
             }
 
-            Instruction::UnaryMinus => {
-                self.unm()?;
-            }
-            Instruction::UnaryBitwiseNot => {
-                self.bnot()?;
-            }
-            Instruction::UnaryLength => {
-                self.len()?;
+            Instruction::ForPrep(base, target) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let base = thread_mut.bp + base;
+                let start = Self::for_number(&thread_mut.data_stack[base], "initial value")?;
+                let limit = Self::for_number(&thread_mut.data_stack[base + 1], "limit")?;
+                let step = Self::for_number(&thread_mut.data_stack[base + 2], "step")?;
+                let skip = match (start, step) {
+                    (_, LuaNumber::Int(0)) => {
+                        return Err(RuntimeError::Custom("'for' step is zero".into()));
+                    }
+                    (LuaNumber::Int(start), LuaNumber::Int(step)) => {
+                        // limit is clipped to integer, in the direction of the loop
+                        let limit = match limit {
+                            LuaNumber::Int(limit) => Some(limit),
+                            LuaNumber::Float(limit) if limit.is_nan() => None,
+                            LuaNumber::Float(limit) if step > 0 => Some(limit.floor() as IntType),
+                            LuaNumber::Float(limit) => Some(limit.ceil() as IntType),
+                        };
+                        match limit {
+                            Some(limit) => {
+                                thread_mut.data_stack[base + 1] = limit.into();
+                                if step > 0 {
+                                    start > limit
+                                } else {
+                                    start < limit
+                                }
+                            }
+                            None => true,
+                        }
+                    }
+                    (start, step) => {
+                        let (start, limit, step) =
+                            (start.to_float(), limit.to_float(), step.to_float());
+                        if step == 0.0 {
+                            return Err(RuntimeError::Custom("'for' step is zero".into()));
+                        }
+                        thread_mut.data_stack[base] = start.into();
+                        thread_mut.data_stack[base + 1] = limit.into();
+                        thread_mut.data_stack[base + 2] = step.into();
+                        // NaN never runs the loop
+                        let run = if step > 0.0 {
+                            start <= limit
+                        } else {
+                            start >= limit
+                        };
+                        !run
+                    }
+                };
+                if skip {
+                    Self::jump(&mut thread_mut, target);
+                }
             }
-            Instruction::UnaryLogicalNot => {
-                let top = self.pop().to_bool();
-                self.push((!top).into());
+            Instruction::ForLoop(base, target) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let base = thread_mut.bp + base;
+                let next = match (
+                    &thread_mut.data_stack[base],
+                    &thread_mut.data_stack[base + 1],
+                    &thread_mut.data_stack[base + 2],
+                ) {
+                    (
+                        LuaValue::Number(LuaNumber::Int(i)),
+                        LuaValue::Number(LuaNumber::Int(limit)),
+                        LuaValue::Number(LuaNumber::Int(step)),
+                    ) => i.checked_add(*step).and_then(|i| {
+                        let next = if *step > 0 { i <= *limit } else { i >= *limit };
+                        next.then_some(LuaNumber::Int(i))
+                    }),
+                    (
+                        LuaValue::Number(LuaNumber::Float(i)),
+                        LuaValue::Number(LuaNumber::Float(limit)),
+                        LuaValue::Number(LuaNumber::Float(step)),
+                    ) => {
+                        let i = i + step;
+                        let next = if *step > 0.0 {
+                            i <= *limit
+                        } else {
+                            i >= *limit
+                        };
+                        next.then_some(LuaNumber::Float(i))
+                    }
+                    // only malformed code changes them after `ForPrep`
+                    _ => return Err(Malformed.into()),
+                };
+                if let Some(next) = next {
+                    thread_mut.data_stack[base] = next.into();
+                    Self::jump(&mut thread_mut, target);
+                }
             }
 
-            Instruction::FunctionCall(expected_ret) => {
-                let (func, num_args) = {
+            Instruction::FunctionCall(func, args_num, expected_ret) => {
+                let (func, args_num) = {
+                    let mut thread_mut = self.running_thread().borrow_mut();
+                    let base = thread_mut.bp + func;
+                    if let Some(args_num) = args_num {
+                        thread_mut.data_stack.truncate(base + 1 + args_num);
+                    }
+                    let args_num = thread_mut.data_stack.len() - base - 1;
+                    if expected_ret.is_some() {
+                        // results are left from `R[func]`; the frame is restored before the next instruction
+                        thread_mut.call_stack.last_mut().unwrap().pending_result = Some(func);
+                    }
+                    (thread_mut.data_stack.remove(base), args_num)
+                };
+                self.function_call(args_num, func, expected_ret)?;
+            }
+            Instruction::TailCall(func, args_num) => {
+                let (func, args_num) = {
                     let mut thread_mut = self.running_thread().borrow_mut();
-                    let func = thread_mut.data_stack.pop().unwrap();
-                    let sp = thread_mut.usize_stack.pop().unwrap();
-                    let num_args = thread_mut.data_stack.len() - sp;
-                    (func, num_args)
+                    let base = thread_mut.bp + func;
+                    if let Some(args_num) = args_num {
+                        thread_mut.data_stack.truncate(base + 1 + args_num);
+                    }
+                    let args_num = thread_mut.data_stack.len() - base - 1;
+                    (thread_mut.data_stack.remove(base), args_num)
                 };
-                self.function_call(num_args, func, expected_ret)?;
+                self.tail_call(args_num, func)?;
             }
 
-            Instruction::Return => {
+            Instruction::Return(base, count) => {
                 let mut thread_mut = self.running_thread().borrow_mut();
                 let frame = thread_mut.call_stack.pop().unwrap();
+                let from = thread_mut.bp + base;
+                let count = count.unwrap_or(thread_mut.data_stack.len().saturating_sub(from));
+                thread_mut.data_stack.truncate(from + count);
+                thread_mut.data_stack.drain(frame.data_stack..from);
                 if thread_mut.call_stack.is_empty() {
                     // end this thread
-                    thread_mut.set_dead();
                     drop(thread_mut);
-                    let yield_thread = self.coroutines.pop().unwrap();
-
-                    if !self.coroutines.is_empty() {
-                        let mut yield_thread_mut = yield_thread.borrow_mut();
-                        let mut resume_thread_mut = self.running_thread().borrow_mut();
-
-                        let return_args_num = yield_thread_mut.data_stack.len() - frame.data_stack;
-                        let resume_expected = match resume_thread_mut.status {
-                            ThreadStatus::ResumePending(expected) => expected,
-                            _ => unreachable!("coroutine must be in resume pending state"),
-                        };
-                        resume_thread_mut.status = ThreadStatus::Running;
-                        resume_thread_mut.data_stack.push(true.into());
-                        resume_thread_mut
-                            .data_stack
-                            .extend(yield_thread_mut.data_stack.drain(frame.data_stack..));
-                        if let Some(resume_expected) = resume_expected {
-                            let adjusted = resume_thread_mut.data_stack.len() - return_args_num - 1
-                                + resume_expected;
-                            resume_thread_mut
-                                .data_stack
-                                .resize_with(adjusted, Default::default);
-                        }
-                    }
+                    self.end_thread(frame.data_stack);
                 } else {
                     // return from function call
-                    thread_mut.local_variables.truncate(frame.local_variables);
-                    thread_mut.usize_stack.truncate(frame.usize_stack);
                     thread_mut.bp = frame.bp;
                     if let Some(expected) = frame.return_expected {
                         let adjusted = frame.data_stack + expected;
@@ -1482,34 +2247,24 @@ impl LuaEnv {
                 }
             }
 
-            Instruction::GetVariadic(expected) => {
-                if let Some(expected) = expected {
-                    let mut variadic = self
-                        .running_thread()
-                        .borrow()
-                        .call_stack
-                        .last()
-                        .unwrap()
-                        .variadic
-                        .clone();
-                    variadic.resize_with(expected, Default::default);
-                    self.running_thread()
-                        .borrow_mut()
-                        .data_stack
-                        .append(&mut variadic);
-                } else {
-                    let mut variadic = self
-                        .running_thread()
-                        .borrow()
-                        .call_stack
-                        .last()
-                        .unwrap()
-                        .variadic
-                        .clone();
-                    self.running_thread()
-                        .borrow_mut()
-                        .data_stack
-                        .append(&mut variadic);
+            Instruction::GetVariadic(dst, expected) => {
+                let mut thread_mut = self.running_thread().borrow_mut();
+                let thread_mut = &mut *thread_mut;
+                let dst = thread_mut.bp + dst;
+                let variadic = &thread_mut.call_stack.last().unwrap().variadic;
+                match expected {
+                    Some(expected) => {
+                        for (idx, value) in thread_mut.data_stack[dst..dst + expected]
+                            .iter_mut()
+                            .enumerate()
+                        {
+                            *value = variadic.get(idx).cloned().unwrap_or_default();
+                        }
+                    }
+                    None => {
+                        thread_mut.data_stack.truncate(dst);
+                        thread_mut.data_stack.extend_from_slice(variadic);
+                    }
                 }
             }
         }
@@ -1518,69 +2273,174 @@ impl LuaEnv {
 
     /// run single instruction
     pub fn cycle(&mut self) -> Result<(), RuntimeError> {
-        if self.coroutines.len() == 0 {
+        if self.coroutines.is_empty() {
             return Ok(());
         }
-        let mut thread_mut = self.running_thread().borrow_mut();
-        let frame_mut = thread_mut.call_stack.last_mut().unwrap();
+        let mut thread_ref = self.running_thread().borrow_mut();
+        let thread_mut = &mut *thread_ref;
+        let Some(frame_mut) = thread_mut.call_stack.last_mut() else {
+            // coroutine with Rust function body returned
+            drop(thread_ref);
+            self.end_thread(0);
+            return Ok(());
+        };
+        if frame_mut.continuation.is_some() {
+            // function call from Rust function returned; call its continuation
+            let frame = thread_mut.call_stack.pop().unwrap();
+            let returned = thread_mut.data_stack.len() - frame.data_stack;
+            drop(thread_ref);
+            return match self.call_continuation(frame, Ok(returned)) {
+                Ok(_) => Ok(()),
+                Err(err) => self.handle_error(err),
+            };
+        }
         let func = frame_mut.function.borrow();
         match &*func {
             LuaFunction::LuaFunc(f) => {
-                if let Some(instruction) = f.chunk.instructions.get(frame_mut.counter).cloned() {
+                if let Some(dst) = frame_mut.pending_result.take() {
+                    // restore registers of the frame after a call or metamethod returned
+                    let top = thread_mut.bp + f.prototype.chunk.stack_size;
+                    if thread_mut.data_stack.len() > top {
+                        let result = thread_mut.data_stack.pop().unwrap();
+                        thread_mut.data_stack.truncate(top);
+                        thread_mut.data_stack[thread_mut.bp + dst] = result;
+                    } else {
+                        thread_mut.data_stack.resize_with(top, Default::default);
+                    }
+                }
+                let counter = frame_mut.counter;
+                if counter < f.prototype.chunk.instructions.len() {
                     frame_mut.counter += 1;
+                    // keep the code alive while the instruction runs, without cloning it
+                    let prototype = Rc::clone(&f.prototype);
                     drop(func);
-                    drop(thread_mut);
-                    match self.run_instruction(instruction.clone()) {
-                        Ok(_) => return Ok(()),
-                        Err(err) => {
-                            // if this error was occured in main chunk, just return it
-                            if self.coroutines.len() == 1 {
-                                return Err(err);
-                            } else {
-                                // if this error was occured in coroutine, propagate it to parent coroutine
-                                let error_object = err.into_lua_value(self);
-
-                                // return 'false' and 'error_object' to parent's 'resume()'
-                                self.coroutines.pop().unwrap().borrow_mut().set_dead();
-                                let status = self.running_thread().borrow().status;
-                                if let ThreadStatus::ResumePending(resume_expected) = status {
-                                    match resume_expected {
-                                        Some(0) => {}
-                                        Some(1) => {
-                                            self.running_thread()
-                                                .borrow_mut()
-                                                .data_stack
-                                                .push(false.into());
-                                        }
-                                        Some(resume_expected) => {
-                                            self.push2(false.into(), error_object);
-                                            self.running_thread().borrow_mut().data_stack.extend(
-                                                std::iter::repeat(LuaValue::Nil)
-                                                    .take(resume_expected - 2),
-                                            );
-                                        }
-                                        None => {
-                                            self.push2(false.into(), error_object);
-                                        }
-                                    }
-                                } else {
-                                    unreachable!("coroutine must be in resume pending state");
-                                }
-                                self.running_thread().borrow_mut().status = ThreadStatus::Running;
-                            }
-                        }
+                    drop(thread_ref);
+                    if let Err(err) = self.count_instruction() {
+                        return self.handle_error(err);
+                    }
+                    self.dispatching = true;
+                    let res = self.run_instruction(&prototype.chunk.instructions[counter]);
+                    self.dispatching = false;
+                    match res {
+                        Ok(_) => Ok(()),
+                        Err(err) => self.handle_error(err),
                     }
-                    return Ok(());
                 } else {
-                    return Ok(());
+                    Ok(())
                 }
             }
-            _ => unimplemented!("cycle: function call from non-lua function"),
+            _ => unreachable!("cycle: Rust function frame without continuation"),
+        }
+    }
+
+    /// Handle error raised in the running thread.
+    ///
+    /// If there is a protected call in the running thread, unwind the stack to it and call its continuation.
+    /// If this error was occured in coroutine, propagate it to parent coroutine.
+    /// Otherwise, returns the error to the caller.
+    pub(crate) fn handle_error(&mut self, mut err: RuntimeError) -> Result<(), RuntimeError> {
+        if !err.is_catchable() {
+            return Err(err);
+        }
+        loop {
+            let thread = Rc::clone(self.running_thread());
+            let mut thread_mut = thread.borrow_mut();
+            let floor = thread_mut.boundaries.last().copied().unwrap_or(0);
+            let protected = (floor..thread_mut.call_stack.len()).rev().find(|&idx| {
+                thread_mut.call_stack[idx]
+                    .continuation
+                    .as_ref()
+                    .is_some_and(|k| k.protected)
+            });
+            if let Some(protected) = protected {
+                let frame = thread_mut.call_stack.drain(protected..).next().unwrap();
+                thread_mut.data_stack.truncate(frame.data_stack);
+                thread_mut.bp = frame.bp;
+                drop(thread_mut);
+                match self.call_continuation(frame, Err(err)) {
+                    Ok(_) => return Ok(()),
+                    Err(e) => {
+                        err = e;
+                        continue;
+                    }
+                }
+            }
+
+            // if this error was occured in main chunk, or in nested call from Rust function, just return it
+            if !thread_mut.boundaries.is_empty() || thread_mut.function.is_none() {
+                return Err(err);
+            }
+            drop(thread_mut);
+
+            // if this error was occured in coroutine, propagate it to parent coroutine
+            let error_object = err.into_lua_value(self);
+
+            // return 'false' and 'error_object' to parent's 'resume()'
+            self.coroutines.pop().unwrap().borrow_mut().set_dead();
+            let status = self.running_thread().borrow().status;
+            if let ThreadStatus::ResumePending(resume_expected) = status {
+                match resume_expected {
+                    Some(0) => {}
+                    Some(1) => {
+                        self.running_thread()
+                            .borrow_mut()
+                            .data_stack
+                            .push(false.into());
+                    }
+                    Some(resume_expected) => {
+                        self.push2(false.into(), error_object);
+                        self.running_thread()
+                            .borrow_mut()
+                            .data_stack
+                            .extend(std::iter::repeat_n(LuaValue::Nil, resume_expected - 2));
+                    }
+                    None => {
+                        self.push2(false.into(), error_object);
+                    }
+                }
+            } else {
+                unreachable!("coroutine must be in resume pending state");
+            }
+            self.running_thread().borrow_mut().status = ThreadStatus::Running;
+            return Ok(());
+        }
+    }
+
+    /// End the running thread.
+    /// Values on the data stack from `return_base` are the return values of the thread.
+    fn end_thread(&mut self, return_base: usize) {
+        let yield_thread = self.coroutines.pop().unwrap();
+        yield_thread.borrow_mut().set_dead();
+
+        // main threads spawned by `LuaEnv::run` hand their return values back to the caller;
+        // only coroutines resume their parent thread.
+        let is_coroutine = yield_thread.borrow().function.is_some();
+        if is_coroutine && !self.coroutines.is_empty() {
+            let mut yield_thread_mut = yield_thread.borrow_mut();
+            let mut resume_thread_mut = self.running_thread().borrow_mut();
+
+            let return_args_num = yield_thread_mut.data_stack.len() - return_base;
+            let resume_expected = match resume_thread_mut.status {
+                ThreadStatus::ResumePending(expected) => expected,
+                _ => unreachable!("coroutine must be in resume pending state"),
+            };
+            resume_thread_mut.status = ThreadStatus::Running;
+            resume_thread_mut.data_stack.push(true.into());
+            resume_thread_mut
+                .data_stack
+                .extend(yield_thread_mut.data_stack.drain(return_base..));
+            if let Some(resume_expected) = resume_expected {
+                let adjusted =
+                    resume_thread_mut.data_stack.len() - return_args_num - 1 + resume_expected;
+                resume_thread_mut
+                    .data_stack
+                    .resize_with(adjusted, Default::default);
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct CallStackFrame {
     pub function: Rc<RefCell<LuaFunction>>,
     /// current instruction counter
@@ -1591,14 +2451,51 @@ pub struct CallStackFrame {
     /// variadic arguments
     pub variadic: Vec<LuaValue>,
 
-    /// data_stack.len() to restore when return
+    /// data_stack.len() to restore when return; the first register of a Lua function
     pub data_stack: usize,
     /// bp to restore when return
     pub bp: usize,
-    /// local_variables.len() to restore when return
-    pub local_variables: usize,
-    // usize_stack.len() to restore when return
-    pub usize_stack: usize,
+    /// cells of captured local variables, by the register of the variable
+    pub cells: Vec<Rc<RefCell<LuaValue>>>,
+    /// set when a call or metamethod was made by the instruction being executed.
+    /// Before the next instruction, the registers of the frame are restored,
+    /// and a single value left above them is stored into this register.
+    pub pending_result: Option<usize>,
+
+    /// If this frame is for a Rust function waiting for a function call to return,
+    /// the continuation to be called.
+    pub(crate) continuation: Option<Continuation>,
+
+    /// `true` if this frame replaced the frame of its caller by a tail call
+    pub tail_call: bool,
+}
+
+impl CallStackFrame {
+    /// cell of captured local variable; a cell not initialized yet holds `nil`
+    pub(crate) fn cell(&mut self, cell: usize) -> &mut Rc<RefCell<LuaValue>> {
+        if self.cells.len() <= cell {
+            self.cells
+                .resize_with(cell + 1, || Rc::new(RefCell::new(LuaValue::Nil)));
+        }
+        &mut self.cells[cell]
+    }
+}
+
+/// Continuation of Rust function; see [`LuaEnv::call_k`].
+type ContinuationFn =
+    Box<dyn FnOnce(&mut LuaEnv, Result<usize, RuntimeError>) -> Result<(), RuntimeError>>;
+
+pub(crate) struct Continuation {
+    k: ContinuationFn,
+    /// if true, errors raised above this frame are caught and passed to `k`
+    protected: bool,
+}
+impl std::fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Continuation")
+            .field("protected", &self.protected)
+            .finish_non_exhaustive()
+    }
 }
 
 /// Status for Lua thread.
@@ -1637,27 +2534,20 @@ impl ThreadStatus {
 /// for error handling, recovering state
 #[derive(Debug, Clone)]
 pub struct ThreadState {
-    pub local_variables: usize,
     pub data_stack: usize,
-    pub usize_stack: usize,
     pub call_stack: usize,
     pub bp: usize,
 }
 
 /// Type for Lua thread.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct LuaThread {
-    /// local variable stack
-    pub local_variables: Vec<RefOrValue>,
-    /// offset of local variables for current scope
+    /// offset of the registers of the running Lua function
     pub bp: usize,
 
-    /// normal stack, for temporary values
+    /// registers of Lua functions, and values passed between functions
     pub data_stack: Vec<LuaValue>,
 
-    /// stack for storing usize values
-    pub usize_stack: Vec<usize>,
-
     // function object, variadic, return values multire expected count
     pub call_stack: Vec<CallStackFrame>,
 
@@ -1666,17 +2556,32 @@ pub struct LuaThread {
     /// If this thread is created by `coroutine.create`, this field is Some.
     /// The function object of the coroutine.
     pub function: Option<Rc<RefCell<LuaFunction>>>,
+
+    /// `call_stack.len()` of each Rust function waiting for a nested function call to return.
+    /// The thread cannot yield while it is not empty.
+    pub(crate) boundaries: Vec<usize>,
+
+    /// `Usage::epoch` and the largest size of the stacks in bytes charged in it
+    pub(crate) stack_charged: (u64, usize),
 }
 impl LuaThread {
     pub fn new_main(chunk: Chunk) -> LuaThread {
-        let mut local_variables = Vec::new();
-        local_variables.resize_with(chunk.stack_size, Default::default);
-        let func = LuaFunctionLua {
+        let prototype = Prototype {
             args: 0,
+            is_variadic: true,
+            upvalues: 0,
             chunk,
-            is_variadic: false,
-            upvalues: Vec::new(),
         };
+        Self::new_function(LuaFunctionLua {
+            upvalues: Vec::new(),
+            env: None,
+            prototype: Rc::new(prototype),
+        })
+    }
+    /// Main thread calling `func`, with its registers reserved.
+    fn new_function(func: LuaFunctionLua) -> LuaThread {
+        let mut data_stack = Vec::new();
+        data_stack.resize_with(func.prototype.chunk.stack_size, Default::default);
         let func = Rc::new(RefCell::new(LuaFunction::LuaFunc(func)));
         let frame = CallStackFrame {
             bp: 0,
@@ -1685,50 +2590,77 @@ impl LuaThread {
             return_expected: None,
             variadic: Vec::new(),
             data_stack: 0,
-            local_variables: 0,
-            usize_stack: 0,
+            cells: Vec::new(),
+            pending_result: None,
+            continuation: None,
+            tail_call: false,
         };
 
         LuaThread {
-            local_variables,
-            data_stack: Vec::new(),
-            usize_stack: Vec::new(),
+            data_stack,
             call_stack: vec![frame],
             bp: 0,
             status: ThreadStatus::Running,
             function: None,
+            boundaries: Vec::new(),
+            stack_charged: (0, 0),
         }
     }
+    /// Main thread running `chunk` with `env` as its `_ENV` table, and `args` as its arguments.
+    pub(crate) fn new_chunk(
+        chunk: &CompiledChunk,
+        env: Option<Rc<RefCell<LuaTable>>>,
+        args: Vec<LuaValue>,
+    ) -> LuaThread {
+        let mut thread = LuaThread::new_function(chunk.to_function(env));
+        let prototype = &chunk.prototype;
+        let mut args = args.into_iter();
+        for (local, arg) in thread
+            .data_stack
+            .iter_mut()
+            .zip(args.by_ref().take(prototype.args))
+        {
+            *local = arg;
+        }
+        if prototype.is_variadic {
+            thread.call_stack[0].variadic = args.collect();
+        }
+        thread
+    }
     pub fn new_coroutine(_env: &LuaEnv, func: Rc<RefCell<LuaFunction>>) -> LuaThread {
         LuaThread {
-            local_variables: Vec::new(),
             data_stack: Vec::new(),
-            usize_stack: Vec::new(),
             call_stack: Vec::new(),
             function: Some(func),
             bp: 0,
             status: ThreadStatus::NotStarted,
+            boundaries: Vec::new(),
+            stack_charged: (0, 0),
         }
     }
 
     pub(crate) fn to_state(&self) -> ThreadState {
         ThreadState {
-            local_variables: self.local_variables.len(),
             data_stack: self.data_stack.len(),
-            usize_stack: self.usize_stack.len(),
             call_stack: self.call_stack.len(),
             bp: self.bp,
         }
     }
     pub(crate) fn from_state(&mut self, state: ThreadState) {
         debug_assert!(self.status == ThreadStatus::Running);
-        self.local_variables.truncate(state.local_variables);
         self.data_stack.truncate(state.data_stack);
-        self.usize_stack.truncate(state.usize_stack);
         self.call_stack.truncate(state.call_stack);
         self.bp = state.bp;
     }
 
+    /// end of the registers of the running Lua function
+    pub(crate) fn frame_top(&self) -> usize {
+        match &*self.call_stack.last().unwrap().function.borrow() {
+            LuaFunction::LuaFunc(f) => self.bp + f.prototype.chunk.stack_size,
+            _ => unreachable!("registers of non-lua function"),
+        }
+    }
+
     pub fn drain_last(&mut self, n: usize) -> impl Iterator<Item = LuaValue> + '_ {
         self.data_stack.drain(self.data_stack.len() - n..)
     }
@@ -1741,18 +2673,154 @@ impl LuaThread {
     }
 }
 
+/// Generated code of a function body, or of a main chunk.
 #[derive(Debug, Clone)]
 pub struct Chunk {
+    /// jump targets are instruction indices
     pub instructions: Vec<Instruction>,
-    pub label_map: Vec<usize>,
+    /// number of registers
     pub stack_size: usize,
+    /// source line of each instruction, or empty if there is no debug information
+    pub lines: Vec<u32>,
+    /// string constants, by `String`, `GetField` and `SetField`
+    pub constants: Vec<LuaString>,
+    /// inline caches, by `GetField` and `SetField`
+    pub(crate) caches: Vec<FieldCache>,
+    /// name of the local variable in each register, or empty if there is no debug information
+    pub local_names: Vec<String>,
+    /// name of each upvalue, or empty if there is no debug information
+    pub upvalue_names: Vec<String>,
+    /// name of the chunk this code was compiled from, for debug information
+    pub source: Rc<str>,
+    /// prototypes of the functions defined in this code, by `FunctionInit`
+    pub prototypes: Vec<Rc<Prototype>>,
 }
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             instructions: Vec::new(),
-            label_map: Vec::new(),
             stack_size: 0,
+            lines: Vec::new(),
+            constants: Vec::new(),
+            caches: Vec::new(),
+            local_names: Vec::new(),
+            upvalue_names: Vec::new(),
+            source: Rc::from("?"),
+            prototypes: Vec::new(),
+        }
+    }
+}
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A chunk compiled by [`LuaEnv::compile`], or loaded from a binary chunk by [`CompiledChunk::undump`].
+///
+/// Running a compiled chunk skips tokenizing, parsing and semantic analysis.
+/// Cloning it is cheap; the compiled code is shared between clones.
+/// It is not `Send`, since the compiled code is shared through `Rc`
+/// and its inline caches are updated through `Cell`.
+#[derive(Debug, Clone)]
+pub struct CompiledChunk {
+    /// function prototype of the chunk; 0 fixed parameters and variadic for chunks compiled from source.
+    /// Its upvalues are set to `nil` each time the chunk is run;
+    /// none for chunks compiled from source, since `_ENV` is not an upvalue.
+    pub(crate) prototype: Rc<Prototype>,
+    pub(crate) name: Rc<str>,
+}
+impl CompiledChunk {
+    /// name of the chunk, given to [`LuaEnv::compile`]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn new_upvalues(&self) -> Vec<Rc<RefCell<LuaValue>>> {
+        (0..self.prototype.upvalues)
+            .map(|_| Rc::new(RefCell::new(LuaValue::Nil)))
+            .collect()
+    }
+    /// Lua function running this chunk with `env` as its `_ENV` table.
+    pub(crate) fn to_function(&self, env: Option<Rc<RefCell<LuaTable>>>) -> LuaFunctionLua {
+        LuaFunctionLua {
+            upvalues: self.new_upvalues(),
+            env,
+            prototype: Rc::clone(&self.prototype),
+        }
+    }
+}
+
+/// tokenize and parse lua chunk from `source`.
+fn parse_chunk(source: &[u8]) -> Result<lua_parser::Block, RuntimeError> {
+    let (chunk, _) = parse(source)?;
+    chunk.ok_or_else(|| RuntimeError::Custom("no statement found".into()))
+}
+
+/// tokenize and parse lua chunk or expression list from `source`.
+/// Expression list is wrapped into a chunk returning its values.
+pub(crate) fn parse_chunk_or_expressions(source: &[u8]) -> Result<lua_parser::Block, RuntimeError> {
+    match parse(source)? {
+        // prefer expression to statement, if both are matched.
+        (_, Some(exprs)) => {
+            let ret = lua_parser::ReturnStatement::new(exprs, lua_parser::Span::new_none());
+            Ok(lua_parser::Block::new(
+                Vec::new(),
+                Some(ret),
+                lua_parser::Span::new_none(),
+            ))
+        }
+        (Some(chunk), None) => Ok(chunk),
+        (None, None) => Err(RuntimeError::Custom("no statement found".into())),
+    }
+}
+
+/// tokenize and parse `source`, returns matched chunk and expression list.
+fn parse(
+    source: &[u8],
+) -> Result<
+    (
+        Option<lua_parser::Block>,
+        Option<Vec<lua_parser::Expression>>,
+    ),
+    RuntimeError,
+> {
+    let mut context = lua_parser::Context::new(());
+    for token in lua_tokenizer::Tokenizer::from_bytes(source) {
+        match token {
+            Ok(token) => {
+                if let Err(err) = context.feed(token) {
+                    return Err(RuntimeError::Custom(err.to_string().into()));
+                }
+            }
+            Err(err) => {
+                return Err(RuntimeError::TokenizeError(err));
+            }
+        }
+    }
+
+    match context.accept_all() {
+        Ok(matched) => {
+            let mut matched_stmt = None;
+            let mut matched_expr = None;
+            for (m, _) in matched {
+                match m {
+                    lua_parser::ChunkOrExpressions::Chunk(chunk) => {
+                        if matched_stmt.is_some() {
+                            return Err(RuntimeError::Custom("ambiguous statement".into()));
+                        }
+                        matched_stmt = Some(chunk);
+                    }
+                    lua_parser::ChunkOrExpressions::Expressions(exprs) => {
+                        if matched_expr.is_some() {
+                            return Err(RuntimeError::Custom("ambiguous expression".into()));
+                        }
+                        matched_expr = Some(exprs);
+                    }
+                }
+            }
+            Ok((matched_stmt, matched_expr))
         }
+        Err(err) => Err(RuntimeError::Custom(err.to_string().into())),
     }
 }
@@ -11,6 +11,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
+#[derive(Clone)]
 pub struct Context {
     pub scopes: Vec<Scope>,
     pub scope_counter: usize,
@@ -98,6 +99,10 @@ impl Context {
                 Scope::Block(blk) => {
                     for var in blk.variables.iter().rev() {
                         if var.borrow().name == name {
+                            if !function_scopes.is_empty() {
+                                // captured by a nested function
+                                var.borrow_mut().is_reference = true;
+                            }
                             found = Some(ExprLocalVariable::Stack(
                                 var.borrow().offset,
                                 name.to_string(),
@@ -131,7 +136,9 @@ impl Context {
             None
         }
     }
-    fn begin_function_scope(&mut self, variadic: bool) {
+    /// open a new function scope.
+    /// Call this before processing a chunk to treat it as the body of a (variadic) function.
+    pub fn begin_function_scope(&mut self, variadic: bool) {
         self.scope_counter += 1;
         self.scopes.push(Scope::Function(ScopeFunction {
             id: self.scope_counter,
@@ -186,7 +193,9 @@ impl Context {
 
         let mut blk = crate::Block::new(Vec::with_capacity(block.statements.len()), None, None);
         for stmt in block.statements.into_iter() {
+            let span = stmt.span();
             self.process_statement(stmt, &mut blk)?;
+            blk.spans.resize(blk.statements.len(), span);
         }
         if let Some(ret) = block.return_statement {
             self.process_return_statement(ret, &mut blk)?;
@@ -237,10 +246,7 @@ impl Context {
                 self.process_statement_function_call(v, blk)?
             }
 
-            _ => {
-                let message = format!("{:?}", stmt);
-                unimplemented!("{}", message);
-            }
+            _ => return Err(ProcessError::Unsupported(stmt.span())),
         }
         Ok(())
     }
@@ -262,10 +268,7 @@ impl Context {
             lua_parser::Expression::Binary(v) => self.process_expression_binary(v),
             lua_parser::Expression::Unary(v) => self.process_expression_unary(v),
 
-            _ => {
-                let message = format!("{:?}", expr);
-                unimplemented!("{}", message);
-            }
+            _ => Err(ProcessError::Unsupported(expr.span())),
         }
     }
 }
@@ -587,6 +590,9 @@ impl Context {
         */
 
         let varinfo = self.begin_variable_scope(stmt.name.to_string());
+        blk.statements.push(crate::Statement::LocalDeclaration(
+            crate::StmtLocalDeclaration::new(vec![(Rc::clone(&varinfo), None)], None),
+        ));
         let func_expr = self.process_expression_function(stmt.body)?;
 
         let var_expr = crate::Expression::LocalVariable(crate::ExprLocalVariable::Stack(
@@ -764,11 +770,12 @@ impl Context {
         stmt: lua_parser::ReturnStatement,
         blk: &mut crate::Block,
     ) -> Result<(), ProcessError> {
+        let span = stmt.span();
         let mut exprs = Vec::with_capacity(stmt.values.len());
         for expr in stmt.values.into_iter() {
             exprs.push(self.process_expression(expr)?);
         }
-        let ret_stmt = crate::ReturnStatement::new(exprs);
+        let ret_stmt = crate::ReturnStatement::new(exprs, span);
         blk.return_statement = Some(ret_stmt);
         Ok(())
     }
@@ -868,10 +875,12 @@ impl Context {
         let span = stmt.span();
         let name = stmt.name.string;
         let scope_tree = self.scope_tree();
-        let label = self
-            .labels
-            .entry(name)
-            .or_insert_with(|| Rc::new(RefCell::new(LabelInfo::default())));
+        let label = self.labels.entry(name.clone()).or_insert_with(|| {
+            Rc::new(RefCell::new(LabelInfo {
+                name,
+                ..Default::default()
+            }))
+        });
         label.borrow_mut().add_from(scope_tree, span)?;
         let goto_stmt = crate::Statement::Goto(crate::StmtGoto::new(Rc::clone(label)));
         blk.statements.push(goto_stmt);
@@ -11,6 +11,8 @@ pub enum ProcessError {
     BreakOutsideLoop(Span),
     InvalidGotoScope(Span, Span),
     InvalidLabel(Span),
+    /// syntax not supported yet
+    Unsupported(Span),
 }
 
 impl Display for ProcessError {
@@ -28,6 +30,7 @@ impl Display for ProcessError {
                 write!(f, "Invalid goto")
             }
             ProcessError::InvalidLabel(_) => write!(f, "Invalid label"),
+            ProcessError::Unsupported(_) => write!(f, "Unsupported syntax"),
         }
     }
 }
@@ -64,6 +67,9 @@ impl ProcessError {
             ProcessError::InvalidLabel(span) => Diagnostic::error()
                 .with_message(message)
                 .with_labels(vec![Label::primary(fileid, *span)]),
+            ProcessError::Unsupported(span) => Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![Label::primary(fileid, *span)]),
         }
     }
 }
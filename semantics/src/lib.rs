@@ -17,6 +17,7 @@ mod statement;
 pub use lua_parser::FloatType;
 pub use lua_parser::IntOrFloat;
 pub use lua_parser::IntType;
+pub use lua_parser::Span;
 
 pub use expression::ExprBinary;
 pub use expression::ExprBinaryData;
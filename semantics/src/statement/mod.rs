@@ -1,4 +1,5 @@
 use crate::Expression;
+use crate::Span;
 
 /// block of statements.
 /// return statement must be optionally placed at the end of the block.
@@ -9,6 +10,9 @@ pub struct Block {
     /// The number of local variables required by this block.
     /// This field is `Some` if the block itself represents a scope.
     pub stack_size: Option<usize>,
+    /// Source span of each statement in `statements`, for debug information.
+    /// May be shorter than `statements` for blocks not generated from source code.
+    pub spans: Vec<Span>,
 }
 impl Block {
     pub fn new(
@@ -20,6 +24,7 @@ impl Block {
             statements,
             return_statement,
             stack_size,
+            spans: Vec::new(),
         }
     }
 }
@@ -28,10 +33,11 @@ impl Block {
 #[derive(Clone, Debug)]
 pub struct ReturnStatement {
     pub values: Vec<Expression>,
+    pub span: Span,
 }
 impl ReturnStatement {
-    pub fn new(values: Vec<Expression>) -> Self {
-        Self { values }
+    pub fn new(values: Vec<Expression>, span: Span) -> Self {
+        Self { values, span }
     }
 }
 